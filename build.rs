@@ -0,0 +1,25 @@
+use std::path::PathBuf;
+
+// The CLI's definition, for man pages matching the binary being built
+#[allow(dead_code)]
+#[path = "src/options.rs"]
+mod options;
+#[allow(dead_code)]
+#[path = "src/ux.rs"]
+mod ux;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=src/options.rs");
+    println!("cargo:rerun-if-changed=src/ux.rs");
+    // Packagers pick them up from `$OUT_DIR/man`, or with `s3sync man <dir>`
+    ux::man_pages(&PathBuf::from(std::env::var("OUT_DIR")?).join("man"))?;
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/control.proto");
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/control.proto"], &["proto"])?;
+    }
+    Ok(())
+}
@@ -0,0 +1,250 @@
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{header::AUTHORIZATION, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use globset::Glob;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+use tokio::sync::{mpsc, Mutex};
+
+use super::{Control, Error, Manager};
+
+const TOKEN_ENV: &str = "S3SYNC_API_TOKEN";
+
+/// HTTP API for adding, changing and removing agents at runtime
+#[derive(Deserialize, Debug, Clone)]
+pub struct ApiSettings {
+    pub listen: SocketAddr,
+    /// Bearer token required on every request, defaults to `S3SYNC_API_TOKEN`
+    token: Option<String>,
+}
+
+impl ApiSettings {
+    /// Serve the API in the background. Changes are written to `config_path` and the new
+    /// contents are sent on `reload` for the watch loop to pick up, while `control` reaches
+    /// the running agents' queues.
+    pub async fn serve(
+        &self,
+        config_path: PathBuf,
+        reload: mpsc::Sender<String>,
+        control: Control,
+    ) -> Result<(), Error> {
+        let token = self
+            .token
+            .clone()
+            .or_else(|| std::env::var(TOKEN_ENV).ok())
+            .filter(|token| !token.is_empty())
+            .ok_or(Error::MissingApiToken)?;
+        let state = Arc::new(ApiState {
+            token,
+            config_path,
+            reload,
+            control,
+            lock: Mutex::new(()),
+        });
+        let app = Router::new()
+            .route("/agents", get(list_agents))
+            .route(
+                "/agents/:name",
+                get(get_agent).put(put_agent).delete(delete_agent),
+            )
+            .route("/prioritize", post(prioritize))
+            .route_layer(middleware::from_fn_with_state(state.clone(), authorize))
+            .with_state(state);
+        let listener = tokio::net::TcpListener::bind(self.listen).await?;
+        tracing::info!("Serving API on {}", self.listen);
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, app).await {
+                tracing::error!("API server stopped: {e}");
+            }
+        });
+        Ok(())
+    }
+}
+
+struct ApiState {
+    token: String,
+    config_path: PathBuf,
+    reload: mpsc::Sender<String>,
+    control: Control,
+    /// Serializes read-modify-write cycles of the config file
+    lock: Mutex<()>,
+}
+
+impl ApiState {
+    async fn read(&self) -> Result<Value, ApiError> {
+        let contents = tokio::fs::read_to_string(&self.config_path).await?;
+        Ok(serde_yaml::from_str(&contents)?)
+    }
+
+    /// Validate, persist and apply a modified config
+    async fn write(&self, config: &Value) -> Result<(), ApiError> {
+        let contents = serde_yaml::to_string(config)?;
+        Manager::from_yaml(&contents).map_err(|e| ApiError::Invalid(e.to_string()))?;
+        tokio::fs::write(&self.config_path, &contents).await?;
+        let _ = self.reload.send(contents).await;
+        Ok(())
+    }
+}
+
+async fn authorize(State(state): State<Arc<ApiState>>, request: Request, next: Next) -> Response {
+    let provided = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if provided
+        .is_some_and(|provided| constant_time_eq(provided.as_bytes(), state.token.as_bytes()))
+    {
+        next.run(request).await
+    } else {
+        StatusCode::UNAUTHORIZED.into_response()
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn agents(config: &mut Value) -> Result<&mut Vec<Value>, ApiError> {
+    config
+        .get_mut("agents")
+        .and_then(Value::as_sequence_mut)
+        .ok_or_else(|| ApiError::Invalid(String::from("Invalid config: no agents list")))
+}
+
+fn position(agents: &[Value], name: &str) -> Option<usize> {
+    agents
+        .iter()
+        .position(|agent| agent.get("name").and_then(Value::as_str) == Some(name))
+}
+
+async fn list_agents(State(state): State<Arc<ApiState>>) -> Result<Json<Value>, ApiError> {
+    let mut config = state.read().await?;
+    Ok(Json(Value::Sequence(agents(&mut config)?.clone())))
+}
+
+async fn get_agent(
+    State(state): State<Arc<ApiState>>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let mut config = state.read().await?;
+    let agents = agents(&mut config)?;
+    let index = position(agents, &name).ok_or(ApiError::NotFound)?;
+    Ok(Json(agents[index].clone()))
+}
+
+/// Create or replace the named agent
+async fn put_agent(
+    State(state): State<Arc<ApiState>>,
+    Path(name): Path<String>,
+    Json(agent): Json<serde_json::Value>,
+) -> Result<StatusCode, ApiError> {
+    let mut agent = serde_yaml::to_value(agent)?;
+    let Some(fields) = agent.as_mapping_mut() else {
+        return Err(ApiError::Invalid(String::from("Agent must be an object")));
+    };
+    fields.insert(Value::from("name"), Value::from(name.as_str()));
+    let _guard = state.lock.lock().await;
+    let mut config = state.read().await?;
+    let agents = agents(&mut config)?;
+    let status = if let Some(index) = position(agents, &name) {
+        agents[index] = agent;
+        StatusCode::OK
+    } else {
+        agents.push(agent);
+        StatusCode::CREATED
+    };
+    state.write(&config).await?;
+    tracing::info!("Agent '{name}' saved via API");
+    Ok(status)
+}
+
+async fn delete_agent(
+    State(state): State<Arc<ApiState>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let _guard = state.lock.lock().await;
+    let mut config = state.read().await?;
+    let agents = agents(&mut config)?;
+    let index = position(agents, &name).ok_or(ApiError::NotFound)?;
+    agents.remove(index);
+    state.write(&config).await?;
+    tracing::info!("Agent '{name}' removed via API");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Files to upload ahead of the rest of the queue, e.g. an export needed during an incident
+#[derive(Deserialize, Debug)]
+struct Prioritize {
+    /// Glob over full local paths, e.g. `/data/exports/2024-06-01*`
+    glob: String,
+    /// Only this agent's queue, rather than every agent's
+    agent: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct Prioritized {
+    prioritized: usize,
+}
+
+async fn prioritize(
+    State(state): State<Arc<ApiState>>,
+    Json(request): Json<Prioritize>,
+) -> Result<Json<Prioritized>, ApiError> {
+    let glob = Glob::new(&request.glob)
+        .map_err(|e| ApiError::Invalid(e.to_string()))?
+        .compile_matcher();
+    let agents: Vec<_> = state
+        .control
+        .agents()
+        .into_iter()
+        .filter(|agent| {
+            request
+                .agent
+                .as_ref()
+                .is_none_or(|name| *name == agent.name)
+        })
+        .collect();
+    if agents.is_empty() && request.agent.is_some() {
+        return Err(ApiError::NotFound);
+    }
+    let prioritized = agents
+        .iter()
+        .map(|agent| agent.deferred.prioritize(&glob))
+        .sum();
+    tracing::info!(
+        "Prioritized {prioritized} queued files matching '{}' via API",
+        request.glob
+    );
+    Ok(Json(Prioritized { prioritized }))
+}
+
+#[derive(thiserror::Error, Debug)]
+enum ApiError {
+    #[error("Agent not found")]
+    NotFound,
+    #[error("{0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Yaml(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Invalid(_) | Self::Yaml(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.to_string()).into_response()
+    }
+}
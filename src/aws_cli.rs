@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+
+/// Transfer tuning from the profile's nested `s3` section in the aws-cli config file, so
+/// settings already made for `aws s3` carry over
+///
+/// ```ini
+/// [profile foo]
+/// s3 =
+///   max_concurrent_requests = 20
+///   multipart_threshold = 64MB
+///   multipart_chunksize = 16MB
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct S3Defaults {
+    pub max_concurrent_requests: Option<usize>,
+    pub multipart_threshold: Option<u64>,
+    pub multipart_chunksize: Option<u64>,
+}
+
+impl S3Defaults {
+    /// Read the profile's values from `config_file`, or else `AWS_CONFIG_FILE` or
+    /// `~/.aws/config`, anything missing or unparsable is left unset
+    pub fn load(profile_name: &str, config_file: Option<&Path>) -> Self {
+        let Some(path) = config_file
+            .map(Path::to_path_buf)
+            .or_else(default_config_file)
+        else {
+            return Self::default();
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => Self::parse(&contents, profile_name),
+            Err(e) => {
+                tracing::debug!("No aws-cli config at '{}': {e}", path.display());
+                Self::default()
+            }
+        }
+    }
+
+    fn parse(contents: &str, profile_name: &str) -> Self {
+        let mut defaults = Self::default();
+        let mut in_profile = false;
+        let mut in_s3 = false;
+        for line in contents.lines() {
+            let line = line.split(['#', ';']).next().unwrap_or_default();
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(section) = line.trim().strip_prefix('[') {
+                let section = section.trim_end_matches(']').trim();
+                let name = section.strip_prefix("profile ").map_or(section, str::trim);
+                in_profile = name == profile_name;
+                in_s3 = false;
+                continue;
+            }
+            if !in_profile {
+                continue;
+            }
+            let nested = line.starts_with([' ', '\t']);
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            if !nested {
+                in_s3 = key == "s3" && value.is_empty();
+                continue;
+            }
+            if !in_s3 {
+                continue;
+            }
+            match key {
+                "max_concurrent_requests" => {
+                    defaults.max_concurrent_requests = parse_value(key, value, str::parse);
+                }
+                "multipart_threshold" => {
+                    defaults.multipart_threshold = parse_value(key, value, parse_size);
+                }
+                "multipart_chunksize" => {
+                    defaults.multipart_chunksize = parse_value(key, value, parse_size);
+                }
+                _ => {}
+            }
+        }
+        defaults
+    }
+}
+
+fn default_config_file() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("AWS_CONFIG_FILE") {
+        return Some(PathBuf::from(path));
+    }
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(".aws").join("config"))
+}
+
+fn parse_value<T, E: std::fmt::Display>(
+    key: &str,
+    value: &str,
+    parse: impl Fn(&str) -> Result<T, E>,
+) -> Option<T> {
+    parse(value)
+        .inspect_err(|e| tracing::warn!("Ignoring aws-cli s3 {key} '{value}': {e}"))
+        .ok()
+}
+
+/// Byte count with an optional aws-cli suffix, which are all powers of 1024 (`KB` and `KiB`
+/// alike)
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(digits);
+    let number: u64 = number.parse().map_err(|e| format!("{e}"))?;
+    let shift = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "KB" | "KIB" => 10,
+        "MB" | "MIB" => 20,
+        "GB" | "GIB" => 30,
+        "TB" | "TIB" => 40,
+        other => return Err(format!("unknown size suffix '{other}'")),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| String::from("size too large"))
+}
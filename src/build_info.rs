@@ -0,0 +1,66 @@
+use std::fmt::Write;
+
+use notify_debouncer_mini::notify::RecommendedWatcher;
+
+/// Cargo features and whether this build has them
+const FEATURES: &[(&str, bool)] = &[
+    ("api", cfg!(feature = "api")),
+    ("cloudwatch", cfg!(feature = "cloudwatch")),
+    ("dynamodb", cfg!(feature = "dynamodb")),
+    ("grpc", cfg!(feature = "grpc")),
+    ("metrics-server", cfg!(feature = "metrics-server")),
+    ("otlp", cfg!(feature = "otlp")),
+    ("self-update", cfg!(feature = "self-update")),
+    ("web-ui", cfg!(feature = "web-ui")),
+];
+
+/// `--version --verbose` output, what a bug report needs to know about the build and host
+#[must_use]
+pub fn report() -> String {
+    let mut report = format!("s3sync {}\n", env!("CARGO_PKG_VERSION"));
+    let features: Vec<_> = FEATURES
+        .iter()
+        .map(|(name, enabled)| format!("{}{name}", if *enabled { '+' } else { '-' }))
+        .collect();
+    let _ = writeln!(report, "features: {}", features.join(" "));
+    let _ = writeln!(
+        report,
+        "watcher: {}",
+        std::any::type_name::<RecommendedWatcher>()
+    );
+    let _ = writeln!(report, "aws-sdk-s3: {}", aws_sdk_s3::meta::PKG_VERSION);
+    let _ = writeln!(
+        report,
+        "platform: {} {}",
+        std::env::consts::OS,
+        std::env::consts::ARCH
+    );
+    for (name, value) in platform_limits() {
+        let _ = writeln!(report, "{name}: {value}");
+    }
+    report
+}
+
+/// inotify limits, the usual reason large trees stop being watched on Linux
+#[cfg(target_os = "linux")]
+fn platform_limits() -> Vec<(&'static str, String)> {
+    [
+        "max_user_watches",
+        "max_user_instances",
+        "max_queued_events",
+    ]
+    .into_iter()
+    .map(|name| {
+        let value = std::fs::read_to_string(format!("/proc/sys/fs/inotify/{name}")).map_or_else(
+            |e| format!("unknown ({e})"),
+            |value| value.trim().to_string(),
+        );
+        (name, value)
+    })
+    .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+const fn platform_limits() -> Vec<(&'static str, String)> {
+    Vec::new()
+}
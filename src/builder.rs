@@ -0,0 +1,427 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+
+use regex::Regex;
+
+use super::{
+    globs::Globs, tune::AutoTune, Agent, AgentWatcher, Backend, Checksum, Collision, Deferred,
+    Error, Manager, MatchOn, OnSuccess, Placeholder, Provider, Queue, Sse, StorageClass,
+    UploadOrder, Vanished,
+};
+
+/// A required [`AgentBuilder`] field that hasn't been set yet
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Unset;
+
+/// Fluent construction of an [`Agent`] for programs embedding the sync engine.
+///
+/// The watched path and the bucket are required by the type, `build` only exists once
+/// both are set, and the remaining settings are checked when building:
+///
+/// ```ignore
+/// let agent = Agent::builder()
+///     .path("/var/log/app")
+///     .bucket("logs")
+///     .pattern(Regex::new(r"\.log$")?)
+///     .build()?;
+/// ```
+#[derive(Debug, Clone, Default)]
+#[must_use]
+pub struct AgentBuilder<P = Unset, B = Unset> {
+    path: P,
+    bucket: B,
+    include: Vec<String>,
+    exclude_globs: Vec<String>,
+    agent: Agent,
+}
+
+impl Agent {
+    pub fn builder() -> AgentBuilder {
+        AgentBuilder::default()
+    }
+}
+
+impl<P, B> AgentBuilder<P, B> {
+    /// Local directory or single file to sync
+    pub fn path(self, path: impl Into<PathBuf>) -> AgentBuilder<PathBuf, B> {
+        AgentBuilder {
+            path: path.into(),
+            bucket: self.bucket,
+            include: self.include,
+            exclude_globs: self.exclude_globs,
+            agent: self.agent,
+        }
+    }
+
+    pub fn bucket(self, bucket: impl Into<String>) -> AgentBuilder<P, String> {
+        AgentBuilder {
+            path: self.path,
+            bucket: bucket.into(),
+            include: self.include,
+            exclude_globs: self.exclude_globs,
+            agent: self.agent,
+        }
+    }
+
+    /// Name identifying the agent in logs, and in the state database
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.agent.name = Some(name.into());
+        self
+    }
+
+    /// Prepended to every key
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.agent.key_prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn pattern(mut self, pattern: Regex) -> Self {
+        self.agent.pattern = Some(pattern);
+        self
+    }
+
+    /// Glob a key must match (e.g. `**/*.csv`), on top of `pattern`. Repeatable, matching
+    /// any of them.
+    pub fn include(mut self, glob: impl Into<String>) -> Self {
+        self.include.push(glob.into());
+        self
+    }
+
+    /// Regex dropping keys the other filters kept. Repeatable, any match excludes.
+    pub fn exclude(mut self, pattern: Regex) -> Self {
+        self.agent
+            .exclude
+            .get_or_insert_with(Vec::new)
+            .push(pattern);
+        self
+    }
+
+    /// Glob dropping keys the other filters kept (e.g. `**/*.tmp`). Repeatable.
+    pub fn exclude_glob(mut self, glob: impl Into<String>) -> Self {
+        self.exclude_globs.push(glob.into());
+        self
+    }
+
+    pub const fn match_on(mut self, match_on: MatchOn) -> Self {
+        self.agent.match_on = Some(match_on);
+        self
+    }
+
+    /// AWS credential profile
+    pub fn profile(mut self, profile: impl Into<String>) -> Self {
+        self.agent.profile_name = Some(profile.into());
+        self
+    }
+
+    /// IAM role to assume with the profile's credentials
+    pub fn role_arn(mut self, role_arn: impl Into<String>) -> Self {
+        self.agent.role_arn = Some(role_arn.into());
+        self
+    }
+
+    pub fn external_id(mut self, external_id: impl Into<String>) -> Self {
+        self.agent.external_id = Some(external_id.into());
+        self
+    }
+
+    pub fn session_name(mut self, session_name: impl Into<String>) -> Self {
+        self.agent.session_name = Some(session_name.into());
+        self
+    }
+
+    pub fn region(mut self, region: impl Into<String>) -> Self {
+        self.agent.region_name = Some(region.into());
+        self
+    }
+
+    /// S3-compatible service to use instead of AWS
+    pub const fn provider(mut self, provider: Provider) -> Self {
+        self.agent.provider = Some(provider);
+        self
+    }
+
+    /// S3-compatible server to use instead of AWS, e.g. MinIO or LocalStack
+    pub fn endpoint_url(mut self, endpoint_url: impl Into<String>) -> Self {
+        self.agent.endpoint_url = Some(endpoint_url.into());
+        self
+    }
+
+    pub const fn force_path_style(mut self, force_path_style: bool) -> Self {
+        self.agent.force_path_style = Some(force_path_style);
+        self
+    }
+
+    /// Cloudflare account id, required with [`Provider::R2`]
+    pub fn account_id(mut self, account_id: impl Into<String>) -> Self {
+        self.agent.account_id = Some(account_id.into());
+        self
+    }
+
+    pub const fn recursive(mut self, recursive: bool) -> Self {
+        self.agent.watcher.settings.recursive = Some(recursive);
+        self
+    }
+
+    /// Where file system events come from
+    pub const fn backend(mut self, backend: Backend) -> Self {
+        self.agent.watcher.settings.backend = Some(backend);
+        self
+    }
+
+    /// Time between scans with [`Backend::Poll`]
+    pub const fn poll_interval(mut self, interval: Duration) -> Self {
+        self.agent.watcher.settings.poll_interval = Some(interval);
+        self
+    }
+
+    /// How long events are gathered before they're handled
+    pub const fn window(mut self, window: Duration) -> Self {
+        self.agent.watcher.settings.window = Some(window);
+        self
+    }
+
+    /// What happens to local files once they're uploaded
+    pub fn on_success(mut self, on_success: OnSuccess) -> Self {
+        self.agent.on_success = Some(on_success);
+        self
+    }
+
+    /// Keep sources whose object doesn't have their size and MD5 once uploaded
+    pub const fn verify_before_delete(mut self, verify_before_delete: bool) -> Self {
+        self.agent.verify_before_delete = Some(verify_before_delete);
+        self
+    }
+
+    /// Delete objects when their local file is removed
+    pub const fn delete_remote(mut self, delete_remote: bool) -> Self {
+        self.agent.delete_remote = Some(delete_remote);
+        self
+    }
+
+    /// Spread keys over this many hashed prefixes
+    pub const fn shards(mut self, shards: u32) -> Self {
+        self.agent.shards = Some(shards);
+        self
+    }
+
+    pub const fn checksum(mut self, checksum: Checksum) -> Self {
+        self.agent.checksum = Some(checksum);
+        self
+    }
+
+    pub const fn sse(mut self, sse: Sse) -> Self {
+        self.agent.sse = Some(sse);
+        self
+    }
+
+    /// Encrypt objects with this KMS key, `aws:kms` unless `sse` says otherwise
+    pub fn kms_key_id(mut self, kms_key_id: impl Into<String>) -> Self {
+        self.agent.kms_key_id = Some(kms_key_id.into());
+        self
+    }
+
+    /// Upload files with `extension` as `content_type` rather than the guessed type
+    pub fn content_type(
+        mut self,
+        extension: impl Into<String>,
+        content_type: impl Into<String>,
+    ) -> Self {
+        self.agent
+            .content_types
+            .get_or_insert_with(HashMap::new)
+            .insert(extension.into(), content_type.into());
+        self
+    }
+
+    pub const fn storage_class(mut self, storage_class: StorageClass) -> Self {
+        self.agent.storage_class = Some(storage_class);
+        self
+    }
+
+    pub const fn collision(mut self, collision: Collision) -> Self {
+        self.agent.collision = Some(collision);
+        self
+    }
+
+    /// What to do about queued files removed or moved before they were uploaded
+    pub const fn vanished(mut self, vanished: Vanished) -> Self {
+        self.agent.vanished = Some(vanished);
+        self
+    }
+
+    /// Keep files waiting to be uploaded, e.g. while held back by the schedule, in this
+    /// queue rather than in memory
+    pub fn queue(mut self, queue: Arc<dyn Queue>) -> Self {
+        self.agent.queue = None;
+        self.agent.deferred = Deferred::new(queue);
+        self
+    }
+
+    pub const fn upload_order(mut self, upload_order: UploadOrder) -> Self {
+        self.agent.upload_order = Some(upload_order);
+        self
+    }
+
+    /// Largest file to upload, in bytes
+    pub const fn max_object_size(mut self, bytes: u64) -> Self {
+        self.agent.max_object_size = Some(bytes);
+        self
+    }
+
+    /// Upload files of at least `bytes` in parts
+    pub const fn multipart_threshold(mut self, bytes: u64) -> Self {
+        self.agent.multipart.threshold = Some(bytes);
+        self
+    }
+
+    pub const fn part_size(mut self, bytes: u64) -> Self {
+        self.agent.multipart.part_size = Some(bytes);
+        self
+    }
+
+    /// Upload this many parts of a file at once
+    pub const fn part_concurrency(mut self, concurrency: usize) -> Self {
+        self.agent.multipart.concurrency = Some(concurrency);
+        self
+    }
+
+    /// Tune multipart uploads from measured throughput within the default bounds
+    pub fn auto_tune(mut self) -> Self {
+        self.agent.auto_tune = Some(AutoTune::default());
+        self
+    }
+
+    /// Announce files with a placeholder object instead of uploading their contents
+    pub const fn placeholder(mut self, placeholder: Placeholder) -> Self {
+        self.agent.placeholder = Some(placeholder);
+        self
+    }
+
+    pub const fn max_uploads_per_second(mut self, rate: f64) -> Self {
+        self.agent.max_uploads_per_second = Some(rate);
+        self
+    }
+
+    /// Upload at most this many of the agent's files at once, within the manager's limit
+    pub const fn max_concurrency(mut self, concurrency: usize) -> Self {
+        self.agent.max_concurrency = Some(concurrency);
+        self
+    }
+
+    /// Write a heartbeat object this often, rounded down to whole seconds
+    pub const fn heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.agent.heartbeat_interval = Some(interval.as_secs());
+        self
+    }
+
+    /// Escalate files still not uploaded this long after they're detected
+    pub const fn max_upload_latency(mut self, latency: Duration) -> Self {
+        self.agent.max_upload_latency = Some(latency);
+        self
+    }
+
+    /// Don't upload the same contents from the same path twice within this long
+    pub const fn duplicate_window(mut self, window: Duration) -> Self {
+        self.agent.duplicate_window = Some(window);
+        self
+    }
+
+    /// Upload a CSV catalog of the state database's entries this often
+    pub const fn catalog_interval(mut self, interval: Duration) -> Self {
+        self.agent.catalog_interval = Some(interval);
+        self
+    }
+
+    /// Store the host, EC2 instance, agent name and s3sync version as object metadata
+    pub const fn stamp_identity(mut self, stamp_identity: bool) -> Self {
+        self.agent.stamp_identity = Some(stamp_identity);
+        self
+    }
+
+    /// Check the object before each upload and skip files it already matches
+    pub const fn skip_unchanged(mut self, skip_unchanged: bool) -> Self {
+        self.agent.skip_unchanged = Some(skip_unchanged);
+        self
+    }
+
+    /// Store modification and birth times as object metadata
+    pub const fn preserve_times(mut self, preserve_times: bool) -> Self {
+        self.agent.preserve_times = Some(preserve_times);
+        self
+    }
+
+    /// Upload what the bucket is missing on startup, before watching for changes
+    pub const fn initial_sync(mut self, initial_sync: bool) -> Self {
+        self.agent.initial_sync = Some(initial_sync);
+        self
+    }
+}
+
+impl AgentBuilder<PathBuf, String> {
+    /// The agent, once its settings are checked and its path exists
+    pub fn build(self) -> Result<Agent, Error> {
+        let Self {
+            path,
+            bucket,
+            include,
+            exclude_globs,
+            mut agent,
+        } = self;
+        std::fs::metadata(&path)?;
+        if agent.watcher.settings.window == Some(Duration::ZERO) {
+            return Err(Error::InvalidSetting("window must be longer than zero"));
+        }
+        if agent.shards == Some(0) {
+            return Err(Error::InvalidSetting("shards must be at least 1"));
+        }
+        if agent
+            .max_uploads_per_second
+            .is_some_and(|rate| !rate.is_finite() || rate <= 0.0)
+        {
+            return Err(Error::InvalidSetting(
+                "max_uploads_per_second must be a positive number",
+            ));
+        }
+        if agent.heartbeat_interval == Some(0) {
+            return Err(Error::InvalidSetting(
+                "heartbeat_interval must be at least a second",
+            ));
+        }
+        if agent.provider == Some(Provider::R2) && agent.account_id.is_none() {
+            return Err(Error::MissingAccountId(agent.name().to_string()));
+        }
+        if !include.is_empty() {
+            agent.include = Some(Globs::new(include)?);
+        }
+        if !exclude_globs.is_empty() {
+            agent.exclude_globs = Some(Globs::new(exclude_globs)?);
+        }
+        agent.watcher = AgentWatcher {
+            local_path: path,
+            settings: agent.watcher.settings,
+        };
+        agent.bucket_name = Some(bucket);
+        Ok(agent)
+    }
+}
+
+impl Manager {
+    /// Manager for agents from [`Agent::builder`], recording uploads in the `state`
+    /// database when given
+    pub fn from_agents(agents: Vec<Agent>, state: Option<PathBuf>) -> Result<Self, Error> {
+        let manager = Self {
+            agents,
+            replications: Vec::new(),
+            metrics: None,
+            cloudwatch_logs: None,
+            otlp: None,
+            api: None,
+            grpc: None,
+            state,
+            shared_state: None,
+            watchdog: None,
+            fault_injection: None,
+            max_concurrency: None,
+        };
+        manager.prepared()
+    }
+}
@@ -0,0 +1,34 @@
+use std::fmt::Write;
+
+use chrono::{DateTime, Utc};
+
+use super::state::Entry;
+
+const HEADER: &str = "host,agent,path,key,size,e_tag,modified,uploaded_at\n";
+
+/// The state database's entries for an agent as CSV, one row per object with the host
+/// that uploaded it, for querying with Athena (`OpenCSVSerde`, skipping the header line)
+pub fn csv(host: &str, entries: &[Entry]) -> String {
+    let mut csv = String::from(HEADER);
+    for entry in entries {
+        let modified = DateTime::<Utc>::from_timestamp_nanos(entry.modified);
+        let _ = writeln!(
+            csv,
+            "{},{},{},{},{},{},{},{}",
+            field(host),
+            field(&entry.agent),
+            field(&entry.path.to_string_lossy()),
+            field(&entry.key),
+            entry.size,
+            field(entry.e_tag.as_deref().unwrap_or_default().trim_matches('"')),
+            modified.to_rfc3339(),
+            entry.uploaded_at.to_rfc3339(),
+        );
+    }
+    csv
+}
+
+/// Every text field is quoted, paths can carry anything
+fn field(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
@@ -0,0 +1,74 @@
+use std::{io::Read, path::Path};
+
+use aws_sdk_s3::{
+    operation::put_object::builders::PutObjectFluentBuilder, types::ChecksumAlgorithm,
+};
+use aws_smithy_types::base64;
+use md5::{Digest, Md5};
+
+use super::Checksum;
+
+impl Checksum {
+    /// Algorithm for per-part trailers, `None` for MD5 which has no trailer
+    #[must_use]
+    pub const fn algorithm(self) -> Option<ChecksumAlgorithm> {
+        match self {
+            Self::Sha256 => Some(ChecksumAlgorithm::Sha256),
+            Self::Sha1 => Some(ChecksumAlgorithm::Sha1),
+            Self::Crc32 => Some(ChecksumAlgorithm::Crc32),
+            Self::Crc32c => Some(ChecksumAlgorithm::Crc32C),
+            Self::Md5 => None,
+        }
+    }
+
+    /// Name of the metadata holding the source file's checksum, e.g.
+    /// `source-checksum-sha256`
+    #[must_use]
+    pub fn metadata_key(self) -> String {
+        let name = match self {
+            Self::Sha256 => "sha256",
+            Self::Sha1 => "sha1",
+            Self::Crc32 => "crc32",
+            Self::Crc32c => "crc32c",
+            Self::Md5 => "md5",
+        };
+        format!("source-checksum-{name}")
+    }
+
+    /// Checksum of a file's contents, base64 encoded as S3 takes and reports it
+    pub fn of_file(self, path: &Path) -> Result<String, std::io::Error> {
+        let mut file = std::fs::File::open(path)?;
+        let algorithm = match self {
+            Self::Sha256 => aws_smithy_checksums::ChecksumAlgorithm::Sha256,
+            Self::Sha1 => aws_smithy_checksums::ChecksumAlgorithm::Sha1,
+            Self::Crc32 => aws_smithy_checksums::ChecksumAlgorithm::Crc32,
+            Self::Crc32c => aws_smithy_checksums::ChecksumAlgorithm::Crc32c,
+            Self::Md5 => {
+                let mut hasher = Md5::new();
+                std::io::copy(&mut file, &mut hasher)?;
+                return Ok(base64::encode(hasher.finalize()));
+            }
+        };
+        let mut checksum = algorithm.into_impl();
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            checksum.update(&buffer[..read]);
+        }
+        Ok(base64::encode(checksum.finalize()))
+    }
+
+    /// Send `value` as the request's checksum, so S3 rejects a body that doesn't match
+    pub fn attach(self, request: PutObjectFluentBuilder, value: String) -> PutObjectFluentBuilder {
+        match self {
+            Self::Sha256 => request.checksum_sha256(value),
+            Self::Sha1 => request.checksum_sha1(value),
+            Self::Crc32 => request.checksum_crc32(value),
+            Self::Crc32c => request.checksum_crc32_c(value),
+            Self::Md5 => request.content_md5(value),
+        }
+    }
+}
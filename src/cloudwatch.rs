@@ -0,0 +1,190 @@
+use std::{fmt::Write, time::Duration};
+
+use aws_config::SdkConfig;
+use aws_sdk_cloudwatchlogs as logs;
+use logs::types::InputLogEvent;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tracing::field::{Field, Visit};
+use tracing_subscriber::{layer::Context, Layer};
+
+/// Log lines buffered while CloudWatch is unreachable, newer lines are dropped beyond this
+const BUFFER_CAPACITY: usize = 10_000;
+/// `PutLogEvents` accepts at most 10,000 events per call
+const MAX_BATCH: usize = 10_000;
+/// Nor more than this many bytes per call, counting each event's message and overhead
+const MAX_BATCH_BYTES: usize = 1_048_576;
+/// Bytes CloudWatch adds to each event's message when sizing a batch
+const EVENT_OVERHEAD: usize = 26;
+/// Largest message a single event can carry, longer ones are cut off
+const MAX_MESSAGE_BYTES: usize = 256 * 1024 - EVENT_OVERHEAD;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+/// Targets whose events would be generated by shipping itself
+const IGNORED_TARGETS: &[&str] = &["aws_", "hyper", "h2", "rustls", module_path!()];
+
+/// Ship the daemon's own logs to a CloudWatch Logs group
+#[derive(Deserialize, Debug, Clone)]
+pub struct CloudWatchLogsSettings {
+    log_group: String,
+    /// Defaults to the hostname
+    log_stream: Option<String>,
+}
+
+impl CloudWatchLogsSettings {
+    #[must_use]
+    pub const fn new(log_group: String) -> Self {
+        Self {
+            log_group,
+            log_stream: None,
+        }
+    }
+
+    /// Start shipping in the background and return the layer feeding it
+    pub fn layer(&self, sdk_config: &SdkConfig) -> CloudWatchLayer {
+        let (tx, rx) = mpsc::channel(BUFFER_CAPACITY);
+        let log_stream = self
+            .log_stream
+            .clone()
+            .unwrap_or_else(super::heartbeat::hostname);
+        tokio::spawn(ship(
+            logs::Client::new(sdk_config),
+            self.log_group.clone(),
+            log_stream,
+            rx,
+        ));
+        CloudWatchLayer { tx }
+    }
+}
+
+struct LogLine {
+    timestamp: i64,
+    message: String,
+}
+
+impl LogLine {
+    /// What the line counts towards a batch's size limit
+    const fn size(&self) -> usize {
+        self.message.len() + EVENT_OVERHEAD
+    }
+}
+
+pub struct CloudWatchLayer {
+    tx: mpsc::Sender<LogLine>,
+}
+
+impl<S: tracing::Subscriber> Layer<S> for CloudWatchLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if IGNORED_TARGETS
+            .iter()
+            .any(|target| metadata.target().starts_with(target))
+        {
+            return;
+        }
+        let mut visitor = MessageVisitor(format!("{} {}:", metadata.level(), metadata.target()));
+        event.record(&mut visitor);
+        let mut message = visitor.0;
+        message.truncate(message.floor_char_boundary(MAX_MESSAGE_BYTES));
+        let _ = self.tx.try_send(LogLine {
+            timestamp: chrono::Utc::now().timestamp_millis(),
+            message,
+        });
+    }
+}
+
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, " {value:?}");
+        } else {
+            let _ = write!(self.0, " {}={value:?}", field.name());
+        }
+    }
+}
+
+async fn ship(
+    client: logs::Client,
+    log_group: String,
+    log_stream: String,
+    mut rx: mpsc::Receiver<LogLine>,
+) {
+    if let Err(e) = client
+        .create_log_group()
+        .log_group_name(&log_group)
+        .send()
+        .await
+    {
+        if !e
+            .as_service_error()
+            .is_some_and(logs::operation::create_log_group::CreateLogGroupError::is_resource_already_exists_exception)
+        {
+            tracing::warn!("Unable to create log group: {e}");
+        }
+    }
+    if let Err(e) = client
+        .create_log_stream()
+        .log_group_name(&log_group)
+        .log_stream_name(&log_stream)
+        .send()
+        .await
+    {
+        if !e
+            .as_service_error()
+            .is_some_and(logs::operation::create_log_stream::CreateLogStreamError::is_resource_already_exists_exception)
+        {
+            tracing::warn!("Unable to create log stream: {e}");
+        }
+    }
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    let mut batch = Vec::new();
+    let mut batch_bytes = 0;
+    loop {
+        tokio::select! {
+            line = rx.recv() => match line {
+                Some(line) => {
+                    // Sent before the line would take the batch past either limit
+                    if batch.len() >= MAX_BATCH || batch_bytes + line.size() > MAX_BATCH_BYTES {
+                        flush(&client, &log_group, &log_stream, &mut batch).await;
+                        batch_bytes = 0;
+                    }
+                    batch_bytes += line.size();
+                    batch.push(line);
+                }
+                None => break,
+            },
+            _ = interval.tick() => {
+                flush(&client, &log_group, &log_stream, &mut batch).await;
+                batch_bytes = 0;
+            }
+        }
+    }
+    flush(&client, &log_group, &log_stream, &mut batch).await;
+}
+
+async fn flush(client: &logs::Client, log_group: &str, log_stream: &str, batch: &mut Vec<LogLine>) {
+    if batch.is_empty() {
+        return;
+    }
+    let events = batch
+        .drain(..)
+        .filter_map(|line| {
+            InputLogEvent::builder()
+                .timestamp(line.timestamp)
+                .message(line.message)
+                .build()
+                .ok()
+        })
+        .collect::<Vec<_>>();
+    if let Err(e) = client
+        .put_log_events()
+        .log_group_name(log_group)
+        .log_stream_name(log_stream)
+        .set_log_events(Some(events))
+        .send()
+        .await
+    {
+        tracing::warn!("Unable to ship logs: {e}");
+    }
+}
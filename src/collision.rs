@@ -0,0 +1,72 @@
+use chrono::{
+    format::{Item, StrftimeItems},
+    Utc,
+};
+use serde::Deserialize;
+
+const DEFAULT_TEMPLATE: &str = "-{timestamp}";
+const DEFAULT_TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+/// Counter values tried before giving up on finding a free key
+pub const MAX_COUNTER: u32 = 1000;
+
+/// Suffix inserted before the extension of a colliding key, e.g. `a.csv` becomes
+/// `a-20240102T030405Z.csv`.
+///
+/// Placeholders are `{timestamp}` (formatted with `timestamp_format`), `{hostname}` and
+/// `{counter}`, which counts up from 1 until the key is free.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SuffixTemplate {
+    template: Option<String>,
+    /// `strftime` format for `{timestamp}`, in UTC
+    timestamp_format: Option<String>,
+}
+
+impl SuffixTemplate {
+    pub const fn new(template: String) -> Self {
+        Self {
+            template: Some(template),
+            timestamp_format: None,
+        }
+    }
+
+    fn template(&self) -> &str {
+        self.template.as_deref().unwrap_or(DEFAULT_TEMPLATE)
+    }
+
+    /// Why the template can't be rendered, if it can't
+    pub fn invalid(&self) -> Option<&'static str> {
+        let format = self.timestamp_format.as_deref()?;
+        StrftimeItems::new(format)
+            .any(|item| item == Item::Error)
+            .then_some("collision_suffix timestamp_format isn't a valid strftime format")
+    }
+
+    /// Whether rendering again with another counter can give a different key
+    pub fn has_counter(&self) -> bool {
+        self.template().contains("{counter}")
+    }
+
+    #[allow(clippy::literal_string_with_formatting_args)]
+    pub fn render(&self, key: &str, counter: u32) -> String {
+        let timestamp = Utc::now()
+            .format(
+                self.timestamp_format
+                    .as_deref()
+                    .unwrap_or(DEFAULT_TIMESTAMP_FORMAT),
+            )
+            .to_string();
+        let suffix = self
+            .template()
+            .replace("{timestamp}", &timestamp)
+            .replace("{hostname}", &super::heartbeat::hostname())
+            .replace("{counter}", &counter.to_string());
+        let name_start = key.rfind('/').map_or(0, |slash| slash + 1);
+        match key[name_start..].rfind('.') {
+            Some(dot) if dot > 0 => {
+                let (stem, extension) = key.split_at(name_start + dot);
+                format!("{stem}{suffix}{extension}")
+            }
+            _ => format!("{key}{suffix}"),
+        }
+    }
+}
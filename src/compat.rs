@@ -0,0 +1,71 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use aws_sdk_s3 as s3;
+use s3::{
+    config::{RequestChecksumCalculation, ResponseChecksumValidation},
+    error::{ProvideErrorMetadata, SdkError},
+    types::ChecksumAlgorithm,
+};
+
+/// Client config for servers that only understand the checksums S3 requires, rather than
+/// the CRC32 the SDK sends by default
+pub fn configure(config: s3::config::Builder) -> s3::config::Builder {
+    config
+        .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+        .response_checksum_validation(ResponseChecksumValidation::WhenRequired)
+}
+
+/// Whether the server rejected a request over a header or feature it doesn't implement
+pub fn is_unsupported<E: ProvideErrorMetadata, R>(error: &SdkError<E, R>) -> bool {
+    matches!(
+        error
+            .as_service_error()
+            .and_then(ProvideErrorMetadata::code),
+        Some("NotImplemented" | "InvalidArgument" | "InvalidRequest")
+    )
+}
+
+/// Upload features an S3-compatible server turned out not to support, dropped for the rest
+/// of the run once one is rejected
+#[derive(Debug, Clone, Default)]
+pub struct Fallbacks {
+    checksum: Arc<AtomicBool>,
+    metadata: Arc<AtomicBool>,
+}
+
+impl Fallbacks {
+    pub fn checksum(&self, checksum: Option<ChecksumAlgorithm>) -> Option<ChecksumAlgorithm> {
+        checksum.filter(|_| !self.checksum.load(Ordering::Relaxed))
+    }
+
+    pub fn metadata(
+        &self,
+        metadata: Option<HashMap<String, String>>,
+    ) -> Option<HashMap<String, String>> {
+        metadata.filter(|_| !self.metadata.load(Ordering::Relaxed))
+    }
+
+    /// Stop sending whichever of the checksum or metadata the rejected request carried,
+    /// checksum first, returning `false` when it carried neither and retrying won't help
+    pub fn degrade(&self, checksum: bool, metadata: bool) -> bool {
+        if checksum {
+            if !self.checksum.swap(true, Ordering::Relaxed) {
+                tracing::warn!("Server rejected the upload checksum, uploading without checksums");
+            }
+            true
+        } else if metadata {
+            if !self.metadata.swap(true, Ordering::Relaxed) {
+                tracing::warn!("Server rejected object metadata, uploading without metadata");
+            }
+            true
+        } else {
+            false
+        }
+    }
+}
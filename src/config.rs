@@ -0,0 +1,171 @@
+use std::{path::Path, time::Duration};
+
+use aws_sdk_s3 as s3;
+use notify_debouncer_mini::{
+    new_debouncer,
+    notify::{RecommendedWatcher, RecursiveMode},
+    DebounceEventResult, Debouncer,
+};
+use serde::de::DeserializeOwned;
+use tokio::sync::mpsc;
+
+use super::{ConfigFormat, Error};
+
+impl ConfigFormat {
+    /// Format going by the extension of a path or `s3://` URL, `.toml` or `.json`
+    #[must_use]
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(std::ffi::OsStr::to_str) {
+            Some(extension) if extension.eq_ignore_ascii_case("toml") => Self::Toml,
+            Some(extension) if extension.eq_ignore_ascii_case("json") => Self::Json,
+            _ => Self::Yaml,
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(self, contents: &str) -> Result<T, Error> {
+        Ok(match self {
+            Self::Yaml => serde_yaml::from_str(contents)?,
+            Self::Toml => toml::from_str(contents)?,
+            Self::Json => serde_json::from_str(contents).map_err(Error::JsonConfig)?,
+        })
+    }
+}
+
+/// Config stored in S3 (`--config s3://bucket/key`) so a fleet can be managed centrally
+#[derive(Debug, Clone)]
+pub struct RemoteConfig {
+    client: s3::Client,
+    bucket: String,
+    key: String,
+}
+
+impl RemoteConfig {
+    /// `None` unless `url` is an `s3://bucket/key` URL
+    pub async fn from_url(
+        url: &str,
+        profile_name: Option<&str>,
+        region_name: Option<&str>,
+    ) -> Option<Self> {
+        let (bucket, key) = url.strip_prefix("s3://")?.split_once('/')?;
+        let sdk_config = super::sdk_config(profile_name, region_name).await;
+        Some(Self {
+            client: s3::Client::new(&sdk_config),
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+        })
+    }
+
+    /// Config contents along with the object's `ETag`
+    #[tracing::instrument(skip(self), fields(bucket = self.bucket, key = self.key))]
+    pub async fn fetch(&self) -> Result<(String, Option<String>), Error> {
+        let object = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .send()
+            .await?;
+        let e_tag = object.e_tag().map(String::from);
+        let bytes = object.body.collect().await?.into_bytes();
+        let contents = String::from_utf8_lossy(&bytes).into_owned();
+        tracing::info!("Fetched config");
+        Ok((contents, e_tag))
+    }
+
+    /// Check the object every `interval` and send its contents whenever the `ETag` changes
+    pub fn poll(self, interval: Duration, mut e_tag: Option<String>, tx: mpsc::Sender<String>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let current = match self
+                    .client
+                    .head_object()
+                    .bucket(&self.bucket)
+                    .key(&self.key)
+                    .send()
+                    .await
+                {
+                    Ok(head) => head.e_tag().map(String::from),
+                    Err(e) => {
+                        tracing::warn!("Unable to check config: {e}");
+                        continue;
+                    }
+                };
+                if current == e_tag {
+                    continue;
+                }
+                match self.fetch().await {
+                    Ok((contents, fetched)) => {
+                        e_tag = fetched;
+                        if tx.send(contents).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => tracing::warn!("Unable to fetch config: {e}"),
+                }
+            }
+        });
+    }
+}
+
+/// How long the config file has to be left alone before it's read again
+const LOCAL_CONFIG_DELAY: Duration = Duration::from_secs(1);
+
+/// Local config file, sent again whenever it's written or, on Unix, the process gets
+/// `SIGHUP`. The file is watched for as long as this lives.
+pub struct LocalConfig {
+    _debouncer: Debouncer<RecommendedWatcher>,
+}
+
+impl LocalConfig {
+    /// Watch the directory holding `path`, as editors replace files rather than write them
+    pub fn watch(path: &Path, tx: mpsc::Sender<String>) -> Result<Self, Error> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        }
+        .canonicalize()?;
+        let path = dir.join(path.file_name().unwrap_or_default());
+        #[cfg(unix)]
+        {
+            let mut hangup =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+            let (path, tx) = (path.clone(), tx.clone());
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    tracing::info!("Got SIGHUP");
+                    if let Some(contents) = read(&path) {
+                        if tx.send(contents).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+        let mut debouncer =
+            new_debouncer(LOCAL_CONFIG_DELAY, move |events: DebounceEventResult| {
+                let Ok(events) = events else {
+                    return;
+                };
+                if events.iter().any(|event| event.path == path) {
+                    if let Some(contents) = read(&path) {
+                        let _ = tx.blocking_send(contents);
+                    }
+                }
+            })?;
+        debouncer
+            .watcher()
+            .watch(&dir, RecursiveMode::NonRecursive)?;
+        Ok(Self {
+            _debouncer: debouncer,
+        })
+    }
+}
+
+fn read(path: &Path) -> Option<String> {
+    std::fs::read_to_string(path)
+        .inspect_err(|e| tracing::warn!("Unable to read config: {e}"))
+        .ok()
+}
@@ -0,0 +1,128 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use super::{
+    deadline::Deadlines, heartbeat::AgentStats, open_files::OpenFiles, queue::Deferred, Manager,
+};
+
+/// An agent's runtime switches, flipped by the control plane
+#[derive(Debug, Clone, Default)]
+pub struct Switches(Arc<Flags>);
+
+#[derive(Debug, Default)]
+struct Flags {
+    paused: AtomicBool,
+    draining: AtomicBool,
+}
+
+impl Switches {
+    /// Whether detected files are being queued rather than uploaded
+    pub fn paused(&self) -> bool {
+        self.0.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.0.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Whether queued files are being uploaded regardless of the schedule or a pause,
+    /// until the next pass over the queue is done
+    pub fn draining(&self) -> bool {
+        self.0.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn drain(&self) {
+        self.0.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn drained(&self) {
+        self.0.draining.store(false, Ordering::Relaxed);
+    }
+}
+
+/// What the control plane sees of a running agent
+#[derive(Debug, Clone)]
+pub struct Handle {
+    pub name: String,
+    pub bucket: String,
+    pub prefix: String,
+    pub switches: Switches,
+    pub stats: AgentStats,
+    pub deferred: Deferred,
+    pub open_files: OpenFiles,
+    pub deadlines: Deadlines,
+}
+
+/// The running agents for the control plane, and their switches and waiting files by
+/// name so a pause and queued uploads survive config reloads
+#[derive(Debug, Clone, Default)]
+pub struct Control(Arc<Mutex<Registry>>);
+
+#[derive(Debug, Default)]
+struct Registry {
+    switches: HashMap<String, Switches>,
+    waiting: HashMap<String, Waiting>,
+    agents: Vec<Handle>,
+}
+
+/// Files an agent holds in memory until it uploads them
+#[derive(Debug)]
+struct Waiting {
+    deferred: Deferred,
+    retries: Deferred,
+    open_files: OpenFiles,
+}
+
+impl Control {
+    /// Hand a newly loaded config's agents their switches and the files the agents of
+    /// the same name were still waiting to upload, and make them the ones reported
+    pub fn attach(&self, manager: &mut Manager) {
+        let mut registry = self.0.lock().unwrap();
+        let mut agents = Vec::with_capacity(manager.agents.len());
+        let mut waiting = HashMap::with_capacity(manager.agents.len());
+        for agent in &mut manager.agents {
+            let name = agent.name().to_string();
+            agent.switches = registry.switches.entry(name.clone()).or_default().clone();
+            if let Some(previous) = registry.waiting.get(&name) {
+                let adopted = agent
+                    .deferred
+                    .adopt(&previous.deferred)
+                    .and_then(|()| agent.retries.adopt(&previous.retries));
+                if let Err(e) = adopted {
+                    tracing::warn!(parent: agent.span(), "Unable to carry queued files over: {e}");
+                }
+                agent.open_files.adopt(&previous.open_files);
+            }
+            waiting.insert(
+                name.clone(),
+                Waiting {
+                    deferred: agent.deferred.clone(),
+                    retries: agent.retries.clone(),
+                    open_files: agent.open_files.clone(),
+                },
+            );
+            agents.push(Handle {
+                name,
+                bucket: agent.bucket_name.clone().unwrap_or_default(),
+                prefix: agent.key_prefix.clone().unwrap_or_default(),
+                switches: agent.switches.clone(),
+                stats: agent.stats.clone(),
+                deferred: agent.deferred.clone(),
+                open_files: agent.open_files.clone(),
+                deadlines: agent.deadlines.clone(),
+            });
+        }
+        registry.agents = agents;
+        registry.waiting = waiting;
+    }
+
+    #[must_use]
+    pub fn agents(&self) -> Vec<Handle> {
+        self.0.lock().unwrap().agents.clone()
+    }
+}
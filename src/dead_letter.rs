@@ -0,0 +1,42 @@
+use std::{fs::OpenOptions, io::Write, path::Path};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// Newline-delimited JSON file recording files an agent gave up on, for later inspection
+/// or a manual retry
+#[derive(Debug)]
+pub struct DeadLetter<'a>(&'a Path);
+
+#[derive(Serialize, Debug)]
+struct Entry<'a> {
+    timestamp: DateTime<Utc>,
+    agent: &'a str,
+    path: &'a Path,
+    reason: &'a str,
+}
+
+impl<'a> DeadLetter<'a> {
+    pub const fn new(file: &'a Path) -> Self {
+        Self(file)
+    }
+
+    /// Append an entry, only warning when it can't be written
+    pub fn record(&self, agent: &str, path: &Path, reason: &str) {
+        let entry = Entry {
+            timestamp: Utc::now(),
+            agent,
+            path,
+            reason,
+        };
+        let result = serde_json::to_string(&entry)
+            .map_err(std::io::Error::from)
+            .and_then(|line| {
+                let mut file = OpenOptions::new().create(true).append(true).open(self.0)?;
+                writeln!(file, "{line}")
+            });
+        if let Err(e) = result {
+            tracing::warn!("Unable to write dead-letter entry: {e}");
+        }
+    }
+}
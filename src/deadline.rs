@@ -0,0 +1,67 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Files matched but not uploaded yet, for agents with a `max_upload_latency`. Keyed on
+/// the path as it appears in logs and events, which is all skips and uploads report.
+#[derive(Debug, Clone, Default)]
+pub struct Deadlines(Arc<Mutex<HashMap<String, Pending>>>);
+
+#[derive(Debug)]
+struct Pending {
+    path: PathBuf,
+    matched: Instant,
+    escalated: bool,
+}
+
+impl Deadlines {
+    /// Start the clock on a file, unless it's already running from an earlier event
+    pub fn start(&self, logged: &str, path: &Path) {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(logged.to_string())
+            .or_insert_with(|| Pending {
+                path: path.to_path_buf(),
+                matched: Instant::now(),
+                escalated: false,
+            });
+    }
+
+    /// Stop the clock on a file that was uploaded or won't be, returning how long it
+    /// waited when that was past its deadline
+    pub fn finish(&self, logged: &str) -> Option<Duration> {
+        let pending = self.0.lock().unwrap().remove(logged)?;
+        pending.escalated.then(|| pending.matched.elapsed())
+    }
+
+    /// Files that have waited longer than `latency` since last checked, with how long,
+    /// forgetting files that have since been removed
+    pub fn overdue(&self, latency: Duration) -> Vec<(String, Duration)> {
+        let mut pending = self.0.lock().unwrap();
+        pending.retain(|_, pending| pending.path.exists());
+        let mut overdue = Vec::new();
+        for (logged, pending) in pending.iter_mut() {
+            let waited = pending.matched.elapsed();
+            if !pending.escalated && waited > latency {
+                pending.escalated = true;
+                overdue.push((logged.clone(), waited));
+            }
+        }
+        drop(pending);
+        overdue
+    }
+
+    /// Files currently past their deadline
+    pub fn overdue_count(&self) -> usize {
+        self.0
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|pending| pending.escalated)
+            .count()
+    }
+}
@@ -0,0 +1,121 @@
+// Stand-ins for settings of features left out of the build, so configs using them fail
+// with an error naming the feature rather than an unknown field.
+
+macro_rules! disabled_settings {
+    ($name:ident, $feature:literal) => {
+        #[derive(Debug, Clone)]
+        pub enum $name {}
+
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D: serde::Deserializer<'de>>(_: D) -> Result<Self, D::Error> {
+                Err(<D::Error as serde::de::Error>::custom(concat!(
+                    "s3sync was built without the `",
+                    $feature,
+                    "` feature"
+                )))
+            }
+        }
+    };
+}
+
+#[cfg(not(feature = "api"))]
+mod api {
+    use std::path::PathBuf;
+
+    use tokio::sync::mpsc;
+
+    use crate::{Control, Error};
+
+    disabled_settings!(ApiSettings, "api");
+
+    impl ApiSettings {
+        #[allow(clippy::unused_async, clippy::uninhabited_references)]
+        pub async fn serve(
+            &self,
+            _: PathBuf,
+            _: mpsc::Sender<String>,
+            _: Control,
+        ) -> Result<(), Error> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(not(feature = "grpc"))]
+mod grpc {
+    use std::path::PathBuf;
+
+    use tokio::sync::mpsc;
+
+    use crate::{Control, Error};
+
+    disabled_settings!(GrpcSettings, "grpc");
+
+    impl GrpcSettings {
+        #[allow(clippy::unused_async, clippy::uninhabited_references)]
+        pub async fn serve(
+            &self,
+            _: PathBuf,
+            _: mpsc::Sender<String>,
+            _: Control,
+        ) -> Result<(), Error> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(not(feature = "cloudwatch"))]
+mod cloudwatch {
+    use aws_config::SdkConfig;
+    use tracing_subscriber::Layer;
+
+    disabled_settings!(CloudWatchLogsSettings, "cloudwatch");
+
+    impl CloudWatchLogsSettings {
+        #[allow(clippy::uninhabited_references)]
+        #[must_use]
+        pub const fn layer(&self, _: &SdkConfig) -> CloudWatchLayer {
+            match *self {}
+        }
+    }
+
+    pub enum CloudWatchLayer {}
+
+    impl<S: tracing::Subscriber> Layer<S> for CloudWatchLayer {}
+}
+
+#[cfg(not(feature = "otlp"))]
+mod otlp {
+    use std::{convert::Infallible, marker::PhantomData};
+
+    use tracing_subscriber::{registry::LookupSpan, Layer};
+
+    use crate::Error;
+
+    disabled_settings!(OtlpSettings, "otlp");
+
+    impl OtlpSettings {
+        #[allow(clippy::uninhabited_references)]
+        pub const fn layer<S>(&self) -> Result<(OtlpLayer<S>, OtlpGuard), Error>
+        where
+            S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+        {
+            match *self {}
+        }
+    }
+
+    pub struct OtlpLayer<S>(Infallible, PhantomData<fn(S)>);
+
+    impl<S: tracing::Subscriber> Layer<S> for OtlpLayer<S> {}
+
+    pub enum OtlpGuard {}
+}
+
+#[cfg(not(feature = "api"))]
+pub use api::ApiSettings;
+#[cfg(not(feature = "cloudwatch"))]
+pub use cloudwatch::{CloudWatchLayer, CloudWatchLogsSettings};
+#[cfg(not(feature = "grpc"))]
+pub use grpc::GrpcSettings;
+#[cfg(not(feature = "otlp"))]
+pub use otlp::{OtlpGuard, OtlpLayer, OtlpSettings};
@@ -0,0 +1,74 @@
+use std::fmt;
+
+/// S3 Standard `PUT` price in us-east-1, per 1,000 requests
+const PUT_COST_PER_THOUSAND: f64 = 0.005;
+/// S3 Standard storage price in us-east-1, per GB-month
+const STORAGE_COST_PER_GB_MONTH: f64 = 0.023;
+const GB: f64 = 1e9;
+
+/// What bringing a bucket up to date with the files already on disk would take
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Estimate {
+    pub files: u64,
+    pub bytes: u64,
+    pub requests: u64,
+}
+
+impl Estimate {
+    /// Count a file of `bytes`, uploaded in `requests` requests
+    pub const fn add(&mut self, bytes: u64, requests: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+        self.requests += requests;
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn request_cost(&self) -> f64 {
+        self.requests as f64 / 1000.0 * PUT_COST_PER_THOUSAND
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn storage_cost(&self) -> f64 {
+        self.bytes as f64 / GB * STORAGE_COST_PER_GB_MONTH
+    }
+}
+
+impl std::iter::Sum for Estimate {
+    fn sum<I: Iterator<Item = Self>>(estimates: I) -> Self {
+        estimates.fold(Self::default(), |total, estimate| Self {
+            files: total.files + estimate.files,
+            bytes: total.bytes + estimate.bytes,
+            requests: total.requests + estimate.requests,
+        })
+    }
+}
+
+/// Bytes in MB under a GB, otherwise in GB
+struct Size(u64);
+
+impl fmt::Display for Size {
+    #[allow(clippy::cast_precision_loss)]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.0 as f64;
+        if bytes < GB {
+            write!(f, "{:.1} MB", bytes / 1e6)
+        } else {
+            write!(f, "{:.2} GB", bytes / GB)
+        }
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} files, {} in {} requests, about ${:.2} in requests and ${:.2}/month in \
+             storage at S3 Standard us-east-1 prices",
+            self.files,
+            Size(self.bytes),
+            self.requests,
+            self.request_cost(),
+            self.storage_cost()
+        )
+    }
+}
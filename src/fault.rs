@@ -0,0 +1,96 @@
+use std::{
+    hash::{BuildHasher, Hasher, RandomState},
+    sync::Once,
+    time::Duration,
+};
+
+use aws_sdk_s3 as s3;
+use s3::{
+    config::{
+        interceptors::BeforeDeserializationInterceptorContextMut, ConfigBag, Intercept,
+        RuntimeComponents,
+    },
+    error::BoxError,
+    primitives::SdkBody,
+};
+use serde::Deserialize;
+
+use super::pull;
+
+const SLOW_DOWN: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+    <Error><Code>SlowDown</Code><Message>Injected by s3sync fault injection</Message></Error>";
+
+/// Chaos testing, for checking retries, dead letters and alerting before trusting real
+/// data to a config. Never meant for production.
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub struct FaultInjection {
+    /// Percentage of S3 responses replaced with a `503 SlowDown`, which the SDK retries
+    #[serde(default)]
+    failure_percent: f64,
+    /// Longest random delay before each batch of file events is handled
+    #[serde(default, with = "humantime_serde")]
+    max_event_delay: Option<Duration>,
+}
+
+impl FaultInjection {
+    /// Off unless either fault is given
+    pub fn new(failure_percent: Option<f64>, max_event_delay: Option<Duration>) -> Option<Self> {
+        (failure_percent.is_some() || max_event_delay.is_some()).then(|| Self {
+            failure_percent: failure_percent.unwrap_or_default(),
+            max_event_delay,
+        })
+    }
+
+    /// Client config failing a share of responses, warning the first time that faults are
+    /// being injected
+    pub fn configure(&self, config: s3::config::Builder) -> s3::config::Builder {
+        static WARNED: Once = Once::new();
+        WARNED.call_once(|| {
+            tracing::warn!(
+                "Fault injection is on: failing {}% of S3 responses and delaying events up to {}",
+                self.failure_percent,
+                humantime::format_duration(self.max_event_delay.unwrap_or_default())
+            );
+        });
+        if self.failure_percent > 0.0 {
+            config.interceptor(FailResponses(self.failure_percent))
+        } else {
+            config
+        }
+    }
+
+    pub async fn delay_events(&self) {
+        if let Some(max) = self.max_event_delay {
+            let delay = pull::jitter(max);
+            tracing::debug!("Delaying events {}", humantime::format_duration(delay));
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Rewrites responses into throttling errors, the percentage of them given
+#[derive(Debug)]
+struct FailResponses(f64);
+
+impl Intercept for FailResponses {
+    fn name(&self) -> &'static str {
+        "FailResponses"
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn modify_before_deserialization(
+        &self,
+        context: &mut BeforeDeserializationInterceptorContextMut<'_>,
+        _: &RuntimeComponents,
+        _: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        let roll = (RandomState::new().build_hasher().finish() % 10_000) as f64 / 100.0;
+        if roll < self.0 {
+            tracing::debug!("Injecting a SlowDown response");
+            let response = context.response_mut();
+            *response.status_mut() = 503.try_into()?;
+            *response.body_mut() = SdkBody::from(SLOW_DOWN);
+        }
+        Ok(())
+    }
+}
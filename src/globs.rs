@@ -0,0 +1,58 @@
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+
+/// Glob filter over object keys, read as a single glob or a list of them
+#[derive(Deserialize, Clone)]
+#[serde(try_from = "OneOrMany")]
+pub struct Globs {
+    globs: Vec<String>,
+    set: GlobSet,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl TryFrom<OneOrMany> for Globs {
+    type Error = globset::Error;
+
+    fn try_from(value: OneOrMany) -> Result<Self, Self::Error> {
+        match value {
+            OneOrMany::One(glob) => Self::new(vec![glob]),
+            OneOrMany::Many(globs) => Self::new(globs),
+        }
+    }
+}
+
+impl Globs {
+    pub fn new(globs: Vec<String>) -> Result<Self, globset::Error> {
+        let mut builder = GlobSetBuilder::new();
+        for glob in &globs {
+            builder.add(Glob::new(glob)?);
+        }
+        Ok(Self {
+            globs,
+            set: builder.build()?,
+        })
+    }
+
+    /// True when the key matches any of the globs
+    pub fn is_match(&self, key: &str) -> bool {
+        self.set.is_match(key)
+    }
+}
+
+impl std::fmt::Display for Globs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.globs.join(", "))
+    }
+}
+
+impl std::fmt::Debug for Globs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(&self.globs).finish()
+    }
+}
@@ -0,0 +1,194 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use serde::Deserialize;
+use tokio::sync::{mpsc, Mutex};
+use tonic::{
+    transport::{server::TcpIncoming, Certificate, Identity, Server, ServerTlsConfig},
+    Request, Response, Status,
+};
+
+use self::proto::{
+    control_server::ControlServer, AgentStatus, AgentsRequest, AgentsResponse, PushConfigRequest,
+    PushConfigResponse, StatusRequest, StatusResponse,
+};
+use super::{control::Handle, heartbeat, Control, Error, Manager};
+
+mod proto {
+    #![allow(clippy::pedantic, clippy::nursery)]
+    tonic::include_proto!("s3sync.control.v1");
+}
+
+/// gRPC control plane, see `proto/control.proto`. Clients must present a certificate
+/// signed by `client_ca`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct GrpcSettings {
+    pub listen: SocketAddr,
+    /// PEM certificate chain the server presents
+    cert: PathBuf,
+    /// PEM private key for `cert`
+    key: PathBuf,
+    /// PEM CA certificates client certificates are checked against
+    client_ca: PathBuf,
+}
+
+impl GrpcSettings {
+    /// Serve in the background. Pushed configs are written to `config_path` and the new
+    /// contents are sent on `reload` for the watch loop to pick up.
+    pub async fn serve(
+        &self,
+        config_path: PathBuf,
+        reload: mpsc::Sender<String>,
+        control: Control,
+    ) -> Result<(), Error> {
+        let identity = Identity::from_pem(
+            tokio::fs::read(&self.cert).await?,
+            tokio::fs::read(&self.key).await?,
+        );
+        let client_ca = Certificate::from_pem(tokio::fs::read(&self.client_ca).await?);
+        let tls = ServerTlsConfig::new()
+            .identity(identity)
+            .client_ca_root(client_ca);
+        let service = ControlService {
+            control,
+            config_path,
+            reload,
+            lock: Mutex::new(()),
+        };
+        let server = Server::builder()
+            .tls_config(tls)?
+            .add_service(ControlServer::new(service));
+        let listener = tokio::net::TcpListener::bind(self.listen).await?;
+        let incoming =
+            TcpIncoming::from_listener(listener, true, None).map_err(std::io::Error::other)?;
+        tracing::info!("Serving gRPC control plane on {}", self.listen);
+        tokio::spawn(async move {
+            if let Err(e) = server.serve_with_incoming(incoming).await {
+                tracing::error!("gRPC server stopped: {e}");
+            }
+        });
+        Ok(())
+    }
+}
+
+struct ControlService {
+    control: Control,
+    config_path: PathBuf,
+    reload: mpsc::Sender<String>,
+    /// Serializes config pushes
+    lock: Mutex<()>,
+}
+
+// Handlers answer with tonic's `Status`, however large
+#[allow(clippy::result_large_err)]
+impl ControlService {
+    /// The named agents, every agent when none are named
+    fn select(&self, names: &[String]) -> Result<Vec<Handle>, Status> {
+        let agents = self.control.agents();
+        if names.is_empty() {
+            return Ok(agents);
+        }
+        names
+            .iter()
+            .map(|name| {
+                agents
+                    .iter()
+                    .find(|agent| agent.name == *name)
+                    .cloned()
+                    .ok_or_else(|| Status::not_found(format!("No agent named '{name}'")))
+            })
+            .collect()
+    }
+
+    /// Apply `switch` to the selected agents, answering with their names
+    fn switch(
+        &self,
+        request: &AgentsRequest,
+        action: &str,
+        switch: impl Fn(&Handle),
+    ) -> Result<Response<AgentsResponse>, Status> {
+        let agents = self.select(&request.agents)?;
+        for agent in &agents {
+            switch(agent);
+            tracing::info!("Agent '{}' {action} via gRPC", agent.name);
+        }
+        Ok(Response::new(AgentsResponse {
+            agents: agents.into_iter().map(|agent| agent.name).collect(),
+        }))
+    }
+}
+
+fn status(agent: Handle) -> AgentStatus {
+    let stats = agent.stats.snapshot();
+    AgentStatus {
+        paused: agent.switches.paused(),
+        queued: agent.deferred.len() as u64,
+        waiting_for_close: agent.open_files.paths().len() as u64,
+        overdue: agent.deadlines.overdue_count() as u64,
+        uploads: stats.uploads,
+        failures: stats.failures,
+        last_upload: stats
+            .last_upload
+            .map(|at| at.to_rfc3339())
+            .unwrap_or_default(),
+        last_failure: stats
+            .last_failure
+            .map(|at| at.to_rfc3339())
+            .unwrap_or_default(),
+        name: agent.name,
+        bucket: agent.bucket,
+        prefix: agent.prefix,
+    }
+}
+
+#[tonic::async_trait]
+impl proto::control_server::Control for ControlService {
+    async fn status(&self, _: Request<StatusRequest>) -> Result<Response<StatusResponse>, Status> {
+        Ok(Response::new(StatusResponse {
+            host: heartbeat::hostname(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            agents: self.control.agents().into_iter().map(status).collect(),
+        }))
+    }
+
+    async fn push_config(
+        &self,
+        request: Request<PushConfigRequest>,
+    ) -> Result<Response<PushConfigResponse>, Status> {
+        let contents = request.into_inner().yaml;
+        Manager::from_yaml(&contents).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let _guard = self.lock.lock().await;
+        tokio::fs::write(&self.config_path, &contents)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let _ = self.reload.send(contents).await;
+        tracing::info!("Config pushed via gRPC");
+        Ok(Response::new(PushConfigResponse {}))
+    }
+
+    async fn pause(
+        &self,
+        request: Request<AgentsRequest>,
+    ) -> Result<Response<AgentsResponse>, Status> {
+        self.switch(request.get_ref(), "paused", |agent| {
+            agent.switches.set_paused(true);
+        })
+    }
+
+    async fn resume(
+        &self,
+        request: Request<AgentsRequest>,
+    ) -> Result<Response<AgentsResponse>, Status> {
+        self.switch(request.get_ref(), "resumed", |agent| {
+            agent.switches.set_paused(false);
+        })
+    }
+
+    async fn drain(
+        &self,
+        request: Request<AgentsRequest>,
+    ) -> Result<Response<AgentsResponse>, Status> {
+        self.switch(request.get_ref(), "draining", |agent| {
+            agent.switches.drain();
+        })
+    }
+}
@@ -0,0 +1,79 @@
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Progress shared between an agent and its clones (e.g. the heartbeat task)
+#[derive(Debug, Clone, Default)]
+pub struct AgentStats(Arc<Mutex<Stats>>);
+
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct Stats {
+    pub last_event: Option<DateTime<Utc>>,
+    pub last_upload: Option<DateTime<Utc>>,
+    pub last_failure: Option<DateTime<Utc>>,
+    pub uploads: u64,
+    pub failures: u64,
+}
+
+impl AgentStats {
+    pub fn snapshot(&self) -> Stats {
+        *self.0.lock().unwrap()
+    }
+    pub fn record_event(&self) {
+        self.0.lock().unwrap().last_event = Some(Utc::now());
+    }
+    pub fn record_upload(&self) {
+        let mut stats = self.0.lock().unwrap();
+        stats.last_upload = Some(Utc::now());
+        stats.uploads += 1;
+    }
+    pub fn record_failure(&self) {
+        let mut stats = self.0.lock().unwrap();
+        stats.last_failure = Some(Utc::now());
+        stats.failures += 1;
+    }
+}
+
+/// Body of the heartbeat object, letting a fleet operator spot dead watchers from S3 alone
+#[derive(Serialize, Debug)]
+pub struct Heartbeat<'a> {
+    pub hostname: String,
+    pub version: &'a str,
+    pub agent: &'a str,
+    pub started_at: DateTime<Utc>,
+    pub timestamp: DateTime<Utc>,
+    /// Seconds until the next heartbeat
+    pub interval: u64,
+    /// Whether the agent deletes remote objects
+    pub mirroring: bool,
+    #[serde(flatten)]
+    pub stats: Stats,
+}
+
+/// Another instance's heartbeat, as far as it's needed to tell whether that instance is
+/// still running
+#[derive(Deserialize, Debug)]
+pub struct Peer {
+    pub hostname: String,
+    pub agent: String,
+    pub timestamp: DateTime<Utc>,
+    /// Missing from heartbeats of older versions
+    pub interval: Option<u64>,
+    #[serde(default)]
+    pub mirroring: bool,
+}
+
+impl Peer {
+    /// Whether the heartbeat is recent enough for the peer to still be running, allowing
+    /// it to miss two. `interval` stands in for peers that don't record theirs.
+    pub fn live(&self, now: DateTime<Utc>, interval: u64) -> bool {
+        let interval = self.interval.unwrap_or(interval).max(1);
+        now - self.timestamp
+            < chrono::Duration::seconds(i64::try_from(interval * 3).unwrap_or(i64::MAX))
+    }
+}
+
+pub fn hostname() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+use tokio::sync::OnceCell;
+
+use super::heartbeat;
+
+/// How long to wait on the instance metadata service, which isn't there off EC2
+const IMDS_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// EC2 instance ID from the instance metadata service, looked up once
+async fn instance_id() -> Option<&'static str> {
+    static INSTANCE_ID: OnceCell<Option<String>> = OnceCell::const_new();
+    INSTANCE_ID
+        .get_or_init(|| async {
+            let client = aws_config::imds::Client::builder()
+                .max_attempts(1)
+                .connect_timeout(IMDS_TIMEOUT)
+                .read_timeout(IMDS_TIMEOUT)
+                .build();
+            match client.get("/latest/meta-data/instance-id").await {
+                Ok(instance_id) => Some(instance_id.as_ref().to_string()),
+                Err(e) => {
+                    tracing::debug!("No EC2 instance ID: {e}");
+                    None
+                }
+            }
+        })
+        .await
+        .as_deref()
+}
+
+/// Object metadata identifying where an upload came from: the host, the EC2 instance when
+/// there is one, the agent and the s3sync version
+pub async fn metadata(agent: &str) -> Vec<(String, String)> {
+    let mut metadata = vec![
+        (String::from("source-host"), heartbeat::hostname()),
+        (String::from("source-agent"), agent.to_string()),
+        (
+            String::from("s3sync-version"),
+            env!("CARGO_PKG_VERSION").to_string(),
+        ),
+    ];
+    if let Some(instance_id) = instance_id().await {
+        metadata.push((String::from("source-instance-id"), instance_id.to_string()));
+    }
+    metadata
+}
@@ -0,0 +1,157 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use aws_sdk_s3 as s3;
+use chrono::{DateTime, Utc};
+use s3::{
+    error::{ProvideErrorMetadata, SdkError},
+    operation::get_object::GetObjectError,
+    types::ServerSideEncryption,
+};
+use serde::{Deserialize, Serialize};
+
+use super::Error;
+
+const DEFAULT_TTL: Duration = Duration::from_secs(30);
+
+/// Active/standby pairing through a lease object in the bucket. Instances running the same
+/// agent take turns holding it, only the holder uploads and deletes, and a standby takes
+/// over once the holder stops renewing.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LeaseSettings {
+    /// How long the lease lasts without being renewed, 30 seconds by default. It's renewed
+    /// every third of this.
+    #[serde(default, with = "humantime_serde")]
+    ttl: Option<Duration>,
+}
+
+impl LeaseSettings {
+    pub fn ttl(&self) -> Duration {
+        self.ttl.unwrap_or(DEFAULT_TTL)
+    }
+
+    pub fn renew_every(&self) -> Duration {
+        self.ttl() / 3
+    }
+}
+
+/// Whether this instance holds an agent's lease, shared with the renewal task, and
+/// whether it took the lease over since the agent last caught up
+#[derive(Debug, Clone, Default)]
+pub struct Held {
+    holding: Arc<AtomicBool>,
+    acquired: Arc<AtomicBool>,
+    renewing: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Held {
+    pub fn get(&self) -> bool {
+        self.holding.load(Ordering::Relaxed)
+    }
+
+    /// Record the outcome of a renewal, logging changes
+    pub fn set(&self, held: bool) {
+        match (self.holding.swap(held, Ordering::Relaxed), held) {
+            (false, true) => {
+                tracing::info!("Acquired the lease, now active");
+                self.acquired.store(true, Ordering::Relaxed);
+            }
+            (true, false) => tracing::warn!("Lost the lease, standing by"),
+            _ => {}
+        }
+    }
+
+    /// Wait for any renewal under way, holding off others until the guard is dropped
+    pub async fn renewing(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.renewing.lock().await
+    }
+
+    /// Whether the lease was acquired since this was last asked, so files that changed
+    /// while standing by have yet to be uploaded
+    pub fn acquired(&self) -> bool {
+        self.acquired.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// Body of the lease object
+#[derive(Serialize, Deserialize, Debug)]
+struct Record {
+    holder: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Identifies this process among the instances sharing a lease
+pub fn holder() -> String {
+    format!("{}:{}", super::heartbeat::hostname(), std::process::id())
+}
+
+/// Take or extend the lease in `key`, returning whether this instance holds it.
+///
+/// Writes are conditional on the object being unchanged since it was read, so of several
+/// instances racing for an expired lease only one wins.
+pub async fn renew(
+    client: &s3::Client,
+    bucket: &str,
+    key: &str,
+    holder: &str,
+    ttl: Duration,
+    sse: Option<ServerSideEncryption>,
+    kms_key_id: Option<&str>,
+) -> Result<bool, Error> {
+    let current = match client.get_object().bucket(bucket).key(key).send().await {
+        Ok(output) => {
+            let e_tag = output.e_tag.clone().unwrap_or_default();
+            let body = output.body.collect().await?.into_bytes();
+            Some((serde_json::from_slice::<Record>(&body)?, e_tag))
+        }
+        Err(e)
+            if e.as_service_error()
+                .is_some_and(GetObjectError::is_no_such_key) =>
+        {
+            None
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let now = Utc::now();
+    let request = client
+        .put_object()
+        .bucket(bucket)
+        .key(key)
+        .content_type("application/json")
+        .set_server_side_encryption(sse)
+        .set_ssekms_key_id(kms_key_id.map(String::from))
+        .body(
+            serde_json::to_vec(&Record {
+                holder: holder.to_string(),
+                expires_at: now + ttl,
+            })?
+            .into(),
+        );
+    let request = match current {
+        None => request.if_none_match("*"),
+        Some((record, e_tag)) if record.holder == holder || record.expires_at <= now => {
+            request.if_match(e_tag)
+        }
+        Some(_) => return Ok(false),
+    };
+    match request.send().await {
+        Ok(_) => Ok(true),
+        Err(e) if is_conflict(&e) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether another instance wrote the lease first
+fn is_conflict<E: ProvideErrorMetadata, R>(error: &SdkError<E, R>) -> bool {
+    matches!(
+        error
+            .as_service_error()
+            .and_then(ProvideErrorMetadata::code),
+        Some("PreconditionFailed" | "ConditionalRequestConflict")
+    )
+}
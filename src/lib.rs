@@ -0,0 +1,4326 @@
+//! The sync engine behind the `s3sync` binary: agents watching local paths and uploading
+//! what changes to S3. Load a [`Manager`] from the same YAML config the binary reads with
+//! [`Manager::from_yaml`], or put agents together with [`Agent::builder`] and
+//! [`Manager::from_agents`], then feed it the watchers' events.
+
+#![warn(clippy::pedantic)]
+#![warn(clippy::nursery)]
+// Everything fallible returns the one `Error`, whose variants say what went wrong, and
+// the only panics are on poisoned locks
+#![allow(clippy::missing_errors_doc, clippy::missing_panics_doc)]
+
+#[cfg(feature = "api")]
+mod api;
+mod aws_cli;
+pub mod build_info;
+pub mod builder;
+mod catalog;
+mod checksum;
+#[cfg(feature = "cloudwatch")]
+mod cloudwatch;
+mod collision;
+mod compat;
+mod config;
+mod control;
+mod dead_letter;
+mod deadline;
+#[cfg(not(all(
+    feature = "api",
+    feature = "cloudwatch",
+    feature = "grpc",
+    feature = "otlp"
+)))]
+mod disabled;
+mod estimate;
+mod fault;
+mod globs;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod heartbeat;
+mod identity;
+mod lease;
+mod metrics;
+mod multipart;
+mod on_success;
+mod open_files;
+mod options;
+#[cfg(feature = "otlp")]
+mod otlp;
+mod output;
+mod pacer;
+mod placeholder;
+mod provider;
+mod pull;
+mod queue;
+mod reconcile;
+mod remote;
+mod replay;
+mod replication;
+mod retry;
+mod scan;
+mod schedule;
+#[cfg(feature = "self-update")]
+pub mod self_update;
+mod shared_state;
+mod snapshot;
+mod sse;
+mod staging;
+mod state;
+mod storage_class;
+pub mod subscribers;
+mod trash;
+mod tune;
+pub mod ux;
+mod vanished;
+mod watch;
+mod watchdog;
+#[cfg(feature = "web-ui")]
+mod web_ui;
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashMap, HashSet},
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use aws_config::{sts::AssumeRoleProvider, Region, SdkConfig};
+use aws_runtime::env_config::file::{EnvConfigFileKind, EnvConfigFiles};
+#[cfg(feature = "dynamodb")]
+use aws_sdk_dynamodb as dynamodb;
+use aws_sdk_s3 as s3;
+use clap::ValueEnum;
+use derive_builder::Builder;
+use md5::{Digest, Md5};
+use notify_debouncer_mini::{notify::RecursiveMode, Config, DebounceEventHandler, DebouncedEvent};
+use regex::Regex;
+use s3::{
+    config::SharedCredentialsProvider,
+    error::{BuildError, ProvideErrorMetadata, SdkError},
+    operation::head_object::HeadObjectError,
+    primitives::ByteStream,
+    types::{ChecksumAlgorithm, CompletedPart, Delete, ObjectIdentifier, ServerSideEncryption},
+};
+use serde::Deserialize;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+use crate::ux::Cli;
+
+#[cfg(feature = "api")]
+pub use self::api::ApiSettings;
+#[cfg(feature = "cloudwatch")]
+pub use self::cloudwatch::{CloudWatchLayer, CloudWatchLogsSettings};
+#[cfg(not(feature = "api"))]
+pub use self::disabled::ApiSettings;
+#[cfg(not(feature = "grpc"))]
+pub use self::disabled::GrpcSettings;
+#[cfg(not(feature = "cloudwatch"))]
+pub use self::disabled::{CloudWatchLayer, CloudWatchLogsSettings};
+#[cfg(not(feature = "otlp"))]
+pub use self::disabled::{OtlpGuard, OtlpLayer, OtlpSettings};
+#[cfg(feature = "grpc")]
+pub use self::grpc::GrpcSettings;
+#[cfg(feature = "self-update")]
+pub use self::options::SelfUpdateOptions;
+#[cfg(feature = "otlp")]
+pub use self::otlp::{OtlpGuard, OtlpLayer, OtlpSettings};
+use self::{
+    aws_cli::S3Defaults,
+    collision::SuffixTemplate,
+    control::Switches,
+    dead_letter::DeadLetter,
+    deadline::Deadlines,
+    estimate::Estimate,
+    fault::FaultInjection,
+    globs::Globs,
+    heartbeat::{AgentStats, Heartbeat, Peer},
+    lease::LeaseSettings,
+    multipart::MultipartCleanup,
+    open_files::OpenFiles,
+    options::{parse_window, DEFAULT_EVENT_WINDOW},
+    output::{FileTimes, Lifecycle},
+    pacer::Pacer,
+    placeholder::Descriptor,
+    reconcile::{Pass, ReconcileSettings},
+    remote::{Lister, RemoteObject},
+    replication::Replication,
+    retry::RetrySettings,
+    schedule::Schedule,
+    shared_state::SharedState,
+    snapshot::SnapshotSettings,
+    staging::StagingSettings,
+    state::{Fingerprint, MultipartEntry, Reconciled, State},
+    trash::Trash,
+    tune::{AutoTune, Tuner},
+    vanished::Queued,
+    watch::DEFAULT_POLL_INTERVAL,
+    watchdog::WatchdogSettings,
+};
+pub use self::{
+    config::{LocalConfig, RemoteConfig},
+    control::Control,
+    metrics::MetricsSettings,
+    options::{
+        Backend, Checksum, Collision, ConfigFormat, DuplicatePolicy, EstimateOptions, KeyLayout,
+        LogFormat, LogPaths, MatchOn, Multipart, OnSuccess, OutputFormat, Placeholder, Provider,
+        PullOptions, ReplayOptions, Sse, StorageClass, UploadOrder, Vanished,
+    },
+    output::SkipReason,
+    queue::{Deferred, Queue, QueueSettings},
+    watch::Watch,
+    watchdog::Watchdog,
+};
+
+/// Directory under the agent's prefix holding objects written by s3sync itself
+const INTERNAL_PREFIX: &str = ".s3sync/";
+/// Default collision suffix under `on_success: truncate`, which uploads the same path
+/// over and over, possibly within a second
+const TRUNCATE_SUFFIX: &str = "-{timestamp}-{counter}";
+
+/// How long an agent's queue is left alone after files in it failed to upload
+const DEFERRED_HOLD_OFF: Duration = Duration::from_secs(30);
+
+/// Longest between checks for files past their upload deadline
+const DEADLINE_CHECK: Duration = Duration::from_secs(10);
+
+/// Maximum number of keys accepted by a single `DeleteObjects` request
+const DELETE_OBJECTS_BATCH_SIZE: usize = 1000;
+
+/// Characters escaped in a `CopyObject` source key, everything but unreserved
+/// characters and `/`
+const COPY_SOURCE: &percent_encoding::AsciiSet = &percent_encoding::NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+/// Uploads attempted before giving up on a checksum mismatch
+const CHECKSUM_ATTEMPTS: u32 = 3;
+
+/// Largest object a single `PutObject` request can create, 5 GiB
+const MAX_PUT_OBJECT_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+
+/// Largest object a multipart upload can create, 5 TiB
+const MAX_MULTIPART_OBJECT_SIZE: u64 = 5 * 1024 * 1024 * 1024 * 1024;
+
+/// AWS config for a credentials profile, with the region falling back to the profile's
+async fn sdk_config(profile_name: Option<&str>, region_name: Option<&str>) -> SdkConfig {
+    sdk_config_from(profile_name, region_name, None, None, false).await
+}
+
+/// [`sdk_config`] reading the profile from alternate config and credentials files, the
+/// default locations are still used for whichever isn't given. Anonymous configs skip
+/// credential resolution and send unsigned requests.
+async fn sdk_config_from(
+    profile_name: Option<&str>,
+    region_name: Option<&str>,
+    config_file: Option<&Path>,
+    credentials_file: Option<&Path>,
+    anonymous: bool,
+) -> SdkConfig {
+    // The loader's own region chain checks the environment, then this profile
+    let mut loader = aws_config::from_env().profile_name(profile_name.unwrap_or("default"));
+    if let Some(region) = region_name {
+        loader = loader.region(Region::new(region.to_string()));
+    }
+    if config_file.is_some() || credentials_file.is_some() {
+        let mut files = EnvConfigFiles::builder()
+            .include_default_config_file(config_file.is_none())
+            .include_default_credentials_file(credentials_file.is_none());
+        if let Some(path) = config_file {
+            files = files.with_file(EnvConfigFileKind::Config, path);
+        }
+        if let Some(path) = credentials_file {
+            files = files.with_file(EnvConfigFileKind::Credentials, path);
+        }
+        loader = loader.profile_files(files.build());
+    }
+    if anonymous {
+        loader = loader.no_credentials();
+    }
+    loader.load().await
+}
+
+/// Hash prefix for a key spread over `shards` prefixes, e.g. `00/` through `ff/` for 256
+fn shard_prefix(key: &str, shards: u32) -> String {
+    let digest = Md5::digest(key.as_bytes());
+    let hash = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % shards;
+    let width = format!("{:x}", shards - 1).len();
+    format!("{hash:0width$x}/")
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("No free suffixed key for {0}")]
+    NoFreeKey(String),
+    #[error("Another instance on {0} is syncing the same prefix")]
+    DuplicateInstance(String),
+    #[cfg(not(all(feature = "dynamodb", feature = "metrics-server", feature = "sqs")))]
+    #[error("s3sync was built without the `{0}` feature")]
+    Disabled(&'static str),
+    #[cfg(feature = "self-update")]
+    #[error("Release {tag} has no {name} asset")]
+    MissingReleaseAsset { tag: String, name: String },
+    #[cfg(feature = "self-update")]
+    #[error("Release tag {0} isn't a version")]
+    ReleaseVersion(String),
+    #[cfg(feature = "self-update")]
+    #[error("Checksum mismatch for {name}: expected {expected}, got {actual}")]
+    ReleaseChecksum {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("Invalid setting: {0}")]
+    InvalidSetting(&'static str),
+    #[error("A state database is required")]
+    MissingState,
+    #[error("Subscribers were already set for this process")]
+    SubscribersSet,
+    #[error("Not starting the upload without confirmation, pass --yes to go ahead")]
+    Unconfirmed,
+    #[error("State database error: {0}")]
+    State(#[from] rusqlite::Error),
+    #[error("Invalid glob: {0}")]
+    Glob(#[from] globset::Error),
+    #[error("The R2 provider needs an account_id, missing for '{0}'")]
+    MissingAccountId(String),
+    #[error("Bucket name is required")]
+    MissingBucket,
+    #[cfg(feature = "api")]
+    #[error("An API token is required")]
+    MissingApiToken,
+    #[error("Path is not under '{}'", .0.display())]
+    OutsideWatchedPath(PathBuf),
+    #[error("Path resolves outside of '{}': '{}'", .root.display(), .resolved.display())]
+    ExternalLink { root: PathBuf, resolved: PathBuf },
+    #[error("Downloaded '{}' doesn't match its ETag", .0.display())]
+    DownloadMismatch(PathBuf),
+    #[error("Non-unicode path: '{}'", .0.display())]
+    NonUnicodePath(PathBuf),
+    #[error("Invalid config: {0}")]
+    Config(#[from] serde_yaml::Error),
+    #[error("Invalid config: {0}")]
+    TomlConfig(#[from] toml::de::Error),
+    #[error("Invalid config: {0}")]
+    JsonConfig(serde_json::Error),
+    #[error(transparent)]
+    Aws(Box<s3::Error>),
+    #[cfg(feature = "dynamodb")]
+    #[error(transparent)]
+    DynamoDb(Box<dynamodb::Error>),
+    #[error(transparent)]
+    Build(#[from] BuildError),
+    #[error(transparent)]
+    ByteStream(#[from] s3::primitives::ByteStreamError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Scan(#[from] jwalk::Error),
+    #[error(transparent)]
+    Notify(#[from] notify_debouncer_mini::notify::Error),
+    #[error(transparent)]
+    Task(#[from] tokio::task::JoinError),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "self-update")]
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[cfg(feature = "metrics-server")]
+    #[error(transparent)]
+    MetricsBuild(#[from] metrics_exporter_prometheus::BuildError),
+    #[cfg(feature = "grpc")]
+    #[error(transparent)]
+    Grpc(#[from] tonic::transport::Error),
+    #[cfg(feature = "otlp")]
+    #[error(transparent)]
+    Otlp(#[from] opentelemetry_otlp::ExporterBuildError),
+}
+
+impl Error {
+    /// Whether the failure was S3's and likely passing, e.g. the host being offline or
+    /// throttled, so the upload may yet succeed. An error with no code never got a
+    /// response, or the response was cut off.
+    fn is_retryable(&self) -> bool {
+        match self {
+            Self::Aws(e) => matches!(
+                e.code(),
+                None | Some(
+                    "InternalError"
+                        | "ServiceUnavailable"
+                        | "SlowDown"
+                        | "RequestTimeout"
+                        | "Throttling"
+                        | "ThrottlingException"
+                        | "RequestLimitExceeded"
+                        | "TooManyRequests"
+                        | "OperationAborted"
+                )
+            ),
+            Self::ByteStream(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl<E, R> From<SdkError<E, R>> for Error
+where
+    s3::Error: From<SdkError<E, R>>,
+{
+    fn from(value: SdkError<E, R>) -> Self {
+        Self::Aws(Box::new(value.into()))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Manager {
+    #[serde(default)]
+    pub agents: Vec<Agent>,
+    /// Bucket-to-bucket agents, with no local path
+    #[serde(default)]
+    pub replications: Vec<Replication>,
+    pub metrics: Option<MetricsSettings>,
+    pub cloudwatch_logs: Option<CloudWatchLogsSettings>,
+    /// Span export to an OpenTelemetry collector
+    pub otlp: Option<OtlpSettings>,
+    pub api: Option<ApiSettings>,
+    /// gRPC control plane for fleet orchestration, with mutual TLS
+    pub grpc: Option<GrpcSettings>,
+    /// SQLite database recording uploaded files
+    pub state: Option<PathBuf>,
+    /// DynamoDB table recording uploaded files for every host, consulted after `state`
+    pub shared_state: Option<SharedState>,
+    /// Canary checks that the watchers are still delivering events
+    pub watchdog: Option<WatchdogSettings>,
+    /// Failed S3 calls and delayed events, for testing a config
+    pub fault_injection: Option<FaultInjection>,
+    /// Files uploaded at once as events come in, across all agents, one by default
+    pub max_concurrency: Option<usize>,
+}
+
+impl Manager {
+    /// Upload files held back by agents whose schedule has since opened, or that
+    /// another process has since closed, and what agents that just took their lease
+    /// over missed. Files that fail stay queued, and the agent's queue is left alone for
+    /// [`DEFERRED_HOLD_OFF`] before it's tried again.
+    pub async fn process_deferred(&self) {
+        for agent in &self.agents {
+            let draining = agent.switches.draining();
+            if agent.deferred.is_empty() || !draining && !agent.uploading() {
+                agent.switches.drained();
+                continue;
+            }
+            if agent.deferred.held() {
+                continue;
+            }
+            if draining {
+                let _span = agent.span().entered();
+                tracing::info!("Draining {} queued files", agent.deferred.len());
+            }
+            let _snapshots = Snapshots::take(vec![agent]);
+            let deferred = agent.deferred.take();
+            let mut failed = 0;
+            while let Some(path) = agent.deferred.next() {
+                let renamed = agent.queued.take(&path);
+                let result = if path.is_file() {
+                    Self::process_file(agent, &path).await
+                } else if let Some(renamed) = agent.vanished(&path, renamed) {
+                    // Renamed onto another queued file, that one's processed anyway
+                    if deferred.contains(&renamed) {
+                        Ok(())
+                    } else {
+                        Self::process_file(agent, &renamed).await
+                    }
+                } else {
+                    Ok(())
+                };
+                if Self::logged(agent, &path, &result) || agent.retried(&result) {
+                    Self::dequeued(agent, &path, agent.deferred.done(&path));
+                } else {
+                    failed += 1;
+                }
+            }
+            if failed > 0 {
+                tracing::warn!(
+                    parent: agent.span(),
+                    "{failed} queued files failed, trying again in {}",
+                    humantime::format_duration(DEFERRED_HOLD_OFF)
+                );
+                agent.deferred.hold_off(DEFERRED_HOLD_OFF);
+            }
+            agent.switches.drained();
+        }
+        for agent in &self.agents {
+            let timeout = agent.wait_for_close.unwrap_or_default();
+            let open = agent.open_files.paths();
+            for path in &open {
+                let result = if !path.is_file() {
+                    agent.open_files.clear(path);
+                    let renamed = agent.queued.take(path);
+                    match agent.vanished(path, renamed) {
+                        Some(renamed) if !open.contains(&renamed) => {
+                            let result = Self::process_file(agent, &renamed).await;
+                            (renamed, result)
+                        }
+                        _ => continue,
+                    }
+                } else if !open_files::is_open(path) || agent.open_files.timed_out(path, timeout) {
+                    agent.queued.take(path);
+                    (path.clone(), Self::process_file(agent, path).await)
+                } else {
+                    continue;
+                };
+                let (path, result) = result;
+                if !Self::logged(agent, &path, &result) && !agent.retried(&result) {
+                    // No longer waited on, so it's queued with the held back files
+                    Self::dequeued(agent, &path, agent.deferred.insert(path.clone()));
+                }
+            }
+        }
+        self.catch_up().await;
+    }
+
+    /// Log a failure to update an agent's queue, which otherwise only means the file
+    /// is handled again
+    fn dequeued(agent: &Agent, path: &Path, result: Result<(), Error>) {
+        if let Err(e) = result {
+            tracing::warn!(
+                parent: agent.span(),
+                path = agent.redact_path(path),
+                "Unable to update the queue: {e}"
+            );
+        }
+    }
+
+    /// Run each agent's reconcile pass when it's due, full or only over files modified
+    /// since the last pass, recording it as complete once every file was processed
+    pub async fn reconcile(&self) {
+        for agent in &self.agents {
+            let (Some(settings), Some(state)) = (&agent.reconcile, &agent.state) else {
+                continue;
+            };
+            if !agent.active() {
+                continue;
+            }
+            if let Err(e) = Self::reconcile_agent(agent, settings, state).await {
+                tracing::warn!(parent: agent.span(), "Reconcile failed: {e}");
+            }
+        }
+    }
+
+    /// One agent's pass, if it's due
+    async fn reconcile_agent(
+        agent: &Agent,
+        settings: &ReconcileSettings,
+        state: &State,
+    ) -> Result<(), Error> {
+        let last = state.last_reconcile(agent.name())?;
+        let started_at = chrono::Utc::now();
+        let Some(pass) = settings.due(last.as_ref(), started_at) else {
+            return Ok(());
+        };
+        match pass {
+            Pass::Full => tracing::info!(parent: agent.span(), "Reconciling every file"),
+            Pass::Since(since) => tracing::info!(
+                parent: agent.span(),
+                "Reconciling files modified since {since}"
+            ),
+        }
+        let _snapshots = Snapshots::take(vec![agent]);
+        let max_depth = if agent.watcher.settings.recursive() {
+            usize::MAX
+        } else {
+            1
+        };
+        let (mut processed, mut unmodified, mut failed) = (0, 0, 0);
+        let mut files =
+            scan::files_in_order(agent.watcher.local_path(), max_depth, agent.upload_order).await?;
+        while let Some(file) = files.next().await {
+            let file = match file {
+                Ok(file) => file,
+                Err(e) => {
+                    tracing::warn!(parent: agent.span(), "Unable to scan: {e}");
+                    failed += 1;
+                    continue;
+                }
+            };
+            if let Pass::Since(since) = pass {
+                let modified = match file.metadata().and_then(|metadata| metadata.modified()) {
+                    Ok(modified) => modified,
+                    // Deleted since the scan listed it
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => {
+                        Self::logged(agent, &file, &Err(e.into()));
+                        failed += 1;
+                        continue;
+                    }
+                };
+                if chrono::DateTime::<chrono::Utc>::from(modified) <= since {
+                    unmodified += 1;
+                    continue;
+                }
+            }
+            if Self::logged(agent, &file, &Self::process_file(agent, &file).await) {
+                processed += 1;
+            } else {
+                failed += 1;
+            }
+        }
+        let last_full = match (pass, last) {
+            (Pass::Since(_), Some(last)) => last.last_full,
+            _ => started_at,
+        };
+        state.record_reconcile(
+            agent.name(),
+            &Reconciled {
+                started: started_at,
+                completed: chrono::Utc::now(),
+                last_full,
+            },
+        )?;
+        tracing::info!(
+            parent: agent.span(),
+            "Reconciled {processed} files, {unmodified} unmodified since the last pass, \
+            {failed} failed"
+        );
+        Ok(())
+    }
+
+    /// Parse a YAML config
+    pub fn from_yaml(contents: &str) -> Result<Self, Error> {
+        Self::from_config(contents, ConfigFormat::Yaml)
+    }
+
+    /// Parse a config in any of the supported formats
+    pub fn from_config(contents: &str, format: ConfigFormat) -> Result<Self, Error> {
+        let manager: Self = format.parse(contents)?;
+        manager.prepared()
+    }
+
+    /// Read a config file, in `format` or else the one its extension names
+    pub fn from_file(path: &Path, format: Option<ConfigFormat>) -> Result<Self, Error> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::from_config(&contents, format.unwrap_or_else(|| ConfigFormat::of(path)))
+    }
+
+    /// Fill in what agents inherit or derive from the rest of the config, and check it
+    fn prepared(self) -> Result<Self, Error> {
+        self.with_agent_names()
+            .with_s3_defaults()
+            .with_fault_injection()
+            .with_watchers()?
+            .with_providers()?
+            .with_backends()?
+            .with_encryption()?
+            .with_roles()?
+            .with_on_success()?
+            .with_queues()?
+            .with_state()
+    }
+
+    fn with_queues(mut self) -> Result<Self, Error> {
+        for agent in &mut self.agents {
+            if let Some(queue) = &agent.queue {
+                agent.deferred = queue.open(agent)?;
+            }
+            if let Some(retry) = &agent.retry {
+                agent.retries = retry.queue().open(agent)?;
+            }
+        }
+        Ok(self)
+    }
+
+    fn with_s3_defaults(mut self) -> Self {
+        for agent in &mut self.agents {
+            agent.s3_defaults = S3Defaults::load(
+                agent.profile_name.as_deref().unwrap_or("default"),
+                agent.aws_config_file.as_deref(),
+            );
+        }
+        self
+    }
+
+    fn with_fault_injection(mut self) -> Self {
+        for agent in &mut self.agents {
+            agent.fault_injection = self.fault_injection;
+        }
+        self
+    }
+
+    /// Make sure every agent using a provider preset has what its endpoint needs
+    fn with_watchers(self) -> Result<Self, Error> {
+        if self
+            .agents
+            .iter()
+            .any(|agent| agent.watcher.local_path.as_os_str().is_empty())
+        {
+            return Err(Error::InvalidSetting("every agent needs a watcher"));
+        }
+        for replication in &self.replications {
+            if replication
+                .sides()
+                .iter()
+                .any(|side| side.bucket_name.is_none())
+            {
+                return Err(Error::MissingBucket);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Agents and the sides of replications, which share their credential settings
+    fn every_agent(&self) -> impl Iterator<Item = &Agent> {
+        self.agents
+            .iter()
+            .chain(self.replications.iter().flat_map(Replication::sides))
+    }
+
+    fn with_providers(self) -> Result<Self, Error> {
+        for agent in self.every_agent() {
+            if agent.provider == Some(Provider::R2) && agent.account_id.is_none() {
+                return Err(Error::MissingAccountId(agent.name().to_string()));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Make sure every agent's watcher backend exists on this platform
+    fn with_backends(self) -> Result<Self, Error> {
+        for agent in &self.agents {
+            if let Some(unavailable) = agent.watcher.settings.backend().unavailable() {
+                return Err(Error::InvalidSetting(unavailable));
+            }
+        }
+        Ok(self)
+    }
+
+    fn with_encryption(self) -> Result<Self, Error> {
+        for agent in &self.agents {
+            if agent.kms_key_id.is_some() && agent.sse.is_some_and(|sse| !sse.uses_kms()) {
+                return Err(Error::InvalidSetting("kms_key_id needs sse aws:kms"));
+            }
+        }
+        Ok(self)
+    }
+
+    fn with_roles(self) -> Result<Self, Error> {
+        for agent in self.every_agent() {
+            if agent.role_arn.is_none()
+                && (agent.external_id.is_some() || agent.session_name.is_some())
+            {
+                return Err(Error::InvalidSetting(
+                    "external_id and session_name need a role_arn",
+                ));
+            }
+            if agent.role_arn.is_some() && agent.anonymous == Some(true) {
+                return Err(Error::InvalidSetting(
+                    "role_arn can't be used with anonymous",
+                ));
+            }
+        }
+        Ok(self)
+    }
+
+    /// Fold the deprecated `delete` flag into each agent's `on_success` and check it
+    fn with_on_success(mut self) -> Result<Self, Error> {
+        for agent in &mut self.agents {
+            if agent.delete == Some(true) {
+                match agent.on_success {
+                    None | Some(OnSuccess::Delete) => {
+                        agent.on_success = Some(OnSuccess::Delete);
+                    }
+                    Some(_) => {
+                        return Err(Error::InvalidSetting(
+                            "delete can't be combined with another on_success",
+                        ))
+                    }
+                }
+            }
+            if agent.on_success == Some(OnSuccess::Truncate) {
+                // Each upload is a new piece of the file, so needs a key of its own
+                match agent.collision {
+                    None | Some(Collision::Suffix) => {
+                        agent.collision = Some(Collision::Suffix);
+                        agent.collision_suffix.get_or_insert_with(|| {
+                            SuffixTemplate::new(TRUNCATE_SUFFIX.to_string())
+                        });
+                    }
+                    Some(_) => {
+                        return Err(Error::InvalidSetting(
+                            "on_success truncate needs collision suffix",
+                        ))
+                    }
+                }
+                if agent.wait_for_close.is_some() {
+                    return Err(Error::InvalidSetting(
+                        "on_success truncate can't wait_for_close, writers keep the file open",
+                    ));
+                }
+            }
+            if let Some(invalid) = agent
+                .on_success
+                .as_ref()
+                .and_then(|on_success| on_success.invalid(agent.watcher.watch_path()))
+            {
+                return Err(Error::InvalidSetting(invalid));
+            }
+            if let Some(invalid) = agent
+                .collision_suffix
+                .as_ref()
+                .and_then(SuffixTemplate::invalid)
+            {
+                return Err(Error::InvalidSetting(invalid));
+            }
+        }
+        Ok(self)
+    }
+
+    fn with_state(mut self) -> Result<Self, Error> {
+        if self.state.is_none()
+            && self.agents.iter().any(|agent| {
+                agent.reconcile.is_some()
+                    || agent.resume.unwrap_or(false)
+                    || agent.catalog_interval.is_some()
+                    || agent.duplicate_window.is_some()
+            })
+        {
+            return Err(Error::MissingState);
+        }
+        if let Some(path) = &self.state {
+            let state = State::open(path)?;
+            for agent in &mut self.agents {
+                agent.state = Some(state.clone());
+            }
+        }
+        #[cfg(not(feature = "dynamodb"))]
+        if self.shared_state.is_some() {
+            return Err(Error::Disabled("dynamodb"));
+        }
+        for agent in &mut self.agents {
+            agent.shared_state = self
+                .shared_state
+                .as_ref()
+                .map(|shared_state| SharedState::new(shared_state.table.clone()));
+        }
+        Ok(self)
+    }
+
+    fn open_state(&self) -> Result<State, Error> {
+        State::open(self.state.as_deref().ok_or(Error::MissingState)?)
+    }
+
+    pub fn export_state(&self, output: impl std::io::Write) -> Result<usize, Error> {
+        self.open_state()?.export(output)
+    }
+
+    pub fn import_state(&self, input: impl std::io::BufRead) -> Result<usize, Error> {
+        self.open_state()?.import(input)
+    }
+
+    /// Upload every agent's catalog of uploaded files
+    pub async fn write_catalogs(&self) -> Result<(), Error> {
+        let state = self.open_state()?;
+        for agent in &self.agents {
+            let count = agent.write_catalog(&state).instrument(agent.span()).await?;
+            tracing::info!(parent: agent.span(), "Catalog of {count} files written");
+        }
+        Ok(())
+    }
+
+    fn with_agent_names(mut self) -> Self {
+        for (index, agent) in self.agents.iter_mut().enumerate() {
+            agent.name.get_or_insert_with(|| format!("agent-{index}"));
+        }
+        self
+    }
+
+    /// Spawn the replication polls, and the periodic tasks of every agent that has them
+    /// configured, the tasks stop when the returned set is dropped
+    #[must_use]
+    pub fn start_background_tasks(&self) -> JoinSet<()> {
+        let started_at = chrono::Utc::now();
+        let mut tasks = JoinSet::new();
+        for replication in &self.replications {
+            let replication = replication.clone();
+            let span = replication.span();
+            tasks.spawn(
+                async move {
+                    let mut interval = tokio::time::interval(replication.interval());
+                    loop {
+                        interval.tick().await;
+                        if let Err(e) = replication.run_once().await {
+                            metrics::record_failure(replication.name());
+                            tracing::warn!("Replication failed: {e}");
+                        }
+                    }
+                }
+                .instrument(span),
+            );
+        }
+        for agent in &self.agents {
+            agent.spawn_background_tasks(&mut tasks, started_at);
+        }
+        tasks
+    }
+
+    /// Look for agents on other hosts syncing the same prefix as a heartbeating agent
+    /// while either deletes remote objects, failing under the `refuse` policy
+    pub async fn check_duplicate_instances(&self) -> Result<(), Error> {
+        for agent in &self.agents {
+            agent.check_duplicate_instances().await?;
+        }
+        Ok(())
+    }
+
+    /// Log shipping layer, using the first agent's credentials
+    pub async fn log_layer(&self) -> Option<CloudWatchLayer> {
+        let settings = self.cloudwatch_logs.as_ref()?;
+        let sdk_config = self.agents.first()?.sdk_config().await;
+        Some(settings.layer(&sdk_config))
+    }
+
+    /// Span export layer, with the guard flushing it on exit
+    pub fn trace_layer<S>(&self) -> Result<Option<(OtlpLayer<S>, OtlpGuard)>, Error>
+    where
+        S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+    {
+        self.otlp.as_ref().map(OtlpSettings::layer).transpose()
+    }
+    #[must_use]
+    pub fn watchers(&self) -> Vec<AgentWatcher> {
+        let mut path_settings: HashMap<&Path, PathSettings> = HashMap::new();
+        for agent in &self.agents {
+            let watch_path = agent.watcher.watch_path();
+            if let Some(settings) = path_settings.get(watch_path) {
+                let settings = agent.watcher.settings.clone() + settings.clone();
+                path_settings.insert(watch_path, settings);
+            } else {
+                path_settings.insert(watch_path, agent.watcher.settings.clone());
+            }
+        }
+        path_settings
+            .into_iter()
+            .map(|(local_path, settings)| AgentWatcher {
+                local_path: local_path.to_path_buf(),
+                settings,
+            })
+            .collect()
+    }
+    pub async fn process_events(&self, events: &[DebouncedEvent]) -> Result<(), Error> {
+        if let Some(fault_injection) = &self.fault_injection {
+            fault_injection.delay_events().await;
+        }
+        let mut removed = Vec::new();
+        let paths = events
+            .iter()
+            .map(|event| event.path.as_path())
+            .collect::<HashSet<_>>();
+        let _snapshots = Snapshots::take(
+            self.agents
+                .iter()
+                .filter(|agent| paths.iter().any(|path| agent.watcher.watches(path)))
+                .collect(),
+        );
+        let mut uploads = Vec::new();
+        for event in events {
+            if event.kind == notify_debouncer_mini::DebouncedEventKind::Any && event.path.is_dir() {
+                self.process_directory(&event.path, &paths, &mut uploads)
+                    .await;
+            } else if event.path.exists() {
+                self.process_event(event, &mut uploads);
+            } else if event.kind == notify_debouncer_mini::DebouncedEventKind::Any {
+                tracing::debug!("Removed: {:?}", event.kind);
+                for agent in self.agents_for(&event.path) {
+                    agent.directories.forget(&event.path);
+                }
+                removed.push(event.path.as_path());
+            }
+        }
+        self.upload_all(uploads).await?;
+        if !removed.is_empty() {
+            for agent in &self.agents {
+                let removed = removed
+                    .iter()
+                    .copied()
+                    .filter(|path| agent.watcher.watches(path))
+                    .collect::<Vec<_>>();
+                if !removed.is_empty() {
+                    agent
+                        .delete_objects(&removed)
+                        .instrument(agent.span())
+                        .await;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the directories already under each recursive agent's path, before the
+    /// watch starts, so only those created or moved in later are walked for files
+    pub fn index_directories(&self) {
+        for agent in &self.agents {
+            if agent.watcher.settings.recursive() {
+                agent.directories.index(agent.watcher.watch_path());
+            }
+        }
+    }
+
+    /// Agents that events for `path` belong to, whose watched directory it's under
+    /// (directly, unless recursive) or whose watched file it is. Watch roots can
+    /// overlap, so there may be several.
+    fn agents_for<'a, 'p>(&'a self, path: &'p Path) -> impl Iterator<Item = &'a Agent> + 'p
+    where
+        'a: 'p,
+    {
+        self.agents
+            .iter()
+            .filter(move |agent| agent.watcher.watches(path))
+    }
+
+    /// Add the file an event is about to `uploads` for each agent it belongs to
+    fn process_event<'a>(
+        &'a self,
+        event: &DebouncedEvent,
+        uploads: &mut Vec<(&'a Agent, PathBuf)>,
+    ) {
+        if event.kind == notify_debouncer_mini::DebouncedEventKind::Any  // ignore AnyContinuous (i.e., still in progress)
+        && event.path.exists()
+        && event.path.is_file()
+        {
+            tracing::debug!("Process: {:?}", event.kind);
+            for agent in self.agents_for(&event.path) {
+                uploads.push((agent, event.path.clone()));
+            }
+        } else if event.kind == notify_debouncer_mini::DebouncedEventKind::Any
+            && event.path.exists()
+        {
+            for agent in self.agents_for(&event.path) {
+                let _span = agent.span().entered();
+                agent.skip(&agent.redact_path(&event.path), SkipReason::NotAFile);
+            }
+        }
+    }
+
+    /// Walk a directory that was created or moved into the tree, since the files it
+    /// brought along don't necessarily get events of their own, adding them to
+    /// `uploads`. Files that do are left to those events.
+    async fn process_directory<'a>(
+        &'a self,
+        dir: &Path,
+        events: &HashSet<&Path>,
+        uploads: &mut Vec<(&'a Agent, PathBuf)>,
+    ) {
+        for agent in self.agents_for(dir) {
+            if !agent.watcher.settings.recursive() {
+                let _span = agent.span().entered();
+                agent.skip(&agent.redact_path(dir), SkipReason::NotAFile);
+                continue;
+            }
+            if !agent.directories.discovered(dir) {
+                tracing::debug!("Directory already watched: {}", agent.redact_path(dir));
+                continue;
+            }
+            tracing::debug!("Walking new directory: {}", agent.redact_path(dir));
+            let mut files = match scan::files_in_order(dir, usize::MAX, agent.upload_order).await {
+                Ok(files) => files,
+                Err(e) => {
+                    tracing::warn!(parent: agent.span(), "Unable to scan: {e}");
+                    metrics::record_failure(agent.name());
+                    continue;
+                }
+            };
+            while let Some(file) = files.next().await {
+                let file = match file {
+                    Ok(file) => file,
+                    Err(e) => {
+                        tracing::warn!(parent: agent.span(), "Unable to scan: {e}");
+                        metrics::record_failure(agent.name());
+                        continue;
+                    }
+                };
+                // Directories it brought along, so their own events don't walk them again
+                for parent in file.ancestors().skip(1).take_while(|parent| *parent != dir) {
+                    agent.directories.discovered(parent);
+                }
+                if !events.contains(file.as_path()) {
+                    uploads.push((agent, file));
+                }
+            }
+        }
+    }
+
+    /// Process files for their agents on a pool of tasks, started in order and limited
+    /// to `max_concurrency` at once overall and each agent's own `max_concurrency`.
+    /// Failures are logged rather than stopping the rest, and counted.
+    async fn upload_all(&self, uploads: Vec<(&Agent, PathBuf)>) -> Result<usize, Error> {
+        let limit = self.max_concurrency.unwrap_or(1).max(1);
+        let mut tasks = JoinSet::new();
+        let mut running = HashMap::<String, usize>::new();
+        let mut failed = 0;
+        for (agent, path) in uploads {
+            let agent_limit = agent
+                .max_concurrency
+                .map_or(usize::MAX, |limit| limit.max(1));
+            while tasks.len() >= limit
+                || running.get(agent.name()).is_some_and(|&n| n >= agent_limit)
+            {
+                let Some(finished) = tasks.join_next().await else {
+                    break;
+                };
+                failed += usize::from(!Self::uploaded(&mut running, finished?));
+            }
+            *running.entry(agent.name().to_string()).or_default() += 1;
+            let agent = agent.clone();
+            tasks.spawn(async move {
+                let result = Self::process_file(&agent, &path).await;
+                (agent, path, result)
+            });
+        }
+        while let Some(finished) = tasks.join_next().await {
+            failed += usize::from(!Self::uploaded(&mut running, finished?));
+        }
+        Ok(failed)
+    }
+
+    /// Account for a finished upload, returning whether it succeeded
+    fn uploaded(
+        running: &mut HashMap<String, usize>,
+        (agent, path, result): (Agent, PathBuf, Result<(), Error>),
+    ) -> bool {
+        if let Some(count) = running.get_mut(agent.name()) {
+            *count -= 1;
+        }
+        Self::logged(&agent, &path, &result)
+    }
+
+    /// Log a failed upload, returning whether it succeeded
+    fn logged(agent: &Agent, path: &Path, result: &Result<(), Error>) -> bool {
+        if let Err(e) = result {
+            tracing::warn!(
+                parent: agent.span(),
+                path = agent.redact_path(path),
+                "Upload failed: {e}"
+            );
+        }
+        result.is_ok()
+    }
+    async fn process_file(agent: &Agent, path: &Path) -> Result<(), Error> {
+        let result = match agent.process_file(path).instrument(agent.span()).await {
+            // Deleted between being listed or queued and being read, so there's nothing
+            // left to upload
+            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound && !path.exists() => {
+                tracing::debug!(parent: agent.span(), path = agent.redact_path(path), "Gone");
+                return Ok(());
+            }
+            result => result,
+        };
+        if let Err(e) = &result {
+            metrics::record_failure(agent.name());
+            agent.stats.record_failure();
+            Lifecycle::Failed {
+                path: &agent.redact_path(path),
+                error: &e.to_string(),
+            }
+            .emit(agent.name());
+            if agent.retried(&result) {
+                tracing::info!(
+                    parent: agent.span(),
+                    path = agent.redact_path(path),
+                    "Queued for retry"
+                );
+                agent.retries.insert(path.to_path_buf())?;
+            }
+        }
+        result
+    }
+    pub async fn diff(&self) -> Result<(), Error> {
+        for agent in &self.agents {
+            let diff = agent.diff().instrument(agent.span()).await?;
+            let bucket_name = agent.bucket_name.as_deref().unwrap_or_default();
+            for key in &diff.missing {
+                println!("+ s3://{bucket_name}/{key}");
+            }
+            for key in &diff.changed {
+                println!("~ s3://{bucket_name}/{key}");
+            }
+            for key in &diff.extra {
+                println!("- s3://{bucket_name}/{key}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Upload what each agent with `initial_sync` has that the bucket lacks, before
+    /// the events that came in meanwhile are handled, as many at once as `sync` would.
+    /// Failures are only logged, the watch goes on regardless.
+    pub async fn initial_sync(&self) {
+        for agent in &self.agents {
+            agent.acquire_lease().instrument(agent.span()).await;
+            // Taken at startup rather than from another instance, there's no catching up
+            agent.lease_held.acquired();
+        }
+        let agents = self
+            .agents
+            .iter()
+            .filter(|agent| agent.initial_sync.unwrap_or(false) && agent.active())
+            .collect();
+        match self.upload_unsynced(agents, "Initial sync of").await {
+            Ok(0) => {}
+            Ok(failed) => tracing::warn!("{failed} initial sync uploads failed"),
+            Err(e) => tracing::warn!("Unable to run the initial sync: {e}"),
+        }
+    }
+
+    /// Upload what each agent has that the bucket lacks in a single pass, as
+    /// `initial_sync` does but without watching afterwards, returning whether every upload
+    /// succeeded. Agents whose lease another instance holds are left to it.
+    pub async fn sync(&self) -> Result<bool, Error> {
+        let mut agents = Vec::new();
+        for agent in &self.agents {
+            agent.acquire_lease().instrument(agent.span()).await;
+            if agent.active() {
+                agents.push(agent);
+            } else {
+                tracing::info!(parent: agent.span(), "Not syncing, another instance holds the lease");
+            }
+        }
+        let failed = self.upload_unsynced(agents, "Syncing").await?;
+        if failed > 0 {
+            tracing::warn!("{failed} uploads failed");
+        }
+        Ok(failed == 0)
+    }
+
+    /// Upload what each agent that just took its lease over from another instance
+    /// missed while standing by
+    async fn catch_up(&self) {
+        for agent in &self.agents {
+            if agent.lease_held.acquired() && agent.active() {
+                match self.upload_unsynced(vec![agent], "Catching up on").await {
+                    Ok(0) => {}
+                    Ok(failed) => tracing::warn!(parent: agent.span(), "{failed} uploads failed"),
+                    Err(e) => tracing::warn!(parent: agent.span(), "Unable to catch up: {e}"),
+                }
+            }
+        }
+    }
+
+    /// Upload what the agents have that their bucket lacks, each in its `upload_order`
+    /// or else by path, returning how many uploads failed
+    async fn upload_unsynced(&self, agents: Vec<&Agent>, verb: &str) -> Result<usize, Error> {
+        let _snapshots = Snapshots::take(agents.clone());
+        let mut uploads = Vec::new();
+        for agent in agents {
+            let files = agent.unsynced().instrument(agent.span()).await?;
+            tracing::info!(parent: agent.span(), "{verb} {} files", files.len());
+            let paths = files.into_iter().map(|file| file.path).collect();
+            uploads.extend(
+                scan::in_order(paths, agent.upload_order)
+                    .into_iter()
+                    .map(|path| (agent, path)),
+            );
+        }
+        self.upload_all(uploads).await
+    }
+
+    /// Estimate what the agents about to backfill would upload, or every agent with
+    /// `--estimate`, and ask whether to go ahead unless `--yes`. Without a terminal to
+    /// ask on, an explicit estimate fails and a first run goes ahead.
+    pub async fn confirm_estimate(&self, options: EstimateOptions) -> Result<bool, Error> {
+        let EstimateOptions { estimate: all, yes } = options;
+        let mut estimates = Vec::new();
+        for agent in &self.agents {
+            if all || agent.backfills()? {
+                let estimate = agent.estimate().instrument(agent.span()).await?;
+                let bucket_name = agent.bucket_name.as_deref().unwrap_or_default();
+                let prefix = agent.key_prefix.as_deref().unwrap_or_default();
+                eprintln!(
+                    "{} would upload to s3://{bucket_name}/{prefix}: {estimate}",
+                    agent.name()
+                );
+                estimates.push(estimate);
+            }
+        }
+        if estimates.is_empty() || yes {
+            return Ok(true);
+        }
+        if estimates.len() > 1 {
+            eprintln!("Total: {}", estimates.into_iter().sum::<Estimate>());
+        }
+        if !std::io::stdin().is_terminal() {
+            if all {
+                return Err(Error::Unconfirmed);
+            }
+            tracing::warn!("Starting the backfill unconfirmed, stdin isn't a terminal");
+            return Ok(true);
+        }
+        eprint!("Upload? [y/N] ");
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+    }
+
+    /// Server-side copy every object from an earlier prefix and shard layout to each
+    /// agent's current one, printing each move
+    pub async fn migrate_keys(&self, from: &KeyLayout) -> Result<(), Error> {
+        for agent in &self.agents {
+            if from.agent.as_ref().is_some_and(|name| name != agent.name()) {
+                continue;
+            }
+            let moves = agent.migrate_keys(from).instrument(agent.span()).await?;
+            let bucket_name = agent.bucket_name.as_deref().unwrap_or_default();
+            for (old, new) in &moves {
+                println!("s3://{bucket_name}/{old} -> s3://{bucket_name}/{new}");
+            }
+            if from.dry_run {
+                tracing::info!("Would migrate {} objects", moves.len());
+            } else {
+                tracing::info!("Migrated {} objects", moves.len());
+            }
+        }
+        Ok(())
+    }
+
+    /// Download each agent's objects, or the versions current at `--as-of`, printing
+    /// each change
+    pub async fn pull(&self, options: &PullOptions) -> Result<(), Error> {
+        let agents = self.agents.iter().filter(|agent| {
+            options
+                .agent
+                .as_ref()
+                .is_none_or(|name| name == agent.name())
+        });
+        if let Some(interval) = options.poll {
+            let options = Arc::new(options.clone());
+            let mut tasks = JoinSet::new();
+            for agent in agents {
+                let agent = agent.clone();
+                let options = options.clone();
+                let span = agent.span();
+                tasks.spawn(async move { agent.poll(&options, interval).await }.instrument(span));
+            }
+            while let Some(result) = tasks.join_next().await {
+                result?;
+            }
+            return Ok(());
+        }
+        for agent in agents {
+            let pull = agent.pull(options, None).instrument(agent.span()).await?;
+            agent.report_pull(&pull, options.dry_run);
+        }
+        Ok(())
+    }
+
+    /// Upload the files in an event log's `uploaded` events again, each to the key
+    /// recorded then rather than one derived from the current config. Files no longer
+    /// there, or recorded with hashed `log_paths`, can't be replayed.
+    pub async fn replay(&self, options: &ReplayOptions) -> Result<(), Error> {
+        let log = std::io::BufReader::new(std::fs::File::open(&options.from)?);
+        let (mut replayed, mut unavailable) = (0, 0);
+        for upload in replay::uploads(log, options.since)?.into_values() {
+            let target = format!("s3://{}/{}", upload.bucket, upload.key);
+            let Some(agent) = self.agents.iter().find(|agent| {
+                agent.name() == upload.agent
+                    && agent.bucket_name.as_deref() == Some(upload.bucket.as_str())
+            }) else {
+                tracing::warn!("No agent {} for {target}, skipping", upload.agent);
+                unavailable += 1;
+                continue;
+            };
+            let Some(path) = upload.path.filter(|path| path.is_file()) else {
+                tracing::warn!(parent: agent.span(), "Source of {target} is gone, skipping");
+                unavailable += 1;
+                continue;
+            };
+            println!("{} -> {target}", path.display());
+            if !options.dry_run {
+                agent
+                    .upload_file(&path, &path, &upload.key, &path.metadata()?)
+                    .instrument(agent.span())
+                    .await?;
+            }
+            replayed += 1;
+        }
+        let verb = if options.dry_run {
+            "Would replay"
+        } else {
+            "Replayed"
+        };
+        tracing::info!("{verb} {replayed} uploads, {unavailable} unavailable");
+        Ok(())
+    }
+
+    /// Print drift between local files and their objects, returning whether
+    /// everything matched
+    pub async fn verify(&self) -> Result<bool, Error> {
+        let mut clean = true;
+        for agent in &self.agents {
+            let verify = agent.verify().instrument(agent.span()).await?;
+            let bucket_name = agent.bucket_name.as_deref().unwrap_or_default();
+            for key in &verify.mismatched {
+                println!("! s3://{bucket_name}/{key}");
+            }
+            for key in &verify.missing {
+                println!("+ s3://{bucket_name}/{key}");
+            }
+            for key in &verify.unverifiable {
+                println!("? s3://{bucket_name}/{key}");
+            }
+            tracing::info!(
+                "Verified {} objects, {} mismatched, {} missing, {} unverifiable",
+                verify.verified,
+                verify.mismatched.len(),
+                verify.missing.len(),
+                verify.unverifiable.len()
+            );
+            clean &= verify.mismatched.is_empty() && verify.missing.is_empty();
+        }
+        Ok(clean)
+    }
+}
+
+/// Snapshots taken for a batch of uploads, removed when dropped so a failing batch
+/// doesn't leave them behind
+struct Snapshots<'a>(Vec<&'a Agent>);
+
+impl<'a> Snapshots<'a> {
+    fn take(agents: Vec<&'a Agent>) -> Self {
+        Self(
+            agents
+                .into_iter()
+                .filter(|agent| agent.create_snapshot())
+                .collect(),
+        )
+    }
+}
+
+impl Drop for Snapshots<'_> {
+    fn drop(&mut self) {
+        for agent in &self.0 {
+            agent.remove_snapshot();
+        }
+    }
+}
+
+/// Local side of a [`Diff`] or [`Verify`]
+#[derive(Debug)]
+struct LocalFile {
+    path: PathBuf,
+    size: u64,
+}
+
+/// Keys whose content no longer matches the `ETag` (`mismatched`), that were never
+/// uploaded (`missing`), or whose `ETag` isn't a plain MD5, e.g. multipart uploads
+/// (`unverifiable`)
+#[derive(Debug, Default)]
+pub struct Verify {
+    pub verified: usize,
+    pub mismatched: Vec<String>,
+    pub missing: Vec<String>,
+    pub unverifiable: Vec<String>,
+}
+
+/// Objects written to local files, files already matching their object (`unchanged`),
+/// and local files removed because their object didn't exist
+#[derive(Debug, Default)]
+pub struct Pull {
+    pub downloaded: Vec<(String, PathBuf)>,
+    pub unchanged: usize,
+    /// Lacking the required tags or metadata
+    pub held_back: usize,
+    pub removed: Vec<PathBuf>,
+}
+
+/// Keys that are only local (`missing`), differ in size (`changed`), or only exist
+/// in the bucket (`extra`)
+#[derive(Debug, Default)]
+pub struct Diff {
+    pub missing: Vec<String>,
+    pub changed: Vec<String>,
+    pub extra: Vec<String>,
+}
+
+impl TryFrom<Cli> for Manager {
+    type Error = Error;
+
+    fn try_from(value: Cli) -> Result<Self, Self::Error> {
+        if let Some(filename) = value.config {
+            Self::from_file(&filename, value.config_format)
+        } else {
+            let watcher = AgentWatcher {
+                settings: PathSettings::from(&value),
+                local_path: value.path,
+            };
+            let agent = Agent {
+                watcher,
+                pattern: value.pattern,
+                include: (!value.include.is_empty())
+                    .then(|| Globs::new(value.include))
+                    .transpose()?,
+                exclude: (!value.exclude.is_empty()).then_some(value.exclude),
+                exclude_globs: (!value.exclude_glob.is_empty())
+                    .then(|| Globs::new(value.exclude_glob))
+                    .transpose()?,
+                match_on: value.match_on,
+                bucket_name: value.bucket,
+                profile_name: value.profile,
+                region_name: value.region,
+                provider: value.provider,
+                account_id: value.account_id,
+                endpoint_url: value.endpoint_url,
+                force_path_style: value.force_path_style,
+                aws_config_file: value.aws_config_file,
+                aws_credentials_file: value.aws_credentials_file,
+                anonymous: value.anonymous,
+                role_arn: value.role_arn,
+                external_id: value.external_id,
+                session_name: value.role_session_name,
+                on_success: value.on_success,
+                verify_before_delete: value.verify_before_delete,
+                delete: value.delete,
+                delete_remote: value.delete_remote,
+                key_prefix: value.prefix,
+                follow_external_links: value.follow_external_links,
+                list_parallelism: value.list_parallelism,
+                shards: value.shards,
+                name: value.name,
+                log_paths: value.log_paths,
+                heartbeat_interval: value.heartbeat_interval,
+                catalog_interval: value.catalog_interval,
+                duplicate_window: value.duplicate_window,
+                max_upload_latency: value.max_upload_latency,
+                duplicate_instance: value.duplicate_instance,
+                max_uploads_per_second: value.max_uploads_per_second,
+                initial_sync: value.initial_sync,
+                upload_order: value.upload_order,
+                preserve_times: value.preserve_times,
+                stamp_identity: value.stamp_identity,
+                skip_unchanged: value.skip_unchanged,
+                checksum: value.checksum,
+                storage_class: value.storage_class,
+                sse: value.sse,
+                kms_key_id: value.kms_key_id,
+                compat: value.compat,
+                collision: value.collision,
+                collision_suffix: value.collision_suffix.map(SuffixTemplate::new),
+                wait_for_close: value.wait_for_close,
+                vanished: value.vanished,
+                resume: value.resume,
+                max_object_size: value.max_object_size,
+                multipart: value.multipart,
+                auto_tune: value.auto_tune.unwrap_or(false).then(AutoTune::default),
+                placeholder: value.placeholder,
+                dead_letter: value.dead_letter,
+                retry: RetrySettings::from_flags(value.retry_interval, value.retry_queue),
+                ..Agent::default()
+            };
+            let manager = Self {
+                agents: vec![agent],
+                replications: Vec::new(),
+                metrics: value.metrics_listen.map(MetricsSettings::new),
+                #[cfg(feature = "cloudwatch")]
+                cloudwatch_logs: value.cloudwatch_log_group.map(CloudWatchLogsSettings::new),
+                #[cfg(not(feature = "cloudwatch"))]
+                cloudwatch_logs: None,
+                #[cfg(feature = "otlp")]
+                otlp: value.otlp_endpoint.map(OtlpSettings::new),
+                #[cfg(not(feature = "otlp"))]
+                otlp: None,
+                api: None,
+                grpc: None,
+                state: value.state,
+                shared_state: value.state_table.map(SharedState::new),
+                watchdog: value.watchdog_interval.map(WatchdogSettings::new),
+                fault_injection: FaultInjection::new(
+                    value.fault_injection,
+                    value.fault_event_delay,
+                ),
+                max_concurrency: value.max_concurrency,
+            };
+            manager.prepared()
+        }
+    }
+}
+
+#[derive(Builder, Deserialize, Debug, Clone, Default)]
+#[builder(build_fn(error = "anyhow::Error"))]
+pub struct AgentWatcher {
+    local_path: PathBuf,
+    settings: PathSettings,
+}
+
+impl AgentWatcher {
+    #[must_use]
+    pub const fn local_path(&self) -> &PathBuf {
+        &self.local_path
+    }
+    /// Whether `path` is the single file being watched, rather than something under a
+    /// watched directory. Still true once the file is removed.
+    fn is_file(&self, path: &Path) -> bool {
+        path == self.local_path && !self.local_path.is_dir()
+    }
+    /// Whether `path` is the watched file, or under the watched directory, directly
+    /// under it unless recursive. Compared by component, so `/data/logs` doesn't
+    /// watch `/data/logs2`.
+    fn watches(&self, path: &Path) -> bool {
+        if self.is_file(path) {
+            return true;
+        }
+        path.strip_prefix(&self.local_path).is_ok_and(|relative| {
+            let depth = relative.components().count();
+            depth == 1 || depth > 1 && self.settings.recursive()
+        })
+    }
+    /// Name of the watched file, when not watching a directory
+    fn file_name(&self) -> Option<&str> {
+        if self.local_path.is_dir() {
+            None
+        } else {
+            self.local_path.file_name()?.to_str()
+        }
+    }
+    /// Directory handed to the OS watcher, the parent when watching a single file
+    /// so the file can be replaced or recreated
+    fn watch_path(&self) -> &Path {
+        if self.local_path.is_file() {
+            self.local_path.parent().unwrap_or(&self.local_path)
+        } else {
+            &self.local_path
+        }
+    }
+    /// Start watching, failing when the path can't be watched, e.g. once it's gone
+    pub fn watch<F: DebounceEventHandler + Clone>(&self, tx: F) -> Result<Watch, Error> {
+        let config = Config::default()
+            .with_timeout(self.settings.window())
+            .with_batch_mode(self.settings.batch())
+            .with_notify_config(
+                notify_debouncer_mini::notify::Config::default()
+                    .with_poll_interval(self.settings.poll_interval()),
+            );
+        let watch = Watch::start(
+            self.settings.backend(),
+            self.watch_path(),
+            self.settings.recursive_mode(),
+            self.settings
+                .watch_shards()
+                .filter(|_| self.local_path.is_dir()),
+            config,
+            tx,
+        )?;
+        tracing::info!("Watching: {self:?}");
+        Ok(watch)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PathSettings {
+    recursive: Option<bool>,
+    /// Humantime duration (e.g. `500ms`) or a number of seconds
+    #[serde(default, deserialize_with = "deserialize_window")]
+    window: Option<std::time::Duration>,
+    /// Deliver a window's events together (the default), or each one as soon as its
+    /// own window has passed
+    batch: Option<bool>,
+    /// Split a recursive watch over this many watcher instances by top-level
+    /// subdirectory, spreading very large trees across threads and kernel watch queues
+    watch_shards: Option<usize>,
+    /// Where events come from, the platform's native notifications by default
+    backend: Option<Backend>,
+    /// Time between scans with the `poll` backend, 30 seconds by default
+    #[serde(default, with = "humantime_serde")]
+    poll_interval: Option<std::time::Duration>,
+}
+
+impl From<&Cli> for PathSettings {
+    fn from(value: &Cli) -> Self {
+        Self {
+            recursive: value.recursive,
+            window: Some(value.window),
+            batch: value.batch,
+            watch_shards: value.watch_shards,
+            backend: value.backend,
+            poll_interval: value.poll_interval,
+        }
+    }
+}
+
+impl PathSettings {
+    #[must_use]
+    pub fn recursive(&self) -> bool {
+        self.recursive.unwrap_or(false)
+    }
+    #[must_use]
+    pub fn recursive_mode(&self) -> RecursiveMode {
+        if self.recursive() {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        }
+    }
+    #[must_use]
+    pub fn window(&self) -> std::time::Duration {
+        self.window.unwrap_or(DEFAULT_EVENT_WINDOW)
+    }
+    #[must_use]
+    pub fn batch(&self) -> bool {
+        self.batch.unwrap_or(true)
+    }
+    #[must_use]
+    pub fn backend(&self) -> Backend {
+        self.backend.unwrap_or_default()
+    }
+    #[must_use]
+    pub fn poll_interval(&self) -> std::time::Duration {
+        self.poll_interval.unwrap_or(DEFAULT_POLL_INTERVAL)
+    }
+    /// Longest an event can take to be delivered: the window, and with the `poll` backend
+    /// the time until the next scan
+    #[must_use]
+    pub fn event_delay(&self) -> std::time::Duration {
+        match self.backend() {
+            Backend::Poll => self.window() + self.poll_interval(),
+            _ => self.window(),
+        }
+    }
+    /// Number of watcher instances, only when recursive and splitting across more than one
+    #[must_use]
+    pub fn watch_shards(&self) -> Option<usize> {
+        self.watch_shards
+            .filter(|shards| self.recursive() && *shards > 1)
+    }
+}
+
+fn deserialize_window<'de, D>(deserializer: D) -> Result<Option<std::time::Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Window {
+        Seconds(u64),
+        Text(String),
+    }
+    let Some(window) = Option::<Window>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let text = match window {
+        Window::Seconds(seconds) => seconds.to_string(),
+        Window::Text(text) => text,
+    };
+    parse_window(&text)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+impl std::ops::Add for PathSettings {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        let window = std::cmp::min(self.window(), rhs.window());
+        let recursive = !matches!(
+            (self.recursive_mode(), rhs.recursive_mode()),
+            (RecursiveMode::NonRecursive, RecursiveMode::NonRecursive)
+        );
+        // Polling sees everything a native backend would, so it wins if either wants it
+        let backend = match (self.backend(), rhs.backend()) {
+            (Backend::Poll, _) | (_, Backend::Poll) => Backend::Poll,
+            (Backend::Auto, backend) | (backend, _) => backend,
+        };
+        Self {
+            window: Some(window),
+            batch: Some(self.batch() && rhs.batch()),
+            recursive: Some(recursive),
+            watch_shards: self.watch_shards.max(rhs.watch_shards),
+            backend: Some(backend),
+            poll_interval: Some(std::cmp::min(self.poll_interval(), rhs.poll_interval())),
+        }
+    }
+}
+
+impl Default for PathSettings {
+    fn default() -> Self {
+        Self {
+            recursive: Some(false),
+            window: Some(DEFAULT_EVENT_WINDOW),
+            batch: None,
+            watch_shards: None,
+            backend: None,
+            poll_interval: None,
+        }
+    }
+}
+
+/// Best-effort abort, an upload left behind is eventually cleaned up by
+/// `multipart_cleanup`
+async fn abort_multipart_upload(
+    client: &s3::Client,
+    bucket_name: &str,
+    key: &str,
+    upload_id: &str,
+) {
+    if let Err(e) = client
+        .abort_multipart_upload()
+        .bucket(bucket_name)
+        .key(key)
+        .upload_id(upload_id)
+        .send()
+        .await
+    {
+        tracing::warn!("Unable to abort multipart upload {upload_id}: {e}");
+    }
+}
+
+/// Whether an object is encrypted with KMS or a customer key, so its `ETag` isn't an MD5
+/// of the content even when uploaded in one part
+fn is_encrypted(
+    sse_customer_algorithm: Option<&str>,
+    server_side_encryption: Option<&ServerSideEncryption>,
+) -> bool {
+    sse_customer_algorithm.is_some()
+        || server_side_encryption.is_some_and(|sse| sse.as_str().starts_with("aws:kms"))
+}
+
+/// Hex MD5 of a file's contents, what S3 uses as the `ETag` of single-part uploads
+fn md5_hex(path: &Path) -> Result<String, Error> {
+    let mut hasher = Md5::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Whether S3 rejected an upload because its content didn't match the checksum
+fn is_checksum_mismatch<E: ProvideErrorMetadata, R>(error: &SdkError<E, R>) -> bool {
+    matches!(
+        error
+            .as_service_error()
+            .and_then(ProvideErrorMetadata::code),
+        Some("BadDigest" | "XAmzContentChecksumMismatch" | "XAmzContentSHA256Mismatch")
+    )
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct Agent {
+    /// Required of every agent but the sides of a replication
+    #[serde(default)]
+    watcher: AgentWatcher,
+    #[serde(with = "serde_regex", default)]
+    pattern: Option<Regex>,
+    /// Globs a key must match, on top of `pattern` when both are set
+    #[serde(alias = "include_globs")]
+    include: Option<Globs>,
+    /// Regexes dropping keys that `pattern` and `include` kept, e.g. `\.tmp$`
+    #[serde(with = "serde_regex", default)]
+    exclude: Option<Vec<Regex>>,
+    /// Globs dropping keys like `exclude`, e.g. `**/*.tmp`
+    exclude_globs: Option<Globs>,
+    /// What the include and exclude filters are matched against, the relative key by
+    /// default
+    match_on: Option<MatchOn>,
+    bucket_name: Option<String>,
+    key_prefix: Option<String>,
+    profile_name: Option<String>,
+    region_name: Option<String>,
+    /// S3-compatible service whose endpoint and quirks to use instead of AWS
+    provider: Option<Provider>,
+    /// Cloudflare account id, required with the R2 provider
+    account_id: Option<String>,
+    /// Endpoint of an S3-compatible server such as MinIO, Ceph RGW or LocalStack, taking
+    /// precedence over the provider's and `AWS_ENDPOINT_URL`. The region defaults to
+    /// `us-east-1` when none is configured.
+    endpoint_url: Option<String>,
+    /// Address buckets in the path rather than the host name, which most self-hosted
+    /// servers need, overriding the provider's choice
+    force_path_style: Option<bool>,
+    /// aws-cli config file to read the profile from instead of `~/.aws/config`
+    aws_config_file: Option<PathBuf>,
+    /// Credentials file to read the profile from instead of `~/.aws/credentials`
+    aws_credentials_file: Option<PathBuf>,
+    /// Send unsigned requests without looking for credentials, for public buckets
+    anonymous: Option<bool>,
+    /// IAM role assumed with the profile's credentials for all of the agent's requests,
+    /// e.g. to deliver to a bucket in another account
+    role_arn: Option<String>,
+    /// External id the role's trust policy asks for
+    external_id: Option<String>,
+    /// Name of the role session in CloudTrail, `s3sync-<hostname>` by default
+    session_name: Option<String>,
+    /// What happens to source files once they're uploaded
+    on_success: Option<OnSuccess>,
+    /// Before `on_success` removes or empties a source, send a `HeadObject` and keep the
+    /// source unless the object has its size and MD5
+    verify_before_delete: Option<bool>,
+    /// Deprecated, the same as `on_success: {type: delete}`
+    delete: Option<bool>,
+    delete_remote: Option<bool>,
+    follow_external_links: Option<bool>,
+    list_parallelism: Option<usize>,
+    shards: Option<u32>,
+    name: Option<String>,
+    log_paths: Option<LogPaths>,
+    /// Seconds between heartbeat objects
+    heartbeat_interval: Option<u64>,
+    /// How often to upload a CSV catalog of the agent's state database entries, with
+    /// the host that uploaded each, to `.s3sync/catalog/host=<host>/<agent>.csv`
+    #[serde(default, with = "humantime_serde")]
+    catalog_interval: Option<Duration>,
+    /// Longest a matched file may wait for its upload (e.g. `10m`), held back by the
+    /// schedule, still open or failing, before it's logged as an error, counted in
+    /// `s3sync_overdue_uploads_total` and sent to `overdue` subscribers
+    #[serde(default, with = "humantime_serde")]
+    max_upload_latency: Option<Duration>,
+    #[serde(skip)]
+    deadlines: Deadlines,
+    /// How long after uploading a file the same contents at the same path are skipped
+    /// as duplicates (e.g. `24h`), e.g. when a restart's initial scan finds files the
+    /// last run already shipped under another key. Needs a state database.
+    #[serde(default, with = "humantime_serde")]
+    duplicate_window: Option<Duration>,
+    /// What to do about other hosts' heartbeats under the same prefix, when either side
+    /// deletes remote objects, warning by default. Only checked with `heartbeat_interval`.
+    duplicate_instance: Option<DuplicatePolicy>,
+    /// Working hours and blackout windows, files detected outside are uploaded later
+    schedule: Option<Schedule>,
+    /// Where files held back by the schedule wait, in memory by default
+    queue: Option<QueueSettings>,
+    /// Queue files whose upload failed against S3 and retry them in the background
+    retry: Option<RetrySettings>,
+    /// Upper bound on uploads per second, unlimited when unset
+    max_uploads_per_second: Option<f64>,
+    /// Files this agent uploads at once as events come in, within the manager's
+    /// `max_concurrency`
+    max_concurrency: Option<usize>,
+    /// On startup, before handling any events, upload matching files the bucket is
+    /// missing or has at a different size, e.g. ones created while s3sync was stopped
+    initial_sync: Option<bool>,
+    /// Periodically upload files the watcher missed
+    reconcile: Option<ReconcileSettings>,
+    /// Share the agent with standby instances, only the lease holder uploading
+    lease: Option<LeaseSettings>,
+    /// Order files found by reconcile passes and new directories are uploaded in, as
+    /// they're found when unset
+    upload_order: Option<UploadOrder>,
+    /// Abort incomplete multipart uploads left behind under the prefix
+    multipart_cleanup: Option<MultipartCleanup>,
+    /// Upload each batch from a filesystem snapshot rather than the live files
+    snapshot: Option<SnapshotSettings>,
+    /// Checksum verified by S3, the transfer is retried when it doesn't match. Computed
+    /// from the file for single-request uploads and kept as `source-checksum-<algorithm>`
+    /// metadata, streamed as a trailer for each multipart part.
+    checksum: Option<Checksum>,
+    /// Storage class of uploaded objects, `STANDARD` by default. Internal objects such as
+    /// heartbeats stay in `STANDARD`.
+    storage_class: Option<StorageClass>,
+    /// Server-side encryption of every object the agent writes, for buckets whose policy
+    /// rejects unencrypted puts. `aws:kms` when only `kms_key_id` is set.
+    sse: Option<Sse>,
+    /// KMS key objects are encrypted with, by id, ARN or alias
+    kms_key_id: Option<String>,
+    /// Content types by file extension (without the dot), over the ones guessed from the
+    /// extension. Keys with no known extension are stored as `binary/octet-stream`.
+    content_types: Option<HashMap<String, String>>,
+    /// For S3-compatible servers that reject newer headers: no checksums unless required,
+    /// and uploads rejected over `checksum` or metadata are retried without them. On by
+    /// default with a `provider`.
+    compat: Option<bool>,
+    /// Store modification and (where the platform records it) birth time as `mtime` and
+    /// `btime` object metadata
+    preserve_times: Option<bool>,
+    /// Store where each object came from as `source-host`, `source-instance-id` (on
+    /// EC2), `source-agent` and `s3sync-version` object metadata
+    stamp_identity: Option<bool>,
+    /// Send a `HeadObject` before each upload and skip the file when the object already
+    /// has its size and MD5, from the `ETag` or the `source-md5` metadata stored on
+    /// uploads. Saves re-uploading files editors save again without changes.
+    skip_unchanged: Option<bool>,
+    /// Copy or link files here at event time and upload the copy
+    staging: Option<StagingSettings>,
+    /// What to do when the key already exists, overwriting by default
+    collision: Option<Collision>,
+    /// How colliding keys are renamed under the `suffix` policy
+    collision_suffix: Option<SuffixTemplate>,
+    /// Hold back files other processes still have open, for up to this long
+    #[serde(default, with = "humantime_serde")]
+    wait_for_close: Option<std::time::Duration>,
+    /// What to do about files removed or moved while deferred or waiting to be closed,
+    /// dropping them by default
+    vanished: Option<Vanished>,
+    /// Keep multipart uploads that fail or are interrupted and resume them, rather than
+    /// starting over. Needs a state database.
+    resume: Option<bool>,
+    /// Largest file to upload, in bytes, capped at the 5 TiB multipart limit
+    max_object_size: Option<u64>,
+    /// Part size, concurrency and the size from which files are uploaded in parts
+    #[serde(default)]
+    multipart: Multipart,
+    /// Adjust multipart part size and concurrency within these bounds from the throughput
+    /// and round trips uploads achieve, rather than sending a fixed part size one part at
+    /// a time. Files from 8 MiB go up in parts unless a threshold is set in `multipart` or
+    /// the aws-cli config.
+    auto_tune: Option<AutoTune>,
+    #[serde(skip)]
+    tuner: Tuner,
+    /// Announce files rather than upload them, with a zero-byte object or a JSON
+    /// descriptor of where each one is and what's in it. Files of any size are
+    /// announced unless `max_object_size` says otherwise.
+    placeholder: Option<Placeholder>,
+    /// Newline-delimited JSON file recording files that were rejected
+    dead_letter: Option<PathBuf>,
+    #[serde(skip)]
+    stats: AgentStats,
+    #[serde(skip)]
+    deferred: Deferred,
+    /// Files waiting for another try under `retry`
+    #[serde(skip)]
+    retries: Deferred,
+    #[serde(skip)]
+    pacer: Pacer,
+    #[serde(skip)]
+    state: Option<State>,
+    #[serde(skip)]
+    shared_state: Option<SharedState>,
+    #[serde(skip)]
+    snapshot_active: snapshot::Active,
+    #[serde(skip)]
+    open_files: OpenFiles,
+    #[serde(skip)]
+    queued: Queued,
+    #[serde(skip)]
+    switches: Switches,
+    #[serde(skip)]
+    fallbacks: compat::Fallbacks,
+    #[serde(skip)]
+    lease_held: lease::Held,
+    /// Transfer tuning from the profile's aws-cli `s3` section
+    #[serde(skip)]
+    s3_defaults: S3Defaults,
+    #[serde(skip)]
+    fault_injection: Option<FaultInjection>,
+    #[serde(skip)]
+    clients: Arc<Clients>,
+    #[serde(skip)]
+    directories: scan::Directories,
+}
+
+/// An agent's AWS config and S3 client, built on first use and shared by its clones so
+/// credentials are resolved once rather than for every request
+#[derive(Debug, Default)]
+struct Clients {
+    sdk_config: tokio::sync::OnceCell<SdkConfig>,
+    s3: tokio::sync::OnceCell<s3::Client>,
+}
+
+/// Only identifying, non-sensitive fields; credentials profile, region and prefix stay out
+/// of logs
+impl std::fmt::Debug for Agent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Agent")
+            .field("name", &self.name())
+            .field("bucket_name", &self.bucket_name)
+            .field("pattern", &self.pattern)
+            .field("include", &self.include)
+            .field("exclude", &self.exclude)
+            .field("exclude_globs", &self.exclude_globs)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Agent {
+    #[must_use]
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or_default()
+    }
+
+    /// Path or key as it should appear in logs, per `log_paths`
+    fn redact(&self, value: &str) -> String {
+        match self.log_paths.unwrap_or_default() {
+            LogPaths::Plain => value.to_string(),
+            LogPaths::Hash => format!("{:x}", Md5::digest(value.as_bytes()))[..12].to_string(),
+        }
+    }
+
+    fn redact_path(&self, path: &Path) -> String {
+        self.redact(&path.to_string_lossy())
+    }
+
+    /// Bucket name for spans, empty when missing
+    fn bucket(&self) -> &str {
+        self.bucket_name.as_deref().unwrap_or_default()
+    }
+
+    /// Span wrapping all of the agent's work
+    fn span(&self) -> tracing::Span {
+        tracing::info_span!(
+            "agent",
+            agent = self.name(),
+            bucket = self.bucket(),
+            prefix = self.key_prefix.as_deref().unwrap_or_default(),
+        )
+    }
+
+    /// Whether the state database, or failing that the shared state table, already has
+    /// this file at its current size and modification time
+    async fn unchanged(&self, key: &str, metadata: &std::fs::Metadata) -> bool {
+        if let Some(state) = &self.state {
+            match state.get(self.name(), key) {
+                Ok(entry) if entry.as_ref().is_some_and(|entry| entry.matches(metadata)) => {
+                    return true;
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Unable to read state: {e}"),
+            }
+        }
+        let Some(shared_state) = &self.shared_state else {
+            return false;
+        };
+        match shared_state
+            .get(&self.sdk_config().await, self.name(), key)
+            .await
+        {
+            Ok(entry) => entry.is_some_and(|entry| entry.matches(metadata)),
+            Err(e) => {
+                tracing::warn!("Unable to read shared state: {e}");
+                false
+            }
+        }
+    }
+
+    fn state_entry(
+        &self,
+        path: &Path,
+        key: &str,
+        e_tag: Option<&str>,
+        metadata: &std::fs::Metadata,
+    ) -> state::Entry {
+        state::Entry {
+            agent: self.name().to_string(),
+            key: key.to_string(),
+            path: path.to_path_buf(),
+            size: metadata.len(),
+            modified: state::modified(metadata).unwrap_or_default(),
+            e_tag: e_tag.map(String::from),
+            uploaded_at: chrono::Utc::now(),
+        }
+    }
+
+    /// Claim the key in the shared state table, where configured, returning whether this
+    /// host is the one to upload the file
+    async fn claim(
+        &self,
+        path: &Path,
+        key: &str,
+        metadata: &std::fs::Metadata,
+    ) -> Result<bool, Error> {
+        let Some(shared_state) = &self.shared_state else {
+            return Ok(true);
+        };
+        let entry = self.state_entry(path, key, None, metadata);
+        shared_state
+            .claim(&self.sdk_config().await, &entry, &lease::holder())
+            .await
+    }
+
+    /// Give up the claim on a key that failed to upload, so another host can try
+    async fn release(&self, key: &str) {
+        let Some(shared_state) = &self.shared_state else {
+            return;
+        };
+        if let Err(e) = shared_state
+            .release(&self.sdk_config().await, self.name(), key, &lease::holder())
+            .await
+        {
+            tracing::warn!("Unable to release the shared state claim: {e}");
+        }
+    }
+
+    /// Record a successful upload in the state database and shared state table, where
+    /// configured, with the file's `metadata` from before it was read for upload
+    async fn record_state(
+        &self,
+        path: &Path,
+        key: &str,
+        e_tag: Option<&str>,
+        metadata: &std::fs::Metadata,
+    ) -> Result<(), Error> {
+        if self.state.is_none() && self.shared_state.is_none() {
+            return Ok(());
+        }
+        let entry = self.state_entry(path, key, e_tag, metadata);
+        if let Some(state) = &self.state {
+            state.record(&entry)?;
+        }
+        if let Some(shared_state) = &self.shared_state {
+            shared_state
+                .record(&self.sdk_config().await, &entry)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Take the agent's snapshot, if it has one configured, returning whether it did
+    fn create_snapshot(&self) -> bool {
+        let _span = self.span().entered();
+        self.snapshot
+            .as_ref()
+            .is_some_and(|snapshot| snapshot.create(&self.snapshot_active))
+    }
+
+    fn remove_snapshot(&self) {
+        let _span = self.span().entered();
+        if let Some(snapshot) = &self.snapshot {
+            snapshot.remove(&self.snapshot_active);
+        }
+    }
+
+    /// Where to read a file's contents from, inside the snapshot while one is active
+    fn source_path(&self, path: &Path) -> PathBuf {
+        self.snapshot.as_ref().map_or_else(
+            || path.to_path_buf(),
+            |snapshot| snapshot.source(&self.snapshot_active, self.watcher.local_path(), path),
+        )
+    }
+
+    /// Smallest file uploaded in parts, from the agent or the aws-cli config, else the
+    /// default part size when tuning and otherwise anything too big for a single PUT
+    fn multipart_threshold(&self) -> u64 {
+        self.multipart
+            .threshold
+            .or(self.s3_defaults.multipart_threshold)
+            .unwrap_or_else(|| {
+                if self.auto_tune.is_some() {
+                    multipart::DEFAULT_PART_SIZE
+                } else {
+                    MAX_PUT_OBJECT_SIZE + 1
+                }
+            })
+    }
+
+    /// Part size for a file of `bytes` when not tuning, within the limits S3 sets
+    fn part_size(&self, bytes: u64) -> u64 {
+        self.multipart
+            .part_size
+            .or(self.s3_defaults.multipart_chunksize)
+            .unwrap_or(multipart::DEFAULT_PART_SIZE)
+            .max(multipart::MIN_PART_SIZE)
+            .max(bytes.div_ceil(multipart::MAX_PARTS))
+    }
+
+    fn max_object_size(&self) -> u64 {
+        let limit = if self.placeholder.is_some() {
+            u64::MAX
+        } else {
+            MAX_MULTIPART_OBJECT_SIZE
+        };
+        self.max_object_size.map_or(limit, |size| size.min(limit))
+    }
+
+    /// Whether the agent uploads and deletes, false for a standby without the lease
+    fn active(&self) -> bool {
+        self.lease.is_none() || self.lease_held.get()
+    }
+
+    /// Whether detected files are uploaded now rather than queued, for the schedule and
+    /// the control plane's pause
+    fn uploading(&self) -> bool {
+        !self.switches.paused() && self.schedule_open()
+    }
+    fn schedule_open(&self) -> bool {
+        self.schedule
+            .as_ref()
+            .is_none_or(|schedule| schedule.is_open(chrono::Utc::now()))
+    }
+
+    /// Key relative to the watched path, or just the file name when watching a single file
+    fn relative_key<'a>(&self, path: &'a Path) -> Result<&'a str, Error> {
+        let relative = if self.watcher.is_file(path) {
+            path.file_name().map(Path::new)
+        } else {
+            path.strip_prefix(self.watcher.local_path())
+                .ok()
+                .filter(|relative| !relative.as_os_str().is_empty())
+        };
+        relative
+            .ok_or_else(|| Error::OutsideWatchedPath(self.watcher.local_path.clone()))?
+            .to_str()
+            .ok_or_else(|| Error::NonUnicodePath(path.to_path_buf()))
+    }
+
+    #[tracing::instrument(skip_all, fields(path = self.redact_path(path)))]
+    /// Object key for a local file, or `None` when the filters exclude it
+    fn object_key(&self, path: &Path) -> Result<Option<String>, Error> {
+        let key = self.relative_key(path)?;
+        if key.starts_with(trash::DIR) || key == watchdog::CANARY {
+            return Ok(None);
+        }
+        tracing::debug!("Proposed object key: '{}'", self.redact(key));
+        if self.matches(key) {
+            let key = self.remote_key(key);
+            tracing::debug!("Final object key '{}'", self.redact(&key));
+            Ok(Some(key))
+        } else {
+            tracing::debug!("Path does not match pattern");
+            Ok(None)
+        }
+    }
+
+    /// Prefix and shard applied to a relative key
+    fn remote_key(&self, key: &str) -> String {
+        let prefix = self.key_prefix.as_deref().unwrap_or_default();
+        let shard = self.shard(key).unwrap_or_default();
+        format!("{prefix}{shard}{key}")
+    }
+
+    fn shard_count(&self) -> Option<u32> {
+        self.shards.filter(|shards| *shards > 1)
+    }
+
+    /// Hash prefix spreading keys over the configured number of shards, e.g. `00/`
+    /// through `ff/` for 256
+    fn shard(&self, key: &str) -> Option<String> {
+        self.shard_count().map(|shards| shard_prefix(key, shards))
+    }
+
+    /// Inverse of the prefix and shard mapping applied by [`Self::object_key`]
+    fn original_key<'a>(&self, key: &'a str) -> Option<&'a str> {
+        let key = key.strip_prefix(self.key_prefix.as_deref().unwrap_or_default())?;
+        if key.starts_with(INTERNAL_PREFIX) {
+            return None;
+        }
+        if let Some(file_name) = self.watcher.file_name() {
+            return self
+                .shard_count()
+                .map_or(Some(key), |_| key.split_once('/').map(|(_, key)| key))
+                .filter(|key| *key == file_name);
+        }
+        if self.shard_count().is_some() {
+            key.split_once('/').map(|(_, key)| key)
+        } else {
+            Some(key)
+        }
+    }
+
+    /// A key is kept when it matches `include` and `pattern`, each matching everything
+    /// when unset, and neither `exclude` nor `exclude_globs`
+    fn matches(&self, key: &str) -> bool {
+        let subject = self.match_subject(key);
+        let key = subject.as_ref();
+        if let Some(include) = &self.include {
+            tracing::debug!("Globs to match: '{include}'");
+            if !include.is_match(key) {
+                return false;
+            }
+        }
+        let applied_pattern = self
+            .pattern
+            .clone()
+            .unwrap_or_else(|| Regex::new(r".*").unwrap());
+        tracing::debug!("Pattern to match: '{applied_pattern}'");
+        if !applied_pattern.is_match(key) {
+            return false;
+        }
+        if let Some(excluded) = self.exclude.iter().flatten().find(|e| e.is_match(key)) {
+            tracing::debug!("Excluded by '{excluded}'");
+            return false;
+        }
+        if let Some(excluded) = self.exclude_globs.as_ref().filter(|e| e.is_match(key)) {
+            tracing::debug!("Excluded by globs '{excluded}'");
+            return false;
+        }
+        true
+    }
+
+    /// Form of the relative key the filters see, per `match_on`
+    fn match_subject<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match self.match_on.unwrap_or_default() {
+            MatchOn::Relative => Cow::Borrowed(key),
+            MatchOn::Filename => Cow::Borrowed(
+                Path::new(key)
+                    .file_name()
+                    .and_then(std::ffi::OsStr::to_str)
+                    .unwrap_or(key),
+            ),
+            MatchOn::Absolute => {
+                let path = if self.watcher.file_name().is_some() {
+                    self.watcher.local_path().clone()
+                } else {
+                    self.watcher.local_path().join(key)
+                };
+                Cow::Owned(path.to_string_lossy().into_owned())
+            }
+        }
+    }
+
+    /// Every file under the watched path that maps to an object key, with its size
+    async fn local_files(&self) -> Result<HashMap<String, LocalFile>, Error> {
+        let max_depth = if self.watcher.settings.recursive() {
+            usize::MAX
+        } else {
+            1
+        };
+        let mut files = HashMap::new();
+        let mut scanned = scan::files(self.watcher.local_path(), max_depth);
+        while let Some(path) = scanned.recv().await {
+            let path = path?;
+            if let Ok(Some(key)) = self.object_key(&path) {
+                let size = path.metadata()?.len();
+                files.insert(key, LocalFile { path, size });
+            }
+        }
+        Ok(files)
+    }
+
+    fn lister(&self, client: s3::Client) -> Result<Lister, Error> {
+        let bucket_name = self.bucket_name.clone().ok_or(Error::MissingBucket)?;
+        Ok(
+            Lister::new(client, bucket_name, self.key_prefix.clone()).parallelism(
+                self.list_parallelism
+                    .or(self.s3_defaults.max_concurrent_requests)
+                    .unwrap_or(1),
+            ),
+        )
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn diff(&self) -> Result<Diff, Error> {
+        let mut local = self.local_files().await?;
+        let mut diff = Diff::default();
+        let mut objects = self.lister(self.client().await)?.stream();
+        while let Some(object) = objects.recv().await {
+            let object = object?;
+            match local.remove(&object.key) {
+                Some(file) if u64::try_from(object.size).ok() == Some(file.size) => {}
+                Some(_) => diff.changed.push(object.key),
+                None => {
+                    if self
+                        .original_key(&object.key)
+                        .is_some_and(|key| self.matches(key))
+                    {
+                        diff.extra.push(object.key);
+                    }
+                }
+            }
+        }
+        diff.missing = local.into_keys().collect();
+        diff.missing.sort();
+        diff.changed.sort();
+        diff.extra.sort();
+        Ok(diff)
+    }
+
+    /// Matching local files the bucket is missing or has at a different size
+    async fn unsynced(&self) -> Result<Vec<LocalFile>, Error> {
+        let mut local = self.local_files().await?;
+        let mut objects = self.lister(self.client().await)?.stream();
+        while let Some(object) = objects.recv().await {
+            let object = object?;
+            if local
+                .get(&object.key)
+                .is_some_and(|file| u64::try_from(object.size).ok() == Some(file.size))
+            {
+                local.remove(&object.key);
+            }
+        }
+        Ok(local.into_values().collect())
+    }
+
+    /// Local files missing from the bucket or of a different size there, and the
+    /// requests uploading them would take
+    #[tracing::instrument(skip(self))]
+    async fn estimate(&self) -> Result<Estimate, Error> {
+        let mut estimate = Estimate::default();
+        for file in self.unsynced().await? {
+            if self.placeholder.is_some() {
+                estimate.add(0, 1);
+            } else {
+                estimate.add(file.size, self.upload_requests(file.size));
+            }
+        }
+        Ok(estimate)
+    }
+
+    /// Requests uploading a file of `bytes` takes, counting those creating and
+    /// completing a multipart upload
+    fn upload_requests(&self, bytes: u64) -> u64 {
+        if bytes < self.multipart_threshold() {
+            return 1;
+        }
+        bytes.div_ceil(self.part_size(bytes)) + 2
+    }
+
+    /// Whether the agent is about to upload every file the bucket is missing, with an
+    /// initial sync or because it has never finished a reconcile pass
+    fn backfills(&self) -> Result<bool, Error> {
+        if self.initial_sync.unwrap_or(false) {
+            return Ok(true);
+        }
+        match (&self.reconcile, &self.state) {
+            (Some(_), Some(state)) => Ok(state.last_reconcile(self.name())?.is_none()),
+            _ => Ok(false),
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn verify(&self) -> Result<Verify, Error> {
+        let mut local = self.local_files().await?;
+        let mut verify = Verify::default();
+        let mut objects = self.lister(self.client().await)?.stream();
+        while let Some(object) = objects.recv().await {
+            let object = object?;
+            let Some(file) = local.remove(&object.key) else {
+                continue;
+            };
+            match self.matches_object(&object, &file.path).await? {
+                Some(true) => verify.verified += 1,
+                Some(false) => verify.mismatched.push(object.key),
+                None => verify.unverifiable.push(object.key),
+            }
+        }
+        verify.missing = local.into_keys().collect();
+        verify.mismatched.sort();
+        verify.missing.sort();
+        verify.unverifiable.sort();
+        Ok(verify)
+    }
+
+    /// Whether a file has the contents of its object, going by the `source-md5` or
+    /// `source-checksum-*` metadata stored with it or else a plain MD5 `ETag`. `None`
+    /// when neither says, e.g. for multipart, KMS or customer-key encrypted objects
+    /// without the metadata, or placeholders.
+    async fn matches_object(
+        &self,
+        object: &RemoteObject,
+        path: &Path,
+    ) -> Result<Option<bool>, Error> {
+        let head = self
+            .client()
+            .await
+            .head_object()
+            .bucket(self.bucket_name.as_deref().ok_or(Error::MissingBucket)?)
+            .key(&object.key)
+            .send()
+            .await?;
+        let metadata = head.metadata.unwrap_or_default();
+        if let Some(md5) = metadata.get("source-md5") {
+            return Ok(Some(md5_hex(path)? == *md5));
+        }
+        for checksum in Checksum::value_variants() {
+            if let Some(value) = metadata.get(&checksum.metadata_key()) {
+                return Ok(Some(checksum.of_file(path)? == *value));
+            }
+        }
+        let encrypted = is_encrypted(
+            head.sse_customer_algorithm.as_deref(),
+            head.server_side_encryption.as_ref(),
+        );
+        let Some(e_tag) = object
+            .e_tag
+            .as_deref()
+            .map(|e_tag| e_tag.trim_matches('"'))
+            .filter(|e_tag| !e_tag.contains('-') && !encrypted && self.placeholder.is_none())
+        else {
+            return Ok(None);
+        };
+        Ok(Some(md5_hex(path)? == e_tag))
+    }
+
+    /// Local path an object's relative key is written to, `None` for keys that would
+    /// land outside the watched path
+    fn local_path_for(&self, relative: &str) -> Option<PathBuf> {
+        if self.watcher.file_name().is_some() {
+            return Some(self.watcher.local_path().clone());
+        }
+        (!relative.starts_with(trash::DIR)
+            && relative != watchdog::CANARY
+            && Path::new(relative)
+                .components()
+                .all(|component| matches!(component, std::path::Component::Normal(_))))
+        .then(|| self.watcher.local_path().join(relative))
+    }
+
+    /// Print what a pull changed
+    fn report_pull(&self, pull: &Pull, dry_run: bool) {
+        let bucket_name = self.bucket_name.as_deref().unwrap_or_default();
+        for (key, path) in &pull.downloaded {
+            println!("s3://{bucket_name}/{key} -> {}", path.display());
+        }
+        for path in &pull.removed {
+            println!("- {}", path.display());
+        }
+        let verb = if dry_run { "Would pull" } else { "Pulled" };
+        tracing::info!(
+            "{verb} {} objects, {} up to date, {} held back, {} local files removed",
+            pull.downloaded.len(),
+            pull.unchanged,
+            pull.held_back,
+            pull.removed.len()
+        );
+    }
+
+    /// Pull every `interval` until stopped, only logging failed polls
+    async fn poll(&self, options: &PullOptions, interval: Duration) {
+        tokio::time::sleep(pull::jitter(interval)).await;
+        let mut cursor = pull::Cursor::default();
+        loop {
+            match self.pull(options, Some(&mut cursor)).await {
+                Ok(pull) if pull.downloaded.is_empty() && pull.removed.is_empty() => {}
+                Ok(pull) => self.report_pull(&pull, options.dry_run),
+                Err(e) => tracing::warn!("Poll failed: {e}"),
+            }
+            tokio::time::sleep(interval + pull::jitter(interval / 10)).await;
+        }
+    }
+
+    /// Download objects, the versions current at `--as-of`, or when polling the current
+    /// objects changed since the `cursor`
+    #[tracing::instrument(skip_all)]
+    async fn pull(
+        &self,
+        options: &PullOptions,
+        mut cursor: Option<&mut pull::Cursor>,
+    ) -> Result<Pull, Error> {
+        let bucket_name = self.bucket_name.clone().ok_or(Error::MissingBucket)?;
+        let client = self.client().await;
+        let prefix = self.key_prefix.as_deref();
+        let versions = if let Some(cursor) = &cursor {
+            let start_after = options.append_only.then_some(&**cursor);
+            let mut versions =
+                pull::current_versions(&client, &bucket_name, prefix, start_after).await?;
+            if options.append_only {
+                versions.extend(pull::held_back_versions(&client, &bucket_name, cursor).await?);
+            }
+            versions
+        } else {
+            let as_of = options.as_of.unwrap_or_else(chrono::Utc::now);
+            pull::versions_as_of(&client, &bucket_name, prefix, &as_of).await?
+        };
+        let mut pull = Pull::default();
+        let mut held_back = BTreeSet::new();
+        let trash = (options.trash && !options.dry_run).then(|| {
+            Trash::new(
+                self.watcher.watch_path(),
+                options.trash_max_size,
+                options.trash_retention,
+            )
+        });
+        for (key, version) in &versions {
+            let Some(relative) = self.original_key(key).filter(|key| self.matches(key)) else {
+                continue;
+            };
+            let Some(path) = self.local_path_for(relative) else {
+                tracing::warn!("Not pulling '{}', it escapes the path", self.redact(key));
+                continue;
+            };
+            let e_tag = version
+                .e_tag
+                .as_deref()
+                .map(|e_tag| e_tag.trim_matches('"'));
+            if cursor.as_ref().is_some_and(|cursor| cursor.pulled(version)) && path.is_file() {
+                pull.unchanged += 1;
+                continue;
+            }
+            if let (Ok(metadata), Some(e_tag)) = (path.metadata(), e_tag) {
+                if u64::try_from(version.size).ok() == Some(metadata.len())
+                    && !e_tag.contains('-')
+                    && md5_hex(&path)? == e_tag
+                {
+                    pull.unchanged += 1;
+                    continue;
+                }
+            }
+            if !options.admits(&client, &bucket_name, key, version).await? {
+                pull.held_back += 1;
+                held_back.insert(key.clone());
+                continue;
+            }
+            if !options.dry_run {
+                let output = client
+                    .get_object()
+                    .bucket(&bucket_name)
+                    .key(key)
+                    .set_version_id(version.id.clone())
+                    .send()
+                    .await?;
+                // Multipart and KMS or customer-key encrypted ETags aren't an MD5 of
+                // the content
+                let encrypted = is_encrypted(
+                    output.sse_customer_algorithm.as_deref(),
+                    output.server_side_encryption.as_ref(),
+                );
+                let md5 = e_tag.filter(|e_tag| !e_tag.contains('-') && !encrypted);
+                if let Some(trash) = trash.as_ref().filter(|_| path.is_file()) {
+                    trash.keep_copy(&path)?;
+                }
+                pull::download(output.body, &path, md5).await?;
+                tracing::debug!("Pulled '{}'", self.redact(key));
+            }
+            pull.downloaded.push((key.clone(), path));
+        }
+        if options.delete {
+            for (key, file) in self.local_files().await? {
+                if !versions.contains_key(&key) {
+                    if let Some(trash) = &trash {
+                        trash.keep(&file.path)?;
+                    } else if !options.dry_run {
+                        std::fs::remove_file(&file.path)?;
+                    }
+                    pull.removed.push(file.path);
+                }
+            }
+            pull.removed.sort();
+        }
+        if let Some(trash) = &trash {
+            trash.prune()?;
+        }
+        if let Some(cursor) = cursor.as_mut() {
+            cursor.advance(&versions, held_back);
+        }
+        Ok(pull)
+    }
+
+    /// Copy objects from the `from` layout to this agent's, returning the old and new
+    /// key of each one copied
+    #[tracing::instrument(skip_all)]
+    async fn migrate_keys(&self, from: &KeyLayout) -> Result<Vec<(String, String)>, Error> {
+        let bucket_name = self.bucket_name.clone().ok_or(Error::MissingBucket)?;
+        let mut old_layout = self.clone();
+        old_layout.key_prefix.clone_from(&from.from_prefix);
+        old_layout.shards = from.from_shards;
+        let client = self.client().await;
+        let new_prefix = self.key_prefix.as_deref().unwrap_or_default();
+        // Listing the old prefix also finds the new layout when it's nested inside
+        let nested = new_prefix.starts_with(from.from_prefix.as_deref().unwrap_or_default())
+            && new_prefix != from.from_prefix.as_deref().unwrap_or_default();
+        let mut moves = Vec::new();
+        let mut objects = old_layout.lister(client.clone())?.stream();
+        while let Some(object) = objects.recv().await {
+            let object = object?;
+            if nested && object.key.starts_with(new_prefix) {
+                continue;
+            }
+            let Some(relative) = old_layout.original_key(&object.key) else {
+                continue;
+            };
+            let new_key = self.remote_key(relative);
+            if new_key == object.key || !self.matches(relative) {
+                continue;
+            }
+            moves.push((object.key, new_key));
+        }
+        if from.dry_run {
+            return Ok(moves);
+        }
+        for (old_key, new_key) in &moves {
+            let source = format!(
+                "{bucket_name}/{}",
+                percent_encoding::utf8_percent_encode(old_key, COPY_SOURCE)
+            );
+            client
+                .copy_object()
+                .bucket(&bucket_name)
+                .key(new_key)
+                .copy_source(source)
+                .set_storage_class(self.storage_class.map(StorageClass::class))
+                .set_server_side_encryption(self.server_side_encryption())
+                .set_ssekms_key_id(self.kms_key_id.clone())
+                .send()
+                .await?;
+            if from.delete_old {
+                client
+                    .delete_object()
+                    .bucket(&bucket_name)
+                    .key(old_key)
+                    .send()
+                    .await?;
+            }
+            if let Some(state) = &self.state {
+                state.rename(self.name(), old_key, new_key)?;
+            }
+            tracing::debug!(
+                "Copied '{}' to '{}'",
+                self.redact(old_key),
+                self.redact(new_key)
+            );
+        }
+        Ok(moves)
+    }
+
+    /// Resolve symlinks and `..` components and make sure the file still lives
+    /// under the watched path, unless following external links is allowed.
+    #[tracing::instrument(skip_all, fields(path = self.redact_path(path)))]
+    fn confine(&self, path: &Path) -> Result<PathBuf, Error> {
+        let root = self.watcher.local_path.canonicalize()?;
+        let resolved = path.canonicalize()?;
+        if resolved.starts_with(&root) {
+            Ok(resolved)
+        } else if self.follow_external_links.unwrap_or(false) {
+            tracing::debug!(
+                "Following external link to '{}'",
+                self.redact_path(&resolved)
+            );
+            Ok(resolved)
+        } else {
+            Err(Error::ExternalLink { root, resolved })
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(file = self.redact_path(file), bucket = self.bucket(), key, size))]
+    async fn process_file(&self, file: &Path) -> Result<(), Error> {
+        let path = &self.redact_path(file);
+        match self.relative_key(file) {
+            Ok(_) => {
+                self.stats.record_event();
+                Lifecycle::Detected { path }.emit(self.name());
+            }
+            Err(Error::OutsideWatchedPath(_)) => {
+                tracing::debug!("Skip processing");
+                return Ok(());
+            }
+            Err(e) => {
+                tracing::debug!("Unable to map to a key: {e}");
+                self.skip(path, SkipReason::InvalidPath);
+                return Ok(());
+            }
+        }
+        let Some(key) = self.object_key(file)? else {
+            self.skip(path, SkipReason::PatternMismatch);
+            return Ok(());
+        };
+        tracing::Span::current().record("key", self.redact(&key));
+        if let Err(e) = self.confine(file) {
+            match self.log_paths.unwrap_or_default() {
+                LogPaths::Plain => tracing::warn!("Refusing to process: {e}"),
+                LogPaths::Hash => tracing::warn!("Refusing to process: path escapes"),
+            }
+            self.skip(path, SkipReason::ExternalLink);
+            return Ok(());
+        }
+        Lifecycle::Matched {
+            path,
+            key: &self.redact(&key),
+        }
+        .emit(self.name());
+        if self.max_upload_latency.is_some() {
+            self.deadlines.start(path, file);
+        }
+        if !self.active() {
+            self.skip(path, SkipReason::Standby);
+            return Ok(());
+        }
+        if !self.switches.draining() && !self.uploading() {
+            let reason = if self.switches.paused() {
+                tracing::debug!("Deferred until resumed");
+                SkipReason::Paused
+            } else {
+                tracing::debug!("Deferred until the schedule opens");
+                SkipReason::OutsideSchedule
+            };
+            self.deferred.insert(file.to_path_buf())?;
+            self.track_queued(file);
+            self.skip(path, reason);
+            return Ok(());
+        }
+        let metadata = file.metadata()?;
+        if self.unchanged(&key, &metadata).await {
+            self.skip(path, SkipReason::Unchanged);
+            return Ok(());
+        }
+        let fingerprint = self.fingerprint(file, &metadata)?;
+        if fingerprint
+            .as_ref()
+            .is_some_and(|fingerprint| self.duplicate(fingerprint))
+        {
+            self.skip(path, SkipReason::Duplicate);
+            return Ok(());
+        }
+        let size = metadata.len();
+        tracing::Span::current().record("size", size);
+        if size == 0 && self.on_success == Some(OnSuccess::Truncate) {
+            self.skip(path, SkipReason::Empty);
+            return Ok(());
+        }
+        if self.oversized(file, size) {
+            self.skip(path, SkipReason::TooLarge);
+            return Ok(());
+        }
+        if self.still_open(file) {
+            self.track_queued(file);
+            self.skip(path, SkipReason::StillOpen);
+            return Ok(());
+        }
+        if self.skip_unchanged.unwrap_or(false)
+            && self.up_to_date(&key, &self.source_path(file)).await?
+        {
+            self.skip(path, SkipReason::UpToDate);
+            return Ok(());
+        }
+        let Some(key) = self.resolve_collision(key).await? else {
+            self.skip(path, SkipReason::Exists);
+            return Ok(());
+        };
+        if !self.claim(file, &key, &metadata).await? {
+            tracing::debug!("Claimed by another host");
+            self.skip(path, SkipReason::Unchanged);
+            return Ok(());
+        }
+        self.upload_claimed(file, &key, fingerprint).await
+    }
+
+    /// Upload once the pace allows, giving up the shared state claim if it fails
+    async fn upload_claimed(
+        &self,
+        file: &Path,
+        key: &str,
+        fingerprint: Option<Fingerprint>,
+    ) -> Result<(), Error> {
+        if let Some(per_second) = self.max_uploads_per_second {
+            self.pacer.wait(per_second).await;
+        }
+        let result = self.upload_and_apply(file, key, fingerprint).await;
+        if result.is_err() {
+            self.release(key).await;
+        }
+        result
+    }
+
+    /// Whether the file is past the object size limit, recording it in the dead letter
+    /// file if there is one
+    fn oversized(&self, file: &Path, size: u64) -> bool {
+        let limit = self.max_object_size();
+        if size <= limit {
+            return false;
+        }
+        tracing::warn!("Rejecting {size} byte file, larger than the {limit} byte limit");
+        if let Some(dead_letter) = &self.dead_letter {
+            DeadLetter::new(dead_letter).record(
+                self.name(),
+                file,
+                &format!("{size} bytes exceeds the {limit} byte object size limit"),
+            );
+        }
+        true
+    }
+
+    /// Whether to wait for another process to close the file before uploading it
+    fn still_open(&self, file: &Path) -> bool {
+        let Some(timeout) = self.wait_for_close else {
+            return false;
+        };
+        if !open_files::is_open(file) {
+            self.open_files.clear(file);
+            false
+        } else if self.open_files.wait(file, timeout) {
+            true
+        } else {
+            tracing::warn!(
+                "Still open after {}, uploading anyway",
+                humantime::format_duration(timeout)
+            );
+            false
+        }
+    }
+
+    /// Upload a file that passed every check, then apply `on_success` to its source
+    async fn upload_and_apply(
+        &self,
+        file: &Path,
+        key: &str,
+        fingerprint: Option<Fingerprint>,
+    ) -> Result<(), Error> {
+        tracing::debug!("Processing");
+        let before = file.metadata()?;
+        let staged = self
+            .staging
+            .as_ref()
+            .map(|staging| staging.stage(&self.source_path(file)))
+            .transpose()?;
+        let source = staged
+            .as_ref()
+            .map_or_else(|| self.source_path(file), |staged| staged.path.clone());
+        self.upload_file(file, &source, key, &before).await?;
+        if let Some(fingerprint) = &fingerprint {
+            self.record_fingerprint(fingerprint);
+        }
+        let on_success = self.on_success.clone().unwrap_or_default();
+        // Whether what was uploaded is still all there is, so removing or emptying the
+        // source can't lose writes that came after
+        let uploaded_all = || {
+            staged.as_ref().map_or_else(
+                || {
+                    file.metadata().is_ok_and(|after| {
+                        after.len() == before.len()
+                            && after.modified().ok() == before.modified().ok()
+                    })
+                },
+                |staged| staged.matches(file),
+            )
+        };
+        if on_success.changes_source()
+            && self.verify_before_delete.unwrap_or(false)
+            && !self.verified(key, &source).await?
+        {
+            tracing::warn!("Object doesn't match what was uploaded, keeping the source");
+        } else if on_success.changes_source() && !uploaded_all() {
+            // Checked after verifying, a round trip long enough for another write to land
+            tracing::warn!("Written to since it was read for upload, keeping the source");
+        } else {
+            on_success
+                .apply(file, self.relative_key(file)?, self.bucket(), key)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// What's about to be uploaded, when the agent suppresses duplicates
+    fn fingerprint(
+        &self,
+        file: &Path,
+        metadata: &std::fs::Metadata,
+    ) -> Result<Option<Fingerprint>, Error> {
+        if self.duplicate_window.is_none() || self.state.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Fingerprint {
+            path: file.to_path_buf(),
+            size: metadata.len(),
+            modified: state::modified(metadata).unwrap_or_default(),
+            md5: md5_hex(&self.source_path(file))?,
+        }))
+    }
+
+    /// Whether the same contents were uploaded from the same path within the agent's
+    /// `duplicate_window`
+    fn duplicate(&self, fingerprint: &Fingerprint) -> bool {
+        let (Some(window), Some(state)) = (self.duplicate_window, &self.state) else {
+            return false;
+        };
+        let since = chrono::Utc::now() - window;
+        state
+            .uploaded_since(self.name(), fingerprint, since)
+            .unwrap_or_else(|e| {
+                tracing::warn!("Unable to read state: {e}");
+                false
+            })
+    }
+
+    fn record_fingerprint(&self, fingerprint: &Fingerprint) {
+        let (Some(window), Some(state)) = (self.duplicate_window, &self.state) else {
+            return;
+        };
+        let expired = chrono::Utc::now() - window;
+        if let Err(e) = state.record_fingerprint(self.name(), fingerprint, expired) {
+            tracing::warn!("Unable to record upload in state: {e}");
+        }
+    }
+
+    /// Key to upload to under the collision policy, `None` when the upload should be skipped
+    async fn resolve_collision(&self, key: String) -> Result<Option<String>, Error> {
+        let collision = self.collision.unwrap_or_default();
+        if collision == Collision::Overwrite || !self.exists(&key).await? {
+            return Ok(Some(key));
+        }
+        if collision == Collision::Skip {
+            return Ok(None);
+        }
+        let template = self.collision_suffix.clone().unwrap_or_default();
+        let mut candidate = template.render(&key, 1);
+        if template.has_counter() {
+            let mut counter = 1;
+            while self.exists(&candidate).await? {
+                if counter == collision::MAX_COUNTER {
+                    return Err(Error::NoFreeKey(self.redact(&key)));
+                }
+                counter += 1;
+                candidate = template.render(&key, counter);
+            }
+        }
+        tracing::debug!(suffixed = self.redact(&candidate), "Key exists, suffixing");
+        Ok(Some(candidate))
+    }
+
+    /// Whether the object at `key` already has the contents of `source`. Failing checks
+    /// are logged and treated as a change.
+    async fn up_to_date(&self, key: &str, source: &Path) -> Result<bool, Error> {
+        match self.has_contents(key, source).await {
+            Err(Error::Aws(e)) => {
+                tracing::warn!("Unable to check the object, uploading: {e}");
+                Ok(false)
+            }
+            result => result,
+        }
+    }
+
+    /// Whether the object just uploaded to `key` has the contents of `source`, failing
+    /// checks logged and treated as a mismatch
+    async fn verified(&self, key: &str, source: &Path) -> Result<bool, Error> {
+        match self.has_contents(key, source).await {
+            Err(Error::Aws(e)) => {
+                tracing::warn!("Unable to verify the upload: {e}");
+                Ok(false)
+            }
+            result => result,
+        }
+    }
+
+    /// Whether the object at `key` has the size and MD5 of `source`, going by its
+    /// `source-md5` metadata or else a plain MD5 `ETag`
+    async fn has_contents(&self, key: &str, source: &Path) -> Result<bool, Error> {
+        let bucket_name = self.bucket_name.as_deref().ok_or(Error::MissingBucket)?;
+        let head = match self
+            .client()
+            .await
+            .head_object()
+            .bucket(bucket_name)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(head) => head,
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(HeadObjectError::is_not_found) =>
+            {
+                return Ok(false);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let stored = head
+            .metadata()
+            .and_then(|metadata| metadata.get("source-md5"))
+            .map(String::as_str);
+        let e_tag = head
+            .e_tag()
+            .map(|e_tag| e_tag.trim_matches('"'))
+            .filter(|e_tag| !e_tag.contains('-') && self.placeholder.is_none());
+        let Some(expected) = stored.or(e_tag) else {
+            return Ok(false);
+        };
+        let size = source.metadata()?.len();
+        if self.placeholder.is_none()
+            && head
+                .content_length()
+                .and_then(|len| u64::try_from(len).ok())
+                != Some(size)
+        {
+            return Ok(false);
+        }
+        Ok(md5_hex(source)? == expected)
+    }
+
+    /// Whether an object already exists at `key`
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        let bucket_name = self.bucket_name.as_deref().ok_or(Error::MissingBucket)?;
+        match self
+            .client()
+            .await
+            .head_object()
+            .bucket(bucket_name)
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(HeadObjectError::is_not_found) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Remember a file as it's queued, when the `follow` policy may need to find it
+    fn track_queued(&self, file: &Path) {
+        if self.vanished == Some(Vanished::Follow) {
+            self.queued.record(file);
+        }
+    }
+
+    /// Apply the agent's policy to a queued file that's gone, returning the file to
+    /// upload instead when it was renamed and the policy is to follow it
+    fn vanished(&self, path: &Path, renamed: Option<PathBuf>) -> Option<PathBuf> {
+        let _span = self.span().entered();
+        let logged = self.redact_path(path);
+        self.deadlines.finish(&logged);
+        match self.vanished.unwrap_or_default() {
+            Vanished::Ignore => {
+                tracing::debug!(path = logged, "Removed before it was uploaded");
+            }
+            Vanished::Warn => {
+                tracing::warn!(path = logged, "Removed before it was uploaded");
+            }
+            Vanished::Fail => {
+                let error = "removed before it was uploaded";
+                tracing::warn!(path = logged, "Failed: {error}");
+                metrics::record_failure(self.name());
+                self.stats.record_failure();
+                Lifecycle::Failed {
+                    path: &logged,
+                    error,
+                }
+                .emit(self.name());
+                if let Some(dead_letter) = &self.dead_letter {
+                    DeadLetter::new(dead_letter).record(self.name(), path, error);
+                }
+            }
+            Vanished::Follow => {
+                if renamed.is_some() {
+                    tracing::info!(path = logged, "Renamed before it was uploaded, following");
+                } else {
+                    tracing::warn!(path = logged, "Removed before it was uploaded");
+                }
+                return renamed;
+            }
+        }
+        None
+    }
+
+    /// Count and report a file this agent won't upload, `path` already redacted
+    fn skip(&self, path: &str, reason: SkipReason) {
+        tracing::debug!(reason = reason.as_str(), "Skip processing");
+        // Only held back, it's still due
+        if !matches!(
+            reason,
+            SkipReason::OutsideSchedule | SkipReason::Paused | SkipReason::StillOpen
+        ) {
+            self.deadlines.finish(path);
+        }
+        metrics::record_skip(self.name(), reason);
+        Lifecycle::Skipped { path, reason }.emit(self.name());
+    }
+
+    #[tracing::instrument(skip_all, fields(path = self.redact_path(path), bucket = self.bucket(), key = self.redact(key), size))]
+    async fn upload_file(
+        &self,
+        path: &Path,
+        source: &Path,
+        key: &str,
+        before: &std::fs::Metadata,
+    ) -> Result<(), Error> {
+        let bucket_name = self.bucket_name.clone().ok_or(Error::MissingBucket)?;
+        let started = std::time::Instant::now();
+        let mut bytes = source.metadata()?.len();
+        tracing::Span::current().record("size", bytes);
+        let mut metadata = HashMap::new();
+        if self.shard_count().is_some() {
+            let original = self.relative_key(path)?.to_string();
+            metadata.insert(String::from("original-path"), original);
+        }
+        let times = if self.preserve_times.unwrap_or(false) {
+            FileTimes::of(&path.metadata()?)
+        } else {
+            FileTimes::default()
+        };
+        if let Some(modified) = times.modified {
+            metadata.insert(String::from("mtime"), modified.to_rfc3339());
+        }
+        if let Some(created) = times.created {
+            metadata.insert(String::from("btime"), created.to_rfc3339());
+        }
+        if self.stamp_identity.unwrap_or(false) {
+            metadata.extend(identity::metadata(self.name()).await);
+        }
+        if self.skip_unchanged.unwrap_or(false) || self.verify_before_delete.unwrap_or(false) {
+            metadata.insert(String::from("source-md5"), md5_hex(source)?);
+        }
+        let placeholder = self
+            .placeholder
+            .map(|placeholder| {
+                let descriptor = Descriptor::of(self.name(), path, source)?;
+                placeholder.body(&descriptor, &mut metadata)
+            })
+            .transpose()?;
+        let metadata = (!metadata.is_empty()).then_some(metadata);
+        let client = self.client().await;
+        let e_tag = if let Some(placeholder) = placeholder {
+            bytes = placeholder.contents.len() as u64;
+            tracing::Span::current().record("size", bytes);
+            self.put_placeholder(&client, &bucket_name, key, placeholder, metadata)
+                .await?
+        } else if bytes >= self.multipart_threshold() {
+            self.upload_multipart(&client, &bucket_name, key, source, bytes, metadata)
+                .await?
+        } else {
+            self.put_object(&client, &bucket_name, key, source, metadata)
+                .await?
+        };
+        tracing::info!("File uploaded");
+        if let Err(e) = self.record_state(path, key, e_tag.as_deref(), before).await {
+            tracing::warn!("Unable to record upload in state: {e}");
+        }
+        metrics::record_upload(self.name(), bytes, started.elapsed());
+        self.stats.record_upload();
+        let logged = self.redact_path(path);
+        if let Some(waited) = self.deadlines.finish(&logged) {
+            tracing::warn!(
+                "Uploaded {} after it was detected, past its deadline",
+                humantime::format_duration(Duration::from_secs(waited.as_secs()))
+            );
+        }
+        Lifecycle::Uploaded {
+            path: Some(&logged),
+            bucket: &bucket_name,
+            key: &self.redact(key),
+            bytes,
+            times,
+        }
+        .emit(self.name());
+        Ok(())
+    }
+
+    /// Single `PutObject`, retried on a checksum mismatch, returning the `ETag`. The
+    /// checksum is of the file as read before each attempt, and stored with the object.
+    async fn put_object(
+        &self,
+        client: &s3::Client,
+        bucket_name: &str,
+        key: &str,
+        source: &Path,
+        metadata: Option<HashMap<String, String>>,
+    ) -> Result<Option<String>, Error> {
+        let mut attempt = 1;
+        loop {
+            // MD5 is left out of the compat fallbacks, every server takes `Content-MD5`
+            let checksum = self.checksum.filter(|checksum| {
+                *checksum == Checksum::Md5 || self.checksum_algorithm().is_some()
+            });
+            let value = checksum
+                .map(|checksum| checksum.of_file(source))
+                .transpose()?;
+            let mut metadata = metadata.clone();
+            if let Some((checksum, value)) = checksum.zip(value.clone()) {
+                metadata
+                    .get_or_insert_with(HashMap::new)
+                    .insert(checksum.metadata_key(), value);
+            }
+            let metadata = self.fallbacks.metadata(metadata);
+            // Dropping `Content-MD5` wouldn't change the retried request
+            let sent_checksum = checksum.is_some_and(|checksum| checksum != Checksum::Md5);
+            let sent_metadata = metadata.is_some();
+            let request = client
+                .put_object()
+                .bucket(bucket_name)
+                .key(key)
+                .set_metadata(metadata)
+                .set_content_type(self.content_type(key))
+                .set_storage_class(self.storage_class.map(StorageClass::class))
+                .set_server_side_encryption(self.server_side_encryption())
+                .set_ssekms_key_id(self.kms_key_id.clone())
+                .body(ByteStream::from_path(source).await?);
+            let request = match checksum.zip(value) {
+                Some((checksum, value)) => checksum.attach(request, value),
+                None => request,
+            };
+            let result = request.send().await;
+            match result {
+                Err(e) if attempt < CHECKSUM_ATTEMPTS && is_checksum_mismatch(&e) => {
+                    tracing::warn!("Checksum mismatch on attempt {attempt}, retrying");
+                    attempt += 1;
+                }
+                Err(e)
+                    if self.compat()
+                        && compat::is_unsupported(&e)
+                        && self.fallbacks.degrade(sent_checksum, sent_metadata) => {}
+                result => return Ok(result?.e_tag().map(String::from)),
+            }
+        }
+    }
+
+    /// Single `PutObject` of what a placeholder agent uploads for a file, returning the
+    /// `ETag`
+    async fn put_placeholder(
+        &self,
+        client: &s3::Client,
+        bucket_name: &str,
+        key: &str,
+        placeholder: placeholder::Body,
+        metadata: Option<HashMap<String, String>>,
+    ) -> Result<Option<String>, Error> {
+        loop {
+            let metadata = self.fallbacks.metadata(metadata.clone());
+            let sent_metadata = metadata.is_some();
+            let result = client
+                .put_object()
+                .bucket(bucket_name)
+                .key(key)
+                .set_metadata(metadata)
+                .set_content_type(placeholder.content_type.map(String::from))
+                .set_storage_class(self.storage_class.map(StorageClass::class))
+                .set_server_side_encryption(self.server_side_encryption())
+                .set_ssekms_key_id(self.kms_key_id.clone())
+                .body(ByteStream::from(placeholder.contents.clone()))
+                .send()
+                .await;
+            match result {
+                Err(e)
+                    if self.compat()
+                        && compat::is_unsupported(&e)
+                        && self.fallbacks.degrade(false, sent_metadata) => {}
+                result => return Ok(result?.e_tag().map(String::from)),
+            }
+        }
+    }
+
+    /// Multipart upload in parts of the configured size, returning the `ETag`.
+    ///
+    /// The upload is aborted if any part fails so nothing is left behind, unless resuming
+    /// is on, in which case it's kept in the state database and picked up where it left
+    /// off by the next upload of the unchanged file.
+    async fn upload_multipart(
+        &self,
+        client: &s3::Client,
+        bucket_name: &str,
+        key: &str,
+        source: &Path,
+        bytes: u64,
+        metadata: Option<HashMap<String, String>>,
+    ) -> Result<Option<String>, Error> {
+        let started = std::time::Instant::now();
+        let modified = state::modified(&source.metadata()?).unwrap_or_default();
+        let (part_size, concurrency) = self.auto_tune.as_ref().map_or_else(
+            || {
+                let concurrency = self.multipart.concurrency.unwrap_or(1).max(1);
+                (self.part_size(bytes), concurrency)
+            },
+            |bounds| {
+                let (part_size, concurrency) = self.tuner.settings(bounds);
+                let part_size = self
+                    .interrupted_part_size(key, bytes, modified)
+                    .unwrap_or(part_size);
+                (part_size, concurrency)
+            },
+        );
+        let part_size = part_size
+            .max(multipart::MIN_PART_SIZE)
+            .max(bytes.div_ceil(multipart::MAX_PARTS));
+        let entry = MultipartEntry {
+            agent: self.name().to_string(),
+            key: key.to_string(),
+            upload_id: String::new(),
+            size: bytes,
+            modified,
+            part_size,
+        };
+        let resumed = self.resumable_upload(client, bucket_name, &entry).await?;
+        let (upload_id, uploaded) = if let Some(resumed) = resumed {
+            resumed
+        } else {
+            let created = std::time::Instant::now();
+            let upload_id = self
+                .create_multipart_upload(client, bucket_name, key, metadata)
+                .await?;
+            self.tuner.record_round_trip(created.elapsed());
+            if let Some(state) = &self.state {
+                state.record_multipart(&MultipartEntry {
+                    upload_id: upload_id.clone(),
+                    ..entry
+                })?;
+            }
+            (upload_id, Vec::new())
+        };
+        let parts = multipart::Parts {
+            bucket: bucket_name,
+            key,
+            upload_id: &upload_id,
+            source,
+            size: bytes,
+            part_size,
+            concurrency,
+            checksum: self.checksum_algorithm(),
+            uploaded,
+            tuner: self.auto_tune.is_some().then(|| self.tuner.clone()),
+        };
+        let result = parts.upload(client).await;
+        if let (Ok(_), Some(bounds)) = (&result, &self.auto_tune) {
+            self.tuner
+                .record_upload(self.name(), bounds, bytes, started.elapsed());
+        }
+        if result.is_err() && self.resume() {
+            tracing::info!("Keeping multipart upload {upload_id} to resume");
+            return result;
+        }
+        if let Some(state) = &self.state {
+            state.remove_multipart(self.name(), key)?;
+        }
+        if result.is_err() {
+            abort_multipart_upload(client, bucket_name, key, &upload_id).await;
+        }
+        result
+    }
+
+    /// Part size of an interrupted upload of the same file, which a resumed upload has to
+    /// keep even though tuning may have moved on
+    fn interrupted_part_size(&self, key: &str, bytes: u64, modified: i64) -> Option<u64> {
+        if !self.resume() {
+            return None;
+        }
+        let previous = self
+            .state
+            .as_ref()?
+            .get_multipart(self.name(), key)
+            .ok()??;
+        (previous.size == bytes && previous.modified == modified).then_some(previous.part_size)
+    }
+
+    /// Upload id and finished parts of an earlier upload of the same unchanged file, when
+    /// resuming. Any other earlier upload of the key is aborted.
+    async fn resumable_upload(
+        &self,
+        client: &s3::Client,
+        bucket_name: &str,
+        entry: &MultipartEntry,
+    ) -> Result<Option<(String, Vec<CompletedPart>)>, Error> {
+        let Some(state) = &self.state else {
+            return Ok(None);
+        };
+        let Some(previous) = state.get_multipart(&entry.agent, &entry.key)? else {
+            return Ok(None);
+        };
+        let unchanged = MultipartEntry {
+            upload_id: previous.upload_id.clone(),
+            ..entry.clone()
+        } == previous;
+        if self.resume() && unchanged {
+            match multipart::list_parts(client, bucket_name, &entry.key, &previous.upload_id).await
+            {
+                Ok(parts) => {
+                    tracing::info!(
+                        "Resuming multipart upload {} with {} parts done",
+                        previous.upload_id,
+                        parts.len()
+                    );
+                    return Ok(Some((previous.upload_id, parts)));
+                }
+                Err(e) => tracing::warn!("Unable to resume, starting over: {e}"),
+            }
+        }
+        abort_multipart_upload(client, bucket_name, &entry.key, &previous.upload_id).await;
+        state.remove_multipart(&entry.agent, &entry.key)?;
+        Ok(None)
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        client: &s3::Client,
+        bucket_name: &str,
+        key: &str,
+        metadata: Option<HashMap<String, String>>,
+    ) -> Result<String, Error> {
+        loop {
+            let checksum = self.checksum_algorithm();
+            let metadata = self.fallbacks.metadata(metadata.clone());
+            let (sent_checksum, sent_metadata) = (checksum.is_some(), metadata.is_some());
+            let result = client
+                .create_multipart_upload()
+                .bucket(bucket_name)
+                .key(key)
+                .set_metadata(metadata)
+                .set_checksum_algorithm(checksum)
+                .set_content_type(self.content_type(key))
+                .set_storage_class(self.storage_class.map(StorageClass::class))
+                .set_server_side_encryption(self.server_side_encryption())
+                .set_ssekms_key_id(self.kms_key_id.clone())
+                .send()
+                .await;
+            match result {
+                Err(e)
+                    if self.compat()
+                        && compat::is_unsupported(&e)
+                        && self.fallbacks.degrade(sent_checksum, sent_metadata) => {}
+                result => return Ok(result?.upload_id.unwrap_or_default()),
+            }
+        }
+    }
+
+    fn resume(&self) -> bool {
+        self.resume.unwrap_or(false)
+    }
+
+    async fn client(&self) -> s3::Client {
+        self.clients
+            .s3
+            .get_or_init(|| self.build_client())
+            .await
+            .clone()
+    }
+
+    async fn build_client(&self) -> s3::Client {
+        let sdk_config = self.sdk_config().await;
+        let mut config = s3::config::Builder::from(&sdk_config);
+        if let Some(provider) = self.provider {
+            let region = sdk_config
+                .region()
+                .cloned()
+                .unwrap_or_else(|| Region::from_static(provider.default_region()));
+            config.set_endpoint_url(
+                provider.endpoint_url(region.as_ref(), self.account_id.as_deref()),
+            );
+            config = config
+                .region(region)
+                .force_path_style(provider.force_path_style());
+        }
+        if let Some(endpoint_url) = &self.endpoint_url {
+            if sdk_config.region().is_none() && self.provider.is_none() {
+                config = config.region(Region::from_static("us-east-1"));
+            }
+            config = config.endpoint_url(endpoint_url);
+        }
+        if let Some(force_path_style) = self.force_path_style {
+            config = config.force_path_style(force_path_style);
+        }
+        if self.compat() {
+            config = compat::configure(config);
+        }
+        if let Some(fault_injection) = &self.fault_injection {
+            config = fault_injection.configure(config);
+        }
+        s3::Client::from_conf(config.build())
+    }
+
+    /// On when asked for, and by default for provider presets
+    fn compat(&self) -> bool {
+        self.compat.unwrap_or_else(|| self.provider.is_some())
+    }
+
+    /// Type of the object at `key` from its extension, configured types first
+    fn content_type(&self, key: &str) -> Option<String> {
+        let extension = Path::new(key).extension()?.to_str()?;
+        self.content_types
+            .iter()
+            .flatten()
+            .find(|(configured, _)| configured.eq_ignore_ascii_case(extension))
+            .map(|(_, content_type)| content_type.clone())
+            .or_else(|| {
+                mime_guess::from_ext(extension)
+                    .first_raw()
+                    .map(String::from)
+            })
+    }
+
+    /// Configured encryption, KMS when only a key id is set
+    const fn server_side_encryption(&self) -> Option<ServerSideEncryption> {
+        match (self.sse, &self.kms_key_id) {
+            (Some(sse), _) => Some(sse.server_side_encryption()),
+            (None, Some(_)) => Some(ServerSideEncryption::AwsKms),
+            (None, None) => None,
+        }
+    }
+
+    /// Configured checksum, unless the server has rejected checksums in compat mode
+    fn checksum_algorithm(&self) -> Option<ChecksumAlgorithm> {
+        self.fallbacks
+            .checksum(self.checksum.and_then(Checksum::algorithm))
+    }
+
+    async fn sdk_config(&self) -> aws_config::SdkConfig {
+        self.clients
+            .sdk_config
+            .get_or_init(|| async {
+                let sdk_config = sdk_config_from(
+                    self.profile_name.as_deref(),
+                    self.region_name.as_deref(),
+                    self.aws_config_file.as_deref(),
+                    self.aws_credentials_file.as_deref(),
+                    self.anonymous.unwrap_or(false),
+                )
+                .await;
+                match &self.role_arn {
+                    Some(role_arn) => self.assume_role(role_arn, sdk_config).await,
+                    None => sdk_config,
+                }
+            })
+            .await
+            .clone()
+    }
+
+    /// `sdk_config` with credentials for `role_arn`, which STS hands out in exchange for
+    /// its own and the clients refresh before they expire
+    async fn assume_role(&self, role_arn: &str, sdk_config: SdkConfig) -> SdkConfig {
+        let session_name = self.session_name.clone().unwrap_or_else(|| {
+            let hostname = heartbeat::hostname().replace(
+                |c: char| !c.is_ascii_alphanumeric() && !"+=,.@-".contains(c),
+                "-",
+            );
+            format!("s3sync-{hostname}").chars().take(64).collect()
+        });
+        let mut provider = AssumeRoleProvider::builder(role_arn)
+            .session_name(session_name)
+            .configure(&sdk_config);
+        if let Some(external_id) = &self.external_id {
+            provider = provider.external_id(external_id);
+        }
+        let provider = provider.build().await;
+        sdk_config
+            .into_builder()
+            .credentials_provider(SharedCredentialsProvider::new(provider))
+            .build()
+    }
+
+    async fn write_heartbeat(
+        &self,
+        started_at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<(), Error> {
+        let bucket_name = self.bucket_name.clone().ok_or(Error::MissingBucket)?;
+        let hostname = heartbeat::hostname();
+        let key = format!(
+            "{}{INTERNAL_PREFIX}heartbeats/{hostname}/{}.json",
+            self.key_prefix.as_deref().unwrap_or_default(),
+            self.name()
+        );
+        let body = serde_json::to_vec(&Heartbeat {
+            hostname,
+            version: env!("CARGO_PKG_VERSION"),
+            agent: self.name(),
+            started_at,
+            timestamp: chrono::Utc::now(),
+            interval: self.heartbeat_interval.unwrap_or_default(),
+            mirroring: self.delete_remote.unwrap_or(false),
+            stats: self.stats.snapshot(),
+        })?;
+        self.client()
+            .await
+            .put_object()
+            .bucket(bucket_name)
+            .key(key)
+            .content_type("application/json")
+            .set_server_side_encryption(self.server_side_encryption())
+            .set_ssekms_key_id(self.kms_key_id.clone())
+            .body(body.into())
+            .send()
+            .await?;
+        tracing::debug!("Heartbeat written");
+        Ok(())
+    }
+
+    /// Spawn the agent's periodic tasks: multipart cleanup, catalog, deadline checks,
+    /// lease renewal and heartbeat, whichever are configured
+    fn spawn_background_tasks(
+        &self,
+        tasks: &mut JoinSet<()>,
+        started_at: chrono::DateTime<chrono::Utc>,
+    ) {
+        if let Some(cleanup) = self.multipart_cleanup.clone() {
+            self.spawn_periodic(tasks, cleanup.interval(), move |agent| {
+                let cleanup = cleanup.clone();
+                async move {
+                    if let Err(e) = agent.abort_stale_uploads(&cleanup).await {
+                        tracing::warn!("Unable to clean up multipart uploads: {e}");
+                    }
+                }
+            });
+        }
+        if let (Some(every), Some(state)) = (self.catalog_interval, self.state.clone()) {
+            self.spawn_periodic(tasks, every, move |agent| {
+                let state = state.clone();
+                async move {
+                    if let Err(e) = agent.write_catalog(&state).await {
+                        tracing::warn!("Unable to write the catalog: {e}");
+                    }
+                }
+            });
+        }
+        if let Some(retry) = &self.retry {
+            self.spawn_periodic(tasks, retry.interval(), |agent| async move {
+                if let Err(e) = agent.retry_failed().await {
+                    tracing::warn!("Unable to retry failed uploads: {e}");
+                }
+            });
+        }
+        if let Some(latency) = self.max_upload_latency {
+            let every = (latency / 10).clamp(Duration::from_secs(1), DEADLINE_CHECK);
+            self.spawn_periodic(tasks, every, move |agent| async move {
+                agent.escalate_overdue(latency);
+            });
+        }
+        if let Some(settings) = self.lease.clone() {
+            let holder = lease::holder();
+            self.spawn_periodic(tasks, settings.renew_every(), move |agent| {
+                let (holder, settings) = (holder.clone(), settings.clone());
+                async move { agent.hold_lease(&holder, &settings).await }
+            });
+        }
+        let Some(interval) = self.heartbeat_interval else {
+            return;
+        };
+        let agent = self.clone();
+        let span = agent.span();
+        tasks.spawn(
+            async move {
+                let mut interval =
+                    tokio::time::interval(std::time::Duration::from_secs(interval.max(1)));
+                // Checked for duplicates before starting
+                interval.tick().await;
+                loop {
+                    if let Err(e) = agent.write_heartbeat(started_at).await {
+                        tracing::warn!("Unable to write heartbeat: {e}");
+                    }
+                    interval.tick().await;
+                    if let Err(e) = agent.check_duplicate_instances().await {
+                        tracing::error!("{e}");
+                    }
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    /// Whether a failed upload was queued under `retry`
+    fn retried(&self, result: &Result<(), Error>) -> bool {
+        matches!(result, Err(e) if self.retry.is_some() && e.is_retryable())
+    }
+
+    /// Upload the files queued under `retry`, stopping at the first failure as S3 is
+    /// likely still unreachable. Files are given up on after `max_attempts` retries, or
+    /// once they fail for good.
+    async fn retry_failed(&self) -> Result<(), Error> {
+        let Some(retry) = &self.retry else {
+            return Ok(());
+        };
+        if self.retries.is_empty() || !self.uploading() {
+            return Ok(());
+        }
+        tracing::info!("Retrying {} failed uploads", self.retries.len());
+        self.retries.take();
+        while let Some(path) = self.retries.next() {
+            if !path.is_file() {
+                tracing::debug!(path = self.redact_path(&path), "Gone before it was retried");
+            } else if let Err(e) = Manager::process_file(self, &path).await {
+                if !e.is_retryable() {
+                    self.give_up(&path, &e.to_string());
+                } else if self.retries.failed(&path) < retry.max_attempts() {
+                    // It and the rest stay queued for the next pass
+                    break;
+                } else {
+                    self.give_up(
+                        &path,
+                        &format!("{e}, after {} retries", retry.max_attempts()),
+                    );
+                }
+            }
+            self.retries.done(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Stop retrying a file, recording it in the dead letter file if there is one
+    fn give_up(&self, path: &Path, reason: &str) {
+        tracing::warn!(path = self.redact_path(path), "Giving up: {reason}");
+        if let Some(dead_letter) = &self.dead_letter {
+            DeadLetter::new(dead_letter).record(self.name(), path, reason);
+        }
+    }
+
+    /// Run `task` every `period`, starting straight away, in the agent's span
+    fn spawn_periodic<F, Fut>(&self, tasks: &mut JoinSet<()>, period: Duration, task: F)
+    where
+        F: Fn(Arc<Self>) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        let agent = Arc::new(self.clone());
+        let span = agent.span();
+        tasks.spawn(
+            async move {
+                let mut interval = tokio::time::interval(period);
+                loop {
+                    interval.tick().await;
+                    task(agent.clone()).await;
+                }
+            }
+            .instrument(span),
+        );
+    }
+
+    /// Report files newly past the upload deadline
+    fn escalate_overdue(&self, latency: Duration) {
+        let overdue = self.deadlines.overdue(latency);
+        metrics::record_overdue(self.name(), overdue.len(), self.deadlines.overdue_count());
+        for (path, waited) in overdue {
+            tracing::error!(
+                path,
+                "Not uploaded {} after it was detected, over the {} deadline",
+                humantime::format_duration(Duration::from_secs(waited.as_secs())),
+                humantime::format_duration(latency)
+            );
+            Lifecycle::Overdue {
+                path: &path,
+                waited_seconds: waited.as_secs(),
+            }
+            .emit(self.name());
+        }
+    }
+
+    /// Upload the agent's catalog, returning how many files it lists
+    async fn write_catalog(&self, state: &State) -> Result<usize, Error> {
+        let bucket_name = self.bucket_name.clone().ok_or(Error::MissingBucket)?;
+        let hostname = heartbeat::hostname();
+        let key = format!(
+            "{}{INTERNAL_PREFIX}catalog/host={hostname}/{}.csv",
+            self.key_prefix.as_deref().unwrap_or_default(),
+            self.name()
+        );
+        let entries = state.entries(self.name())?;
+        self.client()
+            .await
+            .put_object()
+            .bucket(bucket_name)
+            .key(key)
+            .content_type("text/csv")
+            .set_server_side_encryption(self.server_side_encryption())
+            .set_ssekms_key_id(self.kms_key_id.clone())
+            .body(catalog::csv(&hostname, &entries).into_bytes().into())
+            .send()
+            .await?;
+        tracing::debug!("Catalog written");
+        Ok(entries.len())
+    }
+
+    /// Try for the lease straight away rather than on the first renewal, so an agent can
+    /// be active from the start
+    async fn acquire_lease(&self) {
+        if let Some(settings) = &self.lease {
+            self.hold_lease(&lease::holder(), settings).await;
+        }
+    }
+
+    /// Renew the lease and record whether it's still held, one renewal at a time so
+    /// two of this instance's own don't conflict
+    async fn hold_lease(&self, holder: &str, settings: &LeaseSettings) {
+        let _renewing = self.lease_held.renewing().await;
+        match self.renew_lease(holder, settings).await {
+            Ok(held) => self.lease_held.set(held),
+            Err(e) => {
+                // Stop before the lease can expire and another instance take over
+                tracing::warn!("Unable to renew the lease: {e}");
+                self.lease_held.set(false);
+            }
+        }
+    }
+
+    /// Take or extend the agent's lease object
+    async fn renew_lease(&self, holder: &str, settings: &LeaseSettings) -> Result<bool, Error> {
+        let bucket_name = self.bucket_name.clone().ok_or(Error::MissingBucket)?;
+        let key = format!(
+            "{}{INTERNAL_PREFIX}leases/{}.json",
+            self.key_prefix.as_deref().unwrap_or_default(),
+            self.name()
+        );
+        lease::renew(
+            &self.client().await,
+            &bucket_name,
+            &key,
+            holder,
+            settings.ttl(),
+            self.server_side_encryption(),
+            self.kms_key_id.as_deref(),
+        )
+        .await
+    }
+
+    /// Instances sharing a lease are expected to sync the same prefix, so they aren't
+    /// checked
+    #[tracing::instrument(skip_all)]
+    async fn check_duplicate_instances(&self) -> Result<(), Error> {
+        let Some(interval) = self.heartbeat_interval.filter(|_| self.lease.is_none()) else {
+            return Ok(());
+        };
+        let peers = match self.duplicate_instances(interval).await {
+            Ok(peers) => peers,
+            Err(e) => {
+                tracing::warn!("Unable to check for duplicate instances: {e}");
+                return Ok(());
+            }
+        };
+        for peer in &peers {
+            tracing::warn!(
+                "Agent '{}' on {} is syncing the same prefix, last seen {}{}",
+                peer.agent,
+                peer.hostname,
+                peer.timestamp,
+                if peer.mirroring {
+                    " with remote deletes"
+                } else {
+                    ""
+                }
+            );
+        }
+        match peers.into_iter().next() {
+            Some(peer) if self.duplicate_instance == Some(DuplicatePolicy::Refuse) => {
+                Err(Error::DuplicateInstance(peer.hostname))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Live heartbeats from other hosts under the prefix, those that could fight with
+    /// this agent because either side deletes remote objects
+    async fn duplicate_instances(&self, interval: u64) -> Result<Vec<Peer>, Error> {
+        let bucket_name = self.bucket_name.clone().ok_or(Error::MissingBucket)?;
+        let hostname = heartbeat::hostname();
+        let prefix = format!(
+            "{}{INTERNAL_PREFIX}heartbeats/",
+            self.key_prefix.as_deref().unwrap_or_default()
+        );
+        let client = self.client().await;
+        let now = chrono::Utc::now();
+        let mirroring = self.delete_remote.unwrap_or(false);
+        let mut peers = Vec::new();
+        let mut pages = client
+            .list_objects_v2()
+            .bucket(&bucket_name)
+            .prefix(&prefix)
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.try_next().await? {
+            for object in page.contents() {
+                let Some(key) = object.key() else {
+                    continue;
+                };
+                if key[prefix.len()..].split('/').next() == Some(hostname.as_str()) {
+                    continue;
+                }
+                let body = client
+                    .get_object()
+                    .bucket(&bucket_name)
+                    .key(key)
+                    .send()
+                    .await?
+                    .body
+                    .collect()
+                    .await?
+                    .into_bytes();
+                match serde_json::from_slice::<Peer>(&body) {
+                    Ok(peer) if peer.live(now, interval) && (mirroring || peer.mirroring) => {
+                        peers.push(peer);
+                    }
+                    Ok(_) => {}
+                    Err(e) => tracing::debug!("Ignoring unreadable heartbeat {key}: {e}"),
+                }
+            }
+        }
+        Ok(peers)
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn abort_stale_uploads(&self, cleanup: &MultipartCleanup) -> Result<(), Error> {
+        let bucket_name = self.bucket_name.clone().ok_or(Error::MissingBucket)?;
+        let aborted = multipart::abort_stale(
+            &self.client().await,
+            &bucket_name,
+            self.key_prefix.as_deref(),
+            cleanup.older_than(),
+            |key| cleanup.all() || self.original_key(key).is_some_and(|key| self.matches(key)),
+        )
+        .await?;
+        tracing::debug!("Aborted {aborted} stale multipart uploads");
+        Ok(())
+    }
+
+    /// Remove the objects for locally deleted files, batched into `DeleteObjects` calls.
+    /// Objects that fail to delete are logged, counted and left in the bucket.
+    ///
+    /// Skipped when the agent removes its own sources, since those removals are ours.
+    #[tracing::instrument(skip_all, fields(count = paths.len()))]
+    async fn delete_objects(&self, paths: &[&Path]) {
+        if !self.delete_remote.unwrap_or(false)
+            || self
+                .on_success
+                .as_ref()
+                .is_some_and(OnSuccess::removes_source)
+            || !self.active()
+        {
+            return;
+        }
+        let keys = paths
+            .iter()
+            .filter_map(|path| self.object_key(path).ok().flatten())
+            .collect::<Vec<_>>();
+        if keys.is_empty() {
+            return;
+        }
+        let client = self.client().await;
+        for batch in keys.chunks(DELETE_OBJECTS_BATCH_SIZE) {
+            let failed = match self.delete_batch(&client, batch).await {
+                Ok(failed) => failed,
+                Err(e) => {
+                    tracing::warn!(
+                        "Unable to delete {} object(s), leaving them in the bucket: {e}",
+                        batch.len()
+                    );
+                    metrics::record_delete_failures(self.name(), batch.len());
+                    continue;
+                }
+            };
+            metrics::record_delete_failures(self.name(), failed);
+            tracing::info!("Deleted {} object(s)", batch.len() - failed);
+            if let Some(state) = &self.state {
+                for key in batch {
+                    if let Err(e) = state.remove(self.name(), key) {
+                        tracing::warn!("Unable to remove '{}' from state: {e}", self.redact(key));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send one `DeleteObjects` call, returning how many of its keys failed
+    async fn delete_batch(&self, client: &s3::Client, batch: &[String]) -> Result<usize, Error> {
+        let bucket_name = self.bucket_name.clone().ok_or(Error::MissingBucket)?;
+        let objects = batch
+            .iter()
+            .map(|key| ObjectIdentifier::builder().key(key).build())
+            .collect::<Result<Vec<_>, _>>()?;
+        let delete = Delete::builder()
+            .set_objects(Some(objects))
+            .quiet(true)
+            .build()?;
+        let output = client
+            .delete_objects()
+            .bucket(&bucket_name)
+            .delete(delete)
+            .send()
+            .await?;
+        for error in output.errors() {
+            tracing::warn!(
+                "Failed to delete '{}': {}",
+                self.redact(error.key().unwrap_or_default()),
+                error.message().unwrap_or_default()
+            );
+        }
+        Ok(output.errors().len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use regex::Regex;
+
+    use super::{Agent, ConfigFormat, Manager, QueueSettings, Schedule};
+
+    fn manager(roots: &[(&Path, bool)]) -> Manager {
+        let agents = roots
+            .iter()
+            .map(|(root, recursive)| {
+                Agent::builder()
+                    .path(*root)
+                    .bucket("bucket")
+                    .name(root.display().to_string())
+                    .recursive(*recursive)
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        Manager::from_agents(agents, None).unwrap()
+    }
+
+    fn routed(manager: &Manager, path: &Path) -> Vec<String> {
+        manager
+            .agents_for(path)
+            .map(|agent| agent.name().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn routes_to_every_overlapping_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data, logs) = (dir.path().join("data"), dir.path().join("data/logs"));
+        std::fs::create_dir_all(&logs).unwrap();
+        let manager = manager(&[(&data, true), (&logs, true)]);
+        assert_eq!(
+            routed(&manager, &logs.join("app.log")),
+            [data.display().to_string(), logs.display().to_string()]
+        );
+        assert_eq!(
+            routed(&manager, &data.join("report.csv")),
+            [data.display().to_string()]
+        );
+    }
+
+    #[test]
+    fn skips_unrelated_and_sibling_prefix_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let (logs, home) = (dir.path().join("logs"), dir.path().join("home"));
+        let similar = dir.path().join("logs2");
+        for root in [&logs, &home, &similar] {
+            std::fs::create_dir_all(root).unwrap();
+        }
+        let manager = manager(&[(&logs, true), (&home, true)]);
+        assert_eq!(
+            routed(&manager, &home.join("data.bin")),
+            [home.display().to_string()]
+        );
+        assert!(routed(&manager, &similar.join("app.log")).is_empty());
+        assert!(routed(&manager, &logs).is_empty());
+    }
+
+    #[test]
+    fn keeps_nested_events_from_non_recursive_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data, logs) = (dir.path().join("data"), dir.path().join("data/logs"));
+        std::fs::create_dir_all(&logs).unwrap();
+        let manager = manager(&[(&data, false), (&logs, true)]);
+        assert_eq!(
+            routed(&manager, &logs.join("app.log")),
+            [logs.display().to_string()]
+        );
+        assert_eq!(
+            routed(&manager, &data.join("top.txt")),
+            [data.display().to_string()]
+        );
+        assert_eq!(routed(&manager, &logs), [data.display().to_string()]);
+    }
+
+    #[test]
+    fn routes_a_watched_file_to_it_and_its_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("app.log");
+        std::fs::write(&file, "").unwrap();
+        let manager = manager(&[(&file, false), (dir.path(), false)]);
+        assert_eq!(
+            routed(&manager, &file),
+            [file.display().to_string(), dir.path().display().to_string()]
+        );
+        assert_eq!(
+            routed(&manager, &dir.path().join("other.log")),
+            [dir.path().display().to_string()]
+        );
+    }
+
+    #[test]
+    fn guesses_content_types_after_configured_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let agent = Agent::builder()
+            .path(dir.path())
+            .bucket("bucket")
+            .content_type("LOG", "text/plain")
+            .build()
+            .unwrap();
+        assert_eq!(
+            agent.content_type("a/app.log").as_deref(),
+            Some("text/plain")
+        );
+        assert_eq!(
+            agent.content_type("a/b.JSON").as_deref(),
+            Some("application/json")
+        );
+        assert_eq!(
+            agent.content_type("report-1.csv").as_deref(),
+            Some("text/csv")
+        );
+        assert_eq!(agent.content_type("a.d/README"), None);
+    }
+
+    #[test]
+    fn excludes_what_the_include_filters_keep() {
+        let dir = tempfile::tempdir().unwrap();
+        let agent = Agent::builder()
+            .path(dir.path())
+            .bucket("bucket")
+            .include("**/*.csv")
+            .include("**/.*")
+            .exclude(Regex::new(r"^(tmp|cache)/").unwrap())
+            .exclude_glob("**/.DS_Store")
+            .build()
+            .unwrap();
+        assert!(agent.matches("data/report.csv"));
+        assert!(agent.matches(".env"));
+        assert!(!agent.matches("data/report.txt"));
+        assert!(!agent.matches("tmp/report.csv"));
+        assert!(!agent.matches("data/.DS_Store"));
+    }
+
+    #[test]
+    fn reads_the_same_config_in_every_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().display();
+        let configs = [
+            (
+                ConfigFormat::Yaml,
+                format!("agents:\n  - name: a\n    watcher: {{ local_path: {root}, settings: {{ window: 1 }} }}\n    bucket_name: b\n    key_prefix: p/\n"),
+            ),
+            (
+                ConfigFormat::Toml,
+                format!("[[agents]]\nname = \"a\"\nwatcher = {{ local_path = \"{root}\", settings = {{ window = 1 }} }}\nbucket_name = \"b\"\nkey_prefix = \"p/\"\n"),
+            ),
+            (
+                ConfigFormat::Json,
+                format!(r#"{{"agents": [{{"name": "a", "watcher": {{"local_path": "{root}", "settings": {{"window": 1}}}}, "bucket_name": "b", "key_prefix": "p/"}}]}}"#),
+            ),
+        ];
+        for (format, contents) in configs {
+            let manager = Manager::from_config(&contents, format).unwrap();
+            assert_eq!(manager.agents.len(), 1);
+            assert_eq!(manager.agents[0].name(), "a");
+            assert_eq!(manager.agents[0].key_prefix.as_deref(), Some("p/"));
+        }
+        assert!(Manager::from_config("agents = [", ConfigFormat::Toml).is_err());
+        assert_eq!(
+            ConfigFormat::of(Path::new("s3sync.TOML")),
+            ConfigFormat::Toml
+        );
+        assert_eq!(
+            ConfigFormat::of(Path::new("s3sync.json")),
+            ConfigFormat::Json
+        );
+        assert_eq!(
+            ConfigFormat::of(Path::new("s3sync.yml")),
+            ConfigFormat::Yaml
+        );
+    }
+
+    #[test]
+    fn keeps_unhandled_files_queued_on_disk_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = QueueSettings::Disk {
+            path: dir.path().join("queue"),
+        };
+        let queue = settings.open(&Agent::default()).unwrap();
+        for name in ["a", "b", "c"] {
+            queue.insert(dir.path().join(name)).unwrap();
+        }
+        assert_eq!(queue.take().len(), 3);
+        let first = queue.next().unwrap();
+        queue.done(&first).unwrap();
+        let second = queue.next().unwrap();
+        // Stopped, or crashed, before `second` was handled
+        drop(queue);
+        let queue = settings.open(&Agent::default()).unwrap();
+        assert_eq!(queue.len(), 2);
+        queue.take();
+        assert_eq!(queue.next(), Some(second));
+        assert_eq!(queue.next(), Some(dir.path().join("c")));
+        assert_eq!(queue.next(), None);
+    }
+
+    #[test]
+    fn counts_the_hours_past_midnight_towards_the_day_a_window_started() {
+        let schedule: Schedule = serde_yaml::from_str(
+            "timezone: UTC\nwindows: [{days: [Fri], start: '22:00:00', end: '06:00:00'}]",
+        )
+        .unwrap();
+        let at = |time: &str| schedule.is_open(time.parse().unwrap());
+        // 2026-10-16 is a Friday
+        assert!(!at("2026-10-16T05:00:00Z"));
+        assert!(at("2026-10-16T23:00:00Z"));
+        assert!(at("2026-10-17T05:00:00Z"));
+        assert!(!at("2026-10-17T07:00:00Z"));
+        assert!(!at("2026-10-17T23:00:00Z"));
+    }
+
+    #[test]
+    fn rejects_an_invalid_collision_timestamp_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = |format: &str| {
+            format!(
+                "agents:\n  - watcher: {{ local_path: {}, settings: {{ window: 1 }} }}\n    bucket_name: b\n    collision_suffix: {{ timestamp_format: '{format}' }}\n",
+                dir.path().display()
+            )
+        };
+        assert!(Manager::from_config(&config("%Y-%m-%d"), ConfigFormat::Yaml).is_ok());
+        assert!(Manager::from_config(&config("%Y-%"), ConfigFormat::Yaml).is_err());
+        assert!(Manager::from_config(&config("%Q"), ConfigFormat::Yaml).is_err());
+    }
+}
@@ -1,348 +1,305 @@
 #![warn(clippy::pedantic)]
 #![warn(clippy::nursery)]
 
+use std::{sync::mpsc::RecvTimeoutError, time::Duration};
+
 use clap::Parser;
+use s3sync::ux;
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, layer::SubscriberExt, Layer};
 
-const DEFAULT_EVENT_WINDOW_SECONDS: u64 = 5;
+/// How long the watch loop waits for events before checking for a new config
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 #[::tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"))
-        .add_directive("aws_config=warn".parse()?)
-        .add_directive("aws_smithy_runtime=warn".parse()?);
-    let subscriber = tracing_subscriber::fmt()
-        .pretty()
-        .with_file(true)
-        .with_line_number(true)
-        .with_thread_ids(true)
-        .with_target(false)
-        .with_env_filter(filter)
-        .finish();
-    tracing::subscriber::set_global_default(subscriber)?;
-
-    tracing::debug!("Setting up channel");
-    let (tx, rx) = std::sync::mpsc::channel();
-
-    let manager = s3sync::Manager::try_from(ux::Cli::parse())?;
-    // Need a variable name to get the watchers to run
-    let _watchers = manager
-        .watchers()
-        .iter()
-        .map(|watcher| watcher.watch(tx.clone()))
-        .collect::<Vec<_>>();
-
-    for events in rx.into_iter().flatten() {
-        for event in events {
-            manager.process_event(&event).await?;
+    let mut cli = ux::Cli::parse();
+    if run_standalone(&cli).await? {
+        return Ok(());
+    }
+    cli.output.init();
+    let (output, log_format) = (cli.output, cli.log_format);
+    let command = cli.command.take();
+    let config_refresh = cli.config_refresh;
+    let estimate = cli.estimate;
+    let config_path = cli.config.clone();
+    let config_format = cli
+        .config_format
+        .or_else(|| cli.config.as_deref().map(s3sync::ConfigFormat::of))
+        .unwrap_or_default();
+    let remote_config = match cli.config.as_deref().and_then(std::path::Path::to_str) {
+        Some(url) => {
+            s3sync::RemoteConfig::from_url(url, cli.profile.as_deref(), cli.region.as_deref()).await
+        }
+        None => None,
+    };
+    let (manager, e_tag) = match &remote_config {
+        Some(remote_config) => {
+            let (contents, e_tag) = remote_config.fetch().await?;
+            (
+                s3sync::Manager::from_config(&contents, config_format)?,
+                e_tag,
+            )
         }
+        None => (s3sync::Manager::try_from(cli)?, None),
+    };
+    // New config contents, from the remote config poller or the API
+    let (reload_tx, reload_rx) = tokio::sync::mpsc::channel(1);
+    let remote = remote_config.is_some();
+    if let Some((remote_config, seconds)) = remote_config.zip(config_refresh) {
+        remote_config.poll(Duration::from_secs(seconds), e_tag, reload_tx.clone());
     }
-    Ok(())
-}
-
-fn window_seconds_range(s: &str) -> Result<u64, String> {
-    clap_num::number_range(s, 1, 3600)
-}
-
-mod ux {
-    use std::path::PathBuf;
-
-    use clap::Parser;
-    use regex::Regex;
 
-    use crate::{window_seconds_range, DEFAULT_EVENT_WINDOW_SECONDS};
+    let _traces = init_logging(&manager, output, log_format).await?;
 
-    #[derive(Parser, Debug)]
-    #[command(about, long_about = None)]
-    pub struct Cli {
-        /// Local file path to sync
-        #[arg(long, short, default_value = std::env::current_dir().unwrap().into_os_string())]
-        pub path: PathBuf,
-        /// S3 bucket to sync with
-        #[arg(long, short)]
-        pub bucket: Option<String>,
-        /// Prefix to prepend to the key
-        #[arg(long)]
-        pub prefix: Option<String>,
-        /// Regex filter to match events
-        #[arg(long)]
-        pub pattern: Option<Regex>,
-        /// AWS credential profile to use
-        #[arg(long)]
-        pub profile: Option<String>,
-        /// AWS region override
-        #[arg(long)]
-        pub region: Option<String>,
-        /// Delete source file after successful upload
-        #[arg(long, short)]
-        pub delete: Option<bool>,
-        /// Recursively sync the provided path
-        #[arg(short, long)]
-        pub recursive: Option<bool>,
-        /// Number of seconds to aggregate events
-        #[arg(short, long, value_parser=window_seconds_range, default_value_t = DEFAULT_EVENT_WINDOW_SECONDS)]
-        pub window: u64,
-        #[arg(long)]
-        pub config: Option<PathBuf>,
+    let control = s3sync::Control::default();
+    let local_config = config_path.filter(|_| !remote);
+    let served_config = local_config.clone().zip(Some(config_format));
+    serve(&manager, served_config, &reload_tx, &control).await?;
+    if let Some(command) = command {
+        return run_command(&manager, command).await;
     }
+    if !manager.confirm_estimate(estimate).await? {
+        tracing::info!("Not confirmed, exiting");
+        return Ok(());
+    }
+    let _config_watch = local_config
+        .map(|path| s3sync::LocalConfig::watch(&path, reload_tx.clone()))
+        .transpose()?;
+    run(manager, &control, reload_rx, config_format).await
 }
 
-mod s3sync {
-    use std::{
-        collections::HashMap,
-        path::{Path, PathBuf},
-    };
-
-    use anyhow::anyhow;
-    use aws_config::{default_provider::region::DefaultRegionChain, Region};
-    use aws_sdk_s3 as s3;
-    use derive_builder::Builder;
-    use notify_debouncer_mini::{
-        new_debouncer,
-        notify::{FsEventWatcher, RecursiveMode},
-        DebounceEventHandler, DebouncedEvent, Debouncer,
+/// Watch the agents' paths and upload what changes, restarting the watchers when the
+/// watchdog finds them stalled and swapping in each config reloaded whose paths can all
+/// be watched
+async fn run(
+    mut manager: s3sync::Manager,
+    control: &s3sync::Control,
+    mut reload_rx: tokio::sync::mpsc::Receiver<String>,
+    config_format: s3sync::ConfigFormat,
+) -> Result<(), anyhow::Error> {
+    tracing::debug!("Setting up channel");
+    let (tx, rx) = std::sync::mpsc::channel();
+    // Contents of the last config reloaded, as the API's changes come back from the file
+    // watch too
+    let mut loaded = None;
+    let mut initial_sync = true;
+    let watch = |manager: &s3sync::Manager| {
+        manager.index_directories();
+        let watchers = manager.watchers();
+        let started = watchers
+            .iter()
+            .map(|watcher| watcher.watch(tx.clone()))
+            .collect::<Result<Vec<_>, _>>();
+        started.map(|started| (watchers, started))
     };
-    use regex::Regex;
-    use s3::primitives::ByteStream;
-    use serde::Deserialize;
-
-    use crate::{ux::Cli, DEFAULT_EVENT_WINDOW_SECONDS};
-
-    #[derive(Deserialize, Debug)]
-    pub struct Manager {
-        pub agents: Vec<Agent>,
-    }
+    let mut watching = Some(watch(&manager)?);
+    loop {
+        control.attach(&mut manager);
+        manager.check_duplicate_instances().await?;
+        let _tasks = manager.start_background_tasks();
+        // Need a variable name to get the watchers to run
+        let (watchers, _watches) = match watching.take() {
+            Some(watching) => watching,
+            None => watch(&manager)?,
+        };
+        // Once the watchers are up, so files created during the sync still get events
+        if std::mem::take(&mut initial_sync) {
+            manager.initial_sync().await;
+        }
+        let watchdog = s3sync::Watchdog::new(manager.watchdog.as_ref());
 
-    impl Manager {
-        pub fn watchers(&self) -> Vec<AgentWatcher> {
-            let mut path_settings: HashMap<&PathBuf, PathSettings> = HashMap::new();
-            for agent in &self.agents {
-                if let Some(settings) = path_settings.get(agent.watcher.local_path()) {
-                    let settings = agent.watcher.settings.clone() + settings.clone();
-                    path_settings.insert(&agent.watcher.local_path, settings);
-                } else {
-                    path_settings.insert(&agent.watcher.local_path, agent.watcher.settings.clone());
+        loop {
+            match rx.recv_timeout(RELOAD_CHECK_INTERVAL) {
+                Ok(Ok(mut events)) => {
+                    watchdog.filter(&mut events);
+                    manager.process_events(&events).await?;
                 }
+                Ok(Err(e)) => tracing::warn!("Watch error: {e:?}"),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
             }
-            path_settings
-                .into_iter()
-                .map(|(local_path, settings)| AgentWatcher {
-                    local_path: local_path.clone(),
-                    settings,
-                })
-                .collect()
-        }
-        pub async fn process_event(&self, event: &DebouncedEvent) -> Result<(), anyhow::Error> {
-            if event.kind == notify_debouncer_mini::DebouncedEventKind::Any  // ignore AnyContinuous (i.e., still in progress)
-            && event.path.exists()
-            && event.path.is_file()
-            {
-                tracing::debug!("Process: {event:?}");
-                for agent in &self.agents {
-                    agent.process_file(&event.path).await?;
+            manager.process_deferred().await;
+            manager.reconcile().await;
+            if !watchdog.check(&watchers).is_empty() {
+                tracing::warn!("Restarting the watchers");
+                break;
+            }
+            if let Ok(contents) = reload_rx.try_recv() {
+                if loaded.as_ref() == Some(&contents) {
+                    tracing::debug!("Config unchanged");
+                    continue;
+                }
+                let next = s3sync::Manager::from_config(&contents, config_format)
+                    .and_then(|next| Ok((watch(&next)?, next)));
+                match next {
+                    Ok((started, next)) => {
+                        tracing::info!("Reloading config");
+                        manager = next;
+                        watching = Some(started);
+                        loaded = Some(contents);
+                        break;
+                    }
+                    Err(e) => tracing::warn!("Ignoring invalid config: {e}"),
                 }
             }
-            Ok(())
         }
     }
+}
 
-    impl TryFrom<Cli> for Manager {
-        type Error = anyhow::Error;
+/// Log at `RUST_LOG` levels, `info` by default, to stdout or to stderr when stdout carries
+/// the event stream, and to CloudWatch Logs when configured. Spans go to the OTLP
+/// collector when configured, until the returned guard is dropped.
+async fn init_logging(
+    manager: &s3sync::Manager,
+    output: s3sync::OutputFormat,
+    format: s3sync::LogFormat,
+) -> Result<Option<s3sync::OtlpGuard>, anyhow::Error> {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"))
+        .add_directive("aws_config=warn".parse()?)
+        .add_directive("aws_smithy_runtime=warn".parse()?);
+    // Keep stdout clean for the machine-readable event stream
+    let writer = if output == s3sync::OutputFormat::Ndjson {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_file(true)
+        .with_line_number(true)
+        .with_thread_ids(true)
+        .with_target(false);
+    let layer = match format {
+        s3sync::LogFormat::Pretty => layer.pretty().boxed(),
+        s3sync::LogFormat::Compact => layer.compact().boxed(),
+        s3sync::LogFormat::Json => layer.json().flatten_event(true).boxed(),
+    };
+    let subscriber = tracing_subscriber::registry()
+        .with(layer)
+        .with(filter)
+        .with(manager.log_layer().await);
+    let (traces, guard) = manager.trace_layer()?.unzip();
+    tracing::subscriber::set_global_default(subscriber.with(traces))?;
+    Ok(guard)
+}
 
-        fn try_from(value: Cli) -> Result<Self, Self::Error> {
-            if let Some(filename) = value.config {
-                let contents = std::fs::read_to_string(filename)?;
-                Ok(serde_yaml::from_str(&contents)?)
-            } else {
-                let path_settings = PathSettings {
-                    recursive: value.recursive,
-                    window: Some(value.window),
-                };
-                let watcher = AgentWatcher {
-                    local_path: value.path,
-                    settings: path_settings,
-                };
-                let agent = Agent {
-                    watcher,
-                    pattern: value.pattern,
-                    bucket_name: value.bucket,
-                    profile_name: value.profile,
-                    region_name: value.region,
-                    delete: value.delete,
-                    key_prefix: value.prefix,
-                };
-                Ok(Self {
-                    agents: vec![agent],
-                })
+/// Start the metrics endpoint, the API and the gRPC control plane, whichever are configured.
+/// Changes made through the latter two are written to `config` and sent on `reload`.
+async fn serve(
+    manager: &s3sync::Manager,
+    config: Option<(std::path::PathBuf, s3sync::ConfigFormat)>,
+    reload: &tokio::sync::mpsc::Sender<String>,
+    control: &s3sync::Control,
+) -> Result<(), anyhow::Error> {
+    if let Some(metrics) = &manager.metrics {
+        metrics.serve(control.clone()).await?;
+    }
+    let config_path = match config {
+        Some((_, format)) if format != s3sync::ConfigFormat::Yaml => {
+            if manager.api.is_some() || manager.grpc.is_some() {
+                anyhow::bail!("The API and gRPC control plane write YAML, use a YAML --config");
             }
+            None
         }
+        config => config.map(|(path, _)| path),
+    };
+    if let Some(api) = &manager.api {
+        let Some(config_path) = config_path.clone() else {
+            anyhow::bail!("The API requires a local --config file to persist changes");
+        };
+        api.serve(config_path, reload.clone(), control.clone())
+            .await?;
     }
-
-    #[derive(Builder, Deserialize, Debug, Clone)]
-    #[builder(build_fn(error = "anyhow::Error"))]
-    pub struct AgentWatcher {
-        local_path: PathBuf,
-        settings: PathSettings,
+    if let Some(grpc) = &manager.grpc {
+        let Some(config_path) = config_path else {
+            anyhow::bail!(
+                "The gRPC control plane requires a local --config file to persist pushed configs"
+            );
+        };
+        grpc.serve(config_path, reload.clone(), control.clone())
+            .await?;
     }
+    Ok(())
+}
 
-    impl AgentWatcher {
-        pub const fn local_path(&self) -> &PathBuf {
-            &self.local_path
-        }
-        pub fn watch<F: DebounceEventHandler>(&self, tx: F) -> Debouncer<FsEventWatcher> {
-            let mut watcher =
-                new_debouncer(std::time::Duration::from_secs(self.settings.window()), tx).unwrap();
-            watcher
-                .watcher()
-                .watch(self.local_path(), self.settings.recursive_mode())
-                .unwrap();
-            tracing::info!("Watching: {self:?}");
-            watcher
+/// Handle `--version` and the maintenance commands, which run without a config or
+/// credentials, returning whether that was all there was to do
+#[cfg_attr(not(feature = "self-update"), allow(clippy::unused_async))]
+async fn run_standalone(cli: &ux::Cli) -> Result<bool, anyhow::Error> {
+    if cli.version {
+        if cli.verbose {
+            print!("{}", s3sync::build_info::report());
+        } else {
+            println!("s3sync {}", env!("CARGO_PKG_VERSION"));
         }
+        return Ok(true);
     }
-
-    #[derive(Deserialize, Debug, Clone)]
-    pub struct PathSettings {
-        recursive: Option<bool>,
-        window: Option<u64>,
-    }
-
-    impl PathSettings {
-        pub fn recursive(&self) -> bool {
-            self.recursive.unwrap_or(false)
-        }
-        pub fn recursive_mode(&self) -> RecursiveMode {
-            if self.recursive() {
-                RecursiveMode::Recursive
-            } else {
-                RecursiveMode::NonRecursive
-            }
-        }
-        pub fn window(&self) -> u64 {
-            self.window.unwrap_or(DEFAULT_EVENT_WINDOW_SECONDS)
+    match &cli.command {
+        Some(ux::Command::Completions { shell }) => ux::completions(*shell),
+        Some(ux::Command::Man { out_dir }) => ux::man_pages(out_dir)?,
+        #[cfg(feature = "self-update")]
+        Some(ux::Command::SelfUpdate(options)) => {
+            tracing_subscriber::fmt().with_target(false).init();
+            s3sync::self_update::run(options).await?;
         }
+        _ => return Ok(false),
     }
+    Ok(true)
+}
 
-    impl std::ops::Add for PathSettings {
-        type Output = Self;
-
-        fn add(self, rhs: Self) -> Self::Output {
-            let window = std::cmp::min(self.window(), rhs.window());
-            let recursive = !matches!(
-                (self.recursive_mode(), rhs.recursive_mode()),
-                (RecursiveMode::NonRecursive, RecursiveMode::NonRecursive)
-            );
-            Self {
-                window: Some(window),
-                recursive: Some(recursive),
-            }
+/// Run a one-off subcommand instead of watching
+async fn run_command(manager: &s3sync::Manager, command: ux::Command) -> Result<(), anyhow::Error> {
+    match command {
+        #[cfg(feature = "self-update")]
+        ux::Command::SelfUpdate(_) => unreachable!("handled before the config is loaded"),
+        ux::Command::Completions { .. } | ux::Command::Man { .. } => {
+            unreachable!("handled before the config is loaded")
         }
-    }
-
-    impl Default for PathSettings {
-        fn default() -> Self {
-            Self {
-                recursive: Some(false),
-                window: Some(DEFAULT_EVENT_WINDOW_SECONDS),
+        ux::Command::Diff => Ok(manager.diff().await?),
+        ux::Command::Sync => {
+            if manager.sync().await? {
+                Ok(())
+            } else {
+                anyhow::bail!("Some uploads failed")
             }
         }
-    }
-
-    #[derive(Builder, Deserialize, Debug, Clone)]
-    #[builder(build_fn(error = "anyhow::Error"))]
-    pub struct Agent {
-        watcher: AgentWatcher,
-        #[serde(with = "serde_regex", default)]
-        pattern: Option<Regex>,
-        bucket_name: Option<String>,
-        key_prefix: Option<String>,
-        profile_name: Option<String>,
-        region_name: Option<String>,
-        delete: Option<bool>,
-    }
-
-    impl Agent {
-        #[tracing::instrument]
-        fn object_key(&self, path: &Path) -> Result<String, anyhow::Error> {
-            let key = path
-                .strip_prefix(self.watcher.local_path.clone())?
-                .to_str()
-                .ok_or_else(|| anyhow!("Non-unicode path"))?;
-            tracing::debug!("Proposed object key: '{key}'");
-            let applied_pattern = self
-                .pattern
-                .clone()
-                .unwrap_or_else(|| Regex::new(r".*").unwrap());
-            tracing::debug!("Pattern to match: '{applied_pattern}'");
-            if applied_pattern.is_match(key) {
-                let key = self
-                    .key_prefix
-                    .clone()
-                    .map_or(key.to_string(), |prefix| format!("{prefix}{key}"));
-                tracing::debug!("Final object key '{key}'");
-                Ok(key)
+        ux::Command::MigrateKeys(from) => Ok(manager.migrate_keys(&from).await?),
+        ux::Command::Pull(options) => Ok(manager.pull(&options).await?),
+        ux::Command::Replay(options) => Ok(manager.replay(&options).await?),
+        ux::Command::Verify => {
+            if manager.verify().await? {
+                Ok(())
             } else {
-                tracing::debug!("Path does not match pattern");
-                Err(anyhow::Error::msg("Does not match pattern"))
+                anyhow::bail!("Verification found drift")
             }
         }
-
-        #[tracing::instrument]
-        async fn process_file(&self, file: &Path) -> Result<(), anyhow::Error> {
-            if let Ok(key) = self.object_key(file) {
-                tracing::debug!("Processing");
-                self.upload_file(file, &key).await?;
-                if self.delete.unwrap_or(false) {
-                    Self::delete_source(file)?;
-                } else {
-                    tracing::debug!("Skip removal");
-                }
+        ux::Command::State {
+            command: ux::StateCommand::Export { output },
+        } => {
+            // Nothing else goes to stdout, it may be piped straight into `import`
+            if let Some(path) = output {
+                let count = manager.export_state(std::fs::File::create(path)?)?;
+                tracing::info!("Exported {count} entries");
             } else {
-                tracing::debug!("Skip processing");
+                manager.export_state(std::io::stdout().lock())?;
             }
             Ok(())
         }
-
-        #[tracing::instrument]
-        async fn upload_file(&self, path: &Path, key: &str) -> Result<(), anyhow::Error> {
-            let bucket_name = self
-                .bucket_name
-                .clone()
-                .ok_or_else(|| anyhow::Error::msg("Bucket name is required"))?;
-            let body = ByteStream::from_path(path).await?;
-            let profile_name = self
-                .profile_name
-                .clone()
-                .unwrap_or_else(|| String::from("default"));
-            let region = self.region_name.clone().map(Region::new).or({
-                DefaultRegionChain::builder()
-                    .profile_name(&profile_name)
-                    .build()
-                    .region()
-                    .await
-            });
-            let sdk_config = aws_config::from_env()
-                .region(region)
-                .profile_name(profile_name)
-                .load()
-                .await;
-            let client = s3::Client::new(&sdk_config);
-            client
-                .put_object()
-                .bucket(bucket_name)
-                .key(key)
-                .body(body)
-                .send()
-                .await?;
-            tracing::info!("File uploaded");
-            Ok(())
-        }
-
-        #[tracing::instrument]
-        fn delete_source(path: &Path) -> Result<(), anyhow::Error> {
-            std::fs::remove_file(path)?;
-            tracing::info!("Source file removed");
+        ux::Command::State {
+            command: ux::StateCommand::Import { input },
+        } => {
+            let count = match input {
+                Some(path) => {
+                    manager.import_state(std::io::BufReader::new(std::fs::File::open(path)?))?
+                }
+                None => manager.import_state(std::io::stdin().lock())?,
+            };
+            tracing::info!("Imported {count} entries");
             Ok(())
         }
+        ux::Command::State {
+            command: ux::StateCommand::Catalog,
+        } => Ok(manager.write_catalogs().await?),
     }
 }
@@ -0,0 +1,148 @@
+use std::{net::SocketAddr, path::Path, time::Duration};
+
+#[cfg(feature = "metrics-server")]
+use axum::{routing::get, Router};
+#[cfg(feature = "metrics-server")]
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use serde::Deserialize;
+
+use super::{output::SkipReason, Control, Error};
+
+const UPLOADS: &str = "s3sync_uploads_total";
+const UPLOAD_FAILURES: &str = "s3sync_upload_failures_total";
+const DELETE_FAILURES: &str = "s3sync_delete_failures_total";
+const SKIPPED: &str = "s3sync_skipped_files_total";
+const UPLOADED_BYTES: &str = "s3sync_uploaded_bytes_total";
+const UPLOAD_DURATION: &str = "s3sync_upload_duration_seconds";
+const OBJECT_SIZE: &str = "s3sync_object_size_bytes";
+const LAST_UPLOAD: &str = "s3sync_last_upload_timestamp_seconds";
+const WATCHER_ALIVE: &str = "s3sync_watcher_alive";
+const OVERDUE: &str = "s3sync_overdue_uploads_total";
+const OVERDUE_FILES: &str = "s3sync_overdue_files";
+const PART_SIZE: &str = "s3sync_tuned_part_size_bytes";
+const PART_CONCURRENCY: &str = "s3sync_tuned_part_concurrency";
+
+#[cfg(feature = "metrics-server")]
+const DEFAULT_DURATION_BUCKETS: &[f64] = &[
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+#[cfg(feature = "metrics-server")]
+const DEFAULT_SIZE_BUCKETS: &[f64] = &[
+    1_024.0,
+    16_384.0,
+    262_144.0,
+    1_048_576.0,
+    16_777_216.0,
+    104_857_600.0,
+    1_073_741_824.0,
+    5_368_709_120.0,
+];
+
+/// Prometheus endpoint and histogram buckets
+#[derive(Deserialize, Debug, Clone)]
+#[cfg_attr(not(feature = "metrics-server"), allow(dead_code))]
+pub struct MetricsSettings {
+    pub listen: SocketAddr,
+    /// Upload duration buckets, in seconds
+    duration_buckets: Option<Vec<f64>>,
+    /// Object size buckets, in bytes
+    size_buckets: Option<Vec<f64>>,
+}
+
+impl MetricsSettings {
+    #[must_use]
+    pub const fn new(listen: SocketAddr) -> Self {
+        Self {
+            listen,
+            duration_buckets: None,
+            size_buckets: None,
+        }
+    }
+
+    /// Install the global recorder and serve `/metrics` in the background, next to the
+    /// status page when built with the web UI
+    #[cfg(feature = "metrics-server")]
+    pub async fn serve(&self, control: Control) -> Result<(), Error> {
+        let handle = PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full(UPLOAD_DURATION.to_string()),
+                self.duration_buckets
+                    .as_deref()
+                    .unwrap_or(DEFAULT_DURATION_BUCKETS),
+            )?
+            .set_buckets_for_metric(
+                Matcher::Full(OBJECT_SIZE.to_string()),
+                self.size_buckets.as_deref().unwrap_or(DEFAULT_SIZE_BUCKETS),
+            )?
+            .install_recorder()?;
+        let app = Router::new().route("/metrics", get(move || async move { handle.render() }));
+        #[cfg(feature = "web-ui")]
+        let app = app.merge(super::web_ui::router(control));
+        #[cfg(not(feature = "web-ui"))]
+        drop(control);
+        let listener = tokio::net::TcpListener::bind(self.listen).await?;
+        tracing::info!("Serving metrics on {}", self.listen);
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, app).await {
+                tracing::error!("Metrics server stopped: {e}");
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(not(feature = "metrics-server"))]
+    #[allow(clippy::unused_async)]
+    pub async fn serve(&self, _: Control) -> Result<(), Error> {
+        Err(Error::Disabled("metrics-server"))
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+pub fn record_upload(agent: &str, bytes: u64, elapsed: Duration) {
+    let agent = agent.to_string();
+    metrics::gauge!(LAST_UPLOAD, "agent" => agent.clone())
+        .set(chrono::Utc::now().timestamp() as f64);
+    metrics::counter!(UPLOADS, "agent" => agent.clone()).increment(1);
+    metrics::counter!(UPLOADED_BYTES, "agent" => agent.clone()).increment(bytes);
+    metrics::histogram!(UPLOAD_DURATION, "agent" => agent.clone()).record(elapsed.as_secs_f64());
+    metrics::histogram!(OBJECT_SIZE, "agent" => agent).record(bytes as f64);
+}
+
+pub fn record_failure(agent: &str) {
+    metrics::counter!(UPLOAD_FAILURES, "agent" => agent.to_string()).increment(1);
+}
+
+pub fn record_delete_failures(agent: &str, count: usize) {
+    if count > 0 {
+        metrics::counter!(DELETE_FAILURES, "agent" => agent.to_string())
+            .increment(u64::try_from(count).unwrap_or(u64::MAX));
+    }
+}
+
+pub fn record_skip(agent: &str, reason: SkipReason) {
+    metrics::counter!(SKIPPED, "agent" => agent.to_string(), "reason" => reason.as_str())
+        .increment(1);
+}
+
+/// 1 once a watched directory's canary event arrived, 0 when it was overdue
+pub fn record_watcher_alive(path: &Path, alive: bool) {
+    metrics::gauge!(WATCHER_ALIVE, "path" => path.display().to_string()).set(if alive {
+        1.0
+    } else {
+        0.0
+    });
+}
+
+/// A file newly past its agent's upload deadline, out of `pending` overdue in all
+#[allow(clippy::cast_precision_loss)]
+pub fn record_overdue(agent: &str, newly: usize, pending: usize) {
+    metrics::counter!(OVERDUE, "agent" => agent.to_string()).increment(newly as u64);
+    metrics::gauge!(OVERDUE_FILES, "agent" => agent.to_string()).set(pending as f64);
+}
+
+/// Part size and concurrency an agent's auto-tuning has settled on for its next upload
+#[allow(clippy::cast_precision_loss)]
+pub fn record_tuning(agent: &str, part_size: u64, concurrency: usize) {
+    metrics::gauge!(PART_SIZE, "agent" => agent.to_string()).set(part_size as f64);
+    metrics::gauge!(PART_CONCURRENCY, "agent" => agent.to_string()).set(concurrency as f64);
+}
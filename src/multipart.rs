@@ -0,0 +1,279 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use aws_sdk_s3 as s3;
+use s3::{
+    primitives::{ByteStream, Length},
+    types::{ChecksumAlgorithm, CompletedMultipartUpload, CompletedPart},
+};
+use serde::Deserialize;
+use tokio::task::JoinSet;
+use tracing::Instrument;
+
+use super::{is_checksum_mismatch, tune::Tuner, Error, CHECKSUM_ATTEMPTS};
+
+const DEFAULT_OLDER_THAN: Duration = Duration::from_hours(7 * 24);
+const DEFAULT_INTERVAL: Duration = Duration::from_hours(1);
+
+/// Periodic cleanup of incomplete multipart uploads under the agent's prefix
+#[derive(Deserialize, Debug, Clone)]
+pub struct MultipartCleanup {
+    /// Abort uploads initiated longer ago than this, 7 days by default
+    #[serde(default, with = "humantime_serde")]
+    older_than: Option<Duration>,
+    /// Time between checks, an hour by default
+    #[serde(default, with = "humantime_serde")]
+    interval: Option<Duration>,
+    /// Also abort uploads for keys this agent would never have written
+    all: Option<bool>,
+}
+
+impl MultipartCleanup {
+    pub fn older_than(&self) -> Duration {
+        self.older_than.unwrap_or(DEFAULT_OLDER_THAN)
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval.unwrap_or(DEFAULT_INTERVAL)
+    }
+
+    pub fn all(&self) -> bool {
+        self.all.unwrap_or(false)
+    }
+}
+
+/// Abort every incomplete upload under `prefix` started before `older_than` ago whose key
+/// passes `filter`, returning how many were aborted
+pub async fn abort_stale(
+    client: &s3::Client,
+    bucket: &str,
+    prefix: Option<&str>,
+    older_than: Duration,
+    filter: impl Fn(&str) -> bool,
+) -> Result<usize, Error> {
+    let cutoff = chrono::Utc::now()
+        - chrono::Duration::from_std(older_than).unwrap_or(chrono::TimeDelta::MAX);
+    let mut aborted = 0;
+    let mut key_marker = None;
+    let mut upload_id_marker = None;
+    loop {
+        let output = client
+            .list_multipart_uploads()
+            .bucket(bucket)
+            .set_prefix(prefix.map(String::from))
+            .set_key_marker(key_marker)
+            .set_upload_id_marker(upload_id_marker)
+            .send()
+            .await?;
+        for upload in output.uploads() {
+            let (Some(key), Some(upload_id), Some(initiated)) =
+                (upload.key(), upload.upload_id(), upload.initiated())
+            else {
+                continue;
+            };
+            if initiated.secs() >= cutoff.timestamp() || !filter(key) {
+                continue;
+            }
+            tracing::info!("Aborting multipart upload {upload_id} started {initiated}");
+            client
+                .abort_multipart_upload()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .send()
+                .await?;
+            aborted += 1;
+        }
+        if !output.is_truncated().unwrap_or(false) {
+            return Ok(aborted);
+        }
+        key_marker = output.next_key_marker().map(String::from);
+        upload_id_marker = output.next_upload_id_marker().map(String::from);
+    }
+}
+
+/// Size of each part when the aws-cli config doesn't set one, matching aws-cli's default
+pub const DEFAULT_PART_SIZE: u64 = 8 * 1024 * 1024;
+/// Smallest part S3 accepts, except for the last
+pub const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+/// Most parts a single upload can have
+pub const MAX_PARTS: u64 = 10_000;
+
+/// A file split into parts of an already created multipart upload
+pub struct Parts<'a> {
+    pub bucket: &'a str,
+    pub key: &'a str,
+    pub upload_id: &'a str,
+    pub source: &'a Path,
+    pub size: u64,
+    pub part_size: u64,
+    /// Parts in flight at once
+    pub concurrency: usize,
+    pub checksum: Option<ChecksumAlgorithm>,
+    /// Parts already uploaded by an earlier attempt, skipped rather than sent again
+    pub uploaded: Vec<CompletedPart>,
+    /// Told how long parts and the final request took, when the agent tunes itself
+    pub tuner: Option<Tuner>,
+}
+
+/// What each part task needs to know about the upload
+struct Target {
+    bucket: String,
+    key: String,
+    upload_id: String,
+    source: PathBuf,
+    checksum: Option<ChecksumAlgorithm>,
+}
+
+impl Parts<'_> {
+    /// Upload every part, `concurrency` at a time, and complete the upload, returning its
+    /// `ETag`. Parts S3 rejects on a checksum mismatch are retried.
+    pub async fn upload(self, client: &s3::Client) -> Result<Option<String>, Error> {
+        let target = Arc::new(Target {
+            bucket: self.bucket.to_string(),
+            key: self.key.to_string(),
+            upload_id: self.upload_id.to_string(),
+            source: self.source.to_path_buf(),
+            checksum: self.checksum.clone(),
+        });
+        let mut completed = self.uploaded.clone();
+        let mut tasks = JoinSet::new();
+        let mut offset = 0;
+        let mut part_number = 1;
+        while offset < self.size || part_number == 1 {
+            let length = self.part_size.min(self.size - offset);
+            if !self
+                .uploaded
+                .iter()
+                .any(|part| part.part_number() == Some(part_number))
+            {
+                if tasks.len() >= self.concurrency.max(1) {
+                    if let Some(result) = tasks.join_next().await {
+                        completed.push(self.finished(result??));
+                    }
+                }
+                tasks.spawn(
+                    upload_part(client.clone(), target.clone(), part_number, offset, length)
+                        .in_current_span(),
+                );
+            }
+            offset += length;
+            part_number += 1;
+        }
+        while let Some(result) = tasks.join_next().await {
+            completed.push(self.finished(result??));
+        }
+        completed.sort_by_key(CompletedPart::part_number);
+        let started = Instant::now();
+        let output = client
+            .complete_multipart_upload()
+            .bucket(self.bucket)
+            .key(self.key)
+            .upload_id(self.upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(completed))
+                    .build(),
+            )
+            .send()
+            .await?;
+        if let Some(tuner) = &self.tuner {
+            tuner.record_round_trip(started.elapsed());
+        }
+        Ok(output.e_tag().map(String::from))
+    }
+
+    fn finished(&self, (part, length, elapsed): (CompletedPart, u64, Duration)) -> CompletedPart {
+        if let Some(tuner) = &self.tuner {
+            tuner.record_part(length, elapsed);
+        }
+        part
+    }
+}
+
+/// Upload one part, returning it with its length and how long it took
+async fn upload_part(
+    client: s3::Client,
+    target: Arc<Target>,
+    part_number: i32,
+    offset: u64,
+    length: u64,
+) -> Result<(CompletedPart, u64, Duration), Error> {
+    let started = Instant::now();
+    let mut attempt = 1;
+    let output = loop {
+        let body = ByteStream::read_from()
+            .path(&target.source)
+            .offset(offset)
+            .length(Length::Exact(length))
+            .build()
+            .await?;
+        let result = client
+            .upload_part()
+            .bucket(&target.bucket)
+            .key(&target.key)
+            .upload_id(&target.upload_id)
+            .part_number(part_number)
+            .set_checksum_algorithm(target.checksum.clone())
+            .body(body)
+            .send()
+            .await;
+        match result {
+            Err(e) if attempt < CHECKSUM_ATTEMPTS && is_checksum_mismatch(&e) => {
+                tracing::warn!(
+                    "Checksum mismatch on part {part_number} attempt {attempt}, retrying"
+                );
+                attempt += 1;
+            }
+            result => break result?,
+        }
+    };
+    tracing::debug!("Uploaded part {part_number}");
+    let part = CompletedPart::builder()
+        .part_number(part_number)
+        .set_e_tag(output.e_tag().map(String::from))
+        .set_checksum_crc32(output.checksum_crc32().map(String::from))
+        .set_checksum_crc32_c(output.checksum_crc32_c().map(String::from))
+        .set_checksum_sha1(output.checksum_sha1().map(String::from))
+        .set_checksum_sha256(output.checksum_sha256().map(String::from))
+        .build();
+    Ok((part, length, started.elapsed()))
+}
+
+/// Parts S3 already has for an upload, to resume it from
+pub async fn list_parts(
+    client: &s3::Client,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+) -> Result<Vec<CompletedPart>, Error> {
+    let mut parts = Vec::new();
+    let mut marker = None;
+    loop {
+        let output = client
+            .list_parts()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .set_part_number_marker(marker)
+            .send()
+            .await?;
+        parts.extend(output.parts().iter().map(|part| {
+            CompletedPart::builder()
+                .set_part_number(part.part_number())
+                .set_e_tag(part.e_tag().map(String::from))
+                .set_checksum_crc32(part.checksum_crc32().map(String::from))
+                .set_checksum_crc32_c(part.checksum_crc32_c().map(String::from))
+                .set_checksum_sha1(part.checksum_sha1().map(String::from))
+                .set_checksum_sha256(part.checksum_sha256().map(String::from))
+                .build()
+        }));
+        if !output.is_truncated().unwrap_or(false) {
+            return Ok(parts);
+        }
+        marker = output.next_part_number_marker().map(String::from);
+    }
+}
@@ -0,0 +1,84 @@
+use std::path::Path;
+
+use super::OnSuccess;
+
+impl OnSuccess {
+    /// Whether the source is gone afterwards, so its removal isn't a local deletion
+    #[must_use]
+    pub const fn removes_source(&self) -> bool {
+        matches!(self, Self::Delete | Self::Move { .. })
+    }
+
+    /// Whether the source itself is changed, which is skipped when it changed after
+    /// being staged so later writes aren't lost
+    #[must_use]
+    pub const fn changes_source(&self) -> bool {
+        !matches!(self, Self::Keep | Self::Command { .. })
+    }
+
+    /// Problem with the policy for an agent watching `watched`
+    #[must_use]
+    pub fn invalid(&self, watched: &Path) -> Option<&'static str> {
+        match self {
+            Self::Move { dir } if dir.starts_with(watched) => {
+                Some("on_success move dir must be outside the watched path")
+            }
+            Self::Command { program, .. } if program.is_empty() => {
+                Some("on_success command needs a program")
+            }
+            _ => None,
+        }
+    }
+
+    /// Apply the policy to `source`, uploaded to `key` in `bucket` from `relative` under
+    /// the watched path
+    pub async fn apply(
+        &self,
+        source: &Path,
+        relative: &str,
+        bucket: &str,
+        key: &str,
+    ) -> std::io::Result<()> {
+        match self {
+            Self::Keep => tracing::debug!("Skip removal"),
+            Self::Delete => {
+                std::fs::remove_file(source)?;
+                tracing::info!("Source file removed");
+            }
+            Self::Move { dir } => {
+                let destination = dir.join(relative);
+                if let Some(parent) = destination.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                // Falling back to a copy when `dir` is on another filesystem
+                if std::fs::rename(source, &destination).is_err() {
+                    std::fs::copy(source, &destination)?;
+                    std::fs::remove_file(source)?;
+                }
+                tracing::info!("Source file moved to '{}'", destination.display());
+            }
+            Self::Truncate => {
+                std::fs::File::options()
+                    .write(true)
+                    .truncate(true)
+                    .open(source)?;
+                tracing::info!("Source file truncated");
+            }
+            Self::Command { program, args } => {
+                let status = tokio::process::Command::new(program)
+                    .args(args)
+                    .arg(source)
+                    .env("S3SYNC_BUCKET", bucket)
+                    .env("S3SYNC_KEY", key)
+                    .status()
+                    .await;
+                match status {
+                    Ok(status) if status.success() => {}
+                    Ok(status) => tracing::warn!("On-success command {program} {status}"),
+                    Err(e) => tracing::warn!("Unable to run on-success command {program}: {e}"),
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Files held back because another process still had them open, with when that was
+/// first noticed
+#[derive(Debug, Clone, Default)]
+pub struct OpenFiles(Arc<Mutex<HashMap<PathBuf, Instant>>>);
+
+impl OpenFiles {
+    /// Keep waiting on an open file, returning false once it has been open for longer than
+    /// `timeout`
+    pub fn wait(&self, path: &Path, timeout: Duration) -> bool {
+        let mut files = self.0.lock().unwrap();
+        let since = *files.entry(path.to_path_buf()).or_insert_with(Instant::now);
+        if since.elapsed() < timeout {
+            true
+        } else {
+            files.remove(path);
+            false
+        }
+    }
+
+    /// Whether the file has been waited on for longer than `timeout`
+    pub fn timed_out(&self, path: &Path, timeout: Duration) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(path)
+            .is_some_and(|since| since.elapsed() >= timeout)
+    }
+
+    pub fn clear(&self, path: &Path) {
+        self.0.lock().unwrap().remove(path);
+    }
+
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.0.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Take over the files `other` is waiting on, e.g. from before a config reload,
+    /// still timed from when they were first noticed
+    pub fn adopt(&self, other: &Self) {
+        if Arc::ptr_eq(&self.0, &other.0) {
+            return;
+        }
+        let waiting = other.0.lock().unwrap().clone();
+        let mut files = self.0.lock().unwrap();
+        for (path, since) in waiting {
+            files.entry(path).or_insert(since);
+        }
+    }
+}
+
+/// Whether some other process has the file open, scanning `/proc/*/fd`
+#[cfg(target_os = "linux")]
+pub fn is_open(path: &Path) -> bool {
+    let Ok(target) = path.canonicalize() else {
+        return false;
+    };
+    let Ok(processes) = std::fs::read_dir("/proc") else {
+        return false;
+    };
+    let own = std::process::id().to_string();
+    processes
+        .flatten()
+        .filter(|process| process.file_name() != own.as_str())
+        .filter_map(|process| std::fs::read_dir(process.path().join("fd")).ok())
+        .flat_map(Iterator::flatten)
+        .any(|fd| std::fs::read_link(fd.path()).is_ok_and(|link| link == target))
+}
+
+/// Whether some other process has the file open, which shows up as a sharing violation
+/// when asking for exclusive access
+#[cfg(windows)]
+pub fn is_open(path: &Path) -> bool {
+    use std::os::windows::fs::OpenOptionsExt;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    std::fs::OpenOptions::new()
+        .read(true)
+        .share_mode(0)
+        .open(path)
+        .is_err_and(|e| e.raw_os_error() == Some(ERROR_SHARING_VIOLATION))
+}
+
+/// No cheap way to tell on this platform, files are always treated as closed
+#[cfg(not(any(target_os = "linux", windows)))]
+pub const fn is_open(_path: &Path) -> bool {
+    false
+}
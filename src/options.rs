@@ -0,0 +1,400 @@
+// Settings types the CLI shares with config files and the crate, kept to the crate's
+// lighter dependencies so build.rs can include them along with `ux` to write man pages.
+
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+pub const DEFAULT_EVENT_WINDOW: Duration = Duration::from_secs(5);
+
+/// Event window as a humantime duration (e.g. `500ms`, `2m`), or a bare number of seconds
+pub fn parse_window(s: &str) -> Result<Duration, String> {
+    let window = s.parse::<u64>().map_or_else(
+        |_| humantime::parse_duration(s).map_err(|e| e.to_string()),
+        |seconds| Ok(Duration::from_secs(seconds)),
+    )?;
+    if window.is_zero() {
+        Err(String::from("window must be greater than zero"))
+    } else {
+        Ok(window)
+    }
+}
+
+pub const DEFAULT_TRASH_MAX_SIZE: u64 = 1 << 30;
+
+/// `--tag` and `--metadata` values
+pub fn key_value(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected key=value, got '{s}'"))
+}
+
+/// What, if anything, is written to stdout as files move through an agent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// Nothing beyond the regular logs
+    #[default]
+    Log,
+    /// One JSON object per lifecycle event
+    Ndjson,
+}
+
+/// How the daemon's own logs are written to stdout, or stderr under `--output ndjson`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// Several lines per event, for reading in a terminal
+    #[default]
+    Pretty,
+    /// One line per event
+    Compact,
+    /// One JSON object per event, with the fields of the spans it happened in, such as
+    /// the agent, bucket and key
+    Json,
+}
+
+/// How file paths and object keys are written to logs and traces
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogPaths {
+    #[default]
+    Plain,
+    /// Replace with a short digest that still correlates across lines
+    Hash,
+}
+
+/// Which form of a file's path the filters are matched against
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchOn {
+    /// Path relative to the watched directory, before prefixing and sharding
+    #[default]
+    Relative,
+    /// Full local path
+    Absolute,
+    /// Just the file name
+    Filename,
+}
+
+/// S3-compatible services with known endpoints and quirks, so only the bucket, credentials
+/// and (for R2) account id need configuring
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// Cloudflare R2, which needs the account id and uses the `auto` region
+    R2,
+    /// Backblaze B2, e.g. region `us-west-004`
+    B2,
+    /// Wasabi, e.g. region `eu-central-1`
+    Wasabi,
+}
+
+/// What happens to a source file once it's uploaded, e.g. `{type: move, dir: /archive}`
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum OnSuccess {
+    #[default]
+    Keep,
+    Delete,
+    /// Move it under `dir`, at the same path relative to the watched path
+    Move {
+        dir: PathBuf,
+    },
+    /// Empty it in place, for spool files their writer keeps open (and writes to with
+    /// `O_APPEND`). Only done when nothing was written during the upload, and each upload
+    /// goes to a suffixed key, `-{timestamp}-{counter}` unless `collision_suffix` is set.
+    Truncate,
+    /// Run `program` with `args` and the file's path, and the bucket and key in
+    /// `S3SYNC_BUCKET` and `S3SYNC_KEY`. A failing command is only logged.
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+/// `--on-success`: `keep`, `delete`, `truncate`, `move:<dir>` or `command:<program>`
+impl FromStr for OnSuccess {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("move", dir)) => Ok(Self::Move { dir: dir.into() }),
+            Some(("command", program)) => Ok(Self::Command {
+                program: program.to_string(),
+                args: Vec::new(),
+            }),
+            _ => match s {
+                "keep" => Ok(Self::Keep),
+                "delete" => Ok(Self::Delete),
+                "truncate" => Ok(Self::Truncate),
+                _ => Err(format!(
+                    "expected keep, delete, truncate, move:<dir> or command:<program>, got '{s}'"
+                )),
+            },
+        }
+    }
+}
+
+/// Order files found by a scan are uploaded in
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadOrder {
+    /// Least recently modified first
+    Oldest,
+    /// Most recently modified first
+    Newest,
+    Smallest,
+    Largest,
+}
+
+/// Where file system events come from
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// The platform's native notifications, e.g. inotify on Linux and FSEvents on macOS
+    #[default]
+    Auto,
+    /// Linux inotify
+    Inotify,
+    /// macOS FSEvents
+    Fsevents,
+    /// Scanning the tree for changes every `poll_interval`, for network file systems such
+    /// as NFS and SMB mounts that don't deliver native events
+    Poll,
+}
+
+/// Algorithm for the checksum S3 verifies on upload
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Checksum {
+    Sha256,
+    Sha1,
+    Crc32,
+    Crc32c,
+    /// `Content-MD5`, for servers without the newer checksums. Multipart uploads go without.
+    Md5,
+}
+
+/// Storage tier uploads land in, named as S3 names them
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[value(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum StorageClass {
+    Standard,
+    ReducedRedundancy,
+    StandardIa,
+    OnezoneIa,
+    IntelligentTiering,
+    /// Glacier Instant Retrieval
+    GlacierIr,
+    /// Glacier Flexible Retrieval, objects must be restored before they can be read
+    Glacier,
+    /// Objects must be restored before they can be read, which takes up to 48 hours
+    DeepArchive,
+}
+
+/// Server-side encryption S3 applies to the objects an agent writes, with the header
+/// values S3 uses
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Sse {
+    /// S3-managed keys
+    #[serde(rename = "AES256")]
+    #[value(name = "AES256")]
+    Aes256,
+    /// KMS keys, the account's `aws/s3` key unless `kms_key_id` is set
+    #[serde(rename = "aws:kms")]
+    #[value(name = "aws:kms")]
+    Kms,
+    /// Dual-layer encryption with KMS keys
+    #[serde(rename = "aws:kms:dsse")]
+    #[value(name = "aws:kms:dsse")]
+    KmsDsse,
+}
+
+/// What to do when the object key for a file already exists
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Collision {
+    /// Replace the existing object
+    #[default]
+    Overwrite,
+    /// Leave the existing object and don't upload
+    Skip,
+    /// Upload next to it under a suffixed key
+    Suffix,
+}
+
+/// What to do about a queued file, e.g. deferred until the schedule opens or waiting to be
+/// closed, that was removed or moved before it could be uploaded
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Vanished {
+    /// Drop it without a word
+    #[default]
+    Ignore,
+    /// Drop it with a warning
+    Warn,
+    /// Count it as a failed upload, in metrics, events and the dead letter file
+    Fail,
+    /// Upload the file it was renamed to within the same directory, warning when there's none
+    Follow,
+}
+
+/// What to upload in place of a file's contents, for agents that use S3 to announce files
+/// whose data stays where it is
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Placeholder {
+    /// A zero-byte object, with the file's path, size, MD5 and host in its metadata
+    Empty,
+    /// A JSON object with the file's path, size, MD5, modification time and host
+    Descriptor,
+}
+
+/// What to do on finding another host's agent syncing the same prefix while either side
+/// deletes remote objects
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    #[default]
+    Warn,
+    /// Fail to start, later duplicates are still only logged
+    Refuse,
+}
+
+/// Syntax of a config file, YAML unless its extension says otherwise
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigFormat {
+    #[default]
+    Yaml,
+    Toml,
+    Json,
+}
+
+/// Whether to report what's waiting to be uploaded before starting, and to ask about it
+#[derive(clap::Args, Debug, Clone, Copy)]
+pub struct EstimateOptions {
+    /// Report the files, bytes and projected cost of what's waiting to be uploaded and ask
+    /// before starting, as happens anyway before an initial sync or an agent's first
+    /// reconcile pass
+    #[arg(long)]
+    pub estimate: bool,
+    /// Go ahead without asking after an estimate
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+}
+
+/// When and how to split uploads into parts, overriding the aws-cli config's `s3` section
+#[derive(Deserialize, Debug, Clone, Copy, Default, clap::Args)]
+pub struct Multipart {
+    /// Upload files of at least this many bytes in parts (defaults to the aws-cli
+    /// `multipart_threshold`, else only files over the 5 GiB single-PUT limit)
+    #[arg(long = "multipart-threshold")]
+    pub threshold: Option<u64>,
+    /// Size of each part in bytes, raised as needed to stay within 10,000 parts (defaults
+    /// to the aws-cli `multipart_chunksize`, else 8 MiB)
+    #[arg(long = "multipart-part-size")]
+    pub part_size: Option<u64>,
+    /// Parts of a file uploaded at once [default: 1]
+    #[arg(long = "multipart-concurrency")]
+    pub concurrency: Option<usize>,
+}
+
+/// Where objects were written before a prefix or shard change, for `migrate-keys`
+#[derive(clap::Args, Debug)]
+pub struct KeyLayout {
+    /// Prefix the objects were written under
+    #[arg(long)]
+    pub from_prefix: Option<String>,
+    /// Number of shards the objects were spread across
+    #[arg(long)]
+    pub from_shards: Option<u32>,
+    /// Only migrate this agent's objects
+    #[arg(long)]
+    pub agent: Option<String>,
+    /// Remove the old objects once copied
+    #[arg(long)]
+    pub delete_old: bool,
+    /// Print the moves without copying anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Which bucket state `pull` materializes, and what to do with local files it lacks
+#[derive(clap::Args, Debug, Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct PullOptions {
+    /// Restore the object versions current at this time (RFC 3339, e.g.
+    /// `2024-01-02T03:04:05Z`), the latest versions when unset
+    #[arg(long, conflicts_with = "poll")]
+    pub as_of: Option<DateTime<Utc>>,
+    /// Only pull this agent's objects
+    #[arg(long)]
+    pub agent: Option<String>,
+    /// Remove local files whose object didn't exist at that time
+    #[arg(long)]
+    pub delete: bool,
+    /// Print what would change without downloading or removing anything
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Move local files that are overwritten or removed into `.s3sync-trash` under the
+    /// path, rather than losing them
+    #[arg(long)]
+    pub trash: bool,
+    /// Most the trash holds, in bytes, removing the oldest files first
+    #[arg(long, default_value_t = DEFAULT_TRASH_MAX_SIZE)]
+    pub trash_max_size: u64,
+    /// How long trashed files are kept (e.g. `12h`, `30d`)
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "7d")]
+    pub trash_retention: Duration,
+    /// Keep pulling the current objects at this interval (e.g. `30s`, `5m`). Agents start
+    /// at random points in the first interval and each wait varies by up to a tenth, so
+    /// they don't all list the bucket at once.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub poll: Option<Duration>,
+    /// Keys only ever sort after existing ones (e.g. timestamped names), so each poll only
+    /// lists keys after the last one seen. Those held back by `--tag` or `--metadata` are
+    /// checked again each poll.
+    #[arg(long, requires = "poll", conflicts_with = "delete")]
+    pub append_only: bool,
+    /// Only download objects carrying this tag (e.g. `status=approved`), repeatable,
+    /// requiring all of them
+    #[arg(long = "tag", value_parser = key_value)]
+    pub tags: Vec<(String, String)>,
+    /// Only download objects with this user metadata value (e.g. `reviewed=yes`),
+    /// repeatable, requiring all of them
+    #[arg(long = "metadata", value_parser = key_value)]
+    pub metadata: Vec<(String, String)>,
+}
+
+/// Which recorded uploads `replay` repeats
+#[derive(clap::Args, Debug, Clone)]
+pub struct ReplayOptions {
+    /// Event log written with `--output ndjson`
+    #[arg(long)]
+    pub from: PathBuf,
+    /// Only uploads recorded at or after this time (RFC 3339, e.g. `2024-01-02T03:04:05Z`)
+    #[arg(long)]
+    pub since: Option<DateTime<Utc>>,
+    /// Print what would be uploaded without uploading anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[cfg(feature = "self-update")]
+/// Which release `self-update` installs
+#[derive(clap::Args, Debug)]
+pub struct SelfUpdateOptions {
+    /// Install this release tag (e.g. `v0.2.0`) instead of the latest, even if it's older
+    /// than the running version
+    #[arg(long)]
+    pub tag: Option<String>,
+    /// Only report whether a newer release is available
+    #[arg(long)]
+    pub check: bool,
+    /// Reinstall even when the release matches the running version
+    #[arg(long)]
+    pub force: bool,
+}
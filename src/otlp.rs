@@ -0,0 +1,82 @@
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    trace::{SdkTracerProvider, Tracer},
+    Resource,
+};
+use serde::Deserialize;
+use tracing_subscriber::registry::LookupSpan;
+
+use super::Error;
+
+const DEFAULT_SERVICE_NAME: &str = "s3sync";
+const TRACES_PATH: &str = "/v1/traces";
+
+/// Export spans to an OpenTelemetry collector over OTLP/HTTP, e.g. Jaeger or Tempo
+#[derive(Deserialize, Debug, Clone)]
+pub struct OtlpSettings {
+    /// Collector URL, `/v1/traces` is added when it has no path. Defaults to
+    /// `OTEL_EXPORTER_OTLP_ENDPOINT` or else `http://localhost:4318`
+    endpoint: Option<String>,
+    /// `service.name` of the exported spans, `s3sync` by default
+    service_name: Option<String>,
+}
+
+pub type OtlpLayer<S> = tracing_opentelemetry::OpenTelemetryLayer<S, Tracer>;
+
+/// Flushes the spans still batched when dropped
+pub struct OtlpGuard(SdkTracerProvider);
+
+impl Drop for OtlpGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.0.shutdown() {
+            tracing::warn!("Unable to export the last spans: {e}");
+        }
+    }
+}
+
+impl OtlpSettings {
+    #[must_use]
+    pub const fn new(endpoint: String) -> Self {
+        Self {
+            endpoint: Some(endpoint),
+            service_name: None,
+        }
+    }
+
+    /// Start exporting in the background and return the layer feeding it
+    pub fn layer<S>(&self) -> Result<(OtlpLayer<S>, OtlpGuard), Error>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let mut exporter = SpanExporter::builder().with_http();
+        if let Some(endpoint) = &self.endpoint {
+            exporter = exporter.with_endpoint(traces_url(endpoint));
+        }
+        let service_name = self
+            .service_name
+            .clone()
+            .unwrap_or_else(|| String::from(DEFAULT_SERVICE_NAME));
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter.build()?)
+            .with_resource(Resource::builder().with_service_name(service_name).build())
+            .build();
+        let layer =
+            tracing_opentelemetry::layer().with_tracer(provider.tracer(env!("CARGO_PKG_NAME")));
+        Ok((layer, OtlpGuard(provider)))
+    }
+}
+
+/// The collector's traces URL, for an endpoint given as just its base URL
+fn traces_url(endpoint: &str) -> String {
+    let endpoint = endpoint.trim_end_matches('/');
+    let has_path = endpoint
+        .split_once("://")
+        .map_or(endpoint, |(_, rest)| rest)
+        .contains('/');
+    if has_path {
+        endpoint.to_string()
+    } else {
+        format!("{endpoint}{TRACES_PATH}")
+    }
+}
@@ -0,0 +1,164 @@
+use std::{io::Write, sync::OnceLock};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use super::OutputFormat;
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+
+impl OutputFormat {
+    /// Set the process-wide output format, only the first call has any effect
+    pub fn init(self) {
+        let _ = FORMAT.set(self);
+    }
+
+    pub fn current() -> Self {
+        FORMAT.get().copied().unwrap_or_default()
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum Lifecycle<'a> {
+    Detected {
+        path: &'a str,
+    },
+    Matched {
+        path: &'a str,
+        key: &'a str,
+    },
+    Uploaded {
+        /// Local file, absent for bucket-to-bucket replication
+        #[serde(skip_serializing_if = "Option::is_none")]
+        path: Option<&'a str>,
+        bucket: &'a str,
+        key: &'a str,
+        bytes: u64,
+        #[serde(flatten)]
+        times: FileTimes,
+    },
+    Skipped {
+        path: &'a str,
+        reason: SkipReason,
+    },
+    Failed {
+        path: &'a str,
+        error: &'a str,
+    },
+    /// Matched but still not uploaded after the agent's `max_upload_latency`
+    Overdue {
+        path: &'a str,
+        waited_seconds: u64,
+    },
+}
+
+/// Timestamps of an uploaded file, when the agent preserves them
+#[derive(Serialize, Debug, Clone, Copy, Default)]
+pub struct FileTimes {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<DateTime<Utc>>,
+    /// Birth time, only on platforms and filesystems that record it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<DateTime<Utc>>,
+}
+
+impl FileTimes {
+    pub fn of(metadata: &std::fs::Metadata) -> Self {
+        Self {
+            modified: metadata.modified().ok().map(DateTime::from),
+            created: metadata.created().ok().map(DateTime::from),
+        }
+    }
+}
+
+/// Why a file that reached an agent was not uploaded
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// Filtered out by `pattern`, `include` or `match_on`
+    PatternMismatch,
+    /// Resolves outside the watched path and external links are not followed
+    ExternalLink,
+    /// Held back until the agent's schedule opens
+    OutsideSchedule,
+    /// Held back until the control plane resumes the agent
+    Paused,
+    /// Path can't be turned into an object key, e.g. it isn't valid unicode
+    InvalidPath,
+    /// Directory or other non-regular file
+    NotAFile,
+    /// Larger than the agent's `max_object_size` or the single-PUT limit
+    TooLarge,
+    /// Same size and modification time as its last upload in the state database
+    Unchanged,
+    /// Another process still has the file open, retried once it's closed
+    StillOpen,
+    /// The key already exists and the collision policy is `skip`
+    Exists,
+    /// Another instance holds the agent's lease
+    Standby,
+    /// Nothing written since `on_success: truncate` emptied it
+    Empty,
+    /// Same contents at the same path were uploaded within the agent's `duplicate_window`
+    Duplicate,
+    /// The object already has the file's size and MD5, under `skip_unchanged`
+    UpToDate,
+}
+
+impl SkipReason {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::PatternMismatch => "pattern_mismatch",
+            Self::ExternalLink => "external_link",
+            Self::OutsideSchedule => "outside_schedule",
+            Self::Paused => "paused",
+            Self::InvalidPath => "invalid_path",
+            Self::NotAFile => "not_a_file",
+            Self::TooLarge => "too_large",
+            Self::Unchanged => "unchanged",
+            Self::StillOpen => "still_open",
+            Self::Exists => "exists",
+            Self::Standby => "standby",
+            Self::Empty => "empty",
+            Self::Duplicate => "duplicate",
+            Self::UpToDate => "up_to_date",
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Record<'a> {
+    timestamp: DateTime<Utc>,
+    agent: &'a str,
+    #[serde(flatten)]
+    lifecycle: &'a Lifecycle<'a>,
+}
+
+impl Lifecycle<'_> {
+    /// Hand the event to subscribers, and write it to stdout when the ndjson output format
+    /// is enabled
+    pub fn emit(&self, agent: &str) {
+        super::subscribers::notify(agent, self);
+        #[cfg(feature = "web-ui")]
+        super::web_ui::record(agent, self);
+        if OutputFormat::current() != OutputFormat::Ndjson {
+            return;
+        }
+        let record = Record {
+            timestamp: Utc::now(),
+            agent,
+            lifecycle: self,
+        };
+        match serde_json::to_string(&record) {
+            Ok(line) => {
+                let mut stdout = std::io::stdout().lock();
+                if let Err(e) = writeln!(stdout, "{line}").and_then(|()| stdout.flush()) {
+                    tracing::warn!("Unable to write event: {e}");
+                }
+            }
+            Err(e) => tracing::warn!("Unable to serialize event: {e}"),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// Spaces uploads out to a steady rate, so a window's worth of events doesn't hit the
+/// network all at once
+#[derive(Debug, Clone, Default)]
+pub struct Pacer(Arc<Mutex<Option<Instant>>>);
+
+impl Pacer {
+    /// Wait for the next slot at `per_second` uploads, returning immediately when the rate
+    /// isn't a positive number
+    pub async fn wait(&self, per_second: f64) {
+        let Ok(interval) = Duration::try_from_secs_f64(per_second.recip()) else {
+            return;
+        };
+        let slot = {
+            let mut next = self.0.lock().unwrap();
+            let now = Instant::now();
+            let slot = next.map_or(now, |next| next.max(now));
+            *next = Some(slot + interval);
+            slot
+        };
+        tokio::time::sleep_until(slot).await;
+    }
+}
@@ -0,0 +1,67 @@
+use std::{collections::HashMap, path::Path};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use super::{heartbeat, md5_hex, Error, Placeholder};
+
+/// Where a file is and what's in it, without the contents
+#[derive(Serialize, Debug)]
+pub struct Descriptor {
+    host: String,
+    agent: String,
+    path: String,
+    size: u64,
+    md5: String,
+    modified: Option<DateTime<Utc>>,
+}
+
+impl Descriptor {
+    /// Describe the file at `path`, read from `source`, which differs when it's staged
+    pub fn of(agent: &str, path: &Path, source: &Path) -> Result<Self, Error> {
+        let metadata = source.metadata()?;
+        Ok(Self {
+            host: heartbeat::hostname(),
+            agent: agent.to_string(),
+            path: path.display().to_string(),
+            size: metadata.len(),
+            md5: md5_hex(source)?,
+            modified: metadata.modified().ok().map(DateTime::from),
+        })
+    }
+}
+
+/// The object uploaded for a file by a placeholder agent
+#[derive(Debug)]
+pub struct Body {
+    pub contents: Vec<u8>,
+    pub content_type: Option<&'static str>,
+}
+
+impl Placeholder {
+    /// The object to upload for the described file, adding what goes in its metadata
+    pub fn body(
+        self,
+        descriptor: &Descriptor,
+        metadata: &mut HashMap<String, String>,
+    ) -> Result<Body, Error> {
+        match self {
+            Self::Empty => {
+                metadata.extend([
+                    (String::from("source-host"), descriptor.host.clone()),
+                    (String::from("source-path"), descriptor.path.clone()),
+                    (String::from("source-size"), descriptor.size.to_string()),
+                    (String::from("source-md5"), descriptor.md5.clone()),
+                ]);
+                Ok(Body {
+                    contents: Vec::new(),
+                    content_type: None,
+                })
+            }
+            Self::Descriptor => Ok(Body {
+                contents: serde_json::to_vec(descriptor)?,
+                content_type: Some("application/json"),
+            }),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use super::Provider;
+
+impl Provider {
+    /// Region used when none is configured or found in the profile
+    #[must_use]
+    pub const fn default_region(self) -> &'static str {
+        match self {
+            Self::R2 => "auto",
+            Self::B2 => "us-west-004",
+            Self::Wasabi => "us-east-1",
+        }
+    }
+
+    /// Endpoint for `region`, `None` for R2 without an account id
+    #[must_use]
+    pub fn endpoint_url(self, region: &str, account_id: Option<&str>) -> Option<String> {
+        match self {
+            Self::R2 => account_id.map(|id| format!("https://{id}.r2.cloudflarestorage.com")),
+            Self::B2 => Some(format!("https://s3.{region}.backblazeb2.com")),
+            Self::Wasabi => Some(format!("https://s3.{region}.wasabisys.com")),
+        }
+    }
+
+    /// Whether buckets are addressed in the path rather than the host name
+    #[must_use]
+    pub const fn force_path_style(self) -> bool {
+        matches!(self, Self::R2)
+    }
+}
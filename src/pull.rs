@@ -0,0 +1,276 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet},
+    hash::{BuildHasher, Hasher},
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use aws_sdk_s3::{self as s3, operation::head_object::HeadObjectError};
+use chrono::{DateTime, Utc};
+use md5::{Digest, Md5};
+
+use super::{Error, PullOptions};
+
+impl PullOptions {
+    /// Whether the object carries the required tags and metadata, looking them up only
+    /// when some are required
+    pub async fn admits(
+        &self,
+        client: &s3::Client,
+        bucket: &str,
+        key: &str,
+        version: &Version,
+    ) -> Result<bool, Error> {
+        if !self.tags.is_empty() {
+            let output = client
+                .get_object_tagging()
+                .bucket(bucket)
+                .key(key)
+                .set_version_id(version.id.clone())
+                .send()
+                .await?;
+            let has = |(name, value): &(String, String)| {
+                output
+                    .tag_set()
+                    .iter()
+                    .any(|tag| tag.key() == name && tag.value() == value)
+            };
+            if !self.tags.iter().all(has) {
+                return Ok(false);
+            }
+        }
+        if !self.metadata.is_empty() {
+            let output = client
+                .head_object()
+                .bucket(bucket)
+                .key(key)
+                .set_version_id(version.id.clone())
+                .send()
+                .await?;
+            let metadata = output.metadata.unwrap_or_default();
+            // S3 lower-cases metadata names
+            let has = |(name, value): &(String, String)| {
+                metadata.get(&name.to_lowercase()) == Some(value)
+            };
+            if !self.metadata.iter().all(has) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Object version current at some point in time
+#[derive(Debug, Clone)]
+pub struct Version {
+    pub id: Option<String>,
+    pub size: i64,
+    pub e_tag: Option<String>,
+    pub last_modified: Option<s3::primitives::DateTime>,
+}
+
+/// Where the previous poll left off
+#[derive(Debug, Default)]
+pub struct Cursor {
+    /// Newest modification time seen, anything older was already pulled
+    modified: Option<s3::primitives::DateTime>,
+    /// Greatest key seen, where `--append-only` listings start after
+    last_key: Option<String>,
+    /// Keys before `last_key` held back by `--tag` or `--metadata`, checked again each
+    /// poll as they won't be listed again
+    held_back: BTreeSet<String>,
+}
+
+impl Cursor {
+    /// Whether an earlier poll already pulled this version, so its local file needn't be
+    /// hashed again. Versions modified in the same instant as the newest one seen are
+    /// checked again, they may have been written just after that listing.
+    pub fn pulled(&self, version: &Version) -> bool {
+        matches!((self.modified, version.last_modified), (Some(seen), Some(modified)) if modified < seen)
+    }
+
+    /// Move past `versions`, of which `held_back` weren't admitted yet
+    pub fn advance(&mut self, versions: &BTreeMap<String, Version>, held_back: BTreeSet<String>) {
+        let newest = versions
+            .values()
+            .filter_map(|version| version.last_modified);
+        self.modified = self.modified.into_iter().chain(newest).max();
+        if let Some(key) = versions.keys().next_back() {
+            self.last_key = self.last_key.clone().max(Some(key.clone()));
+        }
+        self.held_back = held_back;
+    }
+}
+
+/// Current object under each key the cursor held back, leaving out those since deleted
+pub async fn held_back_versions(
+    client: &s3::Client,
+    bucket: &str,
+    cursor: &Cursor,
+) -> Result<BTreeMap<String, Version>, Error> {
+    let mut current = BTreeMap::new();
+    for key in &cursor.held_back {
+        let output = match client.head_object().bucket(bucket).key(key).send().await {
+            Ok(output) => output,
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(HeadObjectError::is_not_found) =>
+            {
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let version = Version {
+            id: None,
+            size: output.content_length().unwrap_or_default(),
+            e_tag: output.e_tag().map(String::from),
+            last_modified: output.last_modified().copied(),
+        };
+        current.insert(key.clone(), version);
+    }
+    Ok(current)
+}
+
+/// Up to `max`, picked at random
+pub fn jitter(max: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish() % 1000;
+    max * u32::try_from(random).unwrap_or_default() / 1000
+}
+
+/// Current object under each key under `prefix`, starting after the cursor's last key
+/// when given
+pub async fn current_versions(
+    client: &s3::Client,
+    bucket: &str,
+    prefix: Option<&str>,
+    start_after: Option<&Cursor>,
+) -> Result<BTreeMap<String, Version>, Error> {
+    let mut current = BTreeMap::new();
+    let mut pages = client
+        .list_objects_v2()
+        .bucket(bucket)
+        .set_prefix(prefix.map(String::from))
+        .set_start_after(start_after.and_then(|cursor| cursor.last_key.clone()))
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.try_next().await? {
+        for object in page.contents() {
+            if let Some(key) = object.key() {
+                let version = Version {
+                    id: None,
+                    size: object.size().unwrap_or_default(),
+                    e_tag: object.e_tag().map(String::from),
+                    last_modified: object.last_modified().copied(),
+                };
+                current.insert(key.to_string(), version);
+            }
+        }
+    }
+    Ok(current)
+}
+
+fn at_or_before(time: &s3::primitives::DateTime, as_of: &DateTime<Utc>) -> bool {
+    (time.secs(), time.subsec_nanos()) <= (as_of.timestamp(), as_of.timestamp_subsec_nanos())
+}
+
+/// Newest version of each key under `prefix` written at or before `as_of`, leaving out keys
+/// that didn't exist yet or were deleted by then
+pub async fn versions_as_of(
+    client: &s3::Client,
+    bucket: &str,
+    prefix: Option<&str>,
+    as_of: &DateTime<Utc>,
+) -> Result<BTreeMap<String, Version>, Error> {
+    // Newest entry per key, `None` when that entry is a delete marker
+    let mut current: BTreeMap<String, (s3::primitives::DateTime, Option<Version>)> =
+        BTreeMap::new();
+    let mut keep_newest = |key: &str, time: s3::primitives::DateTime, version| {
+        if !at_or_before(&time, as_of) {
+            return;
+        }
+        match current.get(key) {
+            Some((newest, _)) if *newest >= time => {}
+            _ => {
+                current.insert(key.to_string(), (time, version));
+            }
+        }
+    };
+    let mut key_marker = None;
+    let mut version_id_marker = None;
+    loop {
+        let output = client
+            .list_object_versions()
+            .bucket(bucket)
+            .set_prefix(prefix.map(String::from))
+            .set_key_marker(key_marker)
+            .set_version_id_marker(version_id_marker)
+            .send()
+            .await?;
+        for version in output.versions() {
+            if let (Some(key), Some(time)) = (version.key(), version.last_modified()) {
+                let version = Version {
+                    id: version.version_id().map(String::from),
+                    size: version.size().unwrap_or_default(),
+                    e_tag: version.e_tag().map(String::from),
+                    last_modified: Some(*time),
+                };
+                keep_newest(key, *time, Some(version));
+            }
+        }
+        for marker in output.delete_markers() {
+            if let (Some(key), Some(time)) = (marker.key(), marker.last_modified()) {
+                keep_newest(key, *time, None);
+            }
+        }
+        if !output.is_truncated().unwrap_or(false) {
+            break;
+        }
+        key_marker = output.next_key_marker().map(String::from);
+        version_id_marker = output.next_version_id_marker().map(String::from);
+    }
+    Ok(current
+        .into_iter()
+        .filter_map(|(key, (_, version))| Some((key, version?)))
+        .collect())
+}
+
+/// Hidden temporary file a download is written to beside `path`, e.g. `.report.csv.part`
+fn part_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// Write an object's body to `path` through a `.part` file renamed into place once it's
+/// complete and, when `md5` is given, matches it, so readers never see a partial file
+pub async fn download(
+    mut body: s3::primitives::ByteStream,
+    path: &Path,
+    md5: Option<&str>,
+) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let part = part_path(path);
+    let result = async {
+        let mut file = std::fs::File::create(&part)?;
+        let mut hasher = Md5::new();
+        while let Some(bytes) = body.try_next().await? {
+            hasher.update(&bytes);
+            file.write_all(&bytes)?;
+        }
+        file.sync_all()?;
+        if md5.is_some_and(|md5| format!("{:x}", hasher.finalize()) != md5) {
+            return Err(Error::DownloadMismatch(path.to_path_buf()));
+        }
+        std::fs::rename(&part, path)?;
+        Ok(())
+    }
+    .await;
+    if result.is_err() {
+        let _ = std::fs::remove_file(&part);
+    }
+    result
+}
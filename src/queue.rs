@@ -0,0 +1,594 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashMap},
+    fs::OpenOptions,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+#[cfg(feature = "sqs")]
+use std::{collections::HashSet, future::Future};
+
+#[cfg(feature = "sqs")]
+use aws_config::SdkConfig;
+#[cfg(feature = "sqs")]
+use aws_sdk_sqs as sqs;
+use globset::GlobMatcher;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "sqs")]
+use tokio::sync::mpsc;
+
+use super::{Agent, Error};
+
+/// Seconds a received message stays hidden before it's received again, kept short so what
+/// a stopped or crashed run left queued is soon picked up by the next
+#[cfg(feature = "sqs")]
+const SQS_VISIBILITY_TIMEOUT: i32 = 30;
+
+/// Where files waiting to be uploaded are kept, e.g. while held back by the schedule
+pub trait Queue: Send + Sync + std::fmt::Debug {
+    fn push(&self, path: PathBuf) -> Result<(), Error>;
+    /// Drop a file once it's been handled, it stays queued until then
+    fn remove(&self, path: &Path) -> Result<(), Error>;
+    /// Everything queued, left in place
+    fn paths(&self) -> BTreeSet<PathBuf>;
+    fn is_empty(&self) -> bool;
+    fn len(&self) -> usize;
+}
+
+/// Queue backend choice in the config, e.g. `{type: disk, path: /var/lib/s3sync/queue}`
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum QueueSettings {
+    /// Lost on restart
+    #[default]
+    Memory,
+    /// Newline-delimited JSON file of paths, reloaded on restart
+    Disk { path: PathBuf },
+    /// An SQS queue with a message per path, e.g. for a host without a disk that outlives
+    /// it, reached with the agent's credentials and region. Each agent needs a queue of its
+    /// own.
+    Sqs { queue_url: String },
+}
+
+impl QueueSettings {
+    /// Open the queue for `agent`, whose AWS config an SQS queue uses
+    #[cfg_attr(not(feature = "sqs"), allow(unused_variables))]
+    pub fn open(&self, agent: &Agent) -> Result<Deferred, Error> {
+        Ok(match self {
+            Self::Memory => Deferred::default(),
+            Self::Disk { path } => Deferred::new(Arc::new(DiskQueue::open(path)?)),
+            #[cfg(feature = "sqs")]
+            Self::Sqs { queue_url } => {
+                let agent = agent.clone();
+                let sdk_config = async move { agent.sdk_config().await };
+                Deferred::new(Arc::new(SqsQueue::open(queue_url.clone(), sdk_config)?))
+            }
+            #[cfg(not(feature = "sqs"))]
+            Self::Sqs { .. } => return Err(Error::Disabled("sqs")),
+        })
+    }
+}
+
+/// Files held back until the agent's schedule opens
+#[derive(Debug, Clone)]
+pub struct Deferred {
+    queue: Arc<dyn Queue>,
+    batch: Arc<Mutex<Batch>>,
+}
+
+/// Files taken off the queue for the pass over it under way, and which files to upload
+/// ahead of the rest whether they've been taken yet or not
+#[derive(Debug, Default)]
+struct Batch {
+    pending: BTreeSet<PathBuf>,
+    prioritized: BTreeSet<PathBuf>,
+    held_until: Option<Instant>,
+    attempts: HashMap<PathBuf, u32>,
+}
+
+impl Default for Deferred {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryQueue::default()))
+    }
+}
+
+impl Deferred {
+    pub fn new(queue: Arc<dyn Queue>) -> Self {
+        Self {
+            queue,
+            batch: Arc::default(),
+        }
+    }
+    pub fn insert(&self, path: PathBuf) -> Result<(), Error> {
+        self.queue.push(path)
+    }
+    /// Start a pass over everything queued, returning what's in it. The files are then
+    /// handed out one at a time by [`Self::next`], and stay queued until [`Self::done`].
+    #[allow(clippy::must_use_candidate)]
+    pub fn take(&self) -> BTreeSet<PathBuf> {
+        let taken = self.queue.paths();
+        self.batch.lock().unwrap().pending.clone_from(&taken);
+        taken
+    }
+    /// Queue whatever `other` holds that this doesn't, e.g. from before a config reload
+    pub fn adopt(&self, other: &Self) -> Result<(), Error> {
+        if Arc::ptr_eq(&self.queue, &other.queue) {
+            return Ok(());
+        }
+        let queued = self.queue.paths();
+        for path in other.queue.paths() {
+            if !queued.contains(&path) {
+                self.queue.push(path)?;
+            }
+        }
+        Ok(())
+    }
+    /// Take a file of the pass off the queue, once it's uploaded or no longer needs to be
+    pub fn done(&self, path: &Path) -> Result<(), Error> {
+        self.batch.lock().unwrap().attempts.remove(path);
+        self.queue.remove(path)
+    }
+    /// Count another failed attempt at a file, returning how many there have been since
+    /// it was queued, or since it was last reloaded from disk
+    #[must_use]
+    pub fn failed(&self, path: &Path) -> u32 {
+        let mut batch = self.batch.lock().unwrap();
+        let attempts = batch.attempts.entry(path.to_path_buf()).or_default();
+        *attempts += 1;
+        let attempts = *attempts;
+        drop(batch);
+        attempts
+    }
+    /// The next file of the pass, prioritized ones first, otherwise in path order
+    #[must_use]
+    pub fn next(&self) -> Option<PathBuf> {
+        let mut batch = self.batch.lock().unwrap();
+        let prioritized = batch
+            .prioritized
+            .iter()
+            .find(|path| batch.pending.contains(*path))
+            .cloned();
+        let next = if let Some(path) = prioritized {
+            batch.prioritized.remove(&path);
+            batch.pending.take(&path)
+        } else {
+            batch.pending.pop_first()
+        };
+        drop(batch);
+        next
+    }
+    /// Move queued files whose full local path matches `glob` ahead of the rest, including
+    /// those of a pass already under way, returning how many there were
+    #[must_use]
+    pub fn prioritize(&self, glob: &GlobMatcher) -> usize {
+        let queued = self.queue.paths();
+        let mut batch = self.batch.lock().unwrap();
+        let matching: Vec<_> = queued
+            .iter()
+            .chain(&batch.pending)
+            .filter(|path| glob.is_match(path))
+            .cloned()
+            .collect();
+        let count = matching.len();
+        batch.prioritized.extend(matching);
+        count
+    }
+    /// Leave the queue alone for `delay`, e.g. after files in it failed
+    pub fn hold_off(&self, delay: Duration) {
+        self.batch.lock().unwrap().held_until = Some(Instant::now() + delay);
+    }
+    /// Whether it's still being left alone after [`Self::hold_off`]
+    #[must_use]
+    pub fn held(&self) -> bool {
+        self.batch
+            .lock()
+            .unwrap()
+            .held_until
+            .is_some_and(|until| Instant::now() < until)
+    }
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryQueue(Mutex<BTreeSet<PathBuf>>);
+
+impl Queue for MemoryQueue {
+    fn push(&self, path: PathBuf) -> Result<(), Error> {
+        self.0.lock().unwrap().insert(path);
+        Ok(())
+    }
+    fn remove(&self, path: &Path) -> Result<(), Error> {
+        self.0.lock().unwrap().remove(path);
+        Ok(())
+    }
+    fn paths(&self) -> BTreeSet<PathBuf> {
+        self.0.lock().unwrap().clone()
+    }
+    fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+}
+
+/// Appends each path to a file as it's queued and again once it's handled, so queued
+/// files survive a restart, or a crash in the middle of a pass
+#[derive(Debug)]
+pub struct DiskQueue {
+    file: PathBuf,
+    queued: Mutex<BTreeSet<PathBuf>>,
+}
+
+/// A line of the queue file
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Entry<'a> {
+    Queued(Cow<'a, Path>),
+    Done { done: Cow<'a, Path> },
+}
+
+impl DiskQueue {
+    /// Pick up whatever an earlier run left queued in `file`, compacting it to just that
+    pub fn open(file: &Path) -> Result<Self, Error> {
+        let mut queued = BTreeSet::new();
+        let mut handled = false;
+        match std::fs::File::open(file) {
+            Ok(existing) => {
+                for line in BufReader::new(existing).lines() {
+                    match serde_json::from_str(&line?)? {
+                        Entry::Queued(path) => {
+                            queued.insert(path.into_owned());
+                        }
+                        Entry::Done { done } => {
+                            queued.remove(done.as_ref());
+                            handled = true;
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        if handled {
+            let compacted = file.with_extension("compacting");
+            let mut lines = String::new();
+            for path in &queued {
+                lines += &serde_json::to_string(&Entry::Queued(path.into()))?;
+                lines.push('\n');
+            }
+            std::fs::write(&compacted, lines)?;
+            std::fs::rename(&compacted, file)?;
+        }
+        if !queued.is_empty() {
+            tracing::info!("Restored {} queued files", queued.len());
+        }
+        Ok(Self {
+            file: file.to_path_buf(),
+            queued: Mutex::new(queued),
+        })
+    }
+
+    fn append(&self, entry: &Entry) -> Result<(), Error> {
+        let line = serde_json::to_string(entry)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file)?;
+        writeln!(file, "{line}")?;
+        Ok(())
+    }
+}
+
+impl Queue for DiskQueue {
+    fn push(&self, path: PathBuf) -> Result<(), Error> {
+        let mut queued = self.queued.lock().unwrap();
+        if queued.contains(&path) {
+            return Ok(());
+        }
+        self.append(&Entry::Queued(path.as_path().into()))?;
+        queued.insert(path);
+        drop(queued);
+        Ok(())
+    }
+    fn remove(&self, path: &Path) -> Result<(), Error> {
+        let mut queued = self.queued.lock().unwrap();
+        if !queued.remove(path) {
+            return Ok(());
+        }
+        if queued.is_empty() {
+            std::fs::write(&self.file, "")?;
+        } else {
+            self.append(&Entry::Done { done: path.into() })?;
+        }
+        drop(queued);
+        Ok(())
+    }
+    fn paths(&self) -> BTreeSet<PathBuf> {
+        self.queued.lock().unwrap().clone()
+    }
+    fn is_empty(&self) -> bool {
+        self.queued.lock().unwrap().is_empty()
+    }
+    fn len(&self) -> usize {
+        self.queued.lock().unwrap().len()
+    }
+}
+
+/// Sends a message to an SQS queue for each path queued and deletes it once the path is
+/// handled, left to a background task so queueing doesn't wait on SQS. Messages are
+/// received over and over while they're queued, which is how an earlier run's are picked
+/// up, and keeps the latest receipt handle at hand for deleting them.
+#[cfg(feature = "sqs")]
+#[derive(Debug)]
+pub struct SqsQueue {
+    queued: Arc<Mutex<BTreeSet<PathBuf>>>,
+    requests: mpsc::UnboundedSender<SqsRequest>,
+}
+
+#[cfg(feature = "sqs")]
+#[derive(Debug)]
+enum SqsRequest {
+    Send(PathBuf),
+    Delete(PathBuf),
+}
+
+/// A path's message, as far as it's known from sending or receiving it
+#[cfg(feature = "sqs")]
+#[derive(Debug, Default)]
+struct SqsMessage {
+    id: Option<String>,
+    receipt_handle: Option<String>,
+}
+
+#[cfg(feature = "sqs")]
+impl SqsQueue {
+    /// Start the task talking to the queue at `queue_url`, which needs a Tokio runtime
+    pub fn open(
+        queue_url: String,
+        sdk_config: impl Future<Output = SdkConfig> + Send + 'static,
+    ) -> Result<Self, Error> {
+        let runtime = tokio::runtime::Handle::try_current()
+            .map_err(|_| Error::InvalidSetting("an sqs queue needs a Tokio runtime"))?;
+        let queued = Arc::<Mutex<BTreeSet<PathBuf>>>::default();
+        let (requests, pending) = mpsc::unbounded_channel();
+        let worker_queued = queued.clone();
+        runtime.spawn(async move {
+            let worker = SqsWorker {
+                client: sqs::Client::new(&sdk_config.await),
+                queue_url,
+                queued: worker_queued,
+                messages: HashMap::new(),
+                handled: HashSet::new(),
+            };
+            worker.run(pending).await;
+        });
+        Ok(Self { queued, requests })
+    }
+}
+
+#[cfg(feature = "sqs")]
+impl Queue for SqsQueue {
+    fn push(&self, path: PathBuf) -> Result<(), Error> {
+        if self.queued.lock().unwrap().insert(path.clone()) {
+            // Only closed once this is dropped
+            let _ = self.requests.send(SqsRequest::Send(path));
+        }
+        Ok(())
+    }
+    fn remove(&self, path: &Path) -> Result<(), Error> {
+        if self.queued.lock().unwrap().remove(path) {
+            let _ = self.requests.send(SqsRequest::Delete(path.to_path_buf()));
+        }
+        Ok(())
+    }
+    fn paths(&self) -> BTreeSet<PathBuf> {
+        self.queued.lock().unwrap().clone()
+    }
+    fn is_empty(&self) -> bool {
+        self.queued.lock().unwrap().is_empty()
+    }
+    fn len(&self) -> usize {
+        self.queued.lock().unwrap().len()
+    }
+}
+
+/// The task behind an [`SqsQueue`], handling its requests in order along with the
+/// messages received meanwhile
+#[cfg(feature = "sqs")]
+struct SqsWorker {
+    client: sqs::Client,
+    queue_url: String,
+    queued: Arc<Mutex<BTreeSet<PathBuf>>>,
+    messages: HashMap<PathBuf, SqsMessage>,
+    /// Ids of messages whose paths were handled before they were received, to delete once
+    /// they are
+    handled: HashSet<String>,
+}
+
+#[cfg(feature = "sqs")]
+impl SqsWorker {
+    async fn run(mut self, mut requests: mpsc::UnboundedReceiver<SqsRequest>) {
+        let (received_tx, mut received) = mpsc::channel(1);
+        tokio::spawn(receive(
+            self.client.clone(),
+            self.queue_url.clone(),
+            received_tx,
+        ));
+        loop {
+            tokio::select! {
+                request = requests.recv() => match request {
+                    Some(SqsRequest::Send(path)) => self.send(path).await,
+                    Some(SqsRequest::Delete(path)) => self.delete(&path).await,
+                    None => break,
+                },
+                Some(messages) = received.recv() => {
+                    for message in messages {
+                        self.received(message).await;
+                    }
+                }
+            }
+        }
+        // Left for whoever opens the queue next, e.g. after a config reload
+        let receipt_handles = self
+            .messages
+            .into_values()
+            .filter_map(|message| message.receipt_handle);
+        release(&self.client, &self.queue_url, receipt_handles).await;
+    }
+
+    async fn send(&mut self, path: PathBuf) {
+        // Already received from an earlier run
+        if self.messages.contains_key(&path) {
+            return;
+        }
+        let Some(body) = path.to_str() else {
+            tracing::warn!(?path, "Not queueing a non-unicode path in SQS");
+            return;
+        };
+        match self
+            .client
+            .send_message()
+            .queue_url(&self.queue_url)
+            .message_body(body)
+            .send()
+            .await
+        {
+            Ok(output) => {
+                let message = SqsMessage {
+                    id: output.message_id,
+                    receipt_handle: None,
+                };
+                self.messages.insert(path, message);
+            }
+            Err(e) => {
+                let e = sqs::Error::from(e);
+                tracing::warn!(?path, "Unable to queue in SQS, only queued in memory: {e}");
+            }
+        }
+    }
+
+    async fn delete(&mut self, path: &Path) {
+        let Some(message) = self.messages.remove(path) else {
+            return;
+        };
+        match (message.receipt_handle, message.id) {
+            (Some(receipt_handle), _) => self.delete_message(receipt_handle).await,
+            (None, Some(id)) => {
+                self.handled.insert(id);
+            }
+            (None, None) => {}
+        }
+    }
+
+    async fn received(&mut self, message: sqs::types::Message) {
+        let (Some(id), Some(receipt_handle), Some(body)) =
+            (message.message_id, message.receipt_handle, message.body)
+        else {
+            return;
+        };
+        if self.handled.remove(&id) {
+            self.delete_message(receipt_handle).await;
+            return;
+        }
+        let path = PathBuf::from(body);
+        match self.messages.get_mut(&path) {
+            Some(known) if known.id.as_ref() == Some(&id) => {
+                known.receipt_handle = Some(receipt_handle);
+            }
+            // Queued again since, e.g. when a reload adopted it before this came in
+            Some(_) => self.delete_message(receipt_handle).await,
+            None => {
+                self.queued.lock().unwrap().insert(path.clone());
+                let message = SqsMessage {
+                    id: Some(id),
+                    receipt_handle: Some(receipt_handle),
+                };
+                self.messages.insert(path, message);
+            }
+        }
+    }
+
+    async fn delete_message(&self, receipt_handle: String) {
+        if let Err(e) = self
+            .client
+            .delete_message()
+            .queue_url(&self.queue_url)
+            .receipt_handle(receipt_handle)
+            .send()
+            .await
+        {
+            let e = sqs::Error::from(e);
+            tracing::warn!("Unable to delete an SQS message, it'll be received again: {e}");
+        }
+    }
+}
+
+/// Long-poll the queue for messages until the worker stops taking them
+#[cfg(feature = "sqs")]
+async fn receive(
+    client: sqs::Client,
+    queue_url: String,
+    received: mpsc::Sender<Vec<sqs::types::Message>>,
+) {
+    while !received.is_closed() {
+        let messages = match client
+            .receive_message()
+            .queue_url(&queue_url)
+            .max_number_of_messages(10)
+            .visibility_timeout(SQS_VISIBILITY_TIMEOUT)
+            .wait_time_seconds(20)
+            .send()
+            .await
+        {
+            Ok(output) => output.messages.unwrap_or_default(),
+            Err(e) => {
+                let e = sqs::Error::from(e);
+                tracing::warn!("Unable to receive from SQS: {e}");
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+        if messages.is_empty() {
+            continue;
+        }
+        if let Err(mpsc::error::SendError(messages)) = received.send(messages).await {
+            let receipt_handles = messages
+                .into_iter()
+                .filter_map(|message| message.receipt_handle);
+            release(&client, &queue_url, receipt_handles).await;
+            return;
+        }
+    }
+}
+
+/// Make received messages visible again right away
+#[cfg(feature = "sqs")]
+async fn release(
+    client: &sqs::Client,
+    queue_url: &str,
+    receipt_handles: impl Iterator<Item = String>,
+) {
+    for receipt_handle in receipt_handles {
+        if let Err(e) = client
+            .change_message_visibility()
+            .queue_url(queue_url)
+            .receipt_handle(receipt_handle)
+            .visibility_timeout(0)
+            .send()
+            .await
+        {
+            let e = sqs::Error::from(e);
+            tracing::warn!("Unable to release an SQS message: {e}");
+        }
+    }
+}
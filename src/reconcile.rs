@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use super::state::Reconciled;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_hours(1);
+const DEFAULT_FULL_EVERY: Duration = Duration::from_hours(24);
+
+/// Periodic pass over the watched path uploading anything the watcher missed, which needs
+/// a state database to tell what's already uploaded
+#[derive(Deserialize, Debug, Clone)]
+pub struct ReconcileSettings {
+    /// Time between passes, an hour by default
+    #[serde(default, with = "humantime_serde")]
+    interval: Option<Duration>,
+    /// Time between full passes, a day by default. Passes in between only look at files
+    /// modified since the previous one started, so files moved in with an older
+    /// modification time wait for the next full pass.
+    #[serde(default, with = "humantime_serde")]
+    full_every: Option<Duration>,
+}
+
+/// Which files a reconcile pass looks at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    Full,
+    /// Files modified after this time
+    Since(DateTime<Utc>),
+}
+
+impl ReconcileSettings {
+    /// The pass to run now, if one is due after `last`
+    pub fn due(&self, last: Option<&Reconciled>, now: DateTime<Utc>) -> Option<Pass> {
+        let Some(last) = last else {
+            return Some(Pass::Full);
+        };
+        let elapsed = |since: DateTime<Utc>| (now - since).to_std().unwrap_or_default();
+        if elapsed(last.completed) < self.interval.unwrap_or(DEFAULT_INTERVAL) {
+            None
+        } else if elapsed(last.last_full) >= self.full_every.unwrap_or(DEFAULT_FULL_EVERY) {
+            Some(Pass::Full)
+        } else {
+            Some(Pass::Since(last.started))
+        }
+    }
+}
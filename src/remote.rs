@@ -0,0 +1,143 @@
+use aws_sdk_s3 as s3;
+use tokio::{sync::mpsc, task::JoinSet};
+use tracing::Instrument;
+
+use super::Error;
+
+/// Number of listed objects buffered ahead of the consumer
+const LIST_CHANNEL_CAPACITY: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct RemoteObject {
+    pub key: String,
+    pub size: i64,
+    pub e_tag: Option<String>,
+}
+
+impl From<&s3::types::Object> for RemoteObject {
+    fn from(value: &s3::types::Object) -> Self {
+        Self {
+            key: value.key().unwrap_or_default().to_string(),
+            size: value.size().unwrap_or_default(),
+            e_tag: value.e_tag().map(String::from),
+        }
+    }
+}
+
+/// Paginated `ListObjectsV2` scan of everything under a prefix.
+///
+/// Objects are streamed through a bounded channel, so only a page or two is held in
+/// memory regardless of bucket size. With a parallelism above one, the first level of
+/// "directories" under the prefix is discovered and each is listed concurrently.
+#[derive(Debug, Clone)]
+pub struct Lister {
+    client: s3::Client,
+    bucket: String,
+    prefix: String,
+    parallelism: usize,
+}
+
+type Sender = mpsc::Sender<Result<RemoteObject, Error>>;
+
+impl Lister {
+    pub fn new(client: s3::Client, bucket: String, prefix: Option<String>) -> Self {
+        Self {
+            client,
+            bucket,
+            prefix: prefix.unwrap_or_default(),
+            parallelism: 1,
+        }
+    }
+
+    pub fn parallelism(mut self, parallelism: usize) -> Self {
+        self.parallelism = parallelism.max(1);
+        self
+    }
+
+    pub fn stream(self) -> mpsc::Receiver<Result<RemoteObject, Error>> {
+        let (tx, rx) = mpsc::channel(LIST_CHANNEL_CAPACITY);
+        tokio::spawn(
+            async move {
+                if let Err(e) = self.run(&tx).await {
+                    let _ = tx.send(Err(e)).await;
+                }
+            }
+            .in_current_span(),
+        );
+        rx
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn run(&self, tx: &Sender) -> Result<(), Error> {
+        if self.parallelism == 1 {
+            return list_prefix(
+                self.client.clone(),
+                self.bucket.clone(),
+                self.prefix.clone(),
+                tx.clone(),
+            )
+            .await;
+        }
+        let mut shards = Vec::new();
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.bucket)
+            .prefix(&self.prefix)
+            .delimiter("/")
+            .into_paginator()
+            .send();
+        while let Some(page) = pages.try_next().await? {
+            for object in page.contents() {
+                if tx.send(Ok(object.into())).await.is_err() {
+                    return Ok(());
+                }
+            }
+            shards.extend(
+                page.common_prefixes()
+                    .iter()
+                    .filter_map(|prefix| prefix.prefix().map(String::from)),
+            );
+        }
+        tracing::debug!("Listing {} shard(s)", shards.len());
+        let mut tasks = JoinSet::new();
+        for shard in shards {
+            if tasks.len() >= self.parallelism {
+                if let Some(result) = tasks.join_next().await {
+                    result??;
+                }
+            }
+            tasks.spawn(
+                list_prefix(self.client.clone(), self.bucket.clone(), shard, tx.clone())
+                    .in_current_span(),
+            );
+        }
+        while let Some(result) = tasks.join_next().await {
+            result??;
+        }
+        Ok(())
+    }
+}
+
+async fn list_prefix(
+    client: s3::Client,
+    bucket: String,
+    prefix: String,
+    tx: Sender,
+) -> Result<(), Error> {
+    let mut pages = client
+        .list_objects_v2()
+        .bucket(bucket)
+        .prefix(prefix)
+        .into_paginator()
+        .send();
+    while let Some(page) = pages.try_next().await? {
+        for object in page.contents() {
+            if tx.send(Ok(object.into())).await.is_err() {
+                // Consumer hung up, nothing left to do
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
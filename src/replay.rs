@@ -0,0 +1,38 @@
+use std::{collections::BTreeMap, io::BufRead, path::PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use super::Error;
+
+/// An `uploaded` event from the log, the only kind replayed
+#[derive(Deserialize, Debug)]
+pub struct Upload {
+    timestamp: DateTime<Utc>,
+    pub agent: String,
+    event: String,
+    pub bucket: String,
+    pub key: String,
+    /// Missing from logs written before paths were recorded
+    pub path: Option<PathBuf>,
+}
+
+/// Uploads recorded since `since`, the last one for each object when it was uploaded more
+/// than once. Lines that aren't events, e.g. from a log that also holds tracing output,
+/// are skipped.
+pub fn uploads(
+    log: impl BufRead,
+    since: Option<DateTime<Utc>>,
+) -> Result<BTreeMap<(String, String), Upload>, Error> {
+    let mut uploads = BTreeMap::new();
+    for line in log.lines() {
+        let Ok(upload) = serde_json::from_str::<Upload>(&line?) else {
+            continue;
+        };
+        if upload.event != "uploaded" || since.is_some_and(|since| upload.timestamp < since) {
+            continue;
+        }
+        uploads.insert((upload.bucket.clone(), upload.key.clone()), upload);
+    }
+    Ok(uploads)
+}
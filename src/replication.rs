@@ -0,0 +1,371 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use aws_sdk_s3 as s3;
+use s3::types::{CompletedMultipartUpload, CompletedPart, MetadataDirective};
+use serde::Deserialize;
+
+use super::{
+    abort_multipart_upload, metrics, multipart,
+    output::{FileTimes, Lifecycle},
+    remote::RemoteObject,
+    Agent, Error, COPY_SOURCE, MAX_PUT_OBJECT_SIZE,
+};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_mins(1);
+
+/// Metadata recording the source `ETag` a destination object was copied from
+const SOURCE_ETAG_METADATA: &str = "source-etag";
+
+/// Smallest part of a multipart copy, keeping a 5 TiB object within the part limit
+const COPY_PART_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Agent copying new and changed objects from one bucket to another by polling the
+/// source. Each side is configured as an agent without a watcher, for its bucket,
+/// prefix and credentials, with the source's filters and the destination's shards
+/// mapping keys as they would for local files.
+#[derive(Deserialize, Clone)]
+pub struct Replication {
+    name: String,
+    source: Agent,
+    destination: Agent,
+    /// Time between polls of the source, a minute by default
+    #[serde(default, with = "humantime_serde")]
+    interval: Option<Duration>,
+    /// Source `ETag` each destination key was last copied from, as read from its
+    /// metadata or recorded by the copy, for objects whose `ETag` changes in the copy,
+    /// e.g. multipart or KMS-encrypted ones
+    #[serde(skip)]
+    copied: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl std::fmt::Debug for Replication {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Replication")
+            .field("name", &self.name)
+            .field("source", &self.source.bucket())
+            .field("destination", &self.destination.bucket())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Replication {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn span(&self) -> tracing::Span {
+        tracing::info_span!("replication", replication = self.name())
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval.unwrap_or(DEFAULT_INTERVAL)
+    }
+
+    /// The source and destination, for the checks agents get when a config is loaded
+    pub const fn sides(&self) -> [&Agent; 2] {
+        [&self.source, &self.destination]
+    }
+
+    /// Whether a server-side copy can reach both buckets with one set of credentials
+    fn same_account(&self) -> bool {
+        let (source, destination) = (&self.source, &self.destination);
+        source.profile_name == destination.profile_name
+            && source.region_name == destination.region_name
+            && source.endpoint_url == destination.endpoint_url
+            && source.role_arn == destination.role_arn
+            && source.anonymous == destination.anonymous
+    }
+
+    /// One pass over the source, copying objects missing at the destination or that
+    /// differ from what was last copied there
+    #[tracing::instrument(skip_all)]
+    pub async fn run_once(&self) -> Result<(), Error> {
+        let source = self.source.client().await;
+        let destination = self.destination.client().await;
+        let mut existing = HashMap::new();
+        let mut objects = self.destination.lister(destination.clone())?.stream();
+        while let Some(object) = objects.recv().await {
+            let object = object?;
+            existing.insert(object.key.clone(), object);
+        }
+        let (mut copied, mut failed) = (0, 0);
+        let mut objects = self.source.lister(source.clone())?.stream();
+        while let Some(object) = objects.recv().await {
+            let object = object?;
+            let Some(key) = self
+                .source
+                .original_key(&object.key)
+                .filter(|key| self.source.matches(key))
+            else {
+                continue;
+            };
+            let destination_key = self.destination.remote_key(key);
+            if self
+                .up_to_date(&destination, &object, existing.get(&destination_key))
+                .await
+            {
+                continue;
+            }
+            let started = std::time::Instant::now();
+            if let Err(e) = self
+                .copy(&source, &destination, &object, &destination_key)
+                .await
+            {
+                tracing::warn!(key = object.key, "Replication failed: {e}");
+                metrics::record_failure(self.name());
+                failed += 1;
+                continue;
+            }
+            let bytes = u64::try_from(object.size).unwrap_or_default();
+            metrics::record_upload(self.name(), bytes, started.elapsed());
+            Lifecycle::Uploaded {
+                path: None,
+                bucket: self.destination.bucket(),
+                key: &destination_key,
+                bytes,
+                times: FileTimes::default(),
+            }
+            .emit(self.name());
+            copied += 1;
+        }
+        tracing::debug!("Replicated {copied} objects, {failed} failed");
+        Ok(())
+    }
+
+    /// Whether the destination object is the same size as the source and has its `ETag`,
+    /// or was copied from an object with that `ETag`
+    async fn up_to_date(
+        &self,
+        destination: &s3::Client,
+        object: &RemoteObject,
+        existing: Option<&RemoteObject>,
+    ) -> bool {
+        let Some(existing) = existing.filter(|existing| existing.size == object.size) else {
+            return false;
+        };
+        if existing.e_tag == object.e_tag {
+            return true;
+        }
+        let Some(e_tag) = &object.e_tag else {
+            return false;
+        };
+        let recorded = self.copied.lock().unwrap().get(&existing.key).cloned();
+        let recorded = match recorded {
+            Some(recorded) => Some(recorded),
+            None => destination
+                .head_object()
+                .bucket(self.destination.bucket())
+                .key(&existing.key)
+                .send()
+                .await
+                .ok()
+                .and_then(|head| head.metadata?.remove(SOURCE_ETAG_METADATA))
+                .inspect(|recorded| {
+                    self.copied
+                        .lock()
+                        .unwrap()
+                        .insert(existing.key.clone(), recorded.clone());
+                }),
+        };
+        recorded.as_ref() == Some(e_tag)
+    }
+
+    /// Server-side copy when one set of credentials covers both buckets, otherwise stream
+    /// the object through this host, recording the source `ETag` in the copy's metadata
+    async fn copy(
+        &self,
+        source: &s3::Client,
+        destination: &s3::Client,
+        object: &RemoteObject,
+        destination_key: &str,
+    ) -> Result<(), Error> {
+        let head = source
+            .head_object()
+            .bucket(self.source.bucket())
+            .key(&object.key)
+            .send()
+            .await?;
+        let mut metadata = head.metadata.unwrap_or_default();
+        if let Some(e_tag) = &object.e_tag {
+            metadata.insert(SOURCE_ETAG_METADATA.to_string(), e_tag.clone());
+        }
+        if u64::try_from(object.size).unwrap_or_default() > MAX_PUT_OBJECT_SIZE {
+            self.copy_multipart(
+                source,
+                destination,
+                object,
+                destination_key,
+                head.content_type,
+                metadata,
+            )
+            .await?;
+        } else if self.same_account() {
+            destination
+                .copy_object()
+                .bucket(self.destination.bucket())
+                .key(destination_key)
+                .copy_source(self.copy_source(&object.key))
+                .metadata_directive(MetadataDirective::Replace)
+                .set_content_type(head.content_type)
+                .set_metadata(Some(metadata))
+                .send()
+                .await?;
+        } else {
+            let body = source
+                .get_object()
+                .bucket(self.source.bucket())
+                .key(&object.key)
+                .send()
+                .await?;
+            destination
+                .put_object()
+                .bucket(self.destination.bucket())
+                .key(destination_key)
+                .set_content_length(body.content_length())
+                .set_content_type(head.content_type)
+                .set_metadata(Some(metadata))
+                .body(body.body)
+                .send()
+                .await?;
+        }
+        if let Some(e_tag) = &object.e_tag {
+            self.copied
+                .lock()
+                .unwrap()
+                .insert(destination_key.to_string(), e_tag.clone());
+        }
+        tracing::info!("Object replicated");
+        Ok(())
+    }
+
+    /// Copy in parts, for objects larger than a single copy or `PutObject` can create,
+    /// aborting the upload if any part fails
+    async fn copy_multipart(
+        &self,
+        source: &s3::Client,
+        destination: &s3::Client,
+        object: &RemoteObject,
+        destination_key: &str,
+        content_type: Option<String>,
+        metadata: HashMap<String, String>,
+    ) -> Result<(), Error> {
+        let upload_id = destination
+            .create_multipart_upload()
+            .bucket(self.destination.bucket())
+            .key(destination_key)
+            .set_content_type(content_type)
+            .set_metadata(Some(metadata))
+            .send()
+            .await?
+            .upload_id
+            .unwrap_or_default();
+        let size = u64::try_from(object.size).unwrap_or_default();
+        let result = self
+            .copy_parts(
+                source,
+                destination,
+                &object.key,
+                destination_key,
+                size,
+                &upload_id,
+            )
+            .await;
+        if result.is_err() {
+            abort_multipart_upload(
+                destination,
+                self.destination.bucket(),
+                destination_key,
+                &upload_id,
+            )
+            .await;
+        }
+        result
+    }
+
+    async fn copy_parts(
+        &self,
+        source: &s3::Client,
+        destination: &s3::Client,
+        key: &str,
+        destination_key: &str,
+        size: u64,
+        upload_id: &str,
+    ) -> Result<(), Error> {
+        let part_size = COPY_PART_SIZE.max(size.div_ceil(multipart::MAX_PARTS));
+        let mut parts = Vec::new();
+        let mut offset = 0;
+        let mut part_number = 1;
+        while offset < size {
+            let length = part_size.min(size - offset);
+            let range = format!("bytes={offset}-{}", offset + length - 1);
+            let e_tag = if self.same_account() {
+                destination
+                    .upload_part_copy()
+                    .bucket(self.destination.bucket())
+                    .key(destination_key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .copy_source(self.copy_source(key))
+                    .copy_source_range(range)
+                    .send()
+                    .await?
+                    .copy_part_result
+                    .and_then(|part| part.e_tag)
+            } else {
+                let body = source
+                    .get_object()
+                    .bucket(self.source.bucket())
+                    .key(key)
+                    .range(range)
+                    .send()
+                    .await?;
+                destination
+                    .upload_part()
+                    .bucket(self.destination.bucket())
+                    .key(destination_key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .set_content_length(body.content_length())
+                    .body(body.body)
+                    .send()
+                    .await?
+                    .e_tag
+            };
+            tracing::debug!("Copied part {part_number}");
+            parts.push(
+                CompletedPart::builder()
+                    .part_number(part_number)
+                    .set_e_tag(e_tag)
+                    .build(),
+            );
+            offset += length;
+            part_number += 1;
+        }
+        destination
+            .complete_multipart_upload()
+            .bucket(self.destination.bucket())
+            .key(destination_key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await?;
+        Ok(())
+    }
+
+    /// `CopyObject` source of a key in the source bucket
+    fn copy_source(&self, key: &str) -> String {
+        format!(
+            "{}/{}",
+            self.source.bucket(),
+            percent_encoding::utf8_percent_encode(key, COPY_SOURCE)
+        )
+    }
+}
@@ -0,0 +1,47 @@
+use std::{path::PathBuf, time::Duration};
+
+use serde::Deserialize;
+
+use super::queue::QueueSettings;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+
+/// Files whose upload failed against S3, e.g. while the host was offline, queued and tried
+/// again in the background. Use a `disk` queue to keep them across restarts.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct RetrySettings {
+    /// How often the queue is retried, 30 seconds by default
+    #[serde(default, with = "humantime_serde")]
+    interval: Option<Duration>,
+    /// Times a file is retried before it's given up on and recorded in the agent's
+    /// `dead_letter` file, 10 by default
+    max_attempts: Option<u32>,
+    /// Where the failed files wait, in memory by default
+    #[serde(default)]
+    queue: QueueSettings,
+}
+
+impl RetrySettings {
+    /// Settings from the `--retry-interval` and `--retry-queue` flags, retrying nothing
+    /// unless one of them is set
+    pub fn from_flags(interval: Option<Duration>, queue: Option<PathBuf>) -> Option<Self> {
+        (interval.is_some() || queue.is_some()).then(|| Self {
+            interval,
+            max_attempts: None,
+            queue: queue.map_or(QueueSettings::Memory, |path| QueueSettings::Disk { path }),
+        })
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval.unwrap_or(DEFAULT_INTERVAL)
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS)
+    }
+
+    pub const fn queue(&self) -> &QueueSettings {
+        &self.queue
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use tokio::sync::mpsc;
+
+use super::{Error, UploadOrder};
+
+/// Number of scanned paths buffered ahead of the consumer
+const SCAN_CHANNEL_CAPACITY: usize = 1000;
+
+/// Walk the regular files under `root`, down to `max_depth`, reading directories in parallel
+/// on a rayon pool.
+///
+/// Paths are streamed through a bounded channel as they're found, so trees with millions of
+/// files are never held in memory at once. Dropping the receiver stops the walk. Symlinks
+/// aren't followed and hidden files are included.
+pub fn files(root: &Path, max_depth: usize) -> mpsc::Receiver<Result<PathBuf, Error>> {
+    let (tx, rx) = mpsc::channel(SCAN_CHANNEL_CAPACITY);
+    let walk = jwalk::WalkDir::new(root)
+        .skip_hidden(false)
+        .follow_links(false)
+        .max_depth(max_depth);
+    tokio::task::spawn_blocking(move || {
+        for entry in walk {
+            let file = match entry {
+                Ok(entry) if entry.file_type().is_file() => Ok(entry.path()),
+                Ok(_) => continue,
+                Err(e) => Err(e.into()),
+            };
+            if tx.blocking_send(file).is_err() {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+/// Directories whose files are already being watched, so an event for one of them, e.g.
+/// its modification time bumped by a new file under the `poll` backend, isn't taken for a
+/// directory moved into the tree
+#[derive(Debug, Clone, Default)]
+pub struct Directories(Arc<Mutex<HashSet<PathBuf>>>);
+
+impl Directories {
+    /// Record `root` and every directory under it, as the watch starts
+    pub fn index(&self, root: &Path) {
+        let found = jwalk::WalkDir::new(root)
+            .skip_hidden(false)
+            .follow_links(false)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_dir())
+            .map(|entry| entry.path());
+        self.0.lock().unwrap().extend(found);
+    }
+
+    /// Record `dir`, returning whether it wasn't known yet
+    pub fn discovered(&self, dir: &Path) -> bool {
+        self.0.lock().unwrap().insert(dir.to_path_buf())
+    }
+
+    /// Forget `path` and everything under it once it's removed, so a directory later
+    /// moved back in its place is walked again
+    pub fn forget(&self, path: &Path) {
+        self.0.lock().unwrap().retain(|dir| !dir.starts_with(path));
+    }
+}
+
+/// Scanned files, either as they're found or all at once in a chosen order
+pub enum Files {
+    Streamed(mpsc::Receiver<Result<PathBuf, Error>>),
+    Sorted(std::vec::IntoIter<PathBuf>),
+}
+
+impl Files {
+    pub async fn next(&mut self) -> Option<Result<PathBuf, Error>> {
+        match self {
+            Self::Streamed(files) => files.recv().await,
+            Self::Sorted(files) => files.next().map(Ok),
+        }
+    }
+}
+
+/// [`files`] streamed as found, or collected and sorted first when there's an `order`.
+/// Files removed before they could be sorted are left out.
+pub async fn files_in_order(
+    root: &Path,
+    max_depth: usize,
+    order: Option<UploadOrder>,
+) -> Result<Files, Error> {
+    let mut scanned = files(root, max_depth);
+    let Some(order) = order else {
+        return Ok(Files::Streamed(scanned));
+    };
+    let mut found = Vec::new();
+    while let Some(path) = scanned.recv().await {
+        found.push(path?);
+    }
+    Ok(Files::Sorted(in_order(found, Some(order)).into_iter()))
+}
+
+/// Files sorted by `order`, or by path without one. Files that can no longer be read are
+/// left out.
+pub fn in_order(paths: Vec<PathBuf>, order: Option<UploadOrder>) -> Vec<PathBuf> {
+    let Some(order) = order else {
+        let mut paths = paths;
+        paths.sort();
+        return paths;
+    };
+    let mut found = Vec::new();
+    for path in paths {
+        if let Ok(metadata) = path.metadata() {
+            let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            found.push((path, modified, metadata.len()));
+        }
+    }
+    match order {
+        UploadOrder::Oldest => found.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0))),
+        UploadOrder::Newest => found.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))),
+        UploadOrder::Smallest => found.sort_by(|a, b| a.2.cmp(&b.2).then_with(|| a.0.cmp(&b.0))),
+        UploadOrder::Largest => found.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0))),
+    }
+    found.into_iter().map(|(path, ..)| path).collect()
+}
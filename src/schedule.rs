@@ -0,0 +1,59 @@
+use chrono::{DateTime, Datelike, Local, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::Deserialize;
+
+/// When an agent is allowed to upload, evaluated in the agent's own timezone
+#[derive(Deserialize, Debug, Clone)]
+pub struct Schedule {
+    /// IANA timezone name (e.g. `America/Denver`), host-local time when unset
+    timezone: Option<Tz>,
+    /// Uploads only happen inside one of these, or at any time when empty
+    #[serde(default)]
+    windows: Vec<Window>,
+    /// Uploads never happen inside any of these
+    #[serde(default)]
+    blackouts: Vec<Window>,
+}
+
+/// Daily time range, wrapping past midnight when `end` is before `start`
+#[derive(Deserialize, Debug, Clone)]
+pub struct Window {
+    /// Days the window applies to, every day when empty
+    #[serde(default)]
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl Window {
+    /// Whether `time` on `weekday` falls inside, with the part of a window past midnight
+    /// belonging to the day it started on
+    fn contains(&self, weekday: Weekday, time: NaiveTime) -> bool {
+        let applies = |weekday| self.days.is_empty() || self.days.contains(&weekday);
+        if self.start <= self.end {
+            applies(weekday) && self.start <= time && time < self.end
+        } else if time < self.end {
+            applies(weekday.pred())
+        } else {
+            applies(weekday) && self.start <= time
+        }
+    }
+}
+
+impl Schedule {
+    pub fn is_open(&self, now: DateTime<Utc>) -> bool {
+        let (weekday, time) = self.timezone.map_or_else(
+            || {
+                let local = now.with_timezone(&Local);
+                (local.weekday(), local.time())
+            },
+            |timezone| {
+                let local = now.with_timezone(&timezone);
+                (local.weekday(), local.time())
+            },
+        );
+        let in_window =
+            self.windows.is_empty() || self.windows.iter().any(|w| w.contains(weekday, time));
+        in_window && !self.blackouts.iter().any(|w| w.contains(weekday, time))
+    }
+}
@@ -0,0 +1,141 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::{Error, SelfUpdateOptions};
+
+const RELEASES_URL: &str = "https://api.github.com/repos/djeppson/s3sync/releases";
+
+#[derive(Deserialize, Debug)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    fn asset(&self, name: &str) -> Result<&Asset, Error> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| Error::MissingReleaseAsset {
+                tag: self.tag_name.clone(),
+                name: name.to_string(),
+            })
+    }
+}
+
+/// Release asset built for this platform, e.g. `s3sync-x86_64-linux`
+fn asset_name() -> String {
+    format!(
+        "s3sync-{}-{}{}",
+        std::env::consts::ARCH,
+        std::env::consts::OS,
+        std::env::consts::EXE_SUFFIX
+    )
+}
+
+/// Replace the running binary with a GitHub release, after checking it against the
+/// SHA-256 published next to it as `<asset>.sha256`
+pub async fn run(options: &SelfUpdateOptions) -> Result<(), Error> {
+    let client = reqwest::Client::builder()
+        .user_agent(concat!("s3sync/", env!("CARGO_PKG_VERSION")))
+        .build()?;
+    let release = options
+        .tag
+        .as_ref()
+        .map_or_else(|| "latest".to_string(), |tag| format!("tags/{tag}"));
+    let release: Release = client
+        .get(format!("{RELEASES_URL}/{release}"))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let current = env!("CARGO_PKG_VERSION");
+    let version = semver::Version::parse(release.tag_name.trim_start_matches('v'))
+        .map_err(|_| Error::ReleaseVersion(release.tag_name.clone()))?;
+    let running = semver::Version::parse(current).unwrap_or_else(|_| semver::Version::new(0, 0, 0));
+    // A tag asked for by name is installed even if it's older, the latest release only
+    // if it's newer
+    let up_to_date = if options.tag.is_some() {
+        version == running
+    } else {
+        version <= running
+    };
+    if options.check {
+        if up_to_date {
+            tracing::info!("s3sync {current} is up to date");
+        } else {
+            tracing::info!("s3sync {current} can be updated to {}", release.tag_name);
+        }
+        return Ok(());
+    }
+    if up_to_date && !options.force {
+        tracing::info!("s3sync {current} is up to date");
+        return Ok(());
+    }
+
+    let name = asset_name();
+    let binary = release.asset(&name)?;
+    let checksum = release.asset(&format!("{name}.sha256"))?;
+    // `sha256sum` output, the digest followed by the file name
+    let expected = client
+        .get(&checksum.browser_download_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    tracing::info!("Downloading {name} from {}", release.tag_name);
+    let bytes = client
+        .get(&binary.browser_download_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await?;
+    let actual = format!("{:x}", Sha256::digest(&bytes));
+    if actual != expected {
+        return Err(Error::ReleaseChecksum {
+            name,
+            expected,
+            actual,
+        });
+    }
+
+    let exe = std::env::current_exe()?;
+    replace(&exe, &bytes)?;
+    tracing::info!("Updated {} to {}", exe.display(), release.tag_name);
+    Ok(())
+}
+
+/// Swap `exe` for `contents`, written alongside it first so the rename can't cross
+/// filesystems and an interrupted update leaves the old binary in place
+fn replace(exe: &Path, contents: &[u8]) -> Result<(), Error> {
+    let staged = sibling(exe, "new");
+    std::fs::write(&staged, contents)?;
+    std::fs::set_permissions(&staged, std::fs::metadata(exe)?.permissions())?;
+    // Windows won't overwrite a running executable, but lets it be renamed out of the way
+    if cfg!(windows) {
+        std::fs::rename(exe, sibling(exe, "old"))?;
+    }
+    std::fs::rename(&staged, exe)?;
+    Ok(())
+}
+
+fn sibling(exe: &Path, extension: &str) -> PathBuf {
+    let mut name = exe.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{extension}"));
+    exe.with_file_name(name)
+}
@@ -0,0 +1,237 @@
+#[cfg(feature = "dynamodb")]
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use aws_config::SdkConfig;
+#[cfg(feature = "dynamodb")]
+use aws_sdk_dynamodb::{
+    self as dynamodb,
+    operation::{delete_item::DeleteItemError, put_item::PutItemError},
+    types::AttributeValue,
+};
+use serde::Deserialize;
+
+use super::{state::Entry, Error};
+
+/// How long a claim keeps other hosts off a file, long enough for any one upload, so the
+/// claims of a host that died mid-upload run out
+#[cfg(feature = "dynamodb")]
+const CLAIM_TTL: Duration = Duration::from_hours(1);
+
+/// DynamoDB table of uploaded files, for hosts watching the same shared filesystem to skip
+/// what another already uploaded. The table is keyed on the string attributes `agent`
+/// (partition) and `key` (sort), matching agents by name across hosts.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SharedState {
+    pub table: String,
+    /// Built on first use and shared by clones, so each agent given its own copy resolves
+    /// credentials once
+    #[cfg(feature = "dynamodb")]
+    #[serde(skip)]
+    client: Arc<tokio::sync::OnceCell<dynamodb::Client>>,
+}
+
+impl SharedState {
+    #[cfg_attr(not(feature = "dynamodb"), allow(clippy::missing_const_for_fn))]
+    pub fn new(table: String) -> Self {
+        Self {
+            table,
+            #[cfg(feature = "dynamodb")]
+            client: Arc::default(),
+        }
+    }
+
+    #[cfg(feature = "dynamodb")]
+    async fn client(&self, sdk_config: &SdkConfig) -> &dynamodb::Client {
+        self.client
+            .get_or_init(|| async { dynamodb::Client::new(sdk_config) })
+            .await
+    }
+
+    #[cfg(feature = "dynamodb")]
+    pub async fn get(
+        &self,
+        sdk_config: &SdkConfig,
+        agent: &str,
+        key: &str,
+    ) -> Result<Option<Entry>, Error> {
+        let output = self
+            .client(sdk_config)
+            .await
+            .get_item()
+            .table_name(&self.table)
+            .key("agent", AttributeValue::S(agent.to_string()))
+            .key("key", AttributeValue::S(key.to_string()))
+            .consistent_read(true)
+            .send()
+            .await
+            .map_err(|e| Error::DynamoDb(Box::new(e.into())))?;
+        Ok(output.item.as_ref().and_then(item_to_entry))
+    }
+
+    #[cfg(feature = "dynamodb")]
+    pub async fn record(&self, sdk_config: &SdkConfig, entry: &Entry) -> Result<(), Error> {
+        let mut item = HashMap::from([
+            ("agent".to_string(), AttributeValue::S(entry.agent.clone())),
+            ("key".to_string(), AttributeValue::S(entry.key.clone())),
+            (
+                "path".to_string(),
+                AttributeValue::S(entry.path.to_string_lossy().into_owned()),
+            ),
+            (
+                "size".to_string(),
+                AttributeValue::N(entry.size.to_string()),
+            ),
+            (
+                "modified".to_string(),
+                AttributeValue::N(entry.modified.to_string()),
+            ),
+            (
+                "uploaded_at".to_string(),
+                AttributeValue::S(entry.uploaded_at.to_rfc3339()),
+            ),
+        ]);
+        if let Some(e_tag) = &entry.e_tag {
+            item.insert("e_tag".to_string(), AttributeValue::S(e_tag.clone()));
+        }
+        self.client(sdk_config)
+            .await
+            .put_item()
+            .table_name(&self.table)
+            .set_item(Some(item))
+            .send()
+            .await
+            .map_err(|e| Error::DynamoDb(Box::new(e.into())))?;
+        Ok(())
+    }
+
+    /// Claim the key for uploading the file at `entry`'s size and modification time,
+    /// returning whether this host got it. The write is conditional on the table not
+    /// already holding that version, uploaded or claimed, so of several hosts seeing the
+    /// same file only one uploads it. Claims lack `uploaded_at`, so `get` ignores them.
+    #[cfg(feature = "dynamodb")]
+    pub async fn claim(
+        &self,
+        sdk_config: &SdkConfig,
+        entry: &Entry,
+        holder: &str,
+    ) -> Result<bool, Error> {
+        let now = chrono::Utc::now().timestamp();
+        let claimed_until = now + i64::try_from(CLAIM_TTL.as_secs()).unwrap_or(i64::MAX);
+        let result = self
+            .client(sdk_config)
+            .await
+            .put_item()
+            .table_name(&self.table)
+            .item("agent", AttributeValue::S(entry.agent.clone()))
+            .item("key", AttributeValue::S(entry.key.clone()))
+            .item(
+                "path",
+                AttributeValue::S(entry.path.to_string_lossy().into_owned()),
+            )
+            .item("size", AttributeValue::N(entry.size.to_string()))
+            .item("modified", AttributeValue::N(entry.modified.to_string()))
+            .item("claimed_by", AttributeValue::S(holder.to_string()))
+            .item(
+                "claimed_until",
+                AttributeValue::N(claimed_until.to_string()),
+            )
+            .condition_expression(
+                "attribute_not_exists(#k) OR #size <> :size OR #modified <> :modified \
+                 OR #claimed_until < :now",
+            )
+            .expression_attribute_names("#k", "key")
+            .expression_attribute_names("#size", "size")
+            .expression_attribute_names("#modified", "modified")
+            .expression_attribute_names("#claimed_until", "claimed_until")
+            .expression_attribute_values(":size", AttributeValue::N(entry.size.to_string()))
+            .expression_attribute_values(":modified", AttributeValue::N(entry.modified.to_string()))
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(e)
+                if e.as_service_error()
+                    .is_some_and(PutItemError::is_conditional_check_failed_exception) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(Error::DynamoDb(Box::new(e.into()))),
+        }
+    }
+
+    /// Give up a claim after a failed upload, so another host can take the file without
+    /// waiting for the claim to run out. Left alone if it's since been uploaded or
+    /// claimed by someone else.
+    #[cfg(feature = "dynamodb")]
+    pub async fn release(
+        &self,
+        sdk_config: &SdkConfig,
+        agent: &str,
+        key: &str,
+        holder: &str,
+    ) -> Result<(), Error> {
+        let result = self
+            .client(sdk_config)
+            .await
+            .delete_item()
+            .table_name(&self.table)
+            .key("agent", AttributeValue::S(agent.to_string()))
+            .key("key", AttributeValue::S(key.to_string()))
+            .condition_expression("#claimed_by = :holder")
+            .expression_attribute_names("#claimed_by", "claimed_by")
+            .expression_attribute_values(":holder", AttributeValue::S(holder.to_string()))
+            .send()
+            .await;
+        match result {
+            Err(e)
+                if !e
+                    .as_service_error()
+                    .is_some_and(DeleteItemError::is_conditional_check_failed_exception) =>
+            {
+                Err(Error::DynamoDb(Box::new(e.into())))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(not(feature = "dynamodb"))]
+    #[allow(clippy::unused_async)]
+    pub async fn get(&self, _: &SdkConfig, _: &str, _: &str) -> Result<Option<Entry>, Error> {
+        Err(Error::Disabled("dynamodb"))
+    }
+
+    #[cfg(not(feature = "dynamodb"))]
+    #[allow(clippy::unused_async)]
+    pub async fn record(&self, _: &SdkConfig, _: &Entry) -> Result<(), Error> {
+        Err(Error::Disabled("dynamodb"))
+    }
+
+    #[cfg(not(feature = "dynamodb"))]
+    #[allow(clippy::unused_async)]
+    pub async fn claim(&self, _: &SdkConfig, _: &Entry, _: &str) -> Result<bool, Error> {
+        Err(Error::Disabled("dynamodb"))
+    }
+
+    #[cfg(not(feature = "dynamodb"))]
+    #[allow(clippy::unused_async)]
+    pub async fn release(&self, _: &SdkConfig, _: &str, _: &str, _: &str) -> Result<(), Error> {
+        Err(Error::Disabled("dynamodb"))
+    }
+}
+
+/// `None` for items missing attributes, e.g. written by hand
+#[cfg(feature = "dynamodb")]
+fn item_to_entry(item: &HashMap<String, AttributeValue>) -> Option<Entry> {
+    let string = |name: &str| item.get(name)?.as_s().ok().cloned();
+    let number = |name: &str| item.get(name)?.as_n().ok()?.parse::<i64>().ok();
+    Some(Entry {
+        agent: string("agent")?,
+        key: string("key")?,
+        path: string("path")?.into(),
+        size: u64::try_from(number("size")?).ok()?,
+        modified: number("modified")?,
+        e_tag: string("e_tag"),
+        uploaded_at: string("uploaded_at")?.parse().ok()?,
+    })
+}
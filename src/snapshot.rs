@@ -0,0 +1,80 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+/// Commands that take and drop a filesystem snapshot (LVM, ZFS, btrfs, VSS, ...) around
+/// each batch of uploads, so files are read from a frozen copy instead of live
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct SnapshotSettings {
+    /// Shell command creating a read-only snapshot of the watched path
+    create: String,
+    /// Where the watched path's contents appear inside the snapshot
+    path: PathBuf,
+    /// Shell command removing the snapshot once the batch is uploaded
+    remove: Option<String>,
+}
+
+/// Whether the agent's snapshot is currently mounted
+#[derive(Debug, Clone, Default)]
+pub struct Active(Arc<AtomicBool>);
+
+impl SnapshotSettings {
+    /// Run `create`, returning whether uploads can read from the snapshot
+    pub fn create(&self, active: &Active) -> bool {
+        match run(&self.create) {
+            Ok(()) => {
+                tracing::debug!("Snapshot created");
+                active.0.store(true, Ordering::SeqCst);
+                true
+            }
+            Err(e) => {
+                tracing::warn!("Unable to create snapshot, reading live files: {e}");
+                false
+            }
+        }
+    }
+
+    pub fn remove(&self, active: &Active) {
+        if !active.0.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        if let Some(remove) = &self.remove {
+            if let Err(e) = run(remove) {
+                tracing::warn!("Unable to remove snapshot: {e}");
+            }
+        }
+    }
+
+    /// Same file inside the snapshot, when one is active
+    pub fn source(&self, active: &Active, root: &Path, path: &Path) -> PathBuf {
+        if !active.0.load(Ordering::SeqCst) {
+            return path.to_path_buf();
+        }
+        match path.strip_prefix(root) {
+            Ok(relative) if !relative.as_os_str().is_empty() => self.path.join(relative),
+            // A single watched file maps directly to the snapshot path
+            _ => self.path.clone(),
+        }
+    }
+}
+
+fn run(command: &str) -> std::io::Result<()> {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let status = Command::new(shell).arg(flag).arg(command).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(format!(
+            "'{command}' exited with {status}"
+        )))
+    }
+}
@@ -0,0 +1,20 @@
+use aws_sdk_s3::types::ServerSideEncryption;
+
+use super::Sse;
+
+impl Sse {
+    #[must_use]
+    pub const fn server_side_encryption(self) -> ServerSideEncryption {
+        match self {
+            Self::Aes256 => ServerSideEncryption::Aes256,
+            Self::Kms => ServerSideEncryption::AwsKms,
+            Self::KmsDsse => ServerSideEncryption::AwsKmsDsse,
+        }
+    }
+
+    /// Whether the objects are encrypted with a KMS key, so a key id applies
+    #[must_use]
+    pub const fn uses_kms(self) -> bool {
+        matches!(self, Self::Kms | Self::KmsDsse)
+    }
+}
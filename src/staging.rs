@@ -0,0 +1,79 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::SystemTime,
+};
+
+use serde::Deserialize;
+
+/// Suffix keeping staged copies of same-named files from different directories apart
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// Copies made before giving up on a file that keeps changing while it's staged
+const STAGE_ATTEMPTS: u32 = 3;
+
+/// Directory files are copied (or hard-linked) into before upload, so later writes can't
+/// change what's in flight
+#[derive(Deserialize, Debug, Clone)]
+pub struct StagingSettings {
+    dir: PathBuf,
+    /// Hard-link instead of copying. Cheaper, but only protects against the file being
+    /// replaced, not against writes in place. Falls back to a copy across filesystems.
+    link: Option<bool>,
+}
+
+/// Staged copy of a file, removed when dropped
+#[derive(Debug)]
+pub struct Staged {
+    pub path: PathBuf,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl StagingSettings {
+    /// Copy or link `source` into the directory, again if it changed meanwhile so the
+    /// staged copy is of one version of it
+    pub fn stage(&self, source: &Path) -> std::io::Result<Staged> {
+        std::fs::create_dir_all(&self.dir)?;
+        let name = source.file_name().unwrap_or_default().to_string_lossy();
+        let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        let path = self
+            .dir
+            .join(format!("{}-{sequence}-{name}", std::process::id()));
+        for _ in 0..STAGE_ATTEMPTS {
+            let metadata = source.metadata()?;
+            let linked = self.link.unwrap_or(false) && std::fs::hard_link(source, &path).is_ok();
+            if !linked {
+                std::fs::copy(source, &path)?;
+            }
+            let staged = Staged {
+                path: path.clone(),
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+            };
+            if staged.matches(source) {
+                return Ok(staged);
+            }
+            tracing::debug!("Written to while being staged, staging it again");
+        }
+        Err(std::io::Error::other("kept changing while being staged"))
+    }
+}
+
+impl Staged {
+    /// Whether `source` still looks like it did when it was staged, so removing it can't
+    /// lose writes that came after
+    pub fn matches(&self, source: &Path) -> bool {
+        source.metadata().is_ok_and(|metadata| {
+            metadata.len() == self.len && metadata.modified().ok() == self.modified
+        })
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            tracing::warn!("Unable to remove staged copy: {e}");
+        }
+    }
+}
@@ -0,0 +1,375 @@
+use std::{
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::Error;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS uploads (
+    agent TEXT NOT NULL,
+    key TEXT NOT NULL,
+    path TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    modified INTEGER NOT NULL,
+    e_tag TEXT,
+    uploaded_at TEXT NOT NULL,
+    PRIMARY KEY (agent, key)
+);
+CREATE TABLE IF NOT EXISTS reconciles (
+    agent TEXT PRIMARY KEY,
+    started_at TEXT NOT NULL,
+    completed_at TEXT NOT NULL,
+    full_at TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS multipart_uploads (
+    agent TEXT NOT NULL,
+    key TEXT NOT NULL,
+    upload_id TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    modified INTEGER NOT NULL,
+    part_size INTEGER NOT NULL,
+    PRIMARY KEY (agent, key)
+);
+CREATE TABLE IF NOT EXISTS fingerprints (
+    agent TEXT NOT NULL,
+    path TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    modified INTEGER NOT NULL,
+    md5 TEXT NOT NULL,
+    uploaded_at TEXT NOT NULL,
+    PRIMARY KEY (agent, path, size, modified, md5)
+)";
+
+/// The last upload of a file, one per agent and object key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub agent: String,
+    pub key: String,
+    pub path: PathBuf,
+    pub size: u64,
+    /// Modification time, in nanoseconds since the Unix epoch
+    pub modified: i64,
+    pub e_tag: Option<String>,
+    pub uploaded_at: DateTime<Utc>,
+}
+
+impl Entry {
+    /// Whether the file still looks like what was uploaded
+    pub fn matches(&self, metadata: &std::fs::Metadata) -> bool {
+        self.size == metadata.len() && Some(self.modified) == modified(metadata)
+    }
+}
+
+/// A multipart upload in progress, kept until it completes or is aborted so an interrupted
+/// one can be resumed for the same file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultipartEntry {
+    pub agent: String,
+    pub key: String,
+    pub upload_id: String,
+    pub size: u64,
+    /// Modification time of the file being uploaded, in nanoseconds since the Unix epoch
+    pub modified: i64,
+    pub part_size: u64,
+}
+
+/// A file's contents as uploaded, whatever key they went to, so the same contents at the
+/// same path aren't uploaded again within an agent's `duplicate_window`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Modification time, in nanoseconds since the Unix epoch
+    pub modified: i64,
+    pub md5: String,
+}
+
+/// When an agent's last reconcile pass ran, and when the last full one started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reconciled {
+    pub started: DateTime<Utc>,
+    pub completed: DateTime<Utc>,
+    pub last_full: DateTime<Utc>,
+}
+
+/// Modification time in nanoseconds since the Unix epoch, when the platform has one
+pub fn modified(metadata: &std::fs::Metadata) -> Option<i64> {
+    let modified = metadata.modified().ok()?;
+    let since_epoch = modified.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+    i64::try_from(since_epoch.as_nanos()).ok()
+}
+
+/// SQLite database of uploaded files, shared by every agent
+#[derive(Clone)]
+pub struct State(Arc<Mutex<Connection>>);
+
+impl std::fmt::Debug for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("State").finish_non_exhaustive()
+    }
+}
+
+impl State {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self(Arc::new(Mutex::new(connection))))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.0.lock().unwrap()
+    }
+
+    pub fn get(&self, agent: &str, key: &str) -> Result<Option<Entry>, Error> {
+        let entry = self
+            .lock()
+            .query_row(
+                "SELECT agent, key, path, size, modified, e_tag, uploaded_at FROM uploads
+                WHERE agent = ?1 AND key = ?2",
+                params![agent, key],
+                row_to_entry,
+            )
+            .optional()?;
+        Ok(entry)
+    }
+
+    pub fn record(&self, entry: &Entry) -> Result<(), Error> {
+        insert(&self.lock(), entry)?;
+        Ok(())
+    }
+
+    pub fn remove(&self, agent: &str, key: &str) -> Result<(), Error> {
+        self.lock().execute(
+            "DELETE FROM uploads WHERE agent = ?1 AND key = ?2",
+            params![agent, key],
+        )?;
+        Ok(())
+    }
+
+    /// Move an entry to a new key, keeping everything else about it
+    pub fn rename(&self, agent: &str, from: &str, to: &str) -> Result<(), Error> {
+        self.lock().execute(
+            "UPDATE OR REPLACE uploads SET key = ?3 WHERE agent = ?1 AND key = ?2",
+            params![agent, from, to],
+        )?;
+        Ok(())
+    }
+
+    pub fn last_reconcile(&self, agent: &str) -> Result<Option<Reconciled>, Error> {
+        let reconciled = self
+            .lock()
+            .query_row(
+                "SELECT started_at, completed_at, full_at FROM reconciles WHERE agent = ?1",
+                params![agent],
+                |row| {
+                    Ok(Reconciled {
+                        started: row.get(0)?,
+                        completed: row.get(1)?,
+                        last_full: row.get(2)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(reconciled)
+    }
+
+    pub fn record_reconcile(&self, agent: &str, reconciled: &Reconciled) -> Result<(), Error> {
+        self.lock().execute(
+            "INSERT OR REPLACE INTO reconciles (agent, started_at, completed_at, full_at)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![
+                agent,
+                reconciled.started,
+                reconciled.completed,
+                reconciled.last_full
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_multipart(&self, agent: &str, key: &str) -> Result<Option<MultipartEntry>, Error> {
+        let entry = self
+            .lock()
+            .query_row(
+                "SELECT agent, key, upload_id, size, modified, part_size FROM multipart_uploads
+                WHERE agent = ?1 AND key = ?2",
+                params![agent, key],
+                |row| {
+                    Ok(MultipartEntry {
+                        agent: row.get(0)?,
+                        key: row.get(1)?,
+                        upload_id: row.get(2)?,
+                        size: u64::try_from(row.get::<_, i64>(3)?).unwrap_or_default(),
+                        modified: row.get(4)?,
+                        part_size: u64::try_from(row.get::<_, i64>(5)?).unwrap_or_default(),
+                    })
+                },
+            )
+            .optional()?;
+        Ok(entry)
+    }
+
+    pub fn record_multipart(&self, entry: &MultipartEntry) -> Result<(), Error> {
+        self.lock().execute(
+            "INSERT OR REPLACE INTO multipart_uploads
+            (agent, key, upload_id, size, modified, part_size) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                entry.agent,
+                entry.key,
+                entry.upload_id,
+                i64::try_from(entry.size).unwrap_or(i64::MAX),
+                entry.modified,
+                i64::try_from(entry.part_size).unwrap_or(i64::MAX),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn remove_multipart(&self, agent: &str, key: &str) -> Result<(), Error> {
+        self.lock().execute(
+            "DELETE FROM multipart_uploads WHERE agent = ?1 AND key = ?2",
+            params![agent, key],
+        )?;
+        Ok(())
+    }
+
+    /// Whether the same contents were uploaded from the same path at or after `since`
+    pub fn uploaded_since(
+        &self,
+        agent: &str,
+        fingerprint: &Fingerprint,
+        since: DateTime<Utc>,
+    ) -> Result<bool, Error> {
+        let found = self
+            .lock()
+            .query_row(
+                "SELECT 1 FROM fingerprints WHERE agent = ?1 AND path = ?2 AND size = ?3
+                AND modified = ?4 AND md5 = ?5 AND uploaded_at >= ?6",
+                params![
+                    agent,
+                    fingerprint.path.to_string_lossy(),
+                    i64::try_from(fingerprint.size).unwrap_or(i64::MAX),
+                    fingerprint.modified,
+                    fingerprint.md5,
+                    since,
+                ],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    /// Record an upload's fingerprint, dropping the agent's fingerprints recorded before
+    /// `expired`, which can no longer suppress anything
+    pub fn record_fingerprint(
+        &self,
+        agent: &str,
+        fingerprint: &Fingerprint,
+        expired: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let connection = self.lock();
+        connection.execute(
+            "DELETE FROM fingerprints WHERE agent = ?1 AND uploaded_at < ?2",
+            params![agent, expired],
+        )?;
+        connection.execute(
+            "INSERT OR REPLACE INTO fingerprints (agent, path, size, modified, md5, uploaded_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                agent,
+                fingerprint.path.to_string_lossy(),
+                i64::try_from(fingerprint.size).unwrap_or(i64::MAX),
+                fingerprint.modified,
+                fingerprint.md5,
+                Utc::now(),
+            ],
+        )?;
+        drop(connection);
+        Ok(())
+    }
+
+    /// Every entry recorded for an agent, by key
+    pub fn entries(&self, agent: &str) -> Result<Vec<Entry>, Error> {
+        let entries = self
+            .lock()
+            .prepare(
+                "SELECT agent, key, path, size, modified, e_tag, uploaded_at FROM uploads
+                WHERE agent = ?1 ORDER BY key",
+            )?
+            .query_map(params![agent], row_to_entry)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    /// Write every entry as a line of JSON, returning how many were written
+    pub fn export(&self, mut output: impl Write) -> Result<usize, Error> {
+        let entries = self
+            .lock()
+            .prepare(
+                "SELECT agent, key, path, size, modified, e_tag, uploaded_at FROM uploads
+                ORDER BY agent, key",
+            )?
+            .query_map([], row_to_entry)?
+            .collect::<Result<Vec<_>, _>>()?;
+        for entry in &entries {
+            serde_json::to_writer(&mut output, entry)?;
+            writeln!(output)?;
+        }
+        output.flush()?;
+        Ok(entries.len())
+    }
+
+    /// Load lines of JSON written by [`Self::export`] in a single transaction, replacing
+    /// existing entries for the same agent and key
+    pub fn import(&self, input: impl BufRead) -> Result<usize, Error> {
+        let mut entries = Vec::new();
+        for line in input.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                entries.push(serde_json::from_str::<Entry>(&line)?);
+            }
+        }
+        let mut connection = self.lock();
+        let transaction = connection.transaction()?;
+        for entry in &entries {
+            insert(&transaction, entry)?;
+        }
+        transaction.commit()?;
+        drop(connection);
+        Ok(entries.len())
+    }
+}
+
+fn insert(connection: &Connection, entry: &Entry) -> rusqlite::Result<usize> {
+    connection.execute(
+        "INSERT OR REPLACE INTO uploads (agent, key, path, size, modified, e_tag, uploaded_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            entry.agent,
+            entry.key,
+            entry.path.to_string_lossy(),
+            i64::try_from(entry.size).unwrap_or(i64::MAX),
+            entry.modified,
+            entry.e_tag,
+            entry.uploaded_at,
+        ],
+    )
+}
+
+fn row_to_entry(row: &rusqlite::Row<'_>) -> rusqlite::Result<Entry> {
+    Ok(Entry {
+        agent: row.get(0)?,
+        key: row.get(1)?,
+        path: PathBuf::from(row.get::<_, String>(2)?),
+        size: u64::try_from(row.get::<_, i64>(3)?).unwrap_or_default(),
+        modified: row.get(4)?,
+        e_tag: row.get(5)?,
+        uploaded_at: row.get(6)?,
+    })
+}
@@ -0,0 +1,19 @@
+use aws_sdk_s3::types;
+
+use super::StorageClass;
+
+impl StorageClass {
+    #[must_use]
+    pub const fn class(self) -> types::StorageClass {
+        match self {
+            Self::Standard => types::StorageClass::Standard,
+            Self::ReducedRedundancy => types::StorageClass::ReducedRedundancy,
+            Self::StandardIa => types::StorageClass::StandardIa,
+            Self::OnezoneIa => types::StorageClass::OnezoneIa,
+            Self::IntelligentTiering => types::StorageClass::IntelligentTiering,
+            Self::GlacierIr => types::StorageClass::GlacierIr,
+            Self::Glacier => types::StorageClass::Glacier,
+            Self::DeepArchive => types::StorageClass::DeepArchive,
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use super::{
+    output::{Lifecycle, SkipReason},
+    Error,
+};
+
+static SUBSCRIBERS: OnceLock<Subscribers> = OnceLock::new();
+
+type Callback<E> = Arc<dyn Fn(E) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub struct Matched {
+    pub agent: String,
+    pub path: String,
+    pub key: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Uploaded {
+    pub agent: String,
+    pub bucket: String,
+    pub key: String,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Skipped {
+    pub agent: String,
+    pub path: String,
+    pub reason: SkipReason,
+}
+
+#[derive(Debug, Clone)]
+pub struct Failed {
+    pub agent: String,
+    pub path: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct Overdue {
+    pub agent: String,
+    pub path: String,
+    pub waited: Duration,
+}
+
+/// Async callbacks run for lifecycle events, for programs embedding the sync engine to
+/// follow files without parsing logs. Paths are as they appear in logs, so hashed under
+/// `log_paths: hash`.
+///
+/// Each callback runs in its own task, so a slow one doesn't hold up uploads and events
+/// may be handled out of order.
+#[derive(Clone, Default)]
+#[must_use]
+pub struct Subscribers {
+    matched: Vec<Callback<Matched>>,
+    uploaded: Vec<Callback<Uploaded>>,
+    skipped: Vec<Callback<Skipped>>,
+    failed: Vec<Callback<Failed>>,
+    overdue: Vec<Callback<Overdue>>,
+}
+
+impl std::fmt::Debug for Subscribers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Subscribers").finish_non_exhaustive()
+    }
+}
+
+fn callback<E, F, Fut>(f: F) -> Callback<E>
+where
+    F: Fn(E) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    Arc::new(move |event| Box::pin(f(event)))
+}
+
+impl Subscribers {
+    pub fn on_matched<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(Matched) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.matched.push(callback(f));
+        self
+    }
+
+    pub fn on_uploaded<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(Uploaded) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.uploaded.push(callback(f));
+        self
+    }
+
+    pub fn on_skipped<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(Skipped) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.skipped.push(callback(f));
+        self
+    }
+
+    pub fn on_failed<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(Failed) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.failed.push(callback(f));
+        self
+    }
+
+    /// Files past their agent's `max_upload_latency`, for escalating missed deadlines
+    pub fn on_overdue<F, Fut>(mut self, f: F) -> Self
+    where
+        F: Fn(Overdue) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.overdue.push(callback(f));
+        self
+    }
+
+    /// Set the process-wide subscribers, failing if they were already set, as they can
+    /// only be set once
+    pub fn init(self) -> Result<(), Error> {
+        SUBSCRIBERS.set(self).map_err(|_| Error::SubscribersSet)
+    }
+}
+
+fn spawn<E: Clone>(callbacks: &[Callback<E>], event: &E) {
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    for callback in callbacks {
+        runtime.spawn(callback(event.clone()));
+    }
+}
+
+/// Hand the event to whichever subscribers are interested
+pub fn notify(agent: &str, lifecycle: &Lifecycle) {
+    let Some(subscribers) = SUBSCRIBERS.get() else {
+        return;
+    };
+    match *lifecycle {
+        Lifecycle::Matched { path, key } if !subscribers.matched.is_empty() => {
+            let event = Matched {
+                agent: agent.to_string(),
+                path: path.to_string(),
+                key: key.to_string(),
+            };
+            spawn(&subscribers.matched, &event);
+        }
+        Lifecycle::Uploaded {
+            bucket, key, bytes, ..
+        } if !subscribers.uploaded.is_empty() => {
+            let event = Uploaded {
+                agent: agent.to_string(),
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                bytes,
+            };
+            spawn(&subscribers.uploaded, &event);
+        }
+        Lifecycle::Skipped { path, reason } if !subscribers.skipped.is_empty() => {
+            let event = Skipped {
+                agent: agent.to_string(),
+                path: path.to_string(),
+                reason,
+            };
+            spawn(&subscribers.skipped, &event);
+        }
+        Lifecycle::Failed { path, error } if !subscribers.failed.is_empty() => {
+            let event = Failed {
+                agent: agent.to_string(),
+                path: path.to_string(),
+                error: error.to_string(),
+            };
+            spawn(&subscribers.failed, &event);
+        }
+        Lifecycle::Overdue {
+            path,
+            waited_seconds,
+        } if !subscribers.overdue.is_empty() => {
+            let event = Overdue {
+                agent: agent.to_string(),
+                path: path.to_string(),
+                waited: Duration::from_secs(waited_seconds),
+            };
+            spawn(&subscribers.overdue, &event);
+        }
+        // Detected, or nobody subscribed
+        _ => {}
+    }
+}
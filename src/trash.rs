@@ -0,0 +1,136 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use chrono::{NaiveDateTime, Utc};
+
+use super::Error;
+
+/// Directory under the watched path old copies are kept in, never uploaded or pulled into
+pub const DIR: &str = ".s3sync-trash/";
+/// Name of each pull's subdirectory, the time it started
+const BATCH_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Old copies of files `pull` overwrote or deleted, grouped by the pull that did it (e.g.
+/// `.s3sync-trash/20240102T030405Z/reports/q1.csv`) and pruned by age and total size
+#[derive(Debug)]
+pub struct Trash {
+    root: PathBuf,
+    dir: PathBuf,
+    batch: PathBuf,
+    max_size: u64,
+    retention: Duration,
+}
+
+impl Trash {
+    /// Trash for files under `root`
+    pub fn new(root: &Path, max_size: u64, retention: Duration) -> Self {
+        let dir = root.join(DIR);
+        let batch = dir.join(Utc::now().format(BATCH_FORMAT).to_string());
+        Self {
+            root: root.to_path_buf(),
+            dir,
+            batch,
+            max_size,
+            retention,
+        }
+    }
+
+    fn destination(&self, path: &Path) -> Result<PathBuf, Error> {
+        let relative = path
+            .strip_prefix(&self.root)
+            .map_err(|_| Error::OutsideWatchedPath(self.root.clone()))?;
+        let destination = self.batch.join(relative);
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok(destination)
+    }
+
+    /// Keep a copy of `path` before it's overwritten, leaving it in place. A hard link
+    /// where possible, since the new contents are renamed over the original.
+    pub fn keep_copy(&self, path: &Path) -> Result<(), Error> {
+        let destination = self.destination(path)?;
+        if std::fs::hard_link(path, &destination).is_err() {
+            std::fs::copy(path, &destination)?;
+        }
+        Ok(())
+    }
+
+    /// Move `path` into the trash instead of deleting it
+    pub fn keep(&self, path: &Path) -> Result<(), Error> {
+        std::fs::rename(path, self.destination(path)?)?;
+        Ok(())
+    }
+
+    /// Remove batches older than the retention, then the oldest files until what's left
+    /// fits in the size cap
+    pub fn prune(&self) -> Result<(), Error> {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return Ok(());
+        };
+        let cutoff = Utc::now().naive_utc() - self.retention;
+        let mut batches = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let started = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| NaiveDateTime::parse_from_str(name, BATCH_FORMAT).ok());
+            match started {
+                Some(started) if started < cutoff => std::fs::remove_dir_all(&path)?,
+                Some(started) => batches.push((started, path)),
+                // Not ours
+                None => {}
+            }
+        }
+        batches.sort();
+        let mut files = Vec::new();
+        for (_, batch) in &batches {
+            let mut batch_files = Vec::new();
+            walk(batch, &mut batch_files)?;
+            batch_files.sort();
+            files.extend(batch_files);
+        }
+        let mut size: u64 = files.iter().map(|(_, len)| len).sum();
+        for (path, len) in files {
+            if size <= self.max_size {
+                break;
+            }
+            std::fs::remove_file(&path)?;
+            size -= len;
+        }
+        for (_, batch) in &batches {
+            remove_empty_dirs(batch)?;
+        }
+        Ok(())
+    }
+}
+
+fn walk(dir: &Path, files: &mut Vec<(PathBuf, u64)>) -> Result<(), Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            walk(&entry.path(), files)?;
+        } else {
+            files.push((entry.path(), metadata.len()));
+        }
+    }
+    Ok(())
+}
+
+/// Remove `dir` and the directories under it once pruning has emptied them
+fn remove_empty_dirs(dir: &Path) -> Result<(), Error> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_empty_dirs(&entry.path())?;
+        }
+    }
+    if std::fs::read_dir(dir)?.next().is_none() {
+        std::fs::remove_dir(dir)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,160 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+use super::{metrics, multipart};
+
+const DEFAULT_MAX_PART_SIZE: u64 = 64 * 1024 * 1024;
+const DEFAULT_MAX_CONCURRENCY: usize = 16;
+const INITIAL_CONCURRENCY: usize = 4;
+/// Round trips each part should take at least, keeping per-request overhead to a couple
+/// of percent
+const ROUND_TRIPS_PER_PART: f64 = 50.0;
+/// Weight of each new sample in the moving averages
+const SMOOTHING: f64 = 0.3;
+/// Throughput drop that turns the concurrency search around
+const MIN_GAIN: f64 = 0.05;
+const MIB: u64 = 1024 * 1024;
+
+/// Bounds for tuning multipart uploads from measured throughput and round-trip times,
+/// rather than the aws-cli config's fixed `multipart_chunksize`
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub struct AutoTune {
+    /// Smallest part, in bytes, the 5 MiB S3 minimum by default
+    min_part_size: Option<u64>,
+    /// Largest part, in bytes, 64 MiB by default
+    max_part_size: Option<u64>,
+    /// Most parts of a file uploaded at once, 16 by default
+    max_concurrency: Option<usize>,
+}
+
+impl AutoTune {
+    fn part_sizes(&self) -> (u64, u64) {
+        let min = self
+            .min_part_size
+            .unwrap_or_default()
+            .max(multipart::MIN_PART_SIZE);
+        (
+            min,
+            self.max_part_size.unwrap_or(DEFAULT_MAX_PART_SIZE).max(min),
+        )
+    }
+
+    fn max_concurrency(&self) -> usize {
+        self.max_concurrency
+            .unwrap_or(DEFAULT_MAX_CONCURRENCY)
+            .max(1)
+    }
+}
+
+/// What an agent's uploads have achieved so far and where that has taken its settings,
+/// shared with its clones
+#[derive(Debug, Clone, Default)]
+pub struct Tuner(Arc<Mutex<Tuning>>);
+
+#[derive(Debug)]
+struct Tuning {
+    part_size: u64,
+    concurrency: usize,
+    /// Seconds, from requests that carry no data
+    round_trip: Option<f64>,
+    /// Bytes per second of a single part
+    part_rate: Option<f64>,
+    /// Bytes per second of the last whole upload
+    upload_rate: Option<f64>,
+    /// Direction the concurrency search is heading
+    step: isize,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self {
+            part_size: multipart::DEFAULT_PART_SIZE,
+            concurrency: INITIAL_CONCURRENCY,
+            round_trip: None,
+            part_rate: None,
+            upload_rate: None,
+            step: 1,
+        }
+    }
+}
+
+fn smooth(average: Option<f64>, sample: f64) -> f64 {
+    average.map_or(sample, |average| {
+        SMOOTHING.mul_add(sample - average, average)
+    })
+}
+
+impl Tuner {
+    /// Part size and number of parts in flight for the next upload
+    pub fn settings(&self, bounds: &AutoTune) -> (u64, usize) {
+        let tuning = self.0.lock().unwrap();
+        let (min, max) = bounds.part_sizes();
+        (
+            tuning.part_size.clamp(min, max),
+            tuning.concurrency.clamp(1, bounds.max_concurrency()),
+        )
+    }
+
+    /// Time taken by a request without a body, e.g. creating or completing an upload
+    pub fn record_round_trip(&self, elapsed: Duration) {
+        let mut tuning = self.0.lock().unwrap();
+        tuning.round_trip = Some(smooth(tuning.round_trip, elapsed.as_secs_f64()));
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    pub fn record_part(&self, bytes: u64, elapsed: Duration) {
+        if elapsed.is_zero() {
+            return;
+        }
+        let mut tuning = self.0.lock().unwrap();
+        let rate = bytes as f64 / elapsed.as_secs_f64();
+        tuning.part_rate = Some(smooth(tuning.part_rate, rate));
+    }
+
+    /// Adjust the settings after a whole upload: keep moving concurrency the same way while
+    /// throughput holds up, turning around when it drops, and size parts to take enough
+    /// round trips that request overhead stays small
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    pub fn record_upload(&self, agent: &str, bounds: &AutoTune, bytes: u64, elapsed: Duration) {
+        if elapsed.is_zero() {
+            return;
+        }
+        let mut tuning = self.0.lock().unwrap();
+        let rate = bytes as f64 / elapsed.as_secs_f64();
+        if tuning
+            .upload_rate
+            .is_some_and(|previous| rate < previous * (1.0 - MIN_GAIN))
+        {
+            tuning.step = -tuning.step;
+        }
+        tuning.upload_rate = Some(rate);
+        let max_concurrency = bounds.max_concurrency();
+        let concurrency = tuning.concurrency.saturating_add_signed(tuning.step);
+        if concurrency == 0 || concurrency > max_concurrency {
+            tuning.step = -tuning.step;
+        }
+        tuning.concurrency = concurrency.clamp(1, max_concurrency);
+        let (min, max) = bounds.part_sizes();
+        if let (Some(part_rate), Some(round_trip)) = (tuning.part_rate, tuning.round_trip) {
+            let target = (part_rate * round_trip * ROUND_TRIPS_PER_PART) as u64;
+            tuning.part_size = target.div_ceil(MIB).saturating_mul(MIB).clamp(min, max);
+        }
+        let (part_size, concurrency) = (tuning.part_size, tuning.concurrency);
+        drop(tuning);
+        tracing::debug!(
+            part_size,
+            concurrency,
+            "Tuned after {:.1} MiB/s",
+            rate / MIB as f64
+        );
+        metrics::record_tuning(agent, part_size, concurrency);
+    }
+}
@@ -0,0 +1,353 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use regex::Regex;
+
+#[cfg(feature = "self-update")]
+use crate::options::SelfUpdateOptions;
+use crate::options::{
+    parse_window, Backend, Checksum, Collision, ConfigFormat, DuplicatePolicy, EstimateOptions,
+    KeyLayout, LogFormat, LogPaths, MatchOn, Multipart, OnSuccess, OutputFormat, Placeholder,
+    Provider, PullOptions, ReplayOptions, Sse, StorageClass, UploadOrder, Vanished,
+    DEFAULT_EVENT_WINDOW,
+};
+
+#[derive(Parser, Debug)]
+#[command(
+    about = "Watch local paths and upload what changes to S3",
+    long_about = None,
+    disable_version_flag = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Print the version
+    #[arg(long, short = 'V')]
+    pub version: bool,
+    /// With `--version`, also report enabled features, the watcher backend, the SDK
+    /// version and platform limits
+    #[arg(long, requires = "version")]
+    pub verbose: bool,
+    #[command(flatten)]
+    pub estimate: EstimateOptions,
+    /// Name identifying the agent in logs
+    #[arg(long)]
+    pub name: Option<String>,
+    /// How file paths and keys appear in logs
+    #[arg(long, value_enum)]
+    pub log_paths: Option<LogPaths>,
+    /// Emit lifecycle events on stdout
+    #[arg(long, value_enum, default_value_t)]
+    pub output: OutputFormat,
+    /// Format of the daemon's own logs
+    #[arg(long, value_enum, default_value_t)]
+    pub log_format: LogFormat,
+    /// Local directory or single file to sync [default: the current directory]
+    #[arg(
+        long,
+        short,
+        default_value = std::env::current_dir().unwrap().into_os_string(),
+        hide_default_value = true
+    )]
+    pub path: PathBuf,
+    /// S3 bucket to sync with
+    #[arg(long, short)]
+    pub bucket: Option<String>,
+    /// Prefix to prepend to the key
+    #[arg(long)]
+    pub prefix: Option<String>,
+    /// Regex filter to match events
+    #[arg(long)]
+    pub pattern: Option<Regex>,
+    /// Glob filter to match events (e.g. `**/*.csv`), repeatable, applied together with `--pattern`
+    #[arg(long, visible_alias = "glob")]
+    pub include: Vec<String>,
+    /// Regex dropping events the other filters kept (e.g. `\.tmp$`), repeatable
+    #[arg(long)]
+    pub exclude: Vec<Regex>,
+    /// Glob dropping events the other filters kept (e.g. `**/.DS_Store`), repeatable
+    #[arg(long)]
+    pub exclude_glob: Vec<String>,
+    /// What the include and exclude filters are matched against
+    #[arg(long, value_enum)]
+    pub match_on: Option<MatchOn>,
+    /// AWS credential profile to use
+    #[arg(long)]
+    pub profile: Option<String>,
+    /// AWS region override
+    #[arg(long)]
+    pub region: Option<String>,
+    /// S3-compatible service to use instead of AWS
+    #[arg(long, value_enum)]
+    pub provider: Option<Provider>,
+    /// Cloudflare account id, required with `--provider r2`
+    #[arg(long)]
+    pub account_id: Option<String>,
+    /// S3 endpoint to use instead of AWS or the provider's, e.g. MinIO or LocalStack
+    #[arg(long)]
+    pub endpoint_url: Option<String>,
+    /// Address buckets in the path rather than the host name, as MinIO and LocalStack
+    /// usually need
+    #[arg(long)]
+    pub force_path_style: Option<bool>,
+    /// aws-cli config file to read the profile from instead of `~/.aws/config`
+    #[arg(long)]
+    pub aws_config_file: Option<PathBuf>,
+    /// Credentials file to read the profile from instead of `~/.aws/credentials`
+    #[arg(long)]
+    pub aws_credentials_file: Option<PathBuf>,
+    /// Send unsigned requests without looking for credentials, for public buckets
+    #[arg(long)]
+    pub anonymous: Option<bool>,
+    /// IAM role to assume with the profile's credentials, e.g. for a bucket in another
+    /// account
+    #[arg(long)]
+    pub role_arn: Option<String>,
+    /// External id the role's trust policy asks for
+    #[arg(long)]
+    pub external_id: Option<String>,
+    /// Name of the role session, `s3sync-<hostname>` by default
+    #[arg(long)]
+    pub role_session_name: Option<String>,
+    /// What to do with a source file once it's uploaded: `keep`, `delete`, `truncate`,
+    /// `move:<dir>` or `command:<program>`
+    #[arg(long)]
+    pub on_success: Option<OnSuccess>,
+    /// Deprecated, the same as `--on-success delete`
+    #[arg(long, short)]
+    pub delete: Option<bool>,
+    /// Check the uploaded object's size and MD5 before the source is deleted, moved or
+    /// emptied, keeping it on a mismatch
+    #[arg(long)]
+    pub verify_before_delete: Option<bool>,
+    /// Delete the S3 object when the source file is removed
+    #[arg(long)]
+    pub delete_remote: Option<bool>,
+    /// Recursively sync the provided path
+    #[arg(short, long)]
+    pub recursive: Option<bool>,
+    /// Follow symlinks that resolve outside of the provided path
+    #[arg(long)]
+    pub follow_external_links: Option<bool>,
+    /// How long to aggregate events (e.g. `500ms`, `2m`, or a number of seconds)
+    #[arg(short, long, value_parser = parse_window, default_value = humantime::format_duration(DEFAULT_EVENT_WINDOW).to_string())]
+    pub window: Duration,
+    /// Deliver each window's events together rather than as each file settles
+    #[arg(long)]
+    pub batch: Option<bool>,
+    /// Order files in new directories are uploaded in, rather than as they're found
+    #[arg(long, value_enum)]
+    pub upload_order: Option<UploadOrder>,
+    /// Split a recursive watch over this many watchers by top-level subdirectory
+    #[arg(long)]
+    pub watch_shards: Option<usize>,
+    /// Where file system events come from, `poll` for network file systems [default: auto]
+    #[arg(long, value_enum)]
+    pub backend: Option<Backend>,
+    /// Time between scans with `--backend poll` [default: 30s]
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub poll_interval: Option<Duration>,
+    /// Upper bound on uploads per second, spreading bursts out evenly
+    #[arg(long)]
+    pub max_uploads_per_second: Option<f64>,
+    /// Files uploaded at once as events come in [default: 1]
+    #[arg(long)]
+    pub max_concurrency: Option<usize>,
+    /// On startup, upload files the bucket is missing or has at a different size, e.g.
+    /// ones created while s3sync was stopped
+    #[arg(long)]
+    pub initial_sync: Option<bool>,
+    /// Store modification and birth time in object metadata
+    #[arg(long)]
+    pub preserve_times: Option<bool>,
+    /// Store the host, EC2 instance, agent name and s3sync version in object metadata
+    #[arg(long)]
+    pub stamp_identity: Option<bool>,
+    /// Check the object before uploading and skip files whose size and MD5 it already
+    /// has
+    #[arg(long)]
+    pub skip_unchanged: Option<bool>,
+    /// Checksum of the file S3 verifies on upload and that's stored as
+    /// `source-checksum-<algorithm>` metadata, sent as a trailer on multipart parts
+    #[arg(long, value_enum)]
+    pub checksum: Option<Checksum>,
+    /// Storage class of uploaded objects, e.g. `STANDARD_IA` or `DEEP_ARCHIVE`
+    #[arg(long, value_enum)]
+    pub storage_class: Option<StorageClass>,
+    /// Server-side encryption of uploaded objects
+    #[arg(long, value_enum)]
+    pub sse: Option<Sse>,
+    /// KMS key to encrypt objects with, implies `--sse aws:kms`
+    #[arg(long)]
+    pub kms_key_id: Option<String>,
+    /// Degrade gracefully for S3-compatible servers that reject newer checksum and metadata
+    /// headers
+    #[arg(long)]
+    pub compat: Option<bool>,
+    /// What to do when the key already exists in the bucket
+    #[arg(long, value_enum)]
+    pub collision: Option<Collision>,
+    /// Suffix for colliding keys under `--collision suffix`, with `{timestamp}`,
+    /// `{hostname}` and `{counter}` placeholders [default: -{timestamp}]
+    #[arg(long)]
+    pub collision_suffix: Option<String>,
+    /// Hold back files other processes still have open, for up to this long (e.g. `5m`)
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub wait_for_close: Option<Duration>,
+    /// What to do about queued files removed or moved before they were uploaded
+    /// [default: ignore]
+    #[arg(long, value_enum)]
+    pub vanished: Option<Vanished>,
+    /// Resume interrupted multipart uploads of unchanged files instead of starting over
+    #[arg(long)]
+    pub resume: Option<bool>,
+    /// Largest file to upload, in bytes (defaults to the 5 TiB multipart limit)
+    #[arg(long)]
+    pub max_object_size: Option<u64>,
+    #[command(flatten)]
+    pub multipart: Multipart,
+    /// Tune multipart part size and concurrency from measured throughput and round trips,
+    /// uploading files of 8 MiB and up in parts unless a threshold is set
+    #[arg(long)]
+    pub auto_tune: Option<bool>,
+    /// Upload a zero-byte object or a JSON descriptor of each file instead of its contents
+    #[arg(long, value_enum)]
+    pub placeholder: Option<Placeholder>,
+    /// File to append rejected uploads to, as newline-delimited JSON
+    #[arg(long)]
+    pub dead_letter: Option<PathBuf>,
+    /// Queue uploads that fail against S3 in this file, kept across restarts, and retry
+    /// them in the background
+    #[arg(long)]
+    pub retry_queue: Option<PathBuf>,
+    /// How often failed uploads are retried, in memory unless `--retry-queue` is set
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub retry_interval: Option<Duration>,
+    /// Number of hash prefixes to spread keys across (e.g. 256 for `00/` to `ff/`)
+    #[arg(long)]
+    pub shards: Option<u32>,
+    /// CloudWatch Logs group to ship the daemon's own logs to
+    #[cfg(feature = "cloudwatch")]
+    #[arg(long)]
+    pub cloudwatch_log_group: Option<String>,
+    /// OpenTelemetry collector to export spans to over OTLP/HTTP, e.g.
+    /// `http://localhost:4318`
+    #[cfg(feature = "otlp")]
+    #[arg(long)]
+    pub otlp_endpoint: Option<String>,
+    /// Seconds between heartbeat objects written to the bucket
+    #[arg(long)]
+    pub heartbeat_interval: Option<u64>,
+    /// Escalate files not uploaded this long after they're detected (e.g. `10m`)
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub max_upload_latency: Option<Duration>,
+    /// Upload a CSV catalog of the state database this often (e.g. `1h`)
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub catalog_interval: Option<Duration>,
+    /// Don't upload the same contents from the same path twice within this long, across
+    /// restarts (e.g. `24h`). Needs `--state`.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub duplicate_window: Option<Duration>,
+    /// What to do when another host's heartbeat shows it syncing the same prefix while
+    /// either side deletes remote objects
+    #[arg(long)]
+    pub duplicate_instance: Option<DuplicatePolicy>,
+    /// Address to serve Prometheus metrics on (e.g. 127.0.0.1:9184)
+    #[arg(long)]
+    pub metrics_listen: Option<SocketAddr>,
+    /// Touch a canary file in each watched directory this often (e.g. `5m`), restarting
+    /// the watchers when its event never arrives
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub watchdog_interval: Option<Duration>,
+    /// Percentage of S3 responses to replace with a `503 SlowDown`, for testing retries
+    /// and alerting
+    #[arg(long, hide = true)]
+    pub fault_injection: Option<f64>,
+    /// Longest random delay before handling file events, for testing
+    #[arg(long, hide = true, value_parser = humantime::parse_duration)]
+    pub fault_event_delay: Option<Duration>,
+    /// Number of prefixes to list concurrently when scanning the bucket
+    #[arg(long)]
+    pub list_parallelism: Option<usize>,
+    /// Config file path or `s3://bucket/key` URL
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Syntax of the config, by default from its extension: `.toml`, `.json` or else YAML
+    #[arg(long, value_enum)]
+    pub config_format: Option<ConfigFormat>,
+    /// SQLite database recording uploaded files
+    #[arg(long)]
+    pub state: Option<PathBuf>,
+    /// DynamoDB table recording uploaded files for every host sharing the watched path
+    #[arg(long)]
+    pub state_table: Option<String>,
+    /// Seconds between checks for a changed `s3://` config
+    #[arg(long)]
+    pub config_refresh: Option<u64>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Upload what the bucket is missing or has at a different size in one pass over each
+    /// agent's path, then exit, e.g. from cron
+    Sync,
+    /// Compare local files against the bucket and report differences
+    Diff,
+    /// Re-hash local files and compare them against the `ETag`s stored in the bucket,
+    /// exiting non-zero on any drift
+    Verify,
+    /// Copy objects written under an earlier prefix or shard count to the current layout
+    MigrateKeys(KeyLayout),
+    /// Download the bucket's objects into the watched paths, optionally as they were at
+    /// an earlier time in a versioned bucket
+    Pull(PullOptions),
+    /// Upload files again to the keys an `--output ndjson` event log recorded them under,
+    /// e.g. after the bucket was restored from an older backup
+    Replay(ReplayOptions),
+    /// Move the upload state database between hosts
+    State {
+        #[command(subcommand)]
+        command: StateCommand,
+    },
+    /// Print a completion script for the shell
+    Completions { shell: Shell },
+    /// Write man pages for s3sync and each subcommand into a directory
+    Man { out_dir: PathBuf },
+    /// Replace this binary with a release from GitHub, checked against its published
+    /// SHA-256
+    #[cfg(feature = "self-update")]
+    SelfUpdate(SelfUpdateOptions),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum StateCommand {
+    /// Write every entry as newline-delimited JSON
+    Export {
+        /// File to write to instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Load newline-delimited JSON from `export`, replacing matching entries
+    Import {
+        /// File to read instead of stdin
+        input: Option<PathBuf>,
+    },
+    /// Upload each agent's catalog now, as with `catalog_interval`
+    Catalog,
+}
+
+pub fn completions(shell: Shell) {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+}
+
+pub fn man_pages(out_dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(out_dir)?;
+    clap_mangen::generate_to(Cli::command(), out_dir)
+}
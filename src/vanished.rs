@@ -0,0 +1,66 @@
+use std::{
+    collections::HashMap,
+    fs::Metadata,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// What a file is regardless of its name: device and inode on unix, elsewhere size and
+/// modification time, which a rename keeps too
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileId(u64, u128);
+
+impl FileId {
+    #[cfg(unix)]
+    fn of(metadata: &Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt;
+        Self(metadata.dev(), u128::from(metadata.ino()))
+    }
+
+    #[cfg(not(unix))]
+    fn of(metadata: &Metadata) -> Self {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        Self(metadata.len(), modified.as_nanos())
+    }
+}
+
+/// Identities of queued files under the `follow` policy, taken when they're queued so a
+/// rename can be traced once they're gone. Files restored from a persistent queue have
+/// none and are only warned about.
+#[derive(Debug, Clone, Default)]
+pub struct Queued(Arc<Mutex<HashMap<PathBuf, FileId>>>);
+
+impl Queued {
+    /// Remember a file as it's queued, keeping what was taken when it was first queued
+    pub fn record(&self, path: &Path) {
+        if let Ok(metadata) = path.metadata() {
+            self.0
+                .lock()
+                .unwrap()
+                .entry(path.to_path_buf())
+                .or_insert_with(|| FileId::of(&metadata));
+        }
+    }
+
+    /// Forget a file as it leaves the queue, returning where it was renamed to when it's
+    /// gone and a file with the same identity is in its directory
+    pub fn take(&self, path: &Path) -> Option<PathBuf> {
+        let id = self.0.lock().unwrap().remove(path)?;
+        if path.exists() {
+            return None;
+        }
+        std::fs::read_dir(path.parent()?)
+            .ok()?
+            .flatten()
+            .map(|entry| entry.path())
+            .find(|candidate| {
+                candidate
+                    .symlink_metadata()
+                    .is_ok_and(|metadata| metadata.is_file() && FileId::of(&metadata) == id)
+            })
+    }
+}
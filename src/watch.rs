@@ -0,0 +1,191 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use notify_debouncer_mini::{
+    new_debouncer_opt,
+    notify::{PollWatcher, RecommendedWatcher, RecursiveMode, Watcher},
+    Config, DebounceEventHandler, DebounceEventResult, Debouncer,
+};
+
+use super::{Backend, Error};
+
+/// Time between scans with the `poll` backend, notify's default
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+type Shard<W> = Arc<Mutex<Debouncer<W>>>;
+
+impl Backend {
+    /// Why the backend can't be used on this platform, if it can't
+    #[must_use]
+    pub const fn unavailable(self) -> Option<&'static str> {
+        match self {
+            Self::Inotify if !cfg!(target_os = "linux") => {
+                Some("the inotify backend is only available on Linux")
+            }
+            Self::Fsevents if !cfg!(target_os = "macos") => {
+                Some("the fsevents backend is only available on macOS")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Debouncers watching one path, which stop when this is dropped
+pub struct Watch {
+    _debouncers: Box<dyn Any + Send>,
+}
+
+impl Watch {
+    /// Watch `path` with `backend`, spread over `shards` debouncers when given as
+    /// [`Debouncers::sharded`] describes. Native backends other than the platform's are
+    /// turned away before this, when the config is loaded.
+    pub fn start<F: DebounceEventHandler + Clone>(
+        backend: Backend,
+        path: &Path,
+        mode: RecursiveMode,
+        shards: Option<usize>,
+        config: Config,
+        tx: F,
+    ) -> Result<Self, Error> {
+        fn debouncers<W: Watcher + Send + 'static, F: DebounceEventHandler + Clone>(
+            path: &Path,
+            mode: RecursiveMode,
+            shards: Option<usize>,
+            config: Config,
+            tx: F,
+        ) -> Result<Box<dyn Any + Send>, Error> {
+            Ok(Box::new(match shards {
+                Some(shards) => Debouncers::<W>::sharded(path, shards, config, tx)?,
+                None => Debouncers::<W>::single(path, mode, config, tx)?,
+            }))
+        }
+        let debouncers = match backend {
+            Backend::Auto | Backend::Inotify | Backend::Fsevents => {
+                debouncers::<RecommendedWatcher, F>(path, mode, shards, config, tx)
+            }
+            Backend::Poll => debouncers::<PollWatcher, F>(path, mode, shards, config, tx),
+        }?;
+        Ok(Self {
+            _debouncers: debouncers,
+        })
+    }
+}
+
+struct Debouncers<W: Watcher> {
+    _root: Debouncer<W>,
+    _shards: Vec<Shard<W>>,
+}
+
+impl<W: Watcher> Debouncers<W> {
+    /// A single debouncer watching `path`
+    pub fn single<F: DebounceEventHandler>(
+        path: &Path,
+        mode: RecursiveMode,
+        config: Config,
+        tx: F,
+    ) -> Result<Self, Error> {
+        let mut root = new_debouncer_opt(config, tx)?;
+        root.watcher().watch(path, mode)?;
+        Ok(Self {
+            _root: root,
+            _shards: Vec::new(),
+        })
+    }
+
+    /// Spread a recursive watch of `path` over `shards` debouncers, each watching some of
+    /// its top-level subdirectories, while another watches `path` itself non-recursively
+    /// and hands directories created later to the shards round-robin
+    pub fn sharded<F: DebounceEventHandler + Clone>(
+        path: &Path,
+        shards: usize,
+        config: Config,
+        tx: F,
+    ) -> Result<Self, Error>
+    where
+        W: Send + 'static,
+    {
+        let shards = (0..shards)
+            .map(|_| {
+                Ok(Arc::new(Mutex::new(new_debouncer_opt(
+                    config.clone(),
+                    tx.clone(),
+                )?)))
+            })
+            .collect::<Result<Vec<Shard<W>>, Error>>()?;
+        let mut handler = ShardingHandler {
+            tx,
+            root: path.to_path_buf(),
+            shards: shards.clone(),
+            assigned: HashMap::new(),
+            next: 0,
+        };
+        for entry in std::fs::read_dir(path)?.flatten() {
+            if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+                handler.assign(&entry.path());
+            }
+        }
+        let mut root = new_debouncer_opt(config, handler)?;
+        root.watcher().watch(path, RecursiveMode::NonRecursive)?;
+        Ok(Self {
+            _root: root,
+            _shards: shards,
+        })
+    }
+}
+
+/// Forwards the root's events, first keeping the shards' watches in step with the
+/// top-level subdirectories
+struct ShardingHandler<F, W: Watcher> {
+    tx: F,
+    root: PathBuf,
+    shards: Vec<Shard<W>>,
+    /// Shard watching each top-level subdirectory
+    assigned: HashMap<PathBuf, usize>,
+    next: usize,
+}
+
+impl<F, W: Watcher> ShardingHandler<F, W> {
+    fn assign(&mut self, dir: &Path) {
+        let shard = self.next % self.shards.len();
+        let result = self.shards[shard]
+            .lock()
+            .unwrap()
+            .watcher()
+            .watch(dir, RecursiveMode::Recursive);
+        match result {
+            Ok(()) => {
+                tracing::debug!("Watching '{}' in shard {shard}", dir.display());
+                self.assigned.insert(dir.to_path_buf(), shard);
+                self.next += 1;
+            }
+            Err(e) => tracing::warn!("Unable to watch '{}': {e}", dir.display()),
+        }
+    }
+}
+
+impl<F: DebounceEventHandler, W: Watcher + Send + 'static> DebounceEventHandler
+    for ShardingHandler<F, W>
+{
+    fn handle_event(&mut self, event: DebounceEventResult) {
+        if let Ok(events) = &event {
+            for event in events {
+                if event.path.parent() != Some(self.root.as_path()) {
+                    continue;
+                }
+                let watched = self.assigned.contains_key(&event.path);
+                if event.path.is_dir() && !watched {
+                    self.assign(&event.path);
+                } else if !event.path.exists() && watched {
+                    // The OS drops the watch along with the directory
+                    self.assigned.remove(&event.path);
+                }
+            }
+        }
+        self.tx.handle_event(event);
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use notify_debouncer_mini::DebouncedEvent;
+use serde::Deserialize;
+
+use super::{metrics, AgentWatcher};
+
+/// File touched in each watched directory, never uploaded
+pub const CANARY: &str = ".s3sync-canary";
+const DEFAULT_INTERVAL: Duration = Duration::from_mins(5);
+const DEFAULT_TIMEOUT: Duration = Duration::from_mins(1);
+
+/// Periodic self-test of the file watchers, which can stop delivering events without
+/// reporting an error. A canary file is touched in every watched directory and a watcher
+/// whose canary event doesn't arrive is considered dead and restarted.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct WatchdogSettings {
+    /// How often each directory is probed, 5 minutes by default
+    #[serde(default, with = "humantime_serde")]
+    interval: Option<Duration>,
+    /// How long past its event window a canary's event may take, 1 minute by default
+    #[serde(default, with = "humantime_serde")]
+    timeout: Option<Duration>,
+}
+
+impl WatchdogSettings {
+    pub const fn new(interval: Duration) -> Self {
+        Self {
+            interval: Some(interval),
+            timeout: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Probe {
+    touched: Instant,
+    /// When the canary's event is overdue
+    deadline: Instant,
+    seen: bool,
+}
+
+/// Probes of the current set of watchers, which only filters out canary events when
+/// there are no settings
+#[derive(Debug)]
+pub struct Watchdog {
+    /// Probe interval and timeout
+    timing: Option<(Duration, Duration)>,
+    probes: Mutex<HashMap<PathBuf, Probe>>,
+}
+
+impl Watchdog {
+    #[must_use]
+    pub fn new(settings: Option<&WatchdogSettings>) -> Self {
+        Self {
+            timing: settings.map(|settings| {
+                (
+                    settings.interval.unwrap_or(DEFAULT_INTERVAL),
+                    settings.timeout.unwrap_or(DEFAULT_TIMEOUT),
+                )
+            }),
+            probes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Touch the canaries that are due, returning the watched directories whose last
+    /// canary event never arrived
+    pub fn check(&self, watchers: &[AgentWatcher]) -> Vec<PathBuf> {
+        let Some((interval, timeout)) = self.timing else {
+            return Vec::new();
+        };
+        let now = Instant::now();
+        let mut probes = self.probes.lock().unwrap();
+        let mut dead = Vec::new();
+        for watcher in watchers {
+            let dir = watcher.watch_path();
+            match probes.get(dir) {
+                Some(probe) if !probe.seen && now >= probe.deadline => {
+                    tracing::error!(
+                        "No event for the canary in '{}' after {}, the watcher looks dead",
+                        dir.display(),
+                        humantime::format_duration(timeout + watcher.settings.event_delay())
+                    );
+                    metrics::record_watcher_alive(dir, false);
+                    dead.push(dir.to_path_buf());
+                    continue;
+                }
+                Some(probe) if now < probe.touched + interval => continue,
+                _ => {}
+            }
+            let canary = dir.join(CANARY);
+            if let Err(e) = std::fs::write(&canary, chrono::Utc::now().to_rfc3339()) {
+                tracing::warn!("Unable to touch '{}': {e}", canary.display());
+                continue;
+            }
+            probes.insert(
+                dir.to_path_buf(),
+                Probe {
+                    touched: now,
+                    deadline: now + watcher.settings.event_delay() + timeout,
+                    seen: false,
+                },
+            );
+        }
+        drop(probes);
+        dead
+    }
+
+    /// Drop canary events, recording the probes they complete
+    pub fn filter(&self, events: &mut Vec<DebouncedEvent>) {
+        events.retain(|event| !self.observe(&event.path));
+    }
+
+    /// Record the event if `path` is a canary, returning whether it was one
+    fn observe(&self, path: &Path) -> bool {
+        if path.file_name().is_none_or(|name| name != CANARY) {
+            return false;
+        }
+        if let Some(dir) = path.parent() {
+            if let Some(probe) = self.probes.lock().unwrap().get_mut(dir) {
+                probe.seen = true;
+                metrics::record_watcher_alive(dir, true);
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    collections::VecDeque,
+    sync::{LazyLock, Mutex},
+};
+
+use axum::{response::Html, routing::get, Json, Router};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use super::{heartbeat, output::Lifecycle, Control};
+
+/// Transfers and errors kept for the page, each
+const RECENT: usize = 50;
+const PAGE: &str = include_str!("web_ui.html");
+
+static HISTORY: LazyLock<Mutex<History>> = LazyLock::new(Mutex::default);
+
+#[derive(Debug, Default)]
+struct History {
+    transfers: VecDeque<Transfer>,
+    errors: VecDeque<Failure>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct Transfer {
+    timestamp: DateTime<Utc>,
+    agent: String,
+    path: Option<String>,
+    bucket: String,
+    key: String,
+    bytes: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct Failure {
+    timestamp: DateTime<Utc>,
+    agent: String,
+    path: String,
+    error: String,
+}
+
+#[derive(Serialize, Debug)]
+struct Status {
+    host: String,
+    version: &'static str,
+    agents: Vec<AgentStatus>,
+    transfers: Vec<Transfer>,
+    errors: Vec<Failure>,
+}
+
+#[derive(Serialize, Debug)]
+struct AgentStatus {
+    name: String,
+    bucket: String,
+    prefix: String,
+    paused: bool,
+    queued: usize,
+    waiting_for_close: usize,
+    overdue: usize,
+    uploads: u64,
+    failures: u64,
+    last_upload: Option<DateTime<Utc>>,
+    last_failure: Option<DateTime<Utc>>,
+}
+
+fn push<T>(events: &mut VecDeque<T>, event: T) {
+    if events.len() == RECENT {
+        events.pop_back();
+    }
+    events.push_front(event);
+}
+
+/// Keep uploads and failures for the page, newest first
+pub fn record(agent: &str, lifecycle: &Lifecycle) {
+    match *lifecycle {
+        Lifecycle::Uploaded {
+            path,
+            bucket,
+            key,
+            bytes,
+            ..
+        } => {
+            let transfer = Transfer {
+                timestamp: Utc::now(),
+                agent: agent.to_string(),
+                path: path.map(String::from),
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                bytes,
+            };
+            push(&mut HISTORY.lock().unwrap().transfers, transfer);
+        }
+        Lifecycle::Failed { path, error } => {
+            let failure = Failure {
+                timestamp: Utc::now(),
+                agent: agent.to_string(),
+                path: path.to_string(),
+                error: error.to_string(),
+            };
+            push(&mut HISTORY.lock().unwrap().errors, failure);
+        }
+        _ => {}
+    }
+}
+
+/// The page at `/` and the `/status.json` it polls, served next to `/metrics`
+pub fn router(control: Control) -> Router {
+    Router::new()
+        .route("/", get(|| async { Html(PAGE) }))
+        .route(
+            "/status.json",
+            get(move || async move { Json(status(&control)) }),
+        )
+}
+
+fn status(control: &Control) -> Status {
+    let agents = control
+        .agents()
+        .into_iter()
+        .map(|agent| {
+            let stats = agent.stats.snapshot();
+            AgentStatus {
+                paused: agent.switches.paused(),
+                queued: agent.deferred.len(),
+                waiting_for_close: agent.open_files.paths().len(),
+                overdue: agent.deadlines.overdue_count(),
+                uploads: stats.uploads,
+                failures: stats.failures,
+                last_upload: stats.last_upload,
+                last_failure: stats.last_failure,
+                name: agent.name,
+                bucket: agent.bucket,
+                prefix: agent.prefix,
+            }
+        })
+        .collect();
+    let history = HISTORY.lock().unwrap();
+    Status {
+        host: heartbeat::hostname(),
+        version: env!("CARGO_PKG_VERSION"),
+        agents,
+        transfers: history.transfers.iter().cloned().collect(),
+        errors: history.errors.iter().cloned().collect(),
+    }
+}
@@ -0,0 +1,361 @@
+//! End-to-end tests running the `s3sync` binary against an S3-compatible endpoint, e.g.
+//! `docker run -p 4566:4566 localstack/localstack` or MinIO, set with
+//! `S3SYNC_TEST_ENDPOINT` (default `http://127.0.0.1:4566`). An IP endpoint keeps the SDK
+//! on path-style addressing, so buckets need no DNS entries.
+//!
+//! `cargo test --features integration-tests`
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use aws_sdk_s3::{
+    config::{BehaviorVersion, Credentials, Region},
+    Client,
+};
+
+const DEFAULT_ENDPOINT: &str = "http://127.0.0.1:4566";
+/// How long an expected change in the bucket or watched directory may take
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn endpoint() -> String {
+    std::env::var("S3SYNC_TEST_ENDPOINT").unwrap_or_else(|_| DEFAULT_ENDPOINT.to_string())
+}
+
+/// A fresh bucket and watched directory, removed afterwards
+struct Harness {
+    client: Client,
+    bucket: String,
+    dir: tempfile::TempDir,
+}
+
+impl Harness {
+    async fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let config = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .endpoint_url(endpoint())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .force_path_style(true)
+            .build();
+        let client = Client::from_conf(config);
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .subsec_nanos();
+        let bucket = format!(
+            "s3sync-test-{}-{nanos}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        client
+            .create_bucket()
+            .bucket(&bucket)
+            .send()
+            .await
+            .expect("create the test bucket, is the endpoint running?");
+        Self {
+            client,
+            bucket,
+            dir: tempfile::tempdir().unwrap(),
+        }
+    }
+
+    fn path(&self, relative: &str) -> PathBuf {
+        self.dir.path().join(relative)
+    }
+
+    fn write(&self, relative: &str, contents: &str) {
+        let path = self.path(relative);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    /// Watch the directory with `args` on top of the bucket and path, against `endpoint`
+    fn start_with_endpoint(&self, endpoint: &str, args: &[&str]) -> Daemon {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_s3sync"))
+            .arg("--bucket")
+            .arg(&self.bucket)
+            .arg("--path")
+            .arg(self.dir.path())
+            .args(["--window", "1s"])
+            .args(args)
+            .env("AWS_ENDPOINT_URL", endpoint)
+            .env("AWS_ACCESS_KEY_ID", "test")
+            .env("AWS_SECRET_ACCESS_KEY", "test")
+            .env("AWS_REGION", "us-east-1")
+            .env("RUST_LOG", "info")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let log = Arc::new(Mutex::new(String::new()));
+        let stdout = child.stdout.take().unwrap();
+        let lines = Arc::clone(&log);
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                let mut log = lines.lock().unwrap();
+                log.push_str(&line);
+                log.push('\n');
+            }
+        });
+        let daemon = Daemon { child, log };
+        wait_for("the watchers to start", || {
+            daemon.log.lock().unwrap().contains("Watching")
+        });
+        daemon
+    }
+
+    fn start(&self, args: &[&str]) -> Daemon {
+        self.start_with_endpoint(&endpoint(), args)
+    }
+
+    async fn object(&self, key: &str) -> Option<Vec<u8>> {
+        let object = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await
+            .ok()?;
+        Some(object.body.collect().await.ok()?.to_vec())
+    }
+
+    async fn wait_for_object(&self, key: &str) -> Vec<u8> {
+        let started = Instant::now();
+        loop {
+            if let Some(contents) = self.object(key).await {
+                return contents;
+            }
+            assert!(started.elapsed() < TIMEOUT, "'{key}' was never uploaded");
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+
+    async fn wait_for_deletion(&self, key: &str) {
+        let started = Instant::now();
+        while self.object(key).await.is_some() {
+            assert!(started.elapsed() < TIMEOUT, "'{key}' was never deleted");
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+    }
+}
+
+/// Running `s3sync`, killed when dropped
+struct Daemon {
+    child: Child,
+    log: Arc<Mutex<String>>,
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !done() {
+        assert!(started.elapsed() < TIMEOUT, "timed out waiting for {what}");
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn gone(path: &Path) -> bool {
+    !path.exists()
+}
+
+#[tokio::test]
+async fn uploads_new_and_modified_files() {
+    let harness = Harness::new().await;
+    let _daemon = harness.start(&["--recursive", "true"]);
+
+    harness.write("a.txt", "first");
+    harness.write("nested/b.txt", "nested");
+    assert_eq!(harness.wait_for_object("a.txt").await, b"first");
+    assert_eq!(harness.wait_for_object("nested/b.txt").await, b"nested");
+
+    harness.write("a.txt", "second");
+    let started = Instant::now();
+    while harness.object("a.txt").await.as_deref() != Some(b"second".as_slice()) {
+        assert!(
+            started.elapsed() < TIMEOUT,
+            "the modification was never uploaded"
+        );
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
+
+#[tokio::test]
+async fn applies_the_prefix_and_pattern() {
+    let harness = Harness::new().await;
+    let _daemon = harness.start(&["--prefix", "incoming/", "--pattern", r"\.csv$"]);
+
+    harness.write("skipped.txt", "no");
+    harness.write("report.csv", "yes");
+    assert_eq!(harness.wait_for_object("incoming/report.csv").await, b"yes");
+    assert_eq!(harness.object("incoming/skipped.txt").await, None);
+}
+
+#[tokio::test]
+async fn deletes_the_source_on_success() {
+    let harness = Harness::new().await;
+    let _daemon = harness.start(&["--on-success", "delete"]);
+
+    harness.write("a.txt", "contents");
+    assert_eq!(harness.wait_for_object("a.txt").await, b"contents");
+    let path = harness.path("a.txt");
+    wait_for("the source to be deleted", || gone(&path));
+}
+
+#[tokio::test]
+async fn moves_the_source_on_success() {
+    let harness = Harness::new().await;
+    let archive = tempfile::tempdir().unwrap();
+    let policy = format!("move:{}", archive.path().display());
+    let _daemon = harness.start(&["--on-success", &policy]);
+
+    harness.write("a.txt", "contents");
+    assert_eq!(harness.wait_for_object("a.txt").await, b"contents");
+    let moved = archive.path().join("a.txt");
+    wait_for("the source to be moved", || moved.exists());
+    assert!(gone(&harness.path("a.txt")));
+}
+
+#[tokio::test]
+async fn deletes_remote_objects_of_removed_files() {
+    let harness = Harness::new().await;
+    let _daemon = harness.start(&["--delete-remote", "true"]);
+
+    harness.write("a.txt", "contents");
+    harness.wait_for_object("a.txt").await;
+    std::fs::remove_file(harness.path("a.txt")).unwrap();
+    harness.wait_for_deletion("a.txt").await;
+}
+
+#[tokio::test]
+async fn only_walks_new_directories_when_polling() {
+    let harness = Harness::new().await;
+    let _daemon = harness.start(&[
+        "--recursive",
+        "true",
+        "--backend",
+        "poll",
+        "--poll-interval",
+        "500ms",
+    ]);
+
+    harness.write("sub/a.txt", "local");
+    harness.wait_for_object("sub/a.txt").await;
+    // Only a walk of `sub` would upload the local copy again
+    harness
+        .client
+        .put_object()
+        .bucket(&harness.bucket)
+        .key("sub/a.txt")
+        .body("remote".as_bytes().to_vec().into())
+        .send()
+        .await
+        .unwrap();
+    // Bumps the modification time of `sub`, which the poll backend reports
+    harness.write("sub/b.txt", "new");
+    harness.wait_for_object("sub/b.txt").await;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(
+        harness.object("sub/a.txt").await.as_deref(),
+        Some(b"remote".as_slice())
+    );
+}
+
+#[tokio::test]
+async fn keeps_remote_objects_by_default() {
+    let harness = Harness::new().await;
+    let _daemon = harness.start(&[]);
+
+    harness.write("a.txt", "contents");
+    harness.wait_for_object("a.txt").await;
+    std::fs::remove_file(harness.path("a.txt")).unwrap();
+    harness.write("b.txt", "later");
+    harness.wait_for_object("b.txt").await;
+    assert_eq!(
+        harness.object("a.txt").await.as_deref(),
+        Some(b"contents".as_slice())
+    );
+}
+
+#[tokio::test]
+async fn retries_dropped_connections() {
+    let harness = Harness::new().await;
+    let proxy = FlakyProxy::start(2);
+    let _daemon = harness.start_with_endpoint(&proxy.endpoint, &[]);
+
+    harness.write("a.txt", "contents");
+    assert_eq!(harness.wait_for_object("a.txt").await, b"contents");
+    assert!(proxy.dropped.load(Ordering::Relaxed) >= 2);
+}
+
+/// Forwards to the endpoint, closing the first connections without a response
+struct FlakyProxy {
+    endpoint: String,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl FlakyProxy {
+    fn start(drop_first: usize) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let upstream = endpoint_address();
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let count = Arc::clone(&dropped);
+        std::thread::spawn(move || {
+            for client in listener.incoming().map_while(Result::ok) {
+                if count.load(Ordering::Relaxed) < drop_first {
+                    count.fetch_add(1, Ordering::Relaxed);
+                    // Wait for the request so it fails mid-exchange rather than on connect
+                    let _ = (&client).read(&mut [0; 1024]);
+                    let _ = client.shutdown(Shutdown::Both);
+                    continue;
+                }
+                let Ok(server) = TcpStream::connect(&upstream) else {
+                    continue;
+                };
+                for (mut from, mut to) in [
+                    (client.try_clone().unwrap(), server.try_clone().unwrap()),
+                    (server, client),
+                ] {
+                    std::thread::spawn(move || {
+                        let _ = std::io::copy(&mut from, &mut to);
+                        let _ = to.flush();
+                        let _ = to.shutdown(Shutdown::Write);
+                    });
+                }
+            }
+        });
+        Self { endpoint, dropped }
+    }
+}
+
+/// `host:port` of the endpoint
+fn endpoint_address() -> String {
+    let endpoint = endpoint();
+    let authority = endpoint
+        .split_once("://")
+        .map_or(endpoint.as_str(), |(_, rest)| rest)
+        .trim_end_matches('/');
+    if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{authority}:80")
+    }
+}